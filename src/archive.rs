@@ -0,0 +1,416 @@
+use std::io::{self, BufWriter, Read, Seek as _, Write};
+use std::path::{Component, Path};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_graphql::Enum;
+use axum::body::{Body, Bytes};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::runtime::Handle;
+use tokio::sync::mpsc;
+use tracing::{error, warn};
+use zip::write::SimpleFileOptions;
+
+use crate::clients::{ClientError, TiledClient};
+
+/// Number of chunks that can be buffered before the archive writer waits for the client
+const CHANNEL_CAPACITY: usize = 16;
+const WRITE_BUFFER_SIZE: usize = 64 * 1024;
+
+#[derive(Enum, Debug, Default, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveFormat {
+    #[default]
+    Tar,
+    Zip,
+}
+
+impl ArchiveFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Tar => "tar",
+            ArchiveFormat::Zip => "zip",
+        }
+    }
+    fn content_type(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Tar => "application/x-tar",
+            ArchiveFormat::Zip => "application/zip",
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ArchiveParams {
+    #[serde(default)]
+    pub format: ArchiveFormat,
+}
+
+/// The asset being archived and everything needed to request its contents from tiled
+struct Source {
+    client: TiledClient,
+    run: String,
+    stream: String,
+    det: String,
+    id: u32,
+    headers: Option<HeaderMap>,
+}
+
+/// Stream every file in a directory asset to the client as a single archive
+///
+/// The directory listing is fetched before the response is started so that missing assets and
+/// permission errors can be returned with the appropriate status. Files are then fetched from
+/// tiled one at a time and written to the archive as they are received so that the full directory
+/// is never held in memory.
+pub async fn archive_response(
+    client: TiledClient,
+    (run, stream, det, id): (String, String, String, u32),
+    headers: Option<HeaderMap>,
+    format: ArchiveFormat,
+) -> (StatusCode, HeaderMap, Body) {
+    let manifest = match client
+        .asset_manifest(&run, &stream, &det, id, headers.clone())
+        .await
+    {
+        Ok(manifest) => manifest,
+        Err(err) => return manifest_error(err),
+    };
+    // Paths are used as archive entry names so anything that could be extracted outside of the
+    // target directory is refused rather than trusting tiled's listing
+    if let Some(path) = manifest.iter().find(|path| !is_relative_path(path)) {
+        error!("Refusing to archive unsafe path {path:?} in directory asset");
+        return (
+            StatusCode::BAD_GATEWAY,
+            HeaderMap::new(),
+            json!({"detail": "Directory contains an invalid path"})
+                .to_string()
+                .into(),
+        );
+    }
+
+    let mut response_headers = HeaderMap::new();
+    response_headers.insert(
+        "content-type",
+        HeaderValue::from_static(format.content_type()),
+    );
+    let disposition = format!(
+        r#"attachment; filename="{det}-{id}.{}""#,
+        format.extension()
+    );
+    if let Ok(disposition) = HeaderValue::from_str(&disposition) {
+        response_headers.insert("content-disposition", disposition);
+    }
+
+    let (tx, mut rx) = mpsc::channel(CHANNEL_CAPACITY);
+    let source = Source {
+        client,
+        run,
+        stream,
+        det,
+        id,
+        headers,
+    };
+    let handle = Handle::current();
+    tokio::task::spawn_blocking(move || {
+        let writer = BufWriter::with_capacity(WRITE_BUFFER_SIZE, ChannelWriter(tx.clone()));
+        let result = match format {
+            ArchiveFormat::Tar => write_tar(writer, &handle, &source, &manifest),
+            ArchiveFormat::Zip => write_zip(writer, &handle, &source, &manifest),
+        };
+        if let Err(err) = result {
+            if err.kind() == io::ErrorKind::BrokenPipe {
+                warn!("Client disconnected while downloading archive");
+            } else {
+                error!("Error writing archive: {err}");
+                // Abort the response so the client does not receive a truncated archive as if
+                // it were complete
+                let _ = tx.blocking_send(Err(err));
+            }
+        }
+    });
+
+    let body = futures_util::stream::poll_fn(move |cx| rx.poll_recv(cx));
+    (StatusCode::OK, response_headers, Body::from_stream(body))
+}
+
+fn manifest_error(err: ClientError) -> (StatusCode, HeaderMap, Body) {
+    match err {
+        ClientError::TiledRequest(status, body) => (
+            StatusCode::from_u16(status).unwrap_or(StatusCode::BAD_REQUEST),
+            HeaderMap::new(),
+            body.into(),
+        ),
+        other => {
+            error!("Error listing directory asset: {other}");
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                HeaderMap::new(),
+                json!({"detail": "Unable to list directory contents"})
+                    .to_string()
+                    .into(),
+            )
+        }
+    }
+}
+
+/// Whether a path only contains normal components, ie is not absolute and never refers to a
+/// parent directory
+fn is_relative_path(path: &str) -> bool {
+    !path.is_empty()
+        && !path.contains('\\')
+        && Path::new(path)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+}
+
+fn write_tar<W: Write>(
+    writer: W,
+    handle: &Handle,
+    source: &Source,
+    manifest: &[String],
+) -> io::Result<()> {
+    let mtime = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let mut builder = tar::Builder::new(writer);
+    for path in manifest {
+        let mut file = open_file(handle, source, path)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(0o644);
+        header.set_mtime(mtime);
+        match file.length {
+            Some(len) => {
+                header.set_size(len);
+                builder.append_data(&mut header, path, file)?;
+            }
+            None => {
+                // Tar headers need the size of the file in advance so spool it to disk first
+                // rather than holding a potentially large file in memory
+                let mut spool = tempfile::tempfile()?;
+                let len = io::copy(&mut file, &mut spool)?;
+                spool.rewind()?;
+                header.set_size(len);
+                builder.append_data(&mut header, path, spool)?;
+            }
+        }
+    }
+    builder.into_inner()?.flush()
+}
+
+fn write_zip<W: Write>(
+    writer: W,
+    handle: &Handle,
+    source: &Source,
+    manifest: &[String],
+) -> io::Result<()> {
+    // Detector data is rarely compressible enough to be worth the CPU time
+    let options = SimpleFileOptions::default()
+        .compression_method(zip::CompressionMethod::Stored)
+        .large_file(true);
+    let mut zip = zip::ZipWriter::new_stream(writer);
+    for path in manifest {
+        let mut file = open_file(handle, source, path)?;
+        zip.start_file(path.as_str(), options)
+            .map_err(io::Error::other)?;
+        io::copy(&mut file, &mut zip)?;
+    }
+    zip.finish().map_err(io::Error::other)?.flush()
+}
+
+fn open_file(handle: &Handle, source: &Source, path: &str) -> io::Result<BlockingResponse> {
+    let response = handle
+        .block_on(source.client.download(
+            &source.run,
+            &source.stream,
+            &source.det,
+            source.id,
+            Some(path),
+            source.headers.clone(),
+        ))
+        .and_then(|response| Ok(response.error_for_status()?))
+        .map_err(io::Error::other)?;
+    Ok(BlockingResponse {
+        handle: handle.clone(),
+        length: response.content_length(),
+        response,
+        buffer: Bytes::new(),
+    })
+}
+
+/// Synchronous reader over the body of a response for use with the archive writers
+struct BlockingResponse {
+    handle: Handle,
+    length: Option<u64>,
+    response: reqwest::Response,
+    buffer: Bytes,
+}
+
+impl Read for BlockingResponse {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.buffer.is_empty() {
+            match self.handle.block_on(self.response.chunk()) {
+                Ok(Some(chunk)) => self.buffer = chunk,
+                Ok(None) => return Ok(0),
+                Err(err) => return Err(io::Error::other(err)),
+            }
+        }
+        let len = buf.len().min(self.buffer.len());
+        buf[..len].copy_from_slice(&self.buffer.split_to(len));
+        Ok(len)
+    }
+}
+
+/// Writer that passes everything written to it on to the response body
+struct ChannelWriter(mpsc::Sender<io::Result<Bytes>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .blocking_send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Response body was dropped"))?;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use axum::http::StatusCode;
+    use http_body_util::BodyExt as _;
+    use httpmock::MockServer;
+    use serde_json::json;
+
+    use super::{ArchiveFormat, archive_response, is_relative_path};
+    use crate::clients::TiledClient;
+
+    fn mock_directory(server: &MockServer) {
+        server.mock(|when, then| {
+            when.method("GET")
+                .path("/api/v1/asset/manifest/run/primary/det")
+                .query_param("id", "4");
+            then.status(200)
+                .json_body(json!({"manifest": ["a.tiff", "nested/b.tiff"]}));
+        });
+        for (path, content) in [("a.tiff", "first file"), ("nested/b.tiff", "second")] {
+            server.mock(|when, then| {
+                when.method("GET")
+                    .path("/api/v1/asset/bytes/run/primary/det")
+                    .query_param("id", "4")
+                    .query_param("relative_path", path);
+                then.status(200).body(content);
+            });
+        }
+    }
+
+    fn key() -> (String, String, String, u32) {
+        ("run".into(), "primary".into(), "det".into(), 4)
+    }
+
+    #[tokio::test]
+    async fn tar_archive() {
+        let server = MockServer::start();
+        mock_directory(&server);
+        let client = TiledClient::for_mock_server(&server);
+        let (status, headers, body) =
+            archive_response(client, key(), None, ArchiveFormat::Tar).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers["content-type"], "application/x-tar");
+        assert_eq!(
+            headers["content-disposition"],
+            r#"attachment; filename="det-4.tar""#
+        );
+        let bytes = body.collect().await.unwrap().to_bytes();
+
+        let mut archive = tar::Archive::new(bytes.as_ref());
+        let files = archive
+            .entries()
+            .unwrap()
+            .map(|e| {
+                let mut entry = e.unwrap();
+                let mut content = String::new();
+                entry.read_to_string(&mut content).unwrap();
+                (entry.path().unwrap().display().to_string(), content)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            files,
+            [
+                ("a.tiff".into(), "first file".into()),
+                ("nested/b.tiff".into(), "second".into())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn zip_archive() {
+        let server = MockServer::start();
+        mock_directory(&server);
+        let client = TiledClient::for_mock_server(&server);
+        let (status, headers, body) =
+            archive_response(client, key(), None, ArchiveFormat::Zip).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers["content-type"], "application/zip");
+        let bytes = body.collect().await.unwrap().to_bytes();
+
+        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(bytes)).unwrap();
+        assert_eq!(archive.len(), 2);
+        let mut content = String::new();
+        archive
+            .by_name("nested/b.tiff")
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "second");
+    }
+
+    #[test]
+    fn relative_paths() {
+        assert!(is_relative_path("a.tiff"));
+        assert!(is_relative_path("nested/b.tiff"));
+        for path in [
+            "",
+            "/etc/passwd",
+            "../a.tiff",
+            "nested/../../a.tiff",
+            "..\\a.tiff",
+        ] {
+            assert!(!is_relative_path(path), "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn unsafe_manifest() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method("GET")
+                .path("/api/v1/asset/manifest/run/primary/det");
+            then.status(200)
+                .json_body(json!({"manifest": ["a.tiff", "../../etc/passwd"]}));
+        });
+        let client = TiledClient::for_mock_server(&server);
+        let (status, _, _) = archive_response(client, key(), None, ArchiveFormat::Zip).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn missing_directory() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method("GET")
+                .path("/api/v1/asset/manifest/run/primary/det");
+            then.status(404).body(r#"{"detail": "No such asset"}"#);
+        });
+        let client = TiledClient::for_mock_server(&server);
+        let (status, _, body) = archive_response(client, key(), None, ArchiveFormat::Tar).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(
+            body.collect().await.unwrap().to_bytes(),
+            r#"{"detail": "No such asset"}"#
+        );
+    }
+}
@@ -0,0 +1,473 @@
+//! Minimal writer for the Apache Arrow IPC file format, used to send tables to tiled. Only the
+//! column types that can be created through glazed are supported.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use base64::Engine as _;
+use base64::engine::general_purpose::STANDARD;
+use serde_json::Value;
+
+use crate::model::table::ColumnType;
+
+/// Media type of Arrow IPC files
+pub const ARROW_FILE: &str = "application/vnd.apache.arrow.file";
+
+const MAGIC: &[u8] = b"ARROW1";
+const CONTINUATION: u32 = 0xFFFF_FFFF;
+/// Metadata version V5
+const VERSION: i16 = 4;
+
+// Message header and type union discriminants from the Arrow flatbuffer schema
+const SCHEMA: u8 = 1;
+const RECORD_BATCH: u8 = 3;
+const INT: u8 = 2;
+const FLOATING_POINT: u8 = 3;
+const UTF8: u8 = 5;
+const BOOL: u8 = 6;
+const DOUBLE: i16 = 2;
+
+/// A value in a row does not match the type of its column
+#[derive(Debug, PartialEq)]
+pub struct InvalidValue {
+    pub column: String,
+    pub row: usize,
+    pub expected: ColumnType,
+}
+
+impl fmt::Display for InvalidValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Value in row {} of column '{}' is not {:?}",
+            self.row, self.column, self.expected
+        )
+    }
+}
+
+/// The schema of a table as an encapsulated IPC message in a data URI, as used for the
+/// `arrow_schema` of tiled's table structures
+pub fn schema_uri(columns: &[(String, ColumnType)]) -> String {
+    let message = message(SCHEMA, schema(columns), 0);
+    format!("data:{ARROW_FILE};base64,{}", STANDARD.encode(message))
+}
+
+/// Encode the rows of a table as an Arrow IPC file containing a single record batch. Columns
+/// missing from the rows are filled with nulls.
+pub fn table_file(
+    columns: &[(String, ColumnType)],
+    rows: &HashMap<String, Vec<Value>>,
+) -> Result<Vec<u8>, InvalidValue> {
+    let length = rows.values().map(Vec::len).max().unwrap_or_default();
+    let mut body = Vec::new();
+    let mut nodes = Vec::new();
+    let mut buffers = Vec::new();
+    let mut buffer_count = 0;
+    for (name, kind) in columns {
+        let values = rows.get(name).map(Vec::as_slice).unwrap_or_default();
+        let value = |row: usize| values.get(row).unwrap_or(&Value::Null);
+        let invalid = |row| InvalidValue {
+            column: name.clone(),
+            row,
+            expected: *kind,
+        };
+        let valid = (0..length)
+            .map(|row| !value(row).is_null())
+            .collect::<Vec<_>>();
+        let nulls = valid.iter().filter(|v| !**v).count();
+        let mut column = vec![if nulls > 0 { bitmap(&valid) } else { vec![] }];
+        match kind {
+            ColumnType::Int => column.push(
+                (0..length)
+                    .map(|row| match value(row) {
+                        Value::Null => Ok(0),
+                        v => v.as_i64().ok_or_else(|| invalid(row)),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?
+                    .iter()
+                    .flat_map(|v| v.to_le_bytes())
+                    .collect(),
+            ),
+            ColumnType::Float => column.push(
+                (0..length)
+                    .map(|row| match value(row) {
+                        Value::Null => Ok(0.0),
+                        v => v.as_f64().ok_or_else(|| invalid(row)),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?
+                    .iter()
+                    .flat_map(|v| v.to_le_bytes())
+                    .collect(),
+            ),
+            ColumnType::Boolean => column.push(bitmap(
+                &(0..length)
+                    .map(|row| match value(row) {
+                        Value::Null => Ok(false),
+                        v => v.as_bool().ok_or_else(|| invalid(row)),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?,
+            )),
+            ColumnType::String => {
+                let mut offsets = vec![0i32];
+                let mut data = Vec::new();
+                for row in 0..length {
+                    match value(row) {
+                        Value::Null => {}
+                        Value::String(s) => data.extend_from_slice(s.as_bytes()),
+                        _ => return Err(invalid(row)),
+                    }
+                    offsets.push(data.len() as i32);
+                }
+                column.push(offsets.iter().flat_map(|o| o.to_le_bytes()).collect());
+                column.push(data);
+            }
+        }
+        nodes.extend((length as i64).to_le_bytes());
+        nodes.extend((nulls as i64).to_le_bytes());
+        for buffer in column {
+            buffer_count += 1;
+            buffers.extend((body.len() as i64).to_le_bytes());
+            buffers.extend((buffer.len() as i64).to_le_bytes());
+            body.extend(buffer);
+            pad(&mut body, 8);
+        }
+    }
+    let batch = vec![
+        Some(Fb::I64(length as i64)),
+        Some(Fb::Structs(nodes, columns.len())),
+        Some(Fb::Structs(buffers, buffer_count)),
+    ];
+
+    let mut file = MAGIC.to_vec();
+    pad(&mut file, 8);
+    file.extend(message(SCHEMA, schema(columns), 0));
+    let offset = file.len();
+    let metadata = message(RECORD_BATCH, batch, body.len());
+    let mut block = (offset as i64).to_le_bytes().to_vec();
+    block.extend((metadata.len() as i32).to_le_bytes());
+    block.extend([0; 4]);
+    block.extend((body.len() as i64).to_le_bytes());
+    file.extend(metadata);
+    file.extend(body);
+    // End of stream marker
+    file.extend(CONTINUATION.to_le_bytes());
+    file.extend(0u32.to_le_bytes());
+    let footer = finish(vec![
+        Some(Fb::I16(VERSION)),
+        Some(Fb::Table(schema(columns))),
+        Some(Fb::Structs(vec![], 0)),
+        Some(Fb::Structs(block, 1)),
+    ]);
+    file.extend(&footer);
+    file.extend((footer.len() as i32).to_le_bytes());
+    file.extend(MAGIC);
+    Ok(file)
+}
+
+fn schema(columns: &[(String, ColumnType)]) -> Vec<Option<Fb>> {
+    let fields = columns
+        .iter()
+        .map(|(name, kind)| {
+            let (type_id, type_table) = match kind {
+                ColumnType::Int => (INT, vec![Some(Fb::I32(64)), Some(Fb::Bool(true))]),
+                ColumnType::Float => (FLOATING_POINT, vec![Some(Fb::I16(DOUBLE))]),
+                ColumnType::String => (UTF8, vec![]),
+                ColumnType::Boolean => (BOOL, vec![]),
+            };
+            vec![
+                Some(Fb::Str(name.clone())),
+                Some(Fb::Bool(true)),
+                Some(Fb::U8(type_id)),
+                Some(Fb::Table(type_table)),
+                None,
+                Some(Fb::Tables(vec![])),
+            ]
+        })
+        .collect();
+    vec![Some(Fb::I16(0)), Some(Fb::Tables(fields))]
+}
+
+/// An encapsulated IPC message: continuation marker, metadata length and the flatbuffer
+/// `Message`, padded to a multiple of 8 bytes. The body is not included.
+fn message(header_type: u8, header: Vec<Option<Fb>>, body_length: usize) -> Vec<u8> {
+    let mut metadata = finish(vec![
+        Some(Fb::I16(VERSION)),
+        Some(Fb::U8(header_type)),
+        Some(Fb::Table(header)),
+        Some(Fb::I64(body_length as i64)),
+    ]);
+    pad(&mut metadata, 8);
+    let mut message = CONTINUATION.to_le_bytes().to_vec();
+    message.extend((metadata.len() as i32).to_le_bytes());
+    message.extend(metadata);
+    message
+}
+
+/// Bit-packed booleans, least significant bit first
+fn bitmap(values: &[bool]) -> Vec<u8> {
+    let mut bytes = vec![0u8; values.len().div_ceil(8)];
+    for (i, _) in values.iter().enumerate().filter(|(_, v)| **v) {
+        bytes[i / 8] |= 1 << (i % 8);
+    }
+    bytes
+}
+
+fn pad(buffer: &mut Vec<u8>, alignment: usize) {
+    buffer.resize(buffer.len().next_multiple_of(alignment), 0);
+}
+
+/// Field of a flatbuffer table. Tables are lists of fields indexed by their ID.
+enum Fb {
+    Bool(bool),
+    U8(u8),
+    I16(i16),
+    I32(i32),
+    I64(i64),
+    Str(String),
+    Table(Vec<Option<Fb>>),
+    Tables(Vec<Vec<Option<Fb>>>),
+    /// Vector of structs as their raw bytes (all 8 byte aligned) and the number of structs
+    Structs(Vec<u8>, usize),
+}
+
+impl Fb {
+    /// Size and alignment of the field when stored in its table
+    fn inline_size(&self) -> usize {
+        match self {
+            Fb::Bool(_) | Fb::U8(_) => 1,
+            Fb::I16(_) => 2,
+            Fb::I64(_) => 8,
+            Fb::I32(_) | Fb::Str(_) | Fb::Table(_) | Fb::Tables(_) | Fb::Structs(..) => 4,
+        }
+    }
+}
+
+/// Build a flatbuffer with the given root table. The buffer is written front to back with each
+/// table's vtable before it and its children after it, so all offsets to children are forwards.
+fn finish(root: Vec<Option<Fb>>) -> Vec<u8> {
+    let mut buffer = vec![0; 4];
+    let position = write_table(&mut buffer, &root);
+    patch_offset(&mut buffer, 0, position);
+    buffer
+}
+
+fn patch_offset(buffer: &mut [u8], at: usize, target: usize) {
+    buffer[at..at + 4].copy_from_slice(&((target - at) as u32).to_le_bytes());
+}
+
+fn write_table(buffer: &mut Vec<u8>, fields: &[Option<Fb>]) -> usize {
+    // Offset of each field from the start of the table, after the vtable offset
+    let mut size = 4usize;
+    let offsets = fields
+        .iter()
+        .map(|field| {
+            field.as_ref().map_or(0, |field| {
+                size = size.next_multiple_of(field.inline_size());
+                let offset = size;
+                size += field.inline_size();
+                offset
+            })
+        })
+        .collect::<Vec<_>>();
+
+    pad(buffer, 2);
+    let vtable = buffer.len();
+    buffer.extend((4 + 2 * fields.len() as u16).to_le_bytes());
+    buffer.extend((size as u16).to_le_bytes());
+    for offset in &offsets {
+        buffer.extend((*offset as u16).to_le_bytes());
+    }
+
+    pad(buffer, 8);
+    let table = buffer.len();
+    buffer.extend(((table - vtable) as i32).to_le_bytes());
+    let mut children = Vec::new();
+    for (field, offset) in fields.iter().zip(offsets) {
+        let Some(field) = field else {
+            continue;
+        };
+        buffer.resize(table + offset, 0);
+        match field {
+            Fb::Bool(v) => buffer.push(*v as u8),
+            Fb::U8(v) => buffer.push(*v),
+            Fb::I16(v) => buffer.extend(v.to_le_bytes()),
+            Fb::I32(v) => buffer.extend(v.to_le_bytes()),
+            Fb::I64(v) => buffer.extend(v.to_le_bytes()),
+            child => {
+                children.push((table + offset, child));
+                buffer.extend([0; 4]);
+            }
+        }
+    }
+    for (at, child) in children {
+        let position = write_child(buffer, child);
+        patch_offset(buffer, at, position);
+    }
+    table
+}
+
+fn write_child(buffer: &mut Vec<u8>, child: &Fb) -> usize {
+    match child {
+        Fb::Table(fields) => write_table(buffer, fields),
+        Fb::Str(s) => {
+            pad(buffer, 4);
+            let position = buffer.len();
+            buffer.extend((s.len() as u32).to_le_bytes());
+            buffer.extend(s.as_bytes());
+            buffer.push(0);
+            position
+        }
+        Fb::Tables(tables) => {
+            pad(buffer, 4);
+            let position = buffer.len();
+            buffer.extend((tables.len() as u32).to_le_bytes());
+            buffer.resize(position + 4 + 4 * tables.len(), 0);
+            for (i, table) in tables.iter().enumerate() {
+                let table = write_table(buffer, table);
+                patch_offset(buffer, position + 4 + 4 * i, table);
+            }
+            position
+        }
+        Fb::Structs(bytes, count) => {
+            // The structs themselves have to be 8 byte aligned, after the length
+            pad(buffer, 8);
+            buffer.extend([0; 4]);
+            let position = buffer.len();
+            buffer.extend((*count as u32).to_le_bytes());
+            buffer.extend(bytes);
+            position
+        }
+        Fb::Bool(_) | Fb::U8(_) | Fb::I16(_) | Fb::I32(_) | Fb::I64(_) => {
+            unreachable!("Scalars are stored inline")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::Cursor;
+
+    use arrow_array::{Array, BooleanArray, Float64Array, Int64Array, StringArray};
+    use arrow_ipc::reader::FileReader;
+    use base64::Engine as _;
+    use base64::engine::general_purpose::STANDARD;
+    use serde_json::json;
+
+    use super::{InvalidValue, bitmap, schema_uri, table_file};
+    use crate::model::table::ColumnType;
+
+    fn columns() -> Vec<(String, ColumnType)> {
+        vec![
+            ("id".into(), ColumnType::Int),
+            ("x".into(), ColumnType::Float),
+            ("label".into(), ColumnType::String),
+            ("good".into(), ColumnType::Boolean),
+        ]
+    }
+
+    #[test]
+    fn bitmaps() {
+        assert_eq!(bitmap(&[true, false, true]), [0b101]);
+        assert_eq!(
+            bitmap(&[false, false, false, false, false, false, false, false, true]),
+            [0, 1]
+        );
+    }
+
+    #[test]
+    fn file_layout() {
+        let columns = [("x".to_string(), ColumnType::Float)];
+        let rows = HashMap::from([("x".to_string(), vec![json!(1.5), json!(null)])]);
+        let file = table_file(&columns, &rows).unwrap();
+        assert!(file.starts_with(b"ARROW1\0\0"));
+        assert!(file.ends_with(b"ARROW1"));
+        // The schema message follows the magic, before the record batch
+        assert_eq!(file[8..12], [0xFF; 4]);
+        let footer = i32::from_le_bytes(file[file.len() - 10..file.len() - 6].try_into().unwrap());
+        assert_eq!((file.len() - 10 - footer as usize) % 8, 0);
+    }
+
+    #[test]
+    fn read_by_arrow() {
+        let rows = HashMap::from([
+            ("id".to_string(), vec![json!(1), json!(-2), json!(null)]),
+            ("x".to_string(), vec![json!(1.5), json!(null), json!(3)]),
+            (
+                "label".to_string(),
+                vec![json!("a"), json!(null), json!("ccc")],
+            ),
+        ]);
+        let file = table_file(&columns(), &rows).unwrap();
+        let reader = FileReader::try_new(Cursor::new(file), None).unwrap();
+        let names = reader
+            .schema()
+            .fields()
+            .iter()
+            .map(|f| f.name().clone())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["id", "x", "label", "good"]);
+        let batches = reader.collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(batches.len(), 1);
+        let batch = &batches[0];
+        assert_eq!(batch.num_rows(), 3);
+        let column = |name| batch.column_by_name(name).unwrap();
+        let ids = column("id").as_any().downcast_ref::<Int64Array>().unwrap();
+        assert_eq!(ids.iter().collect::<Vec<_>>(), [Some(1), Some(-2), None]);
+        let xs = column("x").as_any().downcast_ref::<Float64Array>().unwrap();
+        assert_eq!(xs.iter().collect::<Vec<_>>(), [Some(1.5), None, Some(3.0)]);
+        let labels = column("label")
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        assert_eq!(
+            labels.iter().collect::<Vec<_>>(),
+            [Some("a"), None, Some("ccc")]
+        );
+        let good = column("good")
+            .as_any()
+            .downcast_ref::<BooleanArray>()
+            .unwrap();
+        assert_eq!(good.null_count(), 3);
+    }
+
+    #[test]
+    fn schema_read_by_arrow() {
+        let uri = schema_uri(&columns());
+        let encoded = uri
+            .strip_prefix("data:application/vnd.apache.arrow.file;base64,")
+            .unwrap();
+        let message = STANDARD.decode(encoded).unwrap();
+        let length = i32::from_le_bytes(message[4..8].try_into().unwrap()) as usize;
+        let message = arrow_ipc::root_as_message(&message[8..8 + length]).unwrap();
+        let schema = arrow_ipc::convert::fb_to_schema(message.header_as_schema().unwrap());
+        let fields = schema
+            .fields()
+            .iter()
+            .map(|f| (f.name().as_str(), f.data_type().to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            fields,
+            [
+                ("id", "Int64".to_string()),
+                ("x", "Float64".to_string()),
+                ("label", "Utf8".to_string()),
+                ("good", "Boolean".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_values() {
+        let columns = [("name".to_string(), ColumnType::String)];
+        let rows = HashMap::from([("name".to_string(), vec![json!("a"), json!(2)])]);
+        assert_eq!(
+            table_file(&columns, &rows),
+            Err(InvalidValue {
+                column: "name".into(),
+                row: 1,
+                expected: ColumnType::String
+            })
+        );
+    }
+}
@@ -0,0 +1,456 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use axum::body::{Body, BodyDataStream, Bytes};
+use axum::http::StatusCode;
+use futures_util::{Stream, StreamExt as _};
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use crate::clients::TiledClient;
+use crate::config::AuditConfig;
+use crate::handlers::AuthHeader;
+
+/// User recorded for credentials that tiled could not identify
+const UNVERIFIED: &str = "unverified";
+/// Maximum number of verified users remembered at once
+const VERIFIED_USERS: usize = 1000;
+/// How long credentials verified by tiled are trusted before being checked again
+const VERIFIED_TTL: Duration = Duration::from_secs(60);
+
+/// Record of a single download attempt, written as one line of JSON to the audit sink
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// Time the download was requested in milliseconds since the Unix epoch
+    pub timestamp: u64,
+    /// Name of the principal that tiled verified the credentials as, "anonymous" if there were no
+    /// credentials or "unverified" if tiled could not identify them
+    pub user: String,
+    /// Hash of the credentials used, so that downloads made with the same credentials can be
+    /// matched up even if they could not be verified
+    pub credential: Option<String>,
+    pub run: String,
+    pub stream: String,
+    pub detector: String,
+    pub asset_id: u32,
+    /// Path within a directory asset, the format of an archive of a directory asset or the
+    /// algorithm of a checksum computed from the asset
+    pub path: Option<String>,
+    pub bytes: u64,
+    /// HTTP status returned to the client
+    pub status: u16,
+    pub outcome: Outcome,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    /// The full response was sent to the client
+    Complete,
+    /// The client disconnected or the upstream response failed part way through
+    Aborted,
+    /// The download was refused before any request was made to tiled
+    Rejected,
+}
+
+impl AuditRecord {
+    pub fn new(
+        auth: Option<&AuthHeader>,
+        (run, stream, detector, asset_id): (&str, &str, &str, u32),
+        path: Option<String>,
+    ) -> Self {
+        Self {
+            timestamp: unix_millis(),
+            user: match auth {
+                Some(_) => UNVERIFIED.into(),
+                None => "anonymous".into(),
+            },
+            credential: auth.map(|auth| AuthHeader::user_key(Some(auth))),
+            run: run.into(),
+            stream: stream.into(),
+            detector: detector.into(),
+            asset_id,
+            path,
+            bytes: 0,
+            status: 0,
+            outcome: Outcome::Rejected,
+        }
+    }
+}
+
+/// The current time in milliseconds since the Unix epoch
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Destination for audit records. Records are discarded if auditing is not configured.
+#[derive(Clone, Default)]
+pub struct AuditLog(Option<Arc<AuditState>>);
+
+struct AuditState {
+    sink: Mutex<Sink>,
+    /// Names of the principals that tiled verified credentials as, keyed by a hash of the
+    /// credentials so that tiled is not asked on every download
+    users: Mutex<LruCache<String, (String, Instant)>>,
+}
+
+enum Sink {
+    Stdout,
+    File(RotatingFile),
+}
+
+impl AuditLog {
+    pub fn new(config: Option<&AuditConfig>) -> io::Result<Self> {
+        let sink = match config {
+            None => return Ok(Self(None)),
+            Some(AuditConfig::Stdout) => Sink::Stdout,
+            Some(AuditConfig::File {
+                path,
+                max_bytes,
+                max_files,
+            }) => Sink::File(RotatingFile::open(path.clone(), *max_bytes, *max_files)?),
+        };
+        let capacity = NonZeroUsize::new(VERIFIED_USERS).expect("Capacity is not zero");
+        Ok(Self(Some(Arc::new(AuditState {
+            sink: Mutex::new(sink),
+            users: Mutex::new(LruCache::new(capacity)),
+        }))))
+    }
+
+    /// Record the user as the principal that tiled verifies their credentials as. The user is
+    /// left as unverified if tiled can't identify them, and tiled is not asked at all if auditing
+    /// is not configured.
+    pub async fn identify(
+        &self,
+        record: &mut AuditRecord,
+        client: &TiledClient,
+        auth: Option<&AuthHeader>,
+    ) {
+        let (Some(state), Some(auth), Some(key)) = (&self.0, auth, &record.credential) else {
+            return;
+        };
+        if let Some((user, expires)) = state
+            .users
+            .lock()
+            .expect("Audit users lock poisoned")
+            .get(key)
+            && *expires > Instant::now()
+        {
+            record.user = user.clone();
+            return;
+        }
+        match client.whoami(auth.as_header_map()).await {
+            Ok(Some(principal)) => record.user = principal.name().into(),
+            Ok(None) => {}
+            Err(err) => {
+                warn!("Unable to verify user for audit record: {err}");
+                return;
+            }
+        }
+        state.users.lock().expect("Audit users lock poisoned").put(
+            key.clone(),
+            (record.user.clone(), Instant::now() + VERIFIED_TTL),
+        );
+    }
+
+    pub fn record(&self, record: &AuditRecord) {
+        let Some(state) = &self.0 else {
+            return;
+        };
+        let mut line = match serde_json::to_string(record) {
+            Ok(line) => line,
+            Err(err) => {
+                error!("Unable to serialize audit record {record:?}: {err}");
+                return;
+            }
+        };
+        line.push('\n');
+        let mut sink = state.sink.lock().expect("Audit log lock poisoned");
+        let result = match &mut *sink {
+            Sink::Stdout => io::stdout().lock().write_all(line.as_bytes()),
+            Sink::File(file) => file.write_line(line.as_bytes()),
+        };
+        if let Err(err) = result {
+            error!("Unable to write audit record {record:?}: {err}");
+        }
+    }
+
+    /// Count the bytes sent in the body and write the record once the body is finished with
+    pub fn track(&self, mut record: AuditRecord, status: StatusCode, body: Body) -> Body {
+        record.status = status.as_u16();
+        Body::from_stream(AuditedBody {
+            inner: body.into_data_stream(),
+            record: Some(record),
+            complete: false,
+            log: self.clone(),
+        })
+    }
+}
+
+/// Body wrapper that writes the audit record when it is dropped so that downloads abandoned by
+/// the client are recorded as well as completed ones
+struct AuditedBody {
+    inner: BodyDataStream,
+    record: Option<AuditRecord>,
+    complete: bool,
+    log: AuditLog,
+}
+
+impl Stream for AuditedBody {
+    type Item = Result<Bytes, axum::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let next = self.inner.poll_next_unpin(cx);
+        match &next {
+            Poll::Ready(Some(Ok(bytes))) => {
+                let len = bytes.len() as u64;
+                if let Some(record) = self.record.as_mut() {
+                    record.bytes += len;
+                }
+            }
+            Poll::Ready(None) => self.complete = true,
+            Poll::Ready(Some(Err(_))) | Poll::Pending => {}
+        }
+        next
+    }
+}
+
+impl Drop for AuditedBody {
+    fn drop(&mut self) {
+        if let Some(mut record) = self.record.take() {
+            record.outcome = if self.complete {
+                Outcome::Complete
+            } else {
+                Outcome::Aborted
+            };
+            self.log.record(&record);
+        }
+    }
+}
+
+/// Append only file that is rotated (file -> file.1 -> file.2 etc) when it reaches a maximum size
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_bytes: Option<u64>,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    pub fn open(path: PathBuf, max_bytes: Option<u64>, max_files: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file,
+            size,
+            max_bytes,
+            max_files,
+        })
+    }
+
+    pub fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        if self
+            .max_bytes
+            .is_some_and(|max| self.size > 0 && self.size + line.len() as u64 > max)
+        {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.file.flush()?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            // Removing the oldest file can fail if it doesn't exist yet which is fine
+            let _ = fs::remove_file(rotated(&self.path, self.max_files));
+            for n in (1..self.max_files).rev() {
+                let from = rotated(&self.path, n);
+                if from.exists() {
+                    fs::rename(from, rotated(&self.path, n + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated(&self.path, 1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{n}"));
+    name.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use axum::body::Body;
+    use axum::http::{HeaderValue, StatusCode};
+    use http_body_util::BodyExt as _;
+    use httpmock::MockServer;
+    use serde_json::json;
+
+    use super::{AuditLog, AuditRecord, Outcome};
+    use crate::clients::TiledClient;
+    use crate::config::AuditConfig;
+    use crate::handlers::AuthHeader;
+
+    fn read_records(path: &Path) -> Vec<AuditRecord> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    fn file_log(path: &Path, max_bytes: Option<u64>) -> AuditLog {
+        AuditLog::new(Some(&AuditConfig::File {
+            path: path.into(),
+            max_bytes,
+            max_files: 2,
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn completed_download_recorded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let log = file_log(&path, None);
+        let auth = AuthHeader::from(HeaderValue::from_static("Basic Ym9iOnBhc3N3b3Jk"));
+        let record = AuditRecord::new(Some(&auth), ("run", "primary", "det", 3), None);
+
+        let body = log.track(record, StatusCode::OK, Body::from("12345"));
+        assert_eq!(body.collect().await.unwrap().to_bytes(), "12345");
+
+        let records = read_records(&path);
+        assert_eq!(records.len(), 1);
+        let record = &records[0];
+        // The claimed username is never trusted
+        assert_eq!(record.user, "unverified");
+        assert_eq!(record.credential, Some(AuthHeader::user_key(Some(&auth))));
+        assert_eq!(
+            (
+                &*record.run,
+                &*record.stream,
+                &*record.detector,
+                record.asset_id
+            ),
+            ("run", "primary", "det", 3)
+        );
+        assert_eq!(record.bytes, 5);
+        assert_eq!(record.status, 200);
+        assert_eq!(record.outcome, Outcome::Complete);
+    }
+
+    #[tokio::test]
+    async fn user_verified_by_tiled() {
+        let server = MockServer::start();
+        let whoami = server
+            .mock_async(|when, then| {
+                when.method("GET")
+                    .path("/api/v1/auth/whoami")
+                    .header("authorization", "Apikey secret");
+                then.status(200).json_body(json!({
+                    "uuid": "alice-uuid",
+                    "type": "user",
+                    "identities": [{"id": "alice", "provider": "toy"}],
+                }));
+            })
+            .await;
+        let client = TiledClient::for_mock_server(&server);
+        let dir = tempfile::tempdir().unwrap();
+        let log = file_log(&dir.path().join("audit.jsonl"), None);
+        let auth = AuthHeader::from(HeaderValue::from_static("Apikey secret"));
+        for _ in 0..2 {
+            let mut record = AuditRecord::new(Some(&auth), ("run", "primary", "det", 3), None);
+            log.identify(&mut record, &client, Some(&auth)).await;
+            assert_eq!(record.user, "alice");
+        }
+        // Verified users are remembered
+        whoami.assert_calls(1);
+
+        let mut record = AuditRecord::new(None, ("run", "primary", "det", 3), None);
+        log.identify(&mut record, &client, None).await;
+        assert_eq!((&*record.user, record.credential), ("anonymous", None));
+    }
+
+    #[tokio::test]
+    async fn abandoned_download_recorded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let log = file_log(&path, None);
+        let record = AuditRecord::new(None, ("run", "primary", "det", 3), Some("a.tiff".into()));
+
+        drop(log.track(record, StatusCode::OK, Body::from("12345")));
+
+        let records = read_records(&path);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].user, "anonymous");
+        assert_eq!(records[0].path.as_deref(), Some("a.tiff"));
+        assert_eq!(records[0].bytes, 0);
+        assert_eq!(records[0].outcome, Outcome::Aborted);
+    }
+
+    #[test]
+    fn rejected_download_recorded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let log = file_log(&path, None);
+        let mut record = AuditRecord::new(None, ("run", "primary", "det", 3), None);
+        record.status = 429;
+        log.record(&record);
+
+        assert_eq!(read_records(&path), [record]);
+    }
+
+    #[test]
+    fn rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let log = file_log(&path, Some(10));
+        for id in 0..4 {
+            log.record(&AuditRecord::new(None, ("run", "primary", "det", id), None));
+        }
+        // Each record is larger than the limit so each is in its own file, and only two old
+        // files are kept
+        let ids = |p: &Path| {
+            read_records(p)
+                .iter()
+                .map(|r| r.asset_id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(&path), [3]);
+        assert_eq!(ids(&dir.path().join("audit.jsonl.1")), [2]);
+        assert_eq!(ids(&dir.path().join("audit.jsonl.2")), [1]);
+        assert!(!dir.path().join("audit.jsonl.3").exists());
+    }
+
+    #[test]
+    fn disabled_log() {
+        let log = AuditLog::new(None).unwrap();
+        log.record(&AuditRecord::new(None, ("run", "primary", "det", 3), None));
+    }
+}
@@ -0,0 +1,309 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::Arc;
+
+use reqwest::header::HeaderMap;
+
+use crate::clients::{ClientError, ClientResult, TiledClient};
+use crate::compat::UnsupportedVersion;
+use crate::config::GlazedConfig;
+
+/// Name of the backend configured by the top level `tiled_client` config
+pub const DEFAULT_BACKEND: &str = "default";
+
+/// A tiled server and the instruments whose data it serves
+#[derive(Clone)]
+pub struct Backend {
+    pub name: Arc<str>,
+    pub client: TiledClient,
+    instruments: Vec<String>,
+    session_prefixes: Vec<String>,
+}
+
+impl Backend {
+    pub fn is_default(&self) -> bool {
+        &*self.name == DEFAULT_BACKEND
+    }
+    /// The same backend, accessed using the service credentials for background tasks
+    pub fn as_service(&self) -> Self {
+        Self {
+            client: self.client.as_service(),
+            ..self.clone()
+        }
+    }
+}
+
+/// All the tiled servers available to glazed. The default backend is always first.
+#[derive(Clone)]
+pub struct Backends(Arc<[Backend]>);
+
+#[derive(Debug)]
+pub enum BackendError {
+    Client(String, ClientError),
+    DuplicateName(String),
+    Version(String, UnsupportedVersion),
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendError::Client(name, err) => {
+                write!(f, "Unable to create client for backend '{name}': {err}")
+            }
+            BackendError::DuplicateName(name) => write!(f, "Duplicate backend name: '{name}'"),
+            BackendError::Version(name, err) => write!(f, "Backend '{name}': {err}"),
+        }
+    }
+}
+
+impl std::error::Error for BackendError {}
+
+/// Returned when a request names a backend that is not configured
+#[derive(Debug)]
+pub struct UnknownBackend(pub String);
+
+impl fmt::Display for UnknownBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unknown backend: '{}'", self.0)
+    }
+}
+
+impl Backends {
+    pub fn from_config(config: &GlazedConfig) -> Result<Self, BackendError> {
+        let default = TiledClient::from_config(&config.tiled_client)
+            .map_err(|e| BackendError::Client(DEFAULT_BACKEND.into(), e))?;
+        let mut backends = vec![Backend {
+            name: DEFAULT_BACKEND.into(),
+            client: default,
+            instruments: vec![],
+            session_prefixes: vec![],
+        }];
+        let mut names = HashSet::from([DEFAULT_BACKEND]);
+        for backend in &config.backends {
+            if !names.insert(&backend.name) {
+                return Err(BackendError::DuplicateName(backend.name.clone()));
+            }
+            backends.push(Backend {
+                name: backend.name.as_str().into(),
+                client: TiledClient::from_config(&backend.tiled_client)
+                    .map_err(|e| BackendError::Client(backend.name.clone(), e))?,
+                instruments: backend.instruments.clone(),
+                session_prefixes: backend.session_prefixes.clone(),
+            });
+        }
+        Ok(Self(backends.into()))
+    }
+
+    /// Backends made up of only the given client
+    pub fn single(client: TiledClient) -> Self {
+        Self(
+            [Backend {
+                name: DEFAULT_BACKEND.into(),
+                client,
+                instruments: vec![],
+                session_prefixes: vec![],
+            }]
+            .into(),
+        )
+    }
+
+    /// Check the version of every backend's tiled server
+    pub async fn negotiate(&self) -> Result<(), BackendError> {
+        for backend in self.all() {
+            backend
+                .client
+                .negotiate()
+                .await
+                .map_err(|e| BackendError::Version(backend.name.to_string(), e))?;
+        }
+        Ok(())
+    }
+
+    pub fn all(&self) -> &[Backend] {
+        &self.0
+    }
+
+    pub fn default_backend(&self) -> &Backend {
+        &self.0[0]
+    }
+
+    pub fn get(&self, name: &str) -> Result<&Backend, UnknownBackend> {
+        self.0
+            .iter()
+            .find(|b| &*b.name == name)
+            .ok_or_else(|| UnknownBackend(name.into()))
+    }
+
+    /// Get the named backend or the default backend if no name is given
+    pub fn get_or_default(&self, name: Option<&str>) -> Result<&Backend, UnknownBackend> {
+        match name {
+            Some(name) => self.get(name),
+            None => Ok(self.default_backend()),
+        }
+    }
+
+    /// Find the backends that should be queried for a request
+    ///
+    /// An explicitly named backend is always used. Otherwise the backend that serves the given
+    /// instrument, or failing that the one whose session prefixes match the given instrument
+    /// session, is used. If none of those identify a backend, every backend is returned so that
+    /// the results from all of them can be merged.
+    pub fn route(
+        &self,
+        backend: Option<&str>,
+        instrument: Option<&str>,
+        session: Option<&str>,
+    ) -> Result<Vec<&Backend>, UnknownBackend> {
+        if let Some(name) = backend {
+            return Ok(vec![self.get(name)?]);
+        }
+        let by_instrument = instrument.and_then(|instrument| {
+            self.0
+                .iter()
+                .find(|b| b.instruments.iter().any(|i| i == instrument))
+        });
+        let by_session = || {
+            session.and_then(|session| {
+                self.0.iter().find(|b| {
+                    b.session_prefixes
+                        .iter()
+                        .any(|prefix| session.starts_with(prefix.as_str()))
+                })
+            })
+        };
+        Ok(match by_instrument.or_else(by_session) {
+            Some(backend) => vec![backend],
+            None => self.0.iter().collect(),
+        })
+    }
+}
+
+/// Find the first of the given backends that contains the node at the given path. Backends
+/// that don't let the user see the node are skipped, as it may be in one that does.
+pub async fn find_node<'a>(
+    backends: Vec<&'a Backend>,
+    path: &str,
+    headers: Option<HeaderMap>,
+) -> ClientResult<Option<&'a Backend>> {
+    for backend in backends {
+        match backend.client.metadata(path.into(), headers.clone()).await {
+            Ok(_) => return Ok(Some(backend)),
+            Err(ClientError::TiledRequest(401 | 403 | 404, _)) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use httpmock::MockServer;
+    use serde_json::json;
+
+    use super::{BackendError, Backends, find_node};
+    use crate::config::{BackendConfig, GlazedConfig, TiledClientConfig};
+
+    fn backend(name: &str, instruments: &[&str], prefixes: &[&str]) -> BackendConfig {
+        BackendConfig {
+            name: name.into(),
+            instruments: instruments.iter().map(|i| i.to_string()).collect(),
+            session_prefixes: prefixes.iter().map(|p| p.to_string()).collect(),
+            tiled_client: GlazedConfig::default().tiled_client,
+        }
+    }
+
+    fn backends() -> Backends {
+        Backends::from_config(&GlazedConfig {
+            backends: vec![
+                backend("saxs", &["i22", "b21"], &["sm"]),
+                backend("mx", &["i03"], &["mx"]),
+            ],
+            ..GlazedConfig::default()
+        })
+        .unwrap()
+    }
+
+    fn names(
+        backends: &Backends,
+        route: (Option<&str>, Option<&str>, Option<&str>),
+    ) -> Vec<String> {
+        backends
+            .route(route.0, route.1, route.2)
+            .unwrap()
+            .iter()
+            .map(|b| b.name.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn routing() {
+        let backends = backends();
+        assert_eq!(names(&backends, (Some("mx"), Some("i22"), None)), ["mx"]);
+        assert_eq!(
+            names(&backends, (None, Some("b21"), Some("mx1234-1"))),
+            ["saxs"]
+        );
+        assert_eq!(
+            names(&backends, (None, Some("p38"), Some("mx1234-1"))),
+            ["mx"]
+        );
+        assert_eq!(
+            names(&backends, (None, None, Some("cm1234-1"))),
+            ["default", "saxs", "mx"]
+        );
+        assert_eq!(
+            names(&backends, (None, None, None)),
+            ["default", "saxs", "mx"]
+        );
+        assert!(backends.route(Some("missing"), None, None).is_err());
+    }
+
+    #[test]
+    fn duplicate_names() {
+        let result = Backends::from_config(&GlazedConfig {
+            backends: vec![backend("default", &[], &[])],
+            ..GlazedConfig::default()
+        });
+        let Err(BackendError::DuplicateName(name)) = result else {
+            panic!("Expected duplicate name error");
+        };
+        assert_eq!(name, "default");
+    }
+
+    #[tokio::test]
+    async fn find_node_skips_forbidden_backends() {
+        let default = MockServer::start();
+        let other = MockServer::start();
+        default
+            .mock_async(|when, then| {
+                when.method("GET").path("/api/v1/metadata/run");
+                then.status(403).json_body(json!({"detail": "Not allowed"}));
+            })
+            .await;
+        other
+            .mock_async(|when, then| {
+                when.method("GET").path("/api/v1/metadata/run");
+                then.status(200)
+                    .body_from_file("resources/metadata_run.json");
+            })
+            .await;
+        let backends = Backends::from_config(&GlazedConfig {
+            tiled_client: TiledClientConfig {
+                address: default.base_url().parse().unwrap(),
+                ..GlazedConfig::default().tiled_client
+            },
+            backends: vec![BackendConfig {
+                tiled_client: TiledClientConfig {
+                    address: other.base_url().parse().unwrap(),
+                    ..GlazedConfig::default().tiled_client
+                },
+                ..backend("other", &[], &[])
+            }],
+            ..GlazedConfig::default()
+        })
+        .unwrap();
+        let routed = backends.route(None, None, None).unwrap();
+        let found = find_node(routed, "run", None).await.unwrap();
+        assert_eq!(found.map(|b| b.name.to_string()).as_deref(), Some("other"));
+    }
+}
@@ -0,0 +1,258 @@
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use lru::LruCache;
+use reqwest::header::{ACCEPT, AUTHORIZATION, HeaderValue};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::config::CacheConfig;
+use crate::credentials::X_FORWARDED_AUTHORIZATION;
+
+/// Cache of successful responses from tiled, keyed by the request URL and the credentials it was
+/// sent with so that one user is never given a response made for another, or for glazed itself.
+#[derive(Clone)]
+pub struct ResponseCache(Arc<CacheState>);
+
+struct CacheState {
+    config: CacheConfig,
+    entries: Mutex<Entries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct Entries {
+    lru: LruCache<CacheKey, CacheEntry>,
+    bytes: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    url: String,
+    auth: Option<HeaderValue>,
+    forwarded: Option<HeaderValue>,
+    accept: Option<HeaderValue>,
+}
+
+impl CacheKey {
+    /// The key of a request once its credentials have been set by the auth policy
+    pub fn for_request(request: &reqwest::Request) -> Self {
+        Self {
+            url: request.url().to_string(),
+            auth: request.headers().get(AUTHORIZATION).cloned(),
+            forwarded: request.headers().get(X_FORWARDED_AUTHORIZATION).cloned(),
+            accept: request.headers().get(ACCEPT).cloned(),
+        }
+    }
+}
+
+struct CacheEntry {
+    body: String,
+    expires: Instant,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+impl ResponseCache {
+    pub fn new(config: CacheConfig) -> Self {
+        let capacity = NonZeroUsize::new(config.max_entries).unwrap_or(NonZeroUsize::MIN);
+        Self(Arc::new(CacheState {
+            config,
+            entries: Mutex::new(Entries {
+                lru: LruCache::new(capacity),
+                bytes: 0,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }))
+    }
+
+    pub fn get(&self, key: &CacheKey) -> Option<String> {
+        let mut entries = self.0.entries.lock().expect("Cache lock poisoned");
+        let cached = match entries.lru.get(key) {
+            Some(entry) if entry.expires > Instant::now() => Some(entry.body.clone()),
+            Some(_) => {
+                if let Some(expired) = entries.lru.pop(key) {
+                    entries.bytes -= expired.body.len();
+                }
+                None
+            }
+            None => None,
+        };
+        let counter = match cached {
+            Some(_) => &self.0.hits,
+            None => &self.0.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        cached
+    }
+
+    /// Cache the body of a response. The time it is cached for depends on whether the data it
+    /// contains can still change.
+    pub fn insert(&self, key: CacheKey, body: String) {
+        if body.len() > self.0.config.max_bytes {
+            return;
+        }
+        let ttl = match serde_json::from_str(&body) {
+            Ok(json) if is_immutable(&json) => self.0.config.immutable_ttl,
+            _ => self.0.config.ttl,
+        };
+        if ttl == 0 {
+            return;
+        }
+        let entry = CacheEntry {
+            expires: Instant::now() + Duration::from_secs(ttl),
+            body,
+        };
+
+        let mut entries = self.0.entries.lock().expect("Cache lock poisoned");
+        entries.bytes += entry.body.len();
+        if let Some((_, replaced)) = entries.lru.push(key, entry) {
+            entries.bytes -= replaced.body.len();
+        }
+        while entries.bytes > self.0.config.max_bytes {
+            match entries.lru.pop_lru() {
+                Some((_, evicted)) => entries.bytes -= evicted.body.len(),
+                None => break,
+            }
+        }
+    }
+
+    /// Drop every cached response, eg once data in tiled has been changed. Writes are rare
+    /// compared to reads so this is simpler than finding every response that included the data.
+    pub fn clear(&self) {
+        let mut entries = self.0.entries.lock().expect("Cache lock poisoned");
+        entries.lru.clear();
+        entries.bytes = 0;
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let entries = self.0.entries.lock().expect("Cache lock poisoned");
+        CacheStats {
+            hits: self.0.hits.load(Ordering::Relaxed),
+            misses: self.0.misses.load(Ordering::Relaxed),
+            entries: entries.lru.len(),
+            bytes: entries.bytes,
+        }
+    }
+}
+
+/// Check whether a response from tiled describes data that will not change
+///
+/// Single nodes are immutable if they are runs that have a stop document or if all their data
+/// comes from immutable or locked data sources. Search results never are, as new nodes can be
+/// added to a container at any time. The metadata of either kind of node can still be edited, so
+/// the cache is cleared whenever glazed writes to tiled; edits made directly in tiled are only
+/// seen once the entry expires.
+fn is_immutable(response: &Value) -> bool {
+    let node = &response["data"]["attributes"];
+    response["data"].is_object()
+        && (node["metadata"]["stop"].is_object() || immutable_sources(node))
+}
+
+fn immutable_sources(node: &Value) -> bool {
+    node["data_sources"].as_array().is_some_and(|sources| {
+        !sources.is_empty()
+            && sources
+                .iter()
+                .all(|source| matches!(source["management"].as_str(), Some("immutable" | "locked")))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::HeaderValue;
+    use serde_json::json;
+
+    use super::{CacheKey, CacheStats, ResponseCache, is_immutable};
+    use crate::config::CacheConfig;
+
+    fn key(url: &str, auth: Option<&'static str>) -> CacheKey {
+        CacheKey {
+            url: url.into(),
+            auth: auth.map(HeaderValue::from_static),
+            forwarded: None,
+            accept: None,
+        }
+    }
+
+    #[test]
+    fn keyed_by_auth() {
+        let cache = ResponseCache::new(CacheConfig::default());
+        cache.insert(key("/a", Some("alice")), "[1]".into());
+        assert_eq!(cache.get(&key("/a", Some("alice"))).as_deref(), Some("[1]"));
+        assert_eq!(cache.get(&key("/a", Some("bob"))), None);
+        assert_eq!(cache.get(&key("/a", None)), None);
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 1,
+                misses: 2,
+                entries: 1,
+                bytes: 3
+            }
+        );
+    }
+
+    #[test]
+    fn expired_entries_removed() {
+        let cache = ResponseCache::new(CacheConfig {
+            ttl: 0,
+            ..CacheConfig::default()
+        });
+        cache.insert(key("/a", None), "[1]".into());
+        assert_eq!(cache.get(&key("/a", None)), None);
+        assert_eq!(cache.stats().entries, 0);
+    }
+
+    #[test]
+    fn size_limits() {
+        let cache = ResponseCache::new(CacheConfig {
+            max_entries: 2,
+            max_bytes: 10,
+            ..CacheConfig::default()
+        });
+        cache.insert(key("/a", None), "[1]".into());
+        cache.insert(key("/b", None), "[2]".into());
+        cache.insert(key("/c", None), "[3]".into());
+        assert_eq!(cache.get(&key("/a", None)), None);
+        assert_eq!(cache.stats().entries, 2);
+
+        cache.insert(key("/d", None), "[4, 5, 6]".into());
+        assert_eq!(cache.stats().bytes, 9);
+        assert_eq!(cache.get(&key("/d", None)).as_deref(), Some("[4, 5, 6]"));
+
+        cache.insert(key("/e", None), "[too large to cache]".into());
+        assert_eq!(cache.get(&key("/e", None)), None);
+    }
+
+    #[test]
+    fn finished_runs_are_immutable() {
+        let run = |stop| json!({"data": {"attributes": {"metadata": {"start": {}, "stop": stop}}}});
+        assert!(is_immutable(&run(json!({"exit_status": "success"}))));
+        assert!(!is_immutable(&run(json!(null))));
+    }
+
+    #[test]
+    fn immutable_data_sources() {
+        let source =
+            |management| json!({"attributes": {"data_sources": [{"management": management}]}});
+        assert!(is_immutable(&json!({"data": source("immutable")})));
+        assert!(is_immutable(&json!({"data": source("locked")})));
+        assert!(!is_immutable(&json!({"data": source("writable")})));
+        // Listings can always gain new nodes
+        assert!(!is_immutable(
+            &json!({"data": [source("immutable"), source("locked")]})
+        ));
+        assert!(!is_immutable(&json!({"data": []})));
+        assert!(!is_immutable(&json!([1, 2, 3])));
+    }
+}
@@ -1,41 +1,271 @@
 use std::borrow::Cow;
 use std::fmt;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
 
+use async_graphql::ErrorExtensions;
+use http_body::{Frame, SizeHint};
 #[cfg(test)]
 use httpmock::MockServer;
-use reqwest::header::HeaderMap;
-use reqwest::{Client, Url};
+use reqwest::header::{ACCEPT, CONTENT_TYPE, HeaderMap, HeaderValue};
+use reqwest::{Client, Method, ResponseBuilderExt as _, Url};
 use serde::de::DeserializeOwned;
-use tracing::{debug, info, instrument};
+use serde_json::{Value, json};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{debug, error, info, instrument, warn};
 
-use crate::model::{app, node, table};
+use crate::arrow::ARROW_FILE;
+use crate::cache::{CacheKey, CacheStats, ResponseCache};
+use crate::compat::{Capabilities, DEFAULT_API_PATH, UnsupportedVersion, VersionCheck};
+use crate::config::{GlazedConfig, RetryConfig, TiledClientConfig, TimeoutConfig};
+use crate::credentials::Authenticator;
+use crate::model::principal::Principal;
+use crate::model::{app, node, revision, table};
+use crate::retry::{BreakerStatus, CircuitBreaker, is_failure, is_transient};
 
 pub type ClientResult<T> = Result<T, ClientError>;
 
+const JSON: &str = "application/json";
+/// How often to retry checking the version of a tiled server that couldn't be reached at startup
+const RENEGOTIATE_INTERVAL: Duration = Duration::from_secs(30);
+/// Content type of metadata patches in the JSON Patch format
+const JSON_PATCH: &str = "application/json-patch+json";
+
 #[derive(Clone)]
 pub struct TiledClient {
     client: Client,
     address: Url,
+    cache: Option<ResponseCache>,
+    concurrency: usize,
+    retry: RetryConfig,
+    breaker: CircuitBreaker,
+    timeouts: TimeoutConfig,
+    connections: Option<Arc<Semaphore>>,
+    auth: Authenticator,
+    version_check: VersionCheck,
+    capabilities: Arc<RwLock<Option<Capabilities>>>,
+}
+
+/// Convert a timeout in seconds to a Duration, treating 0 as no timeout
+fn timeout(seconds: u64) -> Option<Duration> {
+    (seconds > 0).then(|| Duration::from_secs(seconds))
 }
 
 impl TiledClient {
     pub fn new(address: Url) -> Self {
-        if address.cannot_be_a_base() {
+        Self::from_config(&TiledClientConfig {
+            address,
+            ..GlazedConfig::default().tiled_client
+        })
+        .expect("Default HTTP client could not be built")
+    }
+    pub fn from_config(config: &TiledClientConfig) -> ClientResult<Self> {
+        if config.address.cannot_be_a_base() {
             // Panicking is not great but if we've got this far, nothing else is going to work so
             // bail out early.
             panic!("Invalid tiled URL");
         }
-        Self {
-            client: Client::new(),
+        let mut builder = Client::builder()
+            .user_agent(&config.user_agent)
+            .pool_idle_timeout(timeout(config.pool.idle_timeout));
+        if let Some(connect) = timeout(config.timeouts.connect) {
+            builder = builder.connect_timeout(connect);
+        }
+        if let Some(read) = timeout(config.timeouts.read) {
+            builder = builder.read_timeout(read);
+        }
+        if let Some(max_idle) = config.pool.max_idle {
+            builder = builder.pool_max_idle_per_host(max_idle);
+        }
+        if config.h2c {
+            builder = builder.http2_prior_knowledge();
+        } else if !config.http2 {
+            builder = builder.http1_only();
+        }
+        let (builder, address) = config.tls.configure(builder, &config.address)?;
+        Ok(Self {
+            client: builder.build()?,
             address,
+            cache: config.cache.clone().map(ResponseCache::new),
+            concurrency: config.concurrency.max(1),
+            retry: config.retry.clone(),
+            breaker: CircuitBreaker::new(config.circuit_breaker.clone()),
+            timeouts: config.timeouts.clone(),
+            connections: config
+                .pool
+                .max_connections
+                .map(|max| Arc::new(Semaphore::new(max))),
+            auth: Authenticator::new(config.credentials.as_ref(), config.auth_policy)?,
+            version_check: config.version_check,
+            capabilities: Arc::default(),
+        })
+    }
+    /// A client that always uses the service credentials, for requests glazed makes on its own
+    /// behalf rather than for a user
+    pub fn as_service(&self) -> Self {
+        Self {
+            auth: self.auth.service(),
+            ..self.clone()
+        }
+    }
+    /// A client that only sends the user's own credentials, whatever the auth policy
+    pub fn as_user(&self) -> Self {
+        Self {
+            auth: Authenticator::user(),
+            ..self.clone()
+        }
+    }
+    /// A client that never uses the response cache, for requests that poll tiled for changes
+    pub fn uncached(&self) -> Self {
+        Self {
+            cache: None,
+            ..self.clone()
+        }
+    }
+    /// Maximum number of requests that should be made to tiled at once for a single query
+    pub fn concurrency(&self) -> usize {
+        self.concurrency
+    }
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.cache.as_ref().map(ResponseCache::stats)
+    }
+    /// Check the version of tiled and record the features it supports. If tiled can't be
+    /// reached, it is assumed to support everything glazed needs and the check is retried in the
+    /// background until tiled is available.
+    pub async fn negotiate(&self) -> Result<(), UnsupportedVersion> {
+        if self.try_negotiate().await? {
+            return Ok(());
+        }
+        let client = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(RENEGOTIATE_INTERVAL).await;
+                match client.try_negotiate().await {
+                    Ok(true) => break,
+                    Ok(false) => {}
+                    Err(err) => {
+                        // Too late to refuse to start so carry on as if the version were unknown
+                        error!("Tiled at {} is not compatible: {err}", client.address);
+                        break;
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+    /// Negotiate with tiled if it can be reached, returning whether it could
+    async fn try_negotiate(&self) -> Result<bool, UnsupportedVersion> {
+        let metadata = match self.app_metadata().await {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                warn!(
+                    "Unable to check the version of tiled at {}: {err}",
+                    self.address
+                );
+                return Ok(false);
+            }
+        };
+        let capabilities = Capabilities::negotiate(metadata, self.version_check)?;
+        info!(
+            "Tiled at {} is version {} (API version {})",
+            self.address, capabilities.library_version, capabilities.api_version
+        );
+        *self
+            .capabilities
+            .write()
+            .expect("Capabilities lock poisoned") = Some(capabilities);
+        Ok(true)
+    }
+    pub fn capabilities(&self) -> Option<Capabilities> {
+        self.capabilities
+            .read()
+            .expect("Capabilities lock poisoned")
+            .clone()
+    }
+    /// Path of an endpoint of the tiled API, eg `search/` or `metadata/`
+    fn api(&self, endpoint: &str) -> String {
+        let capabilities = self
+            .capabilities
+            .read()
+            .expect("Capabilities lock poisoned");
+        let path = capabilities
+            .as_ref()
+            .map_or(DEFAULT_API_PATH, |c| c.api_path.as_str());
+        format!("{path}{endpoint}")
+    }
+    /// Accept header for a request for a node of the given structure family, choosing the first
+    /// of the preferred formats that tiled supports
+    fn accept(&self, family: &str, preferred: &[&'static str]) -> ClientResult<HeaderValue> {
+        let capabilities = self
+            .capabilities
+            .read()
+            .expect("Capabilities lock poisoned");
+        let format = match capabilities.as_ref() {
+            Some(capabilities) => capabilities.format(family, preferred),
+            None => preferred.first().copied(),
+        };
+        format
+            .map(HeaderValue::from_static)
+            .ok_or_else(|| ClientError::UnsupportedFormat(family.into()))
+    }
+    /// Whether tiled supports the given query type, assuming it does if its version is unknown
+    pub fn supports_query(&self, query: &str) -> bool {
+        self.capabilities
+            .read()
+            .expect("Capabilities lock poisoned")
+            .as_ref()
+            .is_none_or(|c| c.supports(query))
+    }
+    pub fn breaker_status(&self) -> BreakerStatus {
+        self.breaker.status()
+    }
+    /// Wait for a connection slot if the number of concurrent requests to tiled is limited
+    async fn connection(&self) -> Option<OwnedSemaphorePermit> {
+        match &self.connections {
+            Some(connections) => Some(
+                connections
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("Connection semaphore is never closed"),
+            ),
+            None => None,
+        }
+    }
+    /// Send a GET request, retrying it if tiled can't be reached or is temporarily unavailable
+    async fn send(&self, request: reqwest::Request) -> reqwest::Result<reqwest::Response> {
+        let mut retry = 0;
+        loop {
+            let attempt = request
+                .try_clone()
+                .expect("GET requests have no body so can always be cloned");
+            let result = self.client.execute(attempt).await;
+            if !is_transient(&result) || retry >= self.retry.retries {
+                return result;
+            }
+            let delay = self.retry.backoff(retry);
+            warn!("Request to {} failed, retrying in {delay:?}", request.url());
+            tokio::time::sleep(delay).await;
+            retry += 1;
         }
     }
-    #[instrument(skip(self, headers))]
     async fn request<T: DeserializeOwned>(
         &self,
         endpoint: &str,
         headers: Option<HeaderMap>,
         query_params: Option<&[(&str, Cow<'_, str>)]>,
+    ) -> ClientResult<T> {
+        self.get(endpoint, headers, query_params, true).await
+    }
+    #[instrument(skip(self, headers))]
+    async fn get<T: DeserializeOwned>(
+        &self,
+        endpoint: &str,
+        headers: Option<HeaderMap>,
+        query_params: Option<&[(&str, Cow<'_, str>)]>,
+        use_cache: bool,
     ) -> ClientResult<T> {
         let url = self.address.join(endpoint)?;
 
@@ -46,20 +276,106 @@ impl TiledClient {
         if let Some(params) = query_params {
             request = request.query(&params);
         }
-        let request = request.build()?;
-        info!("Querying: {}", request.url());
-
-        let response = self.client.execute(request).await?;
-        let status = response.status().as_u16();
-        let body = response.text().await?;
-        match status {
-            400..500 => Err(ClientError::TiledRequest(status, body)),
-            500..600 => Err(ClientError::TiledInternal(status, body)),
-            _ => serde_json::from_str(&body).map_err(|e| ClientError::InvalidResponse(e, body)),
+        let request = self.build(request).await?;
+        // Responses are cached by the credentials the request is sent with, including the
+        // service credentials, so that nobody is given a response made with access they lack
+        let cache = self.cache.as_ref().filter(|_| use_cache);
+        let cache_key = cache.map(|_| CacheKey::for_request(&request));
+        if let (Some(cache), Some(key)) = (cache, &cache_key)
+            && let Some(body) = cache.get(key)
+        {
+            debug!("Using cached response for {}", request.url());
+            return serde_json::from_str(&body).map_err(|e| ClientError::InvalidResponse(e, body));
+        }
+        let body = self.execute(request).await?;
+        let value = match serde_json::from_str(&body) {
+            Ok(value) => value,
+            Err(e) => return Err(ClientError::InvalidResponse(e, body)),
+        };
+        if let (Some(cache), Some(key)) = (cache, cache_key) {
+            cache.insert(key, body);
+        }
+        Ok(value)
+    }
+    /// Build a request with the credentials required by the auth policy, limited to the
+    /// configured request timeout
+    async fn build(&self, request: reqwest::RequestBuilder) -> ClientResult<reqwest::Request> {
+        let request = match timeout(self.timeouts.request) {
+            Some(timeout) => request.timeout(timeout),
+            None => request,
+        };
+        let mut request = request.build()?;
+        self.auth.apply(&self.client, request.headers_mut()).await?;
+        Ok(request)
+    }
+    /// Send a request to tiled if the circuit breaker allows it, returning the body of the
+    /// response. GET requests are retried, other requests are only sent once.
+    async fn execute(&self, request: reqwest::Request) -> ClientResult<String> {
+        if !self.breaker.allow() {
+            return Err(ClientError::Unavailable);
+        }
+        let _connection = self.connection().await;
+        let response = if request.method() == Method::GET {
+            info!("Querying: {}", request.url());
+            self.send(request).await
+        } else {
+            info!("Updating: {}", request.url());
+            self.client.execute(request).await
+        };
+        if is_failure(&response) {
+            self.breaker.failure();
+        } else {
+            self.breaker.success();
+        }
+        checked_body(response?).await
+    }
+    /// Send a request that changes data in tiled. Writes are not retried as they may have been
+    /// applied even if the response was lost. Cached responses are dropped once a write succeeds.
+    #[instrument(skip(self, headers, body))]
+    async fn write(
+        &self,
+        method: Method,
+        endpoint: &str,
+        headers: Option<HeaderMap>,
+        content_type: &str,
+        body: Vec<u8>,
+    ) -> ClientResult<String> {
+        let url = self.address.join(endpoint)?;
+        let request = self
+            .build(
+                self.client
+                    .request(method, url)
+                    .headers(headers.unwrap_or_default())
+                    .header(CONTENT_TYPE, content_type)
+                    .body(body),
+            )
+            .await?;
+        let body = self.execute(request).await?;
+        if let Some(cache) = &self.cache {
+            cache.clear();
         }
+        Ok(body)
+    }
+    async fn write_json<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        endpoint: &str,
+        headers: Option<HeaderMap>,
+        body: &Value,
+    ) -> ClientResult<T> {
+        let body = self
+            .write(
+                method,
+                endpoint,
+                headers,
+                JSON,
+                body.to_string().into_bytes(),
+            )
+            .await?;
+        serde_json::from_str(&body).map_err(|e| ClientError::InvalidResponse(e, body))
     }
     pub async fn app_metadata(&self) -> ClientResult<app::AppMetadata> {
-        self.request("/api/v1/", None, None).await
+        self.request(DEFAULT_API_PATH, None, None).await
     }
     pub async fn search(
         &self,
@@ -67,7 +383,7 @@ impl TiledClient {
         headers: Option<HeaderMap>,
         query: &[(&str, Cow<'_, str>)],
     ) -> ClientResult<node::Root> {
-        self.request(&format!("api/v1/search/{}", path), headers, Some(query))
+        self.request(&self.api(&format!("search/{}", path)), headers, Some(query))
             .await
     }
 
@@ -76,7 +392,27 @@ impl TiledClient {
         id: String,
         headers: Option<HeaderMap>,
     ) -> ClientResult<node::Metadata> {
-        self.request(&format!("api/v1/metadata/{id}"), headers, None)
+        self.request(&self.api(&format!("metadata/{id}")), headers, None)
+            .await
+    }
+
+    /// The metadata of a node as returned by tiled, never cached so that it can be used as the
+    /// basis of a change
+    pub async fn latest_metadata(
+        &self,
+        id: &str,
+        headers: Option<HeaderMap>,
+    ) -> ClientResult<Value> {
+        self.get(&self.api(&format!("metadata/{id}")), headers, None, false)
+            .await
+    }
+
+    /// The principal that tiled verifies the user's credentials as, or None if they are
+    /// anonymous. Only the user's own credentials are sent so that tiled never identifies them
+    /// as glazed's service principal.
+    pub async fn whoami(&self, headers: HeaderMap) -> ClientResult<Option<Principal>> {
+        self.as_user()
+            .get(&self.api("auth/whoami"), Some(headers), None, false)
             .await
     }
 
@@ -85,9 +421,30 @@ impl TiledClient {
         path: &str,
         columns: Option<Vec<String>>,
         headers: Option<HeaderMap>,
+    ) -> ClientResult<table::Table> {
+        self.read_table(path, columns, headers, true).await
+    }
+
+    /// The full contents of a table, never cached so that it can be used as the basis of a
+    /// change
+    pub async fn latest_table(
+        &self,
+        path: &str,
+        columns: Option<Vec<String>>,
+        headers: Option<HeaderMap>,
+    ) -> ClientResult<table::Table> {
+        self.read_table(path, columns, headers, false).await
+    }
+
+    async fn read_table(
+        &self,
+        path: &str,
+        columns: Option<Vec<String>>,
+        headers: Option<HeaderMap>,
+        use_cache: bool,
     ) -> ClientResult<table::Table> {
         let mut headers = headers.unwrap_or_default();
-        headers.insert("accept", "application/json".parse().unwrap());
+        headers.insert(ACCEPT, self.accept("table", &[JSON])?);
         let query = columns.map(|columns| {
             columns
                 .into_iter()
@@ -95,49 +452,273 @@ impl TiledClient {
                 .collect::<Vec<_>>()
         });
 
-        self.request(
-            &format!("/api/v1/table/full/{}", path),
+        self.get(
+            &self.api(&format!("table/full/{}", path)),
             Some(headers),
             query.as_deref(),
+            use_cache,
+        )
+        .await
+    }
+
+    /// Revisions of the metadata of a node, oldest first. Responses are never cached so that the
+    /// number of revisions is always up to date.
+    pub async fn revisions(
+        &self,
+        path: &str,
+        offset: usize,
+        limit: usize,
+        headers: Option<HeaderMap>,
+    ) -> ClientResult<revision::Revisions> {
+        let query = [
+            ("page[offset]", offset.to_string().into()),
+            ("page[limit]", limit.to_string().into()),
+        ];
+        self.get(
+            &self.api(&format!("revisions/{path}")),
+            headers,
+            Some(&query),
+            false,
+        )
+        .await
+    }
+
+    /// Apply a JSON Patch (RFC 6902) to the metadata of a node
+    pub async fn patch_metadata(
+        &self,
+        path: &str,
+        patch: &Value,
+        headers: Option<HeaderMap>,
+    ) -> ClientResult<Value> {
+        let body = json!({
+            "content-type": JSON_PATCH,
+            "metadata": patch,
+        });
+        self.write_json(
+            Method::PATCH,
+            &self.api(&format!("metadata/{path}")),
+            headers,
+            &body,
+        )
+        .await
+    }
+
+    /// Create a node as a child of the node at the given path
+    pub async fn create_node(
+        &self,
+        parent: &str,
+        node: &Value,
+        headers: Option<HeaderMap>,
+    ) -> ClientResult<Value> {
+        self.write_json(
+            Method::POST,
+            &self.api(&format!("metadata/{parent}")),
+            headers,
+            node,
+        )
+        .await
+    }
+
+    /// Delete a node and its contents
+    pub async fn delete_node(&self, path: &str, headers: Option<HeaderMap>) -> ClientResult<()> {
+        self.write(
+            Method::DELETE,
+            &self.api(&format!("metadata/{path}")),
+            headers,
+            JSON,
+            vec![],
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Replace the contents of a table with an Arrow IPC file
+    pub async fn put_table(
+        &self,
+        path: &str,
+        table: Vec<u8>,
+        headers: Option<HeaderMap>,
+    ) -> ClientResult<()> {
+        self.write(
+            Method::PUT,
+            &self.api(&format!("table/full/{path}")),
+            headers,
+            ARROW_FILE,
+            table,
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Request a single partition of a table
+    pub async fn table_partition(
+        &self,
+        path: &str,
+        partition: usize,
+        columns: Option<&[String]>,
+        headers: Option<HeaderMap>,
+    ) -> ClientResult<table::Table> {
+        let mut headers = headers.unwrap_or_default();
+        headers.insert(ACCEPT, self.accept("table", &[JSON])?);
+        let mut query = vec![("partition", partition.to_string().into())];
+        query.extend(
+            columns
+                .unwrap_or_default()
+                .iter()
+                .map(|col| ("column", col.as_str().into())),
+        );
+
+        self.request(
+            &self.api(&format!("table/partition/{}", path)),
+            Some(headers),
+            Some(&query),
+        )
+        .await
+    }
+
+    pub async fn array_block(
+        &self,
+        path: &str,
+        block: &[usize],
+        slice: Option<String>,
+        headers: Option<HeaderMap>,
+    ) -> ClientResult<Value> {
+        let mut headers = headers.unwrap_or_default();
+        headers.insert(ACCEPT, self.accept("array", &[JSON])?);
+        let block = block
+            .iter()
+            .map(|b| b.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        let mut query = vec![("block", block.into())];
+        if let Some(slice) = slice {
+            query.push(("slice", slice.into()));
+        }
+
+        self.request(
+            &self.api(&format!("array/block/{}", path)),
+            Some(headers),
+            Some(&query),
         )
         .await
     }
 
+    pub async fn asset_manifest(
+        &self,
+        run: &str,
+        stream: &str,
+        det: &str,
+        id: u32,
+        headers: Option<HeaderMap>,
+    ) -> ClientResult<Vec<String>> {
+        let manifest: node::AssetManifest = self
+            .request(
+                &self.api(&format!("asset/manifest/{run}/{stream}/{det}")),
+                headers,
+                Some(&[("id", id.to_string().into())]),
+            )
+            .await?;
+        Ok(manifest.manifest)
+    }
+
+    /// Request the bytes of an asset. For directory assets, `relative_path` selects the file
+    /// within the directory to download.
     pub(crate) async fn download(
         &self,
-        run: String,
-        stream: String,
-        det: String,
+        run: &str,
+        stream: &str,
+        det: &str,
         id: u32,
+        relative_path: Option<&str>,
         headers: Option<HeaderMap>,
-    ) -> reqwest::Result<reqwest::Response> {
+    ) -> ClientResult<reqwest::Response> {
         let mut url = self
             .address
-            .join("/api/v1/asset/bytes")
+            .join(&self.api("asset/bytes"))
             .expect("Base address was cannot_be_a_base");
         url.path_segments_mut()
             .expect("Base address was cannot_be_a_base")
-            .push(&run)
-            .push(&stream)
-            .push(&det);
+            .push(run)
+            .push(stream)
+            .push(det);
+        url.query_pairs_mut().append_pair("id", &id.to_string());
+        if let Some(path) = relative_path {
+            url.query_pairs_mut().append_pair("relative_path", path);
+        }
 
         debug!("Downloading id={id} from {url}");
-        self.client
-            .get(url)
-            .headers(headers.unwrap_or_default())
-            .query(&[("id", &id.to_string())])
-            .send()
-            .await
+        let mut request = self.client.get(url).headers(headers.unwrap_or_default());
+        if let Some(timeout) = timeout(self.timeouts.download) {
+            request = request.timeout(timeout);
+        }
+        let mut request = request.build()?;
+        self.auth.apply(&self.client, request.headers_mut()).await?;
+        let connection = self.connection().await;
+        let response = self.send(request).await?;
+        Ok(match connection {
+            Some(connection) => hold_connection(response, connection),
+            None => response,
+        })
     }
 
     /// Create a new client for the given mock server
     #[cfg(test)]
     pub fn for_mock_server(server: &MockServer) -> Self {
-        Self {
+        Self::from_config(&TiledClientConfig {
             // We're only in tests so panicking is fine
             address: server.base_url().parse().unwrap(),
-            client: Client::new(),
-        }
+            retry: RetryConfig {
+                initial_backoff: 1,
+                ..RetryConfig::default()
+            },
+            ..GlazedConfig::default().tiled_client
+        })
+        .unwrap()
+    }
+}
+
+/// Move a connection slot into the body of a download response so that it is only released once
+/// the body has been read or dropped, rather than when the response headers arrive
+fn hold_connection(
+    response: reqwest::Response,
+    connection: OwnedSemaphorePermit,
+) -> reqwest::Response {
+    let url = response.url().clone();
+    let (parts, body) = axum::http::Response::from(response).into_parts();
+    let body = reqwest::Body::wrap(ConnectionBody {
+        inner: body,
+        _connection: connection,
+    });
+    let mut response = axum::http::Response::builder()
+        .status(parts.status)
+        .version(parts.version)
+        .url(url)
+        .body(body)
+        .expect("Status and version are taken from a valid response");
+    *response.headers_mut() = parts.headers;
+    response.into()
+}
+
+struct ConnectionBody {
+    inner: reqwest::Body,
+    _connection: OwnedSemaphorePermit,
+}
+
+impl http_body::Body for ConnectionBody {
+    type Data = axum::body::Bytes;
+    type Error = reqwest::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        Pin::new(&mut self.inner).poll_frame(cx)
+    }
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
     }
 }
 
@@ -148,7 +729,18 @@ pub enum ClientError {
     InvalidResponse(serde_json::Error, String),
     TiledInternal(u16, String),
     TiledRequest(u16, String),
+    /// Tiled has failed repeatedly and requests are not being sent to it
+    Unavailable,
+    Timeout(reqwest::Error),
+    /// The service credentials could not be read or a token could not be obtained
+    Credentials(String),
+    /// The TLS settings are invalid or the certificates could not be loaded
+    Tls(String),
+    /// Tiled can't return nodes of the structure family in any format glazed can read
+    UnsupportedFormat(String),
 }
+impl std::error::Error for ClientError {}
+
 impl From<url::ParseError> for ClientError {
     fn from(err: url::ParseError) -> ClientError {
         ClientError::InvalidPath(err)
@@ -156,7 +748,11 @@ impl From<url::ParseError> for ClientError {
 }
 impl From<reqwest::Error> for ClientError {
     fn from(err: reqwest::Error) -> ClientError {
-        ClientError::ServerError(err)
+        if err.is_timeout() {
+            ClientError::Timeout(err)
+        } else {
+            ClientError::ServerError(err)
+        }
     }
 }
 
@@ -172,18 +768,150 @@ impl std::fmt::Display for ClientError {
                 write!(f, "Request Error: {sc} - {message}")
             }
             ClientError::InvalidResponse(err, actual) => {
-                write!(f, "Invalid response: {err}, response: {actual}")
+                write!(f, "Invalid response: {err}, response: {}", trim(actual))
+            }
+            ClientError::Unavailable => write!(f, "Tiled is currently unavailable"),
+            ClientError::Timeout(err) => write!(f, "Request to tiled timed out: {err}"),
+            ClientError::Credentials(err) => write!(f, "Service credentials error: {err}"),
+            ClientError::Tls(err) => write!(f, "TLS configuration error: {err}"),
+            ClientError::UnsupportedFormat(family) => {
+                write!(
+                    f,
+                    "Tiled does not support any readable format for {family} data"
+                )
             }
         }
     }
 }
 
+/// Read the body of a response from tiled, treating error statuses as errors
+async fn checked_body(response: reqwest::Response) -> ClientResult<String> {
+    let status = response.status().as_u16();
+    let body = response.text().await?;
+    match status {
+        400..500 => Err(ClientError::TiledRequest(status, body)),
+        500..600 => Err(ClientError::TiledInternal(status, body)),
+        _ => Ok(body),
+    }
+}
+
+/// Maximum length of tiled responses included in errors
+const MAX_DETAIL: usize = 200;
+
+/// Shorten text from tiled so that large responses aren't included in errors
+fn trim(text: &str) -> Cow<'_, str> {
+    match text.char_indices().nth(MAX_DETAIL) {
+        Some((end, _)) => format!("{}...", &text[..end]).into(),
+        None => text.into(),
+    }
+}
+
+/// Extract the detail from a tiled error response. Only JSON error responses are used so that
+/// HTML error pages etc from proxies in front of tiled are not passed on.
+fn tiled_detail(body: &str) -> Option<String> {
+    let detail = match serde_json::from_str::<Value>(body).ok()?.get("detail")? {
+        Value::String(detail) => detail.clone(),
+        // Validation errors are a list of problems with the request
+        Value::Array(errors) => errors
+            .iter()
+            .filter_map(|err| err.get("msg")?.as_str())
+            .collect::<Vec<_>>()
+            .join("; "),
+        _ => return None,
+    };
+    let detail = detail.replace(|c: char| c.is_control(), " ");
+    Some(trim(detail.trim()).into_owned())
+}
+
+impl ErrorExtensions for ClientError {
+    fn extend(&self) -> async_graphql::Error {
+        let (code, status, message) = match self {
+            ClientError::InvalidPath(err) => ("BAD_PATH", None, format!("Invalid path: {err}")),
+            ClientError::TiledRequest(status, _) => match status {
+                401 => ("UNAUTHENTICATED", Some(*status), "Not authenticated".into()),
+                403 => ("FORBIDDEN", Some(*status), "Not authorised".into()),
+                404 => ("NOT_FOUND", Some(*status), "Not found".into()),
+                409 => ("CONFLICT", Some(*status), "Conflicting change".into()),
+                400 | 422 => ("BAD_USER_INPUT", Some(*status), "Invalid request".into()),
+                413 => ("BAD_USER_INPUT", Some(*status), "Request too large".into()),
+                // Tiled is limiting requests so the same request may succeed later
+                429 => (
+                    "UPSTREAM_UNAVAILABLE",
+                    Some(*status),
+                    "Too many requests to tiled".into(),
+                ),
+                _ => ("BAD_PATH", Some(*status), "Invalid request".into()),
+            },
+            ClientError::TiledInternal(status, _) => (
+                "UPSTREAM_UNAVAILABLE",
+                Some(*status),
+                "Internal tiled error".into(),
+            ),
+            ClientError::Unavailable => ("UPSTREAM_UNAVAILABLE", None, self.to_string()),
+            // The details of these can include addresses and configuration of the deployment so
+            // they are only logged rather than returned to the user
+            ClientError::ServerError(_) | ClientError::Credentials(_) | ClientError::Tls(_) => {
+                error!("{self}");
+                ("UPSTREAM_UNAVAILABLE", None, "Unable to reach tiled".into())
+            }
+            ClientError::Timeout(_) => {
+                error!("{self}");
+                (
+                    "UPSTREAM_UNAVAILABLE",
+                    None,
+                    "Request to tiled timed out".into(),
+                )
+            }
+            ClientError::UnsupportedFormat(_) => ("UNSUPPORTED_FORMAT", None, self.to_string()),
+            ClientError::InvalidResponse(err, _) => (
+                "UPSTREAM_INVALID_RESPONSE",
+                None,
+                format!("Invalid response from tiled: {err}"),
+            ),
+        };
+        let detail = match self {
+            ClientError::TiledRequest(_, body) | ClientError::TiledInternal(_, body) => {
+                tiled_detail(body)
+            }
+            _ => None,
+        };
+        let message = match &detail {
+            Some(detail) => format!("{message}: {detail}"),
+            None => message,
+        };
+        async_graphql::Error::new(message).extend_with(|_, ext| {
+            ext.set("code", code);
+            if let Some(status) = status {
+                ext.set("status", status);
+            }
+            if let Some(detail) = detail {
+                ext.set("detail", detail);
+            }
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use axum::http::HeaderMap;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use async_graphql::{ErrorExtensions, Value as GqlValue};
+    use axum::Router;
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::get;
     use httpmock::MockServer;
+    use serde_json::{Value, json};
+    use tokio::net::TcpListener;
 
     use crate::clients::{ClientError, TiledClient};
+    use crate::config::{
+        CacheConfig, CircuitBreakerConfig, GlazedConfig, PoolConfig, RetryConfig, Secret,
+        ServiceCredentials, TiledClientConfig, TimeoutConfig,
+    };
+    use crate::retry::CircuitState;
+    use crate::test_utils::read_json;
 
     #[tokio::test]
     async fn request() {
@@ -245,6 +973,92 @@ mod tests {
         assert_eq!(response.api_version, 0);
         mock.assert();
     }
+    #[tokio::test]
+    async fn http2_negotiated_not_assumed() {
+        let server = MockServer::start();
+        server
+            .mock_async(|when, then| {
+                when.method("GET").path("/demo/api");
+                then.status(200).body("[1,2,3]");
+            })
+            .await;
+        // The mock server only speaks HTTP/1.1 over plain HTTP
+        let client = TiledClient::new(server.base_url().parse().unwrap());
+        assert!(GlazedConfig::default().tiled_client.http2);
+        let response = client
+            .request::<Vec<u8>>("/demo/api", None, None)
+            .await
+            .unwrap();
+        assert_eq!(response, vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn cached_requests() {
+        let server = MockServer::start();
+        let mock = server
+            .mock_async(|when, then| {
+                when.method("GET").path("/demo/api");
+                then.status(200).body("[1,2,3]");
+            })
+            .await;
+        let client = TiledClient::from_config(&TiledClientConfig {
+            address: server.base_url().parse().unwrap(),
+            cache: Some(CacheConfig::default()),
+            ..GlazedConfig::default().tiled_client
+        })
+        .unwrap();
+        let mut alice = HeaderMap::new();
+        alice.insert("Authorization", "alice".parse().unwrap());
+        for _ in 0..3 {
+            let response = client
+                .request::<Vec<u8>>("/demo/api", Some(alice.clone()), None)
+                .await
+                .unwrap();
+            assert_eq!(response, vec![1, 2, 3]);
+        }
+        client
+            .request::<Vec<u8>>("/demo/api", None, None)
+            .await
+            .unwrap();
+        // One request for alice and one anonymous request
+        mock.assert_calls(2);
+        let stats = client.cache_stats().unwrap();
+        assert_eq!((stats.hits, stats.misses), (2, 2));
+    }
+
+    #[tokio::test]
+    async fn finished_runs_refreshed_after_write() {
+        let server = MockServer::start();
+        let metadata = server
+            .mock_async(|when, then| {
+                when.method("GET").path("/api/v1/metadata/run");
+                then.status(200)
+                    .body_from_file("resources/metadata_run.json");
+            })
+            .await;
+        server
+            .mock_async(|when, then| {
+                when.method("PATCH").path("/api/v1/metadata/run");
+                then.status(200).json_body(json!({}));
+            })
+            .await;
+        let client = TiledClient::from_config(&TiledClientConfig {
+            address: server.base_url().parse().unwrap(),
+            cache: Some(CacheConfig::default()),
+            ..GlazedConfig::default().tiled_client
+        })
+        .unwrap();
+        client.metadata("run".into(), None).await.unwrap();
+        client.metadata("run".into(), None).await.unwrap();
+        metadata.assert_calls(1);
+        client
+            .patch_metadata("run", &json!([]), None)
+            .await
+            .unwrap();
+        client.metadata("run".into(), None).await.unwrap();
+        metadata.assert_calls(2);
+    }
+
     #[tokio::test]
     async fn server_unavailable() {
         let client = TiledClient::new("http://non-existent.example.com".parse().unwrap());
@@ -259,6 +1073,31 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn negotiated_api_path() {
+        let server = MockServer::start();
+        let mut metadata: Value = read_json("resources/metadata_app.json");
+        metadata["links"]["self"] = server.url("/tiled/api/v1").into();
+        server
+            .mock_async(|when, then| {
+                when.method("GET").path("/api/v1/");
+                then.status(200).json_body(metadata);
+            })
+            .await;
+        let search = server
+            .mock_async(|when, then| {
+                when.method("GET").path("/tiled/api/v1/search/run");
+                then.status(200)
+                    .body_from_file("resources/search_root.json");
+            })
+            .await;
+        let client = TiledClient::for_mock_server(&server);
+        client.negotiate().await.unwrap();
+        assert_eq!(client.capabilities().unwrap().api_path, "/tiled/api/v1/");
+        client.search("run", None, &[]).await.unwrap();
+        search.assert();
+    }
+
     #[tokio::test]
     async fn internal_tiled_error() {
         let server = MockServer::start();
@@ -278,7 +1117,8 @@ mod tests {
 
         assert_eq!(err, "Tiled is broken inside");
 
-        mock.assert();
+        // The initial request and the two default retries
+        mock.assert_calls(3);
     }
 
     #[tokio::test]
@@ -301,4 +1141,313 @@ mod tests {
         assert!(err.is_data());
         mock.assert();
     }
+
+    #[tokio::test]
+    async fn transient_errors_retried() {
+        // httpmock can't change its response between calls so use a server that fails the first
+        // request and succeeds after that
+        let calls = Arc::new(AtomicUsize::new(0));
+        let app = Router::new().route(
+            "/demo/api",
+            get({
+                let calls = calls.clone();
+                || async move {
+                    match calls.fetch_add(1, Ordering::SeqCst) {
+                        0 => (StatusCode::SERVICE_UNAVAILABLE, "down"),
+                        _ => (StatusCode::OK, "[1,2,3]"),
+                    }
+                }
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let client = TiledClient::new(address.parse().unwrap());
+        let response = client.request::<Vec<u8>>("/demo/api", None, None).await;
+        assert_eq!(response.unwrap(), vec![1, 2, 3]);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn circuit_breaker() {
+        let server = MockServer::start();
+        let mock = server
+            .mock_async(|when, then| {
+                when.method("GET").path("/demo/api");
+                then.status(503).body("unavailable");
+            })
+            .await;
+        let client = TiledClient::from_config(&TiledClientConfig {
+            address: server.base_url().parse().unwrap(),
+            retry: RetryConfig {
+                retries: 1,
+                initial_backoff: 1,
+                max_backoff: 1,
+            },
+            circuit_breaker: CircuitBreakerConfig {
+                failure_threshold: 2,
+                reset_timeout: 60,
+            },
+            ..GlazedConfig::default().tiled_client
+        })
+        .unwrap();
+        for _ in 0..2 {
+            let response = client.request::<Value>("/demo/api", None, None).await;
+            let Err(ClientError::TiledInternal(503, _)) = response else {
+                panic!("Expected 503 error but got {response:?}");
+            };
+        }
+        mock.assert_calls(4);
+
+        let response = client.request::<Value>("/demo/api", None, None).await;
+        let Err(ClientError::Unavailable) = response else {
+            panic!("Expected request to be rejected but got {response:?}");
+        };
+        mock.assert_calls(4);
+        assert_eq!(client.breaker_status().state, CircuitState::Open);
+    }
+
+    #[tokio::test]
+    async fn request_timeout() {
+        let server = MockServer::start();
+        let mock = server
+            .mock_async(|when, then| {
+                when.method("GET").path("/demo/api");
+                then.status(200)
+                    .delay(Duration::from_secs(3))
+                    .body("[1,2,3]");
+            })
+            .await;
+        let client = TiledClient::from_config(&TiledClientConfig {
+            address: server.base_url().parse().unwrap(),
+            timeouts: TimeoutConfig {
+                request: 1,
+                ..TimeoutConfig::default()
+            },
+            ..GlazedConfig::default().tiled_client
+        })
+        .unwrap();
+        let response = client.request::<Vec<u8>>("/demo/api", None, None).await;
+        let Err(ClientError::Timeout(_)) = response else {
+            panic!("Expected timeout but got {response:?}");
+        };
+        // Timed out requests are not retried
+        mock.assert_calls(1);
+    }
+
+    #[tokio::test]
+    async fn user_agent() {
+        let server = MockServer::start();
+        let mock = server
+            .mock_async(|when, then| {
+                when.method("GET")
+                    .path("/demo/api")
+                    .header("user-agent", "glazed-test");
+                then.status(200).body("[1,2,3]");
+            })
+            .await;
+        let client = TiledClient::from_config(&TiledClientConfig {
+            address: server.base_url().parse().unwrap(),
+            user_agent: "glazed-test".into(),
+            ..GlazedConfig::default().tiled_client
+        })
+        .unwrap();
+        client
+            .request::<Vec<u8>>("/demo/api", None, None)
+            .await
+            .unwrap();
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn service_credentials() {
+        let server = MockServer::start();
+        let mock = server
+            .mock_async(|when, then| {
+                when.method("GET")
+                    .path("/demo/api")
+                    .header("authorization", "Apikey service-key");
+                then.status(200).body("[1,2,3]");
+            })
+            .await;
+        let dir = tempfile::tempdir().unwrap();
+        let key = dir.path().join("api_key");
+        std::fs::write(&key, "service-key").unwrap();
+        let client = TiledClient::from_config(&TiledClientConfig {
+            address: server.base_url().parse().unwrap(),
+            credentials: Some(ServiceCredentials::ApiKey {
+                key: Secret::File(key),
+            }),
+            ..GlazedConfig::default().tiled_client
+        })
+        .unwrap();
+        client
+            .as_service()
+            .request::<Vec<u8>>("/demo/api", None, None)
+            .await
+            .unwrap();
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn service_responses_not_cached_for_anonymous_users() {
+        let server = MockServer::start();
+        let service = server
+            .mock_async(|when, then| {
+                when.method("GET")
+                    .path("/demo/api")
+                    .header("authorization", "Apikey service-key");
+                then.status(200).body("[1,2,3]");
+            })
+            .await;
+        let anonymous = server
+            .mock_async(|when, then| {
+                when.method("GET")
+                    .path("/demo/api")
+                    .header_missing("authorization");
+                then.status(200).body("[]");
+            })
+            .await;
+        let dir = tempfile::tempdir().unwrap();
+        let key = dir.path().join("api_key");
+        std::fs::write(&key, "service-key").unwrap();
+        let client = TiledClient::from_config(&TiledClientConfig {
+            address: server.base_url().parse().unwrap(),
+            credentials: Some(ServiceCredentials::ApiKey {
+                key: Secret::File(key),
+            }),
+            cache: Some(CacheConfig::default()),
+            ..GlazedConfig::default().tiled_client
+        })
+        .unwrap();
+        let read = client
+            .as_service()
+            .request::<Vec<u8>>("/demo/api", None, None)
+            .await
+            .unwrap();
+        assert_eq!(read, vec![1, 2, 3]);
+        let read = client
+            .request::<Vec<u8>>("/demo/api", None, None)
+            .await
+            .unwrap();
+        assert_eq!(read, Vec::<u8>::new());
+        service.assert();
+        anonymous.assert();
+    }
+
+    #[tokio::test]
+    async fn downloads_hold_connection_until_read() {
+        let server = MockServer::start();
+        server
+            .mock_async(|when, then| {
+                when.method("GET")
+                    .path("/api/v1/asset/bytes/run/primary/det");
+                then.status(200).body("detector data");
+            })
+            .await;
+        server
+            .mock_async(|when, then| {
+                when.method("GET").path("/demo/api");
+                then.status(200).body("[1,2,3]");
+            })
+            .await;
+        let client = TiledClient::from_config(&TiledClientConfig {
+            address: server.base_url().parse().unwrap(),
+            pool: PoolConfig {
+                max_connections: Some(1),
+                ..PoolConfig::default()
+            },
+            ..GlazedConfig::default().tiled_client
+        })
+        .unwrap();
+        let download = client
+            .download("run", "primary", "det", 1, None, None)
+            .await
+            .unwrap();
+        assert_eq!(download.content_length(), Some(13));
+        let blocked = tokio::time::timeout(
+            Duration::from_millis(100),
+            client.request::<Vec<u8>>("/demo/api", None, None),
+        )
+        .await;
+        assert!(blocked.is_err(), "Request made while download was open");
+
+        assert_eq!(download.text().await.unwrap(), "detector data");
+        client
+            .request::<Vec<u8>>("/demo/api", None, None)
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn error_extensions() {
+        let body = r#"{"detail": "Not\nallowed"}"#.to_owned();
+        let err = ClientError::TiledRequest(403, body).extend();
+        assert_eq!(err.message, "Not authorised: Not allowed");
+        let ext = err.extensions.unwrap();
+        assert_eq!(ext.get("code"), Some(&GqlValue::from("FORBIDDEN")));
+        assert_eq!(ext.get("status"), Some(&GqlValue::from(403)));
+        assert_eq!(ext.get("detail"), Some(&GqlValue::from("Not allowed")));
+
+        let body = r#"{"detail": [{"msg": "bad slice"}, {"msg": "bad block"}]}"#.to_owned();
+        let err = ClientError::TiledRequest(422, body).extend();
+        assert_eq!(err.message, "Invalid request: bad slice; bad block");
+        let ext = err.extensions.unwrap();
+        assert_eq!(ext.get("code"), Some(&GqlValue::from("BAD_USER_INPUT")));
+        assert_eq!(
+            ext.get("detail"),
+            Some(&GqlValue::from("bad slice; bad block"))
+        );
+
+        let body = r#"{"detail": "Unknown query type"}"#.to_owned();
+        let err = ClientError::TiledRequest(400, body).extend();
+        assert_eq!(err.message, "Invalid request: Unknown query type");
+        let ext = err.extensions.unwrap();
+        assert_eq!(ext.get("code"), Some(&GqlValue::from("BAD_USER_INPUT")));
+        assert_eq!(ext.get("status"), Some(&GqlValue::from(400)));
+
+        let err = ClientError::TiledRequest(413, String::new()).extend();
+        let ext = err.extensions.unwrap();
+        assert_eq!(ext.get("code"), Some(&GqlValue::from("BAD_USER_INPUT")));
+
+        let err = ClientError::TiledRequest(429, "Slow down".into()).extend();
+        assert_eq!(err.message, "Too many requests to tiled");
+        let ext = err.extensions.unwrap();
+        assert_eq!(
+            ext.get("code"),
+            Some(&GqlValue::from("UPSTREAM_UNAVAILABLE"))
+        );
+        assert_eq!(ext.get("status"), Some(&GqlValue::from(429)));
+
+        let err = ClientError::TiledRequest(405, String::new()).extend();
+        let ext = err.extensions.unwrap();
+        assert_eq!(ext.get("code"), Some(&GqlValue::from("BAD_PATH")));
+
+        let err = ClientError::TiledInternal(502, "<html>Bad gateway</html>".into()).extend();
+        assert_eq!(err.message, "Internal tiled error");
+        let ext = err.extensions.unwrap();
+        assert_eq!(
+            ext.get("code"),
+            Some(&GqlValue::from("UPSTREAM_UNAVAILABLE"))
+        );
+        assert_eq!(ext.get("detail"), None);
+
+        let body = "x".repeat(10_000);
+        let parse_err = serde_json::from_str::<Value>(&body).unwrap_err();
+        let err = ClientError::InvalidResponse(parse_err, body);
+        assert!(err.to_string().len() < 300);
+        let err = err.extend();
+        assert!(!err.message.contains("xxx"));
+        let ext = err.extensions.unwrap();
+        assert_eq!(
+            ext.get("code"),
+            Some(&GqlValue::from("UPSTREAM_INVALID_RESPONSE"))
+        );
+
+        let err = ClientError::Credentials("Unable to read secret from \"/etc/key\"".into());
+        assert_eq!(err.extend().message, "Unable to reach tiled");
+        let err = ClientError::Tls("Invalid client certificate".into());
+        assert_eq!(err.extend().message, "Unable to reach tiled");
+    }
 }
@@ -0,0 +1,243 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::ops::RangeInclusive;
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use url::Url;
+
+use crate::model::app::AppMetadata;
+
+/// Versions of the tiled API whose requests and responses glazed understands
+pub const SUPPORTED_API_VERSIONS: RangeInclusive<i64> = 0..=0;
+
+/// Tiled releases older than this may return responses glazed can't read
+const MIN_LIBRARY_VERSION: &str = "0.1.0b1";
+
+/// Path of the tiled API, relative to the tiled address, used until the path advertised by tiled
+/// is known
+pub const DEFAULT_API_PATH: &str = "api/v1/";
+
+/// Query type used to fetch several nodes with one search
+pub const KEYS_FILTER: &str = "keys_filter";
+/// Query type used to filter runs by their start document
+pub const EQ_FILTER: &str = "eq";
+
+/// What to do if tiled reports an API version glazed does not support
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum VersionCheck {
+    /// Refuse to start
+    #[default]
+    Strict,
+    /// Log a warning and carry on as if the version were supported
+    Warn,
+}
+
+/// The versions and features of a tiled server, read from its app metadata when glazed starts
+#[derive(Debug, Clone, Serialize)]
+pub struct Capabilities {
+    pub api_version: i64,
+    pub library_version: String,
+    /// Path that tiled serves its API from, with a trailing slash
+    pub api_path: String,
+    queries: HashSet<String>,
+    formats: HashMap<String, HashSet<String>>,
+}
+
+#[derive(Debug)]
+pub struct UnsupportedVersion(pub i64);
+
+impl fmt::Display for UnsupportedVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Tiled API version {} is not supported (supported versions: {}-{})",
+            self.0,
+            SUPPORTED_API_VERSIONS.start(),
+            SUPPORTED_API_VERSIONS.end()
+        )
+    }
+}
+
+impl std::error::Error for UnsupportedVersion {}
+
+impl Capabilities {
+    /// Check that glazed can talk to a tiled server with the given metadata
+    pub fn negotiate(
+        metadata: AppMetadata,
+        check: VersionCheck,
+    ) -> Result<Self, UnsupportedVersion> {
+        if !SUPPORTED_API_VERSIONS.contains(&metadata.api_version) {
+            match check {
+                VersionCheck::Strict => return Err(UnsupportedVersion(metadata.api_version)),
+                VersionCheck::Warn => warn!("{}", UnsupportedVersion(metadata.api_version)),
+            }
+        }
+        match compare_versions(&metadata.library_version, MIN_LIBRARY_VERSION) {
+            Some(Ordering::Less) => warn!(
+                "Tiled {} is older than {MIN_LIBRARY_VERSION} and may not be compatible",
+                metadata.library_version
+            ),
+            None => warn!(
+                "Unable to parse tiled version '{}'",
+                metadata.library_version
+            ),
+            Some(_) => {}
+        }
+        Ok(Self {
+            api_version: metadata.api_version,
+            library_version: metadata.library_version,
+            api_path: api_path(&metadata.links.self_field),
+            queries: metadata.queries.into_iter().collect(),
+            formats: metadata
+                .formats
+                .into_iter()
+                .map(|(family, formats)| (family, formats.into_iter().collect()))
+                .collect(),
+        })
+    }
+
+    /// Whether tiled advertises support for the given query type
+    pub fn supports(&self, query: &str) -> bool {
+        self.queries.contains(query)
+    }
+
+    /// The first of the given formats that tiled can return nodes of the structure family in.
+    /// If tiled doesn't list any formats for the family, the first is assumed to be supported.
+    pub fn format<'f>(&self, family: &str, preferred: &[&'f str]) -> Option<&'f str> {
+        match self.formats.get(family) {
+            Some(formats) => preferred.iter().find(|f| formats.contains(**f)).copied(),
+            None => preferred.first().copied(),
+        }
+    }
+}
+
+/// The path of the API from the link tiled gives to its own app metadata
+fn api_path(link: &str) -> String {
+    match Url::parse(link) {
+        Ok(url) => format!("{}/", url.path().trim_end_matches('/')),
+        Err(_) => {
+            warn!("Unable to read the API path from '{link}', using {DEFAULT_API_PATH}");
+            DEFAULT_API_PATH.into()
+        }
+    }
+}
+
+/// A tiled (python) version number, eg 0.1.0, 0.1.0b20 or 0.1.0a118
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Version {
+    release: Vec<u64>,
+    /// Pre-releases (a, b, rc) sort before the release they precede
+    pre: (u8, u64),
+}
+
+fn parse_version(version: &str) -> Option<Version> {
+    // Ignore local and dev/post suffixes - they are close enough for a compatibility warning
+    let version = version.split(['+', '-']).next()?;
+    let version = version.split(".dev").next()?.split(".post").next()?;
+    let split = version
+        .find(|c: char| c.is_ascii_alphabetic())
+        .unwrap_or(version.len());
+    let (release, pre) = version.split_at(split);
+    let mut release = release
+        .split('.')
+        .map(|part| part.parse().ok())
+        .collect::<Option<Vec<u64>>>()?;
+    while release.last() == Some(&0) {
+        release.pop();
+    }
+    let pre = match pre {
+        "" => (3, 0),
+        pre => {
+            let digits = pre.find(|c: char| c.is_ascii_digit()).unwrap_or(pre.len());
+            let rank = match &pre[..digits] {
+                "a" => 0,
+                "b" => 1,
+                "rc" => 2,
+                _ => return None,
+            };
+            (rank, pre[digits..].parse().unwrap_or(0))
+        }
+    };
+    Some(Version { release, pre })
+}
+
+fn compare_versions(left: &str, right: &str) -> Option<Ordering> {
+    Some(parse_version(left)?.cmp(&parse_version(right)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+
+    use super::{Capabilities, VersionCheck, api_path, compare_versions};
+    use crate::model::app::AppMetadata;
+    use crate::test_utils::read_json;
+
+    fn metadata(api_version: i64) -> AppMetadata {
+        let mut metadata: AppMetadata = read_json("resources/metadata_app.json");
+        metadata.api_version = api_version;
+        metadata
+    }
+
+    #[test]
+    fn versions() {
+        for (left, right, expected) in [
+            ("0.1.0", "0.1.0b1", Ordering::Greater),
+            ("0.1.0a118", "0.1.0b1", Ordering::Less),
+            ("0.1.0b20", "0.1.0b3", Ordering::Greater),
+            ("0.1.0rc1", "0.1.0b30", Ordering::Greater),
+            ("0.1", "0.1.0", Ordering::Equal),
+            ("0.2.0.dev3+g1234", "0.1.1", Ordering::Greater),
+        ] {
+            assert_eq!(
+                compare_versions(left, right),
+                Some(expected),
+                "{left} {right}"
+            );
+        }
+        assert_eq!(compare_versions("unknown", "0.1.0"), None);
+    }
+
+    #[test]
+    fn supported_version() {
+        let capabilities = Capabilities::negotiate(metadata(0), VersionCheck::Strict).unwrap();
+        assert!(capabilities.supports("keys_filter"));
+        assert!(!capabilities.supports("unknown"));
+    }
+
+    #[test]
+    fn api_paths() {
+        assert_eq!(api_path("http://127.0.0.1:8000/api/v1"), "/api/v1/");
+        assert_eq!(
+            api_path("https://tiled.example.com/tiled/api/v2/"),
+            "/tiled/api/v2/"
+        );
+        assert_eq!(api_path("not a url"), "api/v1/");
+    }
+
+    #[test]
+    fn formats() {
+        let capabilities = Capabilities::negotiate(metadata(0), VersionCheck::Strict).unwrap();
+        assert_eq!(
+            capabilities.format("table", &["text/x-unknown", "application/json"]),
+            Some("application/json")
+        );
+        assert_eq!(capabilities.format("array", &["text/x-unknown"]), None);
+        assert_eq!(
+            capabilities.format("unknown_family", &["application/json"]),
+            Some("application/json")
+        );
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let Err(err) = Capabilities::negotiate(metadata(3), VersionCheck::Strict) else {
+            panic!("Expected unsupported version error");
+        };
+        assert_eq!(err.0, 3);
+        assert!(Capabilities::negotiate(metadata(3), VersionCheck::Warn).is_ok());
+    }
+}
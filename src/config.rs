@@ -1,15 +1,37 @@
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use config::{Config, ConfigError, File};
 use serde::Deserialize;
 use url::Url;
 
+use crate::compat::VersionCheck;
+use crate::digest::DigestAlgorithm;
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct GlazedConfig {
     pub bind_address: SocketAddr,
     pub public_address: Option<Url>,
+    /// The default tiled server, used for any requests not routed to another backend
     pub tiled_client: TiledClientConfig,
+    /// Additional tiled servers, eg for beamlines that run their own
+    #[serde(default)]
+    pub backends: Vec<BackendConfig>,
+    #[serde(default)]
+    pub downloads: DownloadConfig,
+    /// Where to record downloads made through glazed. No records are kept if this is not set.
+    pub audit: Option<AuditConfig>,
+    #[serde(default)]
+    pub subscriptions: SubscriptionConfig,
+    #[serde(default)]
+    pub preview: PreviewConfig,
+    #[serde(default)]
+    pub webhooks: WebhookConfig,
+    /// Where to store users' bookmarks and saved searches. The `me` query and the mutations
+    /// that change it are unavailable if this is not set.
+    pub user_data: Option<UserDataConfig>,
+    #[serde(default)]
+    pub persisted_queries: PersistedQueryConfig,
 }
 impl GlazedConfig {
     pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
@@ -23,12 +45,435 @@ impl GlazedConfig {
             public_address: None,
             tiled_client: TiledClientConfig {
                 address: Url::parse("http://localhost:8000").expect("Static URL is valid"),
+                cache: None,
+                concurrency: default_concurrency(),
+                retry: RetryConfig::default(),
+                circuit_breaker: CircuitBreakerConfig::default(),
+                timeouts: TimeoutConfig::default(),
+                pool: PoolConfig::default(),
+                http2: default_http2(),
+                h2c: false,
+                user_agent: default_user_agent(),
+                credentials: None,
+                auth_policy: AuthPolicy::default(),
+                tls: TlsConfig::default(),
+                version_check: VersionCheck::default(),
             },
+            backends: vec![],
+            downloads: DownloadConfig::default(),
+            audit: None,
+            subscriptions: SubscriptionConfig::default(),
+            preview: PreviewConfig::default(),
+            webhooks: WebhookConfig::default(),
+            user_data: None,
+            persisted_queries: PersistedQueryConfig::default(),
         }
     }
 }
 
+/// A named tiled server and the data that should be requested from it. The user's credentials
+/// are sent to every backend a request is routed to, including queries across every backend.
+#[derive(Deserialize, Debug, Clone)]
+pub struct BackendConfig {
+    pub name: String,
+    /// Instruments whose data is stored in this backend
+    #[serde(default)]
+    pub instruments: Vec<String>,
+    /// Prefixes of the instrument sessions stored in this backend (eg "mx" for "mx12345-1")
+    #[serde(default)]
+    pub session_prefixes: Vec<String>,
+    pub tiled_client: TiledClientConfig,
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct TiledClientConfig {
     pub address: Url,
+    /// Cache responses from tiled in memory. Responses are not cached if this is not set.
+    pub cache: Option<CacheConfig>,
+    /// Maximum number of requests made to tiled at once when fetching the children of several
+    /// nodes, eg the streams of a run
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerConfig,
+    #[serde(default)]
+    pub timeouts: TimeoutConfig,
+    #[serde(default)]
+    pub pool: PoolConfig,
+    /// Offer HTTP/2 when connecting to tiled over HTTPS. It is only used if tiled accepts it
+    /// and HTTP/1.1 is used otherwise. If disabled, only HTTP/1.1 is used.
+    #[serde(default = "default_http2")]
+    pub http2: bool,
+    /// Use HTTP/2 without negotiating it first (h2c), for tiled servers that support HTTP/2 over
+    /// plain HTTP. Requests fail if tiled only supports HTTP/1.1.
+    #[serde(default)]
+    pub h2c: bool,
+    /// User-Agent sent with requests to tiled
+    #[serde(default = "default_user_agent")]
+    pub user_agent: String,
+    /// Credentials glazed uses to access tiled on its own behalf
+    pub credentials: Option<ServiceCredentials>,
+    /// How the credentials of users and the service credentials are sent to tiled
+    #[serde(default)]
+    pub auth_policy: AuthPolicy,
+    #[serde(default)]
+    pub tls: TlsConfig,
+    /// What to do if tiled reports an API version glazed does not support
+    #[serde(default)]
+    pub version_check: VersionCheck,
+}
+
+/// Somewhere to read a secret from so that it does not have to be included in the config file
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum Secret {
+    File(PathBuf),
+    Env(String),
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServiceCredentials {
+    /// A static tiled API key
+    ApiKey { key: Secret },
+    /// Tokens requested from an OAuth2 token endpoint using the client credentials grant
+    ClientCredentials {
+        token_url: Url,
+        client_id: String,
+        client_secret: Secret,
+        scope: Option<String>,
+    },
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthPolicy {
+    /// Forward the user's credentials. Requests from anonymous users are made anonymously and
+    /// the service credentials are only used by glazed's own background tasks, eg webhooks.
+    #[default]
+    User,
+    /// Always use the service credentials and never forward the user's
+    Service,
+    /// Use the service credentials and forward the user's credentials in an
+    /// `X-Forwarded-Authorization` header for tiled to verify
+    ServiceWithUser,
+}
+
+fn default_http2() -> bool {
+    true
+}
+
+fn default_user_agent() -> String {
+    concat!("glazed/", env!("CARGO_PKG_VERSION")).into()
+}
+
+/// TLS settings for HTTPS connections to tiled
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct TlsConfig {
+    /// PEM files of CA certificates to trust in addition to the default roots, eg for tiled
+    /// servers using certificates signed by an internal CA
+    pub ca_certs: Vec<PathBuf>,
+    /// PEM file containing the certificate (chain) to present to tiled if it requires client
+    /// certificates
+    pub client_cert: Option<PathBuf>,
+    /// PEM file containing the private key of the client certificate
+    pub client_key: Option<PathBuf>,
+    /// Name to send as the SNI and to verify the certificate of tiled against, if it differs
+    /// from the host in the address. The host in the address is only used to find the IP
+    /// address to connect to and is resolved once when glazed starts.
+    pub server_name: Option<String>,
+    /// Accept any certificate presented by tiled. This should only be used for development.
+    pub insecure: bool,
+}
+
+/// Timeouts (in seconds) for requests made to tiled. A value of 0 disables the timeout.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct TimeoutConfig {
+    /// Time to wait for a connection to be established
+    pub connect: u64,
+    /// Time to wait between receiving parts of a response
+    pub read: u64,
+    /// Total time for a metadata, search or data request including reading the response
+    pub request: u64,
+    /// Total time for a download. Downloads can be large so this is disabled by default and the
+    /// read timeout is relied on to detect stalled downloads.
+    pub download: u64,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        Self {
+            connect: 10,
+            read: 30,
+            request: 60,
+            download: 0,
+        }
+    }
+}
+
+/// Connection pool settings for the connections to tiled
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PoolConfig {
+    /// Time (in seconds) to keep idle connections open. 0 keeps them open indefinitely.
+    pub idle_timeout: u64,
+    /// Maximum number of idle connections to keep open
+    pub max_idle: Option<usize>,
+    /// Maximum number of concurrent requests to this tiled server. Downloads hold their
+    /// connection until the body has been sent to the client. Each backend has its own limit.
+    pub max_connections: Option<usize>,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout: 90,
+            max_idle: None,
+            max_connections: None,
+        }
+    }
+}
+
+fn default_concurrency() -> usize {
+    8
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// Maximum number of responses to keep
+    pub max_entries: usize,
+    /// Maximum total size of cached responses
+    pub max_bytes: usize,
+    /// Time (in seconds) to keep responses for data that could still change
+    pub ttl: u64,
+    /// Time (in seconds) to keep responses for finished runs and nodes backed by immutable or
+    /// locked data sources. Glazed's own writes clear the cache but changes made directly in
+    /// tiled are not seen until the entry expires.
+    pub immutable_ttl: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: 1000,
+            max_bytes: 64 * 1024 * 1024,
+            ttl: 5,
+            immutable_ttl: 3600,
+        }
+    }
+}
+
+/// Retry policy for requests to tiled that fail because tiled could not be reached or was
+/// temporarily unavailable (502, 503 and 504 responses).
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// Number of times to retry a failed request before giving up
+    pub retries: u32,
+    /// Delay (in milliseconds) before the first retry. This is doubled for each subsequent retry
+    /// and a random jitter of up to half the delay is subtracted from it.
+    pub initial_backoff: u64,
+    /// Maximum delay (in milliseconds) between retries
+    pub max_backoff: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            retries: 2,
+            initial_backoff: 100,
+            max_backoff: 2000,
+        }
+    }
+}
+
+/// Stop sending requests to tiled for a while once it has failed repeatedly
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CircuitBreakerConfig {
+    /// Number of consecutive failed requests (after retries) before requests are rejected without
+    /// trying tiled. Set to 0 to disable the circuit breaker.
+    pub failure_threshold: u32,
+    /// Time (in seconds) to wait before letting a request through to check whether tiled has
+    /// recovered
+    pub reset_timeout: u64,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            reset_timeout: 30,
+        }
+    }
+}
+
+/// Settings for GraphQL subscriptions, which watch tiled for changes by polling it
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SubscriptionConfig {
+    /// Time (in milliseconds) between checks for changes
+    pub poll_interval: u64,
+}
+
+impl Default for SubscriptionConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval: 2000,
+        }
+    }
+}
+
+/// Settings for the images generated to preview array data
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PreviewConfig {
+    /// Largest frame (in pixels) that can be previewed. The whole frame is read into memory
+    /// before it is downsampled so this limits the memory used by each preview.
+    pub max_pixels: usize,
+}
+
+impl Default for PreviewConfig {
+    fn default() -> Self {
+        Self {
+            // Enough for the frames of a 16M detector
+            max_pixels: 25_000_000,
+        }
+    }
+}
+
+/// Queries that clients can run by sending the hash of the query instead of its text
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PersistedQueryConfig {
+    /// Accept Apollo-style automatic persisted queries, where a client that sends a hash glazed
+    /// doesn't know is asked to send the full query once
+    pub automatic: bool,
+    /// Number of automatically persisted queries to remember
+    pub cache_size: usize,
+    /// Persisted query manifest listing the only operations that may be run. Any other query
+    /// is rejected, including those sent in full.
+    pub allow_list: Option<PathBuf>,
+}
+
+impl Default for PersistedQueryConfig {
+    fn default() -> Self {
+        Self {
+            automatic: true,
+            cache_size: 1000,
+            allow_list: None,
+        }
+    }
+}
+
+/// Requests sent to other services when runs finish, eg to start processing the data
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct WebhookConfig {
+    pub hooks: Vec<Webhook>,
+    /// Time (in milliseconds) between checks for finished runs
+    pub poll_interval: u64,
+    /// Retry policy for deliveries that fail or are rejected by the receiver
+    pub retry: RetryConfig,
+    /// Time (in seconds) to wait for the receiver to respond to each delivery
+    pub timeout: u64,
+    /// File to record the outcome of each delivery in, one line of JSON per delivery. Deliveries
+    /// are only logged if this is set. The log is read when glazed starts so that failed
+    /// deliveries are retried and runs that finished while glazed was stopped are sent.
+    pub delivery_log: Option<PathBuf>,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            hooks: vec![],
+            poll_interval: 10000,
+            retry: RetryConfig {
+                retries: 5,
+                initial_backoff: 1000,
+                max_backoff: 60000,
+            },
+            timeout: 10,
+            delivery_log: None,
+        }
+    }
+}
+
+/// A URL that is sent a POST request when a run matching all of the given filters finishes
+#[derive(Deserialize, Debug, Clone)]
+pub struct Webhook {
+    pub url: Url,
+    pub instrument: Option<String>,
+    pub plan_name: Option<String>,
+    pub instrument_session: Option<String>,
+    /// Key used to sign each request with HMAC-SHA256 so that the receiver can check that it was
+    /// sent by glazed
+    pub secret: Option<Secret>,
+}
+
+/// Limits applied to downloads passing through glazed to protect both glazed and tiled from being
+/// saturated by a single user. All limits are disabled by default.
+#[derive(Deserialize, Debug, Clone)]
+pub struct DownloadConfig {
+    /// Maximum number of concurrent downloads made with the same credentials
+    pub max_per_user: Option<usize>,
+    /// Maximum number of concurrent downloads across all users
+    pub max_global: Option<usize>,
+    /// Maximum transfer rate of each individual download
+    pub bytes_per_second: Option<u64>,
+    /// How long (in seconds) a download can wait for a free slot before being rejected
+    #[serde(default)]
+    pub queue_timeout: u64,
+    /// Value (in seconds) of the Retry-After header sent when a download is rejected
+    #[serde(default = "default_retry_after")]
+    pub retry_after: u64,
+    /// Digest to compute while streaming downloads, sent to clients that accept trailers
+    pub digest: Option<DigestAlgorithm>,
+}
+
+fn default_retry_after() -> u64 {
+    10
+}
+
+impl Default for DownloadConfig {
+    fn default() -> Self {
+        Self {
+            max_per_user: None,
+            max_global: None,
+            bytes_per_second: None,
+            queue_timeout: 0,
+            retry_after: default_retry_after(),
+            digest: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "sink", rename_all = "lowercase")]
+pub enum AuditConfig {
+    /// Write records to stdout alongside the application logs
+    Stdout,
+    /// Write records to a file, optionally rotating it once it reaches `max_bytes`
+    File {
+        path: PathBuf,
+        max_bytes: Option<u64>,
+        /// Number of rotated files to keep in addition to the current one
+        #[serde(default = "default_max_files")]
+        max_files: usize,
+    },
+}
+
+fn default_max_files() -> usize {
+    5
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct UserDataConfig {
+    /// SQLite database file, created if it doesn't exist
+    pub path: PathBuf,
 }
@@ -0,0 +1,322 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use reqwest::header::{AUTHORIZATION, HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Client, Url};
+use serde::Deserialize;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use tracing::debug;
+
+use crate::clients::{ClientError, ClientResult};
+use crate::config::{AuthPolicy, Secret, ServiceCredentials};
+
+pub(crate) const X_FORWARDED_AUTHORIZATION: HeaderName =
+    HeaderName::from_static("x-forwarded-authorization");
+
+/// Tokens are refreshed this long before they expire so that they don't expire while a request
+/// is in flight
+const REFRESH_MARGIN: Duration = Duration::from_secs(30);
+
+/// How long to use a token for if the token endpoint doesn't say when it expires
+const DEFAULT_TOKEN_LIFETIME: Duration = Duration::from_secs(300);
+
+impl Secret {
+    pub fn read(&self) -> ClientResult<String> {
+        match self {
+            Secret::File(path) => std::fs::read_to_string(path)
+                .map(|secret| secret.trim().to_owned())
+                .map_err(|e| {
+                    ClientError::Credentials(format!("Unable to read secret from {path:?}: {e}"))
+                }),
+            Secret::Env(var) => std::env::var(var).map_err(|e| {
+                ClientError::Credentials(format!("Unable to read secret from ${var}: {e}"))
+            }),
+        }
+    }
+}
+
+/// Decides which credentials are sent with each request to tiled
+#[derive(Clone)]
+pub struct Authenticator {
+    policy: AuthPolicy,
+    service: Option<Arc<ServiceAuth>>,
+}
+
+enum ServiceAuth {
+    Static(HeaderValue),
+    ClientCredentials(Box<TokenSource>),
+}
+
+/// OAuth2 client credentials used to request tokens, and the most recent token
+struct TokenSource {
+    token_url: Url,
+    client_id: String,
+    client_secret: String,
+    scope: Option<String>,
+    token: Mutex<Option<Token>>,
+}
+
+struct Token {
+    header: HeaderValue,
+    expires: Instant,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+}
+
+fn header_value(value: String) -> ClientResult<HeaderValue> {
+    HeaderValue::try_from(value)
+        .map_err(|_| ClientError::Credentials("Credentials are not a valid header".into()))
+}
+
+impl Authenticator {
+    pub fn new(credentials: Option<&ServiceCredentials>, policy: AuthPolicy) -> ClientResult<Self> {
+        let service = match credentials {
+            None if policy != AuthPolicy::User => {
+                return Err(ClientError::Credentials(format!(
+                    "Auth policy {policy:?} requires service credentials"
+                )));
+            }
+            None => None,
+            Some(ServiceCredentials::ApiKey { key }) => {
+                let mut header = header_value(format!("Apikey {}", key.read()?))?;
+                header.set_sensitive(true);
+                Some(ServiceAuth::Static(header))
+            }
+            Some(ServiceCredentials::ClientCredentials {
+                token_url,
+                client_id,
+                client_secret,
+                scope,
+            }) => Some(ServiceAuth::ClientCredentials(Box::new(TokenSource {
+                token_url: token_url.clone(),
+                client_id: client_id.clone(),
+                client_secret: client_secret.read()?,
+                scope: scope.clone(),
+                token: Mutex::new(None),
+            }))),
+        };
+        Ok(Self {
+            policy,
+            service: service.map(Arc::new),
+        })
+    }
+
+    /// An authenticator that never adds the service credentials, so that requests are made as
+    /// the user alone
+    pub fn user() -> Self {
+        Self {
+            policy: AuthPolicy::User,
+            service: None,
+        }
+    }
+
+    /// An authenticator that always uses the service credentials, for requests glazed makes on
+    /// its own behalf rather than for a user
+    pub fn service(&self) -> Self {
+        Self {
+            policy: AuthPolicy::Service,
+            service: self.service.clone(),
+        }
+    }
+
+    /// Set the credentials of a request according to the policy. The headers should contain the
+    /// user's own credentials, if they provided any.
+    pub async fn apply(&self, client: &Client, headers: &mut HeaderMap) -> ClientResult<()> {
+        let Some(service) = &self.service else {
+            return Ok(());
+        };
+        match self.policy {
+            // Requests from anonymous users stay anonymous rather than gaining the service's
+            // access
+            AuthPolicy::User => return Ok(()),
+            AuthPolicy::Service => {}
+            AuthPolicy::ServiceWithUser => {
+                // The user's own credentials are passed on for tiled to verify, as any identity
+                // glazed could extract from them would be unverified
+                if let Some(user) = headers.remove(AUTHORIZATION) {
+                    headers.insert(X_FORWARDED_AUTHORIZATION, user);
+                }
+            }
+        }
+        headers.insert(AUTHORIZATION, service.header(client).await?);
+        Ok(())
+    }
+}
+
+impl ServiceAuth {
+    async fn header(&self, client: &Client) -> ClientResult<HeaderValue> {
+        match self {
+            ServiceAuth::Static(header) => Ok(header.clone()),
+            ServiceAuth::ClientCredentials(source) => source.header(client).await,
+        }
+    }
+}
+
+impl TokenSource {
+    /// Get the current token, requesting a new one if it has expired or is about to
+    async fn header(&self, client: &Client) -> ClientResult<HeaderValue> {
+        // Holding the lock while refreshing means concurrent requests wait for a single new
+        // token instead of all requesting their own
+        let mut token = self.token.lock().await;
+        if let Some(current) = &*token
+            && current.expires > Instant::now() + REFRESH_MARGIN
+        {
+            return Ok(current.header.clone());
+        }
+        debug!("Requesting service token from {}", self.token_url);
+        let mut form = vec![
+            ("grant_type", "client_credentials"),
+            ("client_id", &self.client_id),
+            ("client_secret", &self.client_secret),
+        ];
+        if let Some(scope) = &self.scope {
+            form.push(("scope", scope));
+        }
+        let response = client
+            .post(self.token_url.clone())
+            .form(&form)
+            .send()
+            .await?;
+        let status = response.status();
+        let body = response.text().await?;
+        if !status.is_success() {
+            return Err(ClientError::Credentials(format!(
+                "Token request failed: {status} - {body}"
+            )));
+        }
+        let response: TokenResponse =
+            serde_json::from_str(&body).map_err(|e| ClientError::InvalidResponse(e, body))?;
+        let mut header = header_value(format!("Bearer {}", response.access_token))?;
+        header.set_sensitive(true);
+        let lifetime = response
+            .expires_in
+            .map_or(DEFAULT_TOKEN_LIFETIME, Duration::from_secs);
+        *token = Some(Token {
+            header: header.clone(),
+            expires: Instant::now() + lifetime,
+        });
+        Ok(header)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use httpmock::MockServer;
+    use reqwest::Client;
+    use reqwest::header::{AUTHORIZATION, HeaderMap, HeaderValue};
+
+    use super::Authenticator;
+    use crate::clients::ClientError;
+    use crate::config::{AuthPolicy, Secret, ServiceCredentials};
+
+    fn api_key(policy: AuthPolicy) -> Authenticator {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key");
+        std::fs::write(&path, "secret\n").unwrap();
+        let credentials = ServiceCredentials::ApiKey {
+            key: Secret::File(path),
+        };
+        Authenticator::new(Some(&credentials), policy).unwrap()
+    }
+
+    fn user_headers() -> HeaderMap {
+        // bob:password
+        [(
+            AUTHORIZATION,
+            HeaderValue::from_static("Basic Ym9iOnBhc3N3b3Jk"),
+        )]
+        .into_iter()
+        .collect()
+    }
+
+    #[tokio::test]
+    async fn user_policy() {
+        let auth = api_key(AuthPolicy::User);
+        let mut headers = user_headers();
+        auth.apply(&Client::new(), &mut headers).await.unwrap();
+        assert_eq!(headers, user_headers());
+
+        let mut headers = HeaderMap::new();
+        auth.apply(&Client::new(), &mut headers).await.unwrap();
+        assert!(headers.is_empty());
+
+        let mut headers = HeaderMap::new();
+        auth.service()
+            .apply(&Client::new(), &mut headers)
+            .await
+            .unwrap();
+        assert_eq!(headers[AUTHORIZATION], "Apikey secret");
+    }
+
+    #[tokio::test]
+    async fn service_policies() {
+        let mut headers = user_headers();
+        api_key(AuthPolicy::Service)
+            .apply(&Client::new(), &mut headers)
+            .await
+            .unwrap();
+        assert_eq!(headers[AUTHORIZATION], "Apikey secret");
+        assert!(!headers.contains_key("x-forwarded-authorization"));
+
+        let mut headers = user_headers();
+        api_key(AuthPolicy::ServiceWithUser)
+            .apply(&Client::new(), &mut headers)
+            .await
+            .unwrap();
+        assert_eq!(headers[AUTHORIZATION], "Apikey secret");
+        assert_eq!(
+            headers["x-forwarded-authorization"],
+            "Basic Ym9iOnBhc3N3b3Jk"
+        );
+
+        let mut headers = HeaderMap::new();
+        api_key(AuthPolicy::ServiceWithUser)
+            .apply(&Client::new(), &mut headers)
+            .await
+            .unwrap();
+        assert!(!headers.contains_key("x-forwarded-authorization"));
+    }
+
+    #[test]
+    fn service_policy_requires_credentials() {
+        let result = Authenticator::new(None, AuthPolicy::Service);
+        assert!(matches!(result, Err(ClientError::Credentials(_))));
+    }
+
+    #[tokio::test]
+    async fn client_credentials_token_reused() {
+        let server = MockServer::start();
+        let mock = server
+            .mock_async(|when, then| {
+                when.method("POST")
+                    .path("/token")
+                    .form_urlencoded_tuple("grant_type", "client_credentials")
+                    .form_urlencoded_tuple("client_id", "glazed")
+                    .form_urlencoded_tuple("client_secret", "hunter2");
+                then.status(200)
+                    .json_body(serde_json::json!({"access_token": "abc", "expires_in": 3600}));
+            })
+            .await;
+        let dir = tempfile::tempdir().unwrap();
+        let secret = dir.path().join("client_secret");
+        std::fs::write(&secret, "hunter2").unwrap();
+        let credentials = ServiceCredentials::ClientCredentials {
+            token_url: server.url("/token").parse().unwrap(),
+            client_id: "glazed".into(),
+            client_secret: Secret::File(secret),
+            scope: None,
+        };
+        let auth = Authenticator::new(Some(&credentials), AuthPolicy::Service).unwrap();
+        for _ in 0..2 {
+            let mut headers = HeaderMap::new();
+            auth.apply(&Client::new(), &mut headers).await.unwrap();
+            assert_eq!(headers[AUTHORIZATION], "Bearer abc");
+        }
+        mock.assert_calls(1);
+    }
+}
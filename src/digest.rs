@@ -0,0 +1,474 @@
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, ready};
+
+use async_graphql::{Enum, ErrorExtensions as _};
+use axum::body::{Body, Bytes};
+use axum::http::{HeaderMap, HeaderValue};
+use base64::Engine as _;
+use base64::engine::general_purpose::STANDARD;
+use http_body::Frame;
+use reqwest::header::{CONTENT_LENGTH, HeaderName, TE, TRAILER};
+use serde::Deserialize;
+use sha2::Digest as _;
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::audit::{AuditLog, AuditRecord, Outcome};
+use crate::clients::{ClientError, ClientResult, TiledClient};
+use crate::handlers::AuthHeader;
+use crate::limits::DownloadLimiter;
+
+const DIGEST: HeaderName = HeaderName::from_static("digest");
+
+/// Maximum number of checksums to remember before the cache is cleared
+const MAX_CACHED_CHECKSUMS: usize = 10_000;
+
+#[derive(Enum, Debug, Default, Copy, Clone, Eq, PartialEq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DigestAlgorithm {
+    #[default]
+    Sha256,
+    Xxh3,
+}
+
+impl DigestAlgorithm {
+    /// Name of the algorithm as used in the Digest header
+    fn name(&self) -> &'static str {
+        match self {
+            DigestAlgorithm::Sha256 => "sha-256",
+            DigestAlgorithm::Xxh3 => "xxh3",
+        }
+    }
+    fn hasher(&self) -> Hasher {
+        match self {
+            DigestAlgorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            DigestAlgorithm::Xxh3 => Hasher::Xxh3(Box::new(Xxh3::new())),
+        }
+    }
+}
+
+enum Hasher {
+    Sha256(sha2::Sha256),
+    Xxh3(Box<Xxh3>),
+}
+
+impl Hasher {
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Xxh3(hasher) => hasher.update(data),
+        }
+    }
+    fn finalize(self) -> Vec<u8> {
+        match self {
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+            Hasher::Xxh3(hasher) => hasher.digest().to_be_bytes().to_vec(),
+        }
+    }
+}
+
+/// Add a `Digest` of a download to the response. If the hex encoded checksum of the content is
+/// already known it is sent as a header, otherwise the digest is computed as the download is
+/// streamed to the client and sent as a trailer once the body is complete.
+///
+/// Trailers can only be sent to clients that ask for them (with `TE: trailers`) and only when the
+/// body is sent using chunked encoding, so the content-length is dropped in that case. For other
+/// clients the body is returned unchanged.
+pub fn with_digest(
+    algorithm: DigestAlgorithm,
+    known: Option<String>,
+    request_headers: &HeaderMap,
+    response_headers: &mut HeaderMap,
+    body: Body,
+) -> Body {
+    if let Some(digest) = known
+        .as_deref()
+        .and_then(|hex| digest_value(algorithm, hex))
+    {
+        response_headers.insert(DIGEST, digest);
+        return body;
+    }
+    let accepts_trailers = request_headers
+        .get_all(TE)
+        .iter()
+        .filter_map(|te| te.to_str().ok())
+        .flat_map(|te| te.split(','))
+        .any(|te| te.trim().eq_ignore_ascii_case("trailers"));
+    if !accepts_trailers {
+        return body;
+    }
+    response_headers.remove(CONTENT_LENGTH);
+    response_headers.insert(TRAILER, HeaderValue::from_static("digest"));
+    Body::new(DigestBody {
+        inner: body,
+        algorithm,
+        hasher: Some(algorithm.hasher()),
+    })
+}
+
+/// Value of the `Digest` header for a hex encoded checksum
+fn digest_value(algorithm: DigestAlgorithm, hex: &str) -> Option<HeaderValue> {
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<_>>>()?;
+    let digest = format!("{}={}", algorithm.name(), STANDARD.encode(bytes));
+    HeaderValue::from_str(&digest).ok()
+}
+
+struct DigestBody {
+    inner: Body,
+    algorithm: DigestAlgorithm,
+    hasher: Option<Hasher>,
+}
+
+impl http_body::Body for DigestBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        match ready!(Pin::new(&mut self.inner).poll_frame(cx)) {
+            Some(Ok(frame)) => {
+                if let (Some(data), Some(hasher)) = (frame.data_ref(), self.hasher.as_mut()) {
+                    hasher.update(data);
+                }
+                Poll::Ready(Some(Ok(frame)))
+            }
+            Some(Err(err)) => {
+                // Never send a digest for a body that wasn't sent in full
+                self.hasher = None;
+                Poll::Ready(Some(Err(err)))
+            }
+            None => {
+                let Some(hasher) = self.hasher.take() else {
+                    return Poll::Ready(None);
+                };
+                let digest = format!(
+                    "{}={}",
+                    self.algorithm.name(),
+                    STANDARD.encode(hasher.finalize())
+                );
+                let mut trailers = HeaderMap::new();
+                if let Ok(digest) = HeaderValue::from_str(&digest) {
+                    trailers.insert(DIGEST, digest);
+                }
+                Poll::Ready(Some(Ok(Frame::trailers(trailers))))
+            }
+        }
+    }
+}
+
+/// Identifies the content of a file asset as seen by a specific user
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ChecksumKey {
+    /// Name of the backend the asset is in, as asset IDs are only unique within one tiled server
+    pub backend: String,
+    pub user: String,
+    pub run: String,
+    pub stream: String,
+    pub det: String,
+    pub id: u32,
+    pub algorithm: DigestAlgorithm,
+}
+
+/// Checksums of assets that have already been computed. Keys include a hash of the user's
+/// credentials so that a user without access to an asset can't learn its checksum from another
+/// user's request.
+///
+/// Computing a checksum downloads the whole asset so it is subject to the same limits and
+/// auditing as a download made through the asset endpoints.
+#[derive(Clone)]
+pub struct ChecksumCache {
+    checksums: Arc<Mutex<HashMap<ChecksumKey, String>>>,
+    limiter: DownloadLimiter,
+    audit: AuditLog,
+}
+
+impl ChecksumCache {
+    pub fn new(limiter: DownloadLimiter, audit: AuditLog) -> Self {
+        Self {
+            checksums: Default::default(),
+            limiter,
+            audit,
+        }
+    }
+
+    /// The hex encoded checksum of the asset if it has already been computed
+    pub fn cached(&self, key: &ChecksumKey) -> Option<String> {
+        self.checksums
+            .lock()
+            .expect("Checksum lock poisoned")
+            .get(key)
+            .cloned()
+    }
+
+    /// Get the hex encoded checksum of the asset, downloading it from tiled if it has not been
+    /// computed already
+    pub async fn checksum(
+        &self,
+        client: &TiledClient,
+        key: ChecksumKey,
+        auth: Option<&AuthHeader>,
+    ) -> async_graphql::Result<String> {
+        if let Some(checksum) = self.cached(&key) {
+            return Ok(checksum);
+        }
+        let mut record = AuditRecord::new(
+            auth,
+            (&key.run, &key.stream, &key.det, key.id),
+            Some(format!("checksum:{}", key.algorithm.name())),
+        );
+        self.audit.identify(&mut record, client, auth).await;
+        let _permit = match self.limiter.acquire(&key.user).await {
+            Ok(permit) => permit,
+            Err(rejected) => {
+                record.status = 429;
+                self.audit.record(&record);
+                return Err(rejected.extend());
+            }
+        };
+        let hashed = self.download_hash(client, &key, auth, &mut record).await;
+        self.audit.record(&record);
+        let checksum = hashed.map_err(|e| e.extend())?;
+
+        let mut cache = self.checksums.lock().expect("Checksum lock poisoned");
+        if cache.len() >= MAX_CACHED_CHECKSUMS {
+            cache.clear();
+        }
+        cache.insert(key, checksum.clone());
+        Ok(checksum)
+    }
+
+    /// Download the asset and hash its content, keeping track of the download in the record
+    async fn download_hash(
+        &self,
+        client: &TiledClient,
+        key: &ChecksumKey,
+        auth: Option<&AuthHeader>,
+        record: &mut AuditRecord,
+    ) -> ClientResult<String> {
+        let headers = auth.map(AuthHeader::as_header_map);
+        let mut response = client
+            .download(&key.run, &key.stream, &key.det, key.id, None, headers)
+            .await?;
+        let status = response.status().as_u16();
+        record.status = status;
+        record.outcome = Outcome::Aborted;
+        if !response.status().is_success() {
+            let body = response.text().await?;
+            return Err(match status {
+                400..500 => ClientError::TiledRequest(status, body),
+                _ => ClientError::TiledInternal(status, body),
+            });
+        }
+        let mut hasher = key.algorithm.hasher();
+        while let Some(chunk) = response.chunk().await? {
+            record.bytes += chunk.len() as u64;
+            hasher.update(&chunk);
+        }
+        record.outcome = Outcome::Complete;
+        Ok(hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::http::{HeaderMap, HeaderValue};
+    use http_body_util::BodyExt as _;
+    use httpmock::MockServer;
+
+    use super::{ChecksumCache, ChecksumKey, DigestAlgorithm, with_digest};
+    use crate::audit::{AuditLog, AuditRecord, Outcome};
+    use crate::clients::TiledClient;
+    use crate::config::{AuditConfig, DownloadConfig};
+    use crate::limits::DownloadLimiter;
+
+    const SHA_CONTENT: &str = "9045b4d25f53cf4582a334e08cade87eeb9e2064dfee0950c336f60309e023f3";
+
+    fn trailers_requested() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("te", HeaderValue::from_static("trailers"));
+        headers
+    }
+
+    #[tokio::test]
+    async fn digest_trailer() {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-length", HeaderValue::from_static("7"));
+        let body = with_digest(
+            DigestAlgorithm::Sha256,
+            None,
+            &trailers_requested(),
+            &mut headers,
+            Body::from("content"),
+        );
+        assert_eq!(headers.get("content-length"), None);
+        assert_eq!(headers["trailer"], "digest");
+
+        let collected = body.collect().await.unwrap();
+        assert_eq!(
+            collected.trailers().unwrap()["digest"],
+            "sha-256=7XACtDnprIRfIjV9giusFERzD722AW0+yUMil7nsn3M="
+        );
+        assert_eq!(collected.to_bytes(), "content");
+    }
+
+    #[tokio::test]
+    async fn xxh3_digest_trailer() {
+        let body = with_digest(
+            DigestAlgorithm::Xxh3,
+            None,
+            &trailers_requested(),
+            &mut HeaderMap::new(),
+            Body::from("content"),
+        );
+        let collected = body.collect().await.unwrap();
+        let digest = collected.trailers().unwrap()["digest"].to_str().unwrap();
+        assert!(digest.starts_with("xxh3="), "Unexpected digest {digest}");
+    }
+
+    #[tokio::test]
+    async fn no_trailer_unless_requested() {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-length", HeaderValue::from_static("7"));
+        let body = with_digest(
+            DigestAlgorithm::Sha256,
+            None,
+            &HeaderMap::new(),
+            &mut headers,
+            Body::from("content"),
+        );
+        assert_eq!(headers["content-length"], "7");
+        assert!(body.collect().await.unwrap().trailers().is_none());
+    }
+
+    #[tokio::test]
+    async fn known_digest_sent_as_header() {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-length", HeaderValue::from_static("13"));
+        let body = with_digest(
+            DigestAlgorithm::Sha256,
+            Some(SHA_CONTENT.into()),
+            &HeaderMap::new(),
+            &mut headers,
+            Body::from("detector data"),
+        );
+        assert_eq!(headers["content-length"], "13");
+        assert_eq!(
+            headers["digest"],
+            "sha-256=kEW00l9Tz0WCozTgjK3ofuueIGTf7glQwzb2AwngI/M="
+        );
+        assert_eq!(headers.get("trailer"), None);
+        assert!(body.collect().await.unwrap().trailers().is_none());
+    }
+
+    #[tokio::test]
+    async fn cached_checksum() {
+        let server = MockServer::start();
+        let mock = server
+            .mock_async(|when, then| {
+                when.method("GET")
+                    .path("/api/v1/asset/bytes/run/primary/det")
+                    .query_param("id", "3");
+                then.status(200).body("detector data");
+            })
+            .await;
+        let client = TiledClient::for_mock_server(&server);
+        let cache = ChecksumCache::new(
+            DownloadLimiter::new(DownloadConfig::default()),
+            AuditLog::default(),
+        );
+        let key = ChecksumKey {
+            backend: "default".into(),
+            user: "alice".into(),
+            run: "run".into(),
+            stream: "primary".into(),
+            det: "det".into(),
+            id: 3,
+            algorithm: DigestAlgorithm::Sha256,
+        };
+        for _ in 0..2 {
+            let checksum = cache.checksum(&client, key.clone(), None).await.unwrap();
+            assert_eq!(checksum, SHA_CONTENT);
+        }
+        mock.assert_calls(1);
+        // The same asset ID in another backend is a different asset
+        let other = ChecksumKey {
+            backend: "other".into(),
+            ..key
+        };
+        assert_eq!(cache.cached(&other), None);
+    }
+
+    #[tokio::test]
+    async fn checksum_limited_and_audited() {
+        let server = MockServer::start();
+        server
+            .mock_async(|when, then| {
+                when.method("GET")
+                    .path("/api/v1/asset/bytes/run/primary/det");
+                then.status(200).body("detector data");
+            })
+            .await;
+        let client = TiledClient::for_mock_server(&server);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let audit = AuditLog::new(Some(&AuditConfig::File {
+            path: path.clone(),
+            max_bytes: None,
+            max_files: 0,
+        }))
+        .unwrap();
+        let limiter = DownloadLimiter::new(DownloadConfig {
+            max_per_user: Some(1),
+            queue_timeout: 0,
+            ..DownloadConfig::default()
+        });
+        let cache = ChecksumCache::new(limiter.clone(), audit);
+        let key = |id| ChecksumKey {
+            backend: "default".into(),
+            user: "alice".into(),
+            run: "run".into(),
+            stream: "primary".into(),
+            det: "det".into(),
+            id,
+            algorithm: DigestAlgorithm::Sha256,
+        };
+
+        let permit = limiter.acquire("alice").await.unwrap();
+        let err = cache.checksum(&client, key(1), None).await.unwrap_err();
+        assert_eq!(
+            err.extensions.unwrap().get("code"),
+            Some(&async_graphql::Value::from("RATE_LIMITED"))
+        );
+        drop(permit);
+        let checksum = cache.checksum(&client, key(2), None).await.unwrap();
+        assert_eq!(checksum, SHA_CONTENT);
+
+        let records = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<AuditRecord>(line).unwrap())
+            .collect::<Vec<_>>();
+        let summary = records
+            .iter()
+            .map(|r| (r.asset_id, r.status, r.bytes, r.outcome, r.path.as_deref()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                (1, 429, 0, Outcome::Rejected, Some("checksum:sha-256")),
+                (2, 200, 13, Outcome::Complete, Some("checksum:sha-256")),
+            ]
+        );
+    }
+}
@@ -3,6 +3,8 @@ use axum::http::{HeaderMap, StatusCode};
 use serde_json::{Value, json};
 use tracing::error;
 
+use crate::clients::{ClientError, ClientResult};
+
 const FORWARDED_HEADERS: [&str; 4] = [
     "content-disposition",
     "content-type",
@@ -11,7 +13,7 @@ const FORWARDED_HEADERS: [&str; 4] = [
 ];
 
 pub async fn forward_download_response(
-    response: Result<reqwest::Response, reqwest::Error>,
+    response: ClientResult<reqwest::Response>,
 ) -> (StatusCode, HeaderMap, Body) {
     match response {
         Ok(mut resp) => match resp.status().as_u16() {
@@ -53,16 +55,18 @@ pub async fn forward_download_response(
             },
         Err(err) => {
             error!("Error sending request to tiled: {err}");
-            let (status, message) = if err.is_connect() {
-                (
+            let (status, message) = match &err {
+                ClientError::Timeout(_) => {
+                    (StatusCode::GATEWAY_TIMEOUT, "Request to tiled timed out")
+                }
+                ClientError::ServerError(err) if err.is_connect() => (
                     StatusCode::SERVICE_UNAVAILABLE,
                     "Could not connect to tiled",
-                )
-            } else {
-                (
+                ),
+                _ => (
                     StatusCode::INTERNAL_SERVER_ERROR,
                     "Error making request to tiled",
-                )
+                ),
             };
 
             (status, HeaderMap::new(), message.into())
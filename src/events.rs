@@ -0,0 +1,483 @@
+use std::time::Duration;
+
+use async_graphql::ErrorExtensions as _;
+use axum::Json;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::sse::{Event, KeepAlive, KeepAliveStream, Sse};
+use axum::response::{IntoResponse, Response};
+use futures_util::{Stream, StreamExt as _, future, stream};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::audit;
+use crate::backends::{Backends, find_node};
+use crate::clients::ClientError;
+use crate::handlers::AuthHeader;
+use crate::model::node::{self, NodeAttributes};
+use crate::model::run::RunStatus;
+use crate::watch::{self, RunStatusChange};
+
+const LAST_EVENT_ID: &str = "last-event-id";
+
+#[derive(Debug, Deserialize)]
+pub struct SessionEventParams {
+    backend: Option<String>,
+    instrument: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RunEventParams {
+    backend: Option<String>,
+}
+
+/// Published when a run is added to an instrument session and again when it finishes
+#[derive(Debug, Serialize)]
+struct SessionRun {
+    id: String,
+    backend: String,
+    scan_number: Option<i64>,
+    start_time: Option<f64>,
+    status: Option<RunStatus>,
+}
+
+type EventStream = Sse<KeepAliveStream<stream::BoxStream<'static, Result<Event, axum::Error>>>>;
+
+fn not_found(detail: String) -> Response {
+    (StatusCode::NOT_FOUND, Json(json!({"detail": detail}))).into_response()
+}
+
+/// Event sent in place of the expected events if tiled rejects a request or returns something
+/// unexpected. The stream ends after an error.
+fn error_event(err: ClientError) -> Result<Event, axum::Error> {
+    let err = err.extend();
+    let code = err.extensions.and_then(|ext| ext.get("code").cloned());
+    Event::default()
+        .event("error")
+        .json_data(json!({"message": err.message, "code": code}))
+}
+
+fn sse(events: impl Stream<Item = Result<Event, axum::Error>> + Send + 'static) -> EventStream {
+    Sse::new(events.boxed()).keep_alive(KeepAlive::default())
+}
+
+/// Position of a client in the events of an instrument session. It is sent as the ID of each
+/// event so that a client reconnecting with `Last-Event-ID` is sent the runs started and the runs
+/// finished since its last event.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct SessionPosition {
+    /// Latest start time of the runs that have been added
+    started: Option<f64>,
+    /// Latest stop time of the runs that have finished
+    stopped: Option<f64>,
+}
+
+impl SessionPosition {
+    /// Read a position from an event ID of the form `started,stopped`, either of which may be
+    /// empty
+    fn parse(id: &str) -> Self {
+        let (started, stopped) = id.split_once(',').unwrap_or((id, ""));
+        Self {
+            started: started.parse().ok(),
+            stopped: stopped.parse().ok(),
+        }
+    }
+
+    fn advance(&mut self, event: SessionPosition) {
+        let later = |a: Option<f64>, b: Option<f64>| match (a, b) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            (a, b) => a.or(b),
+        };
+        self.started = later(self.started, event.started);
+        self.stopped = later(self.stopped, event.stopped);
+    }
+
+    fn id(&self) -> String {
+        let time = |time: Option<f64>| time.map(|t| t.to_string()).unwrap_or_default();
+        format!("{},{}", time(self.started), time(self.stopped))
+    }
+}
+
+/// Runs added to and finished in an instrument session. A new client's position starts with the
+/// runs stopped from now, so that it can resume from there even if no run finishes before it
+/// disconnects.
+pub fn session_events(
+    backends: &Backends,
+    name: String,
+    params: SessionEventParams,
+    auth: Option<AuthHeader>,
+    headers: &HeaderMap,
+    interval: Duration,
+) -> Response {
+    let resumed = headers
+        .get(LAST_EVENT_ID)
+        .and_then(|id| id.to_str().ok())
+        .map(SessionPosition::parse)
+        .unwrap_or_default();
+    let backends = match backends.route(
+        params.backend.as_deref(),
+        params.instrument.as_deref(),
+        Some(&name),
+    ) {
+        Ok(backends) => backends,
+        Err(unknown) => return not_found(unknown.to_string()),
+    };
+    let headers = auth.as_ref().map(AuthHeader::as_header_map);
+    let watches = backends.into_iter().cloned().flat_map(|backend| {
+        let (added_name, finished_name) = (backend.name.to_string(), backend.name.to_string());
+        let added = watch::runs_added(
+            backend.clone(),
+            name.clone(),
+            headers.clone(),
+            interval,
+            resumed.started,
+        )
+        .map(move |run| match run {
+            Ok(run) => run_added(added_name.clone(), run),
+            Err(err) => (SessionPosition::default(), error_event(err)),
+        });
+        let finished = watch::session_runs_finished(
+            backend,
+            name.clone(),
+            headers.clone(),
+            interval,
+            resumed.stopped,
+        )
+        .map(move |run| match run {
+            Ok(run) => run_finished(finished_name.clone(), run),
+            Err(err) => (SessionPosition::default(), error_event(err)),
+        });
+        [added.boxed(), finished.boxed()]
+    });
+    let position = SessionPosition {
+        stopped: resumed
+            .stopped
+            .or(Some(audit::unix_millis() as f64 / 1000.0)),
+        ..resumed
+    };
+    let events = stream::select_all(watches).scan(position, |position, (event_position, event)| {
+        position.advance(event_position);
+        let id = position.id();
+        future::ready(Some(event.map(|event| event.id(id))))
+    });
+    sse(events).into_response()
+}
+
+fn session_run(backend: String, run: node::Data) -> SessionRun {
+    let start = match &*run.attributes {
+        NodeAttributes::Container(attrs) => attrs.metadata.start_doc(),
+        _ => None,
+    };
+    SessionRun {
+        backend,
+        scan_number: start.map(|start| start.scan_id),
+        start_time: start.map(|start| start.time),
+        status: watch::run_status(&run),
+        id: run.id,
+    }
+}
+
+fn run_added(backend: String, run: node::Data) -> (SessionPosition, Result<Event, axum::Error>) {
+    let added = session_run(backend, run);
+    let position = SessionPosition {
+        started: added.start_time,
+        stopped: None,
+    };
+    (
+        position,
+        Event::default().event("run_added").json_data(added),
+    )
+}
+
+fn run_finished(backend: String, run: node::Data) -> (SessionPosition, Result<Event, axum::Error>) {
+    let position = SessionPosition {
+        started: None,
+        stopped: watch::stop_time(&run),
+    };
+    let event = Event::default()
+        .event("run_finished")
+        .json_data(session_run(backend, run));
+    (position, event)
+}
+
+fn status_name(status: RunStatus) -> &'static str {
+    match status {
+        RunStatus::Running => "running",
+        RunStatus::Finished => "finished",
+        RunStatus::Aborted => "aborted",
+    }
+}
+
+/// Changes to a run's status and streams. Each event's ID identifies the state of the run so
+/// that a client reconnecting with `Last-Event-ID` is not sent the state it already has.
+pub async fn run_events(
+    backends: &Backends,
+    id: String,
+    params: RunEventParams,
+    auth: Option<AuthHeader>,
+    headers: &HeaderMap,
+    interval: Duration,
+) -> Response {
+    let last_event = headers
+        .get(LAST_EVENT_ID)
+        .and_then(|id| id.to_str().ok())
+        .map(str::to_owned);
+    let candidates = match backends.route(params.backend.as_deref(), None, None) {
+        Ok(backends) => backends,
+        Err(unknown) => return not_found(unknown.to_string()),
+    };
+    let headers = auth.as_ref().map(AuthHeader::as_header_map);
+    let backend = match find_node(candidates, &id, headers.clone()).await {
+        Ok(Some(backend)) => backend.clone(),
+        Ok(None) => return not_found(format!("Run '{id}' not found")),
+        Err(err) => return sse(stream::iter([error_event(err)])).into_response(),
+    };
+    let changes = watch::run_status_changes(backend, id, headers, interval);
+    let events = changes.enumerate().filter_map(move |(index, change)| {
+        let event = match change {
+            Err(err) => Some(error_event(err)),
+            Ok(change) => {
+                let event_id = format!("{}-{}", status_name(change.status), change.streams.len());
+                // The first change is the current state of the run which a resuming client may
+                // already have
+                (index > 0 || last_event.as_deref() != Some(&event_id))
+                    .then(|| run_event(event_id, change))
+            }
+        };
+        async move { event }
+    });
+    sse(events).into_response()
+}
+
+fn run_event(event_id: String, change: RunStatusChange) -> Result<Event, axum::Error> {
+    let name = match change.status {
+        RunStatus::Running => "run_status",
+        RunStatus::Finished | RunStatus::Aborted => "run_finished",
+    };
+    Event::default().event(name).id(event_id).json_data(change)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::body::to_bytes;
+    use axum::http::{HeaderMap, StatusCode};
+    use futures_util::StreamExt as _;
+    use httpmock::MockServer;
+    use serde_json::Value;
+
+    use super::{RunEventParams, SessionEventParams, run_events, session_events};
+    use crate::backends::Backends;
+    use crate::clients::TiledClient;
+    use crate::test_utils::read_json;
+
+    fn finished_run() -> Value {
+        read_json("resources/metadata_run.json")
+    }
+
+    async fn run_body(server: &MockServer, last_event: Option<&str>) -> String {
+        let backends = Backends::single(TiledClient::for_mock_server(server));
+        let mut headers = HeaderMap::new();
+        if let Some(id) = last_event {
+            headers.insert("last-event-id", id.parse().unwrap());
+        }
+        let response = run_events(
+            &backends,
+            "run".into(),
+            RunEventParams { backend: None },
+            None,
+            &headers,
+            Duration::from_millis(10),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    async fn mock_run(server: &MockServer) {
+        server
+            .mock_async(|when, then| {
+                when.method("GET").path("/api/v1/metadata/run");
+                then.status(200).json_body(finished_run());
+            })
+            .await;
+        server
+            .mock_async(|when, then| {
+                when.method("GET").path("/api/v1/search/run");
+                then.status(200)
+                    .body_from_file("resources/search_run_container.json");
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn run_finished_event() {
+        let server = MockServer::start();
+        mock_run(&server).await;
+        let body = run_body(&server, None).await;
+        assert!(body.starts_with("event: run_finished\nid: finished-1\ndata: {"));
+        assert!(body.contains(r#""status":"finished""#));
+    }
+
+    #[tokio::test]
+    async fn resumed_run_skips_current_state() {
+        let server = MockServer::start();
+        mock_run(&server).await;
+        assert_eq!(run_body(&server, Some("finished-1")).await, "");
+    }
+
+    #[tokio::test]
+    async fn unknown_run() {
+        let server = MockServer::start();
+        server
+            .mock_async(|when, then| {
+                when.method("GET").path("/api/v1/metadata/missing");
+                then.status(404)
+                    .json_body(serde_json::json!({"detail": "Not found"}));
+            })
+            .await;
+        let backends = Backends::single(TiledClient::for_mock_server(&server));
+        let response = run_events(
+            &backends,
+            "missing".into(),
+            RunEventParams { backend: None },
+            None,
+            &HeaderMap::new(),
+            Duration::from_millis(10),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    /// Read the given number of events from a session's event stream
+    async fn session_body(backends: Backends, last_event: Option<&str>, events: usize) -> String {
+        let mut headers = HeaderMap::new();
+        if let Some(id) = last_event {
+            headers.insert("last-event-id", id.parse().unwrap());
+        }
+        let response = session_events(
+            &backends,
+            "cm12345-2".into(),
+            SessionEventParams {
+                backend: None,
+                instrument: None,
+            },
+            None,
+            &headers,
+            Duration::from_millis(10),
+        );
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut body = response.into_body().into_data_stream();
+        let mut text = String::new();
+        while text.matches("\n\n").count() < events {
+            let chunk = tokio::time::timeout(Duration::from_secs(5), body.next())
+                .await
+                .expect("Timed out waiting for events")
+                .unwrap()
+                .unwrap();
+            text.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+        text
+    }
+
+    /// Mock the searches for new runs in the session. Only the later of its two runs was started
+    /// at or after the start time of either run.
+    async fn mock_new_runs(server: &MockServer) {
+        let mut latest: Value = read_json("resources/search_root.json");
+        latest["data"].as_array_mut().unwrap().truncate(1);
+        server
+            .mock_async(|when, then| {
+                when.method("GET")
+                    .path("/api/v1/search/")
+                    .query_param("page[limit]", "1");
+                then.status(200).json_body(latest.clone());
+            })
+            .await;
+        server
+            .mock_async(|when, then| {
+                when.method("GET")
+                    .path("/api/v1/search/")
+                    .query_param("filter[comparison][condition][operator]", "ge");
+                then.status(200).json_body(latest);
+            })
+            .await;
+    }
+
+    /// Mock the search for recent runs used to find runs that have finished
+    async fn mock_recent_runs<'s>(server: &'s MockServer, runs: &Value) -> httpmock::Mock<'s> {
+        server
+            .mock_async(|when, then| {
+                when.method("GET")
+                    .path("/api/v1/search/")
+                    .query_param("filter[eq][condition][value]", r#""cm12345-2""#)
+                    .query_param("page[limit]", "50");
+                then.status(200).json_body(runs.clone());
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn resumed_session_sends_later_runs() {
+        let server = MockServer::start();
+        mock_new_runs(&server).await;
+        mock_recent_runs(&server, &read_json("resources/search_root.json")).await;
+        let backends = Backends::single(TiledClient::for_mock_server(&server));
+        // The start time of the earlier of the two runs in the session
+        let body = session_body(backends, Some("1762786856.890415"), 1).await;
+        assert!(
+            body.starts_with("event: run_added\n") && body.contains("\nid: 1762787606.0709949,"),
+            "Unexpected events {body}"
+        );
+        assert!(body.contains(r#""id":"4866611f-e6d9-4517-bedf-fc5526df57ad""#));
+    }
+
+    #[tokio::test]
+    async fn resumed_session_sends_runs_finished_since() {
+        let server = MockServer::start();
+        mock_new_runs(&server).await;
+        let runs: Value = read_json("resources/search_root.json");
+        mock_recent_runs(&server, &runs).await;
+        server
+            .mock_async(|when, then| {
+                when.method("GET")
+                    .path("/api/v1/search/")
+                    .query_param("filter[comparison][condition][key]", "stop.time");
+                then.status(200).json_body(runs);
+            })
+            .await;
+        let backends = Backends::single(TiledClient::for_mock_server(&server));
+        // Both runs had been added and the earlier of them had finished
+        let body = session_body(backends, Some("1762787606.0709949,1762786871.6394713"), 1).await;
+        assert!(
+            body.starts_with("event: run_finished\n")
+                && body.contains("\nid: 1762787606.0709949,1762787618.4661725\n"),
+            "Unexpected events {body}"
+        );
+        assert!(body.contains(r#""id":"4866611f-e6d9-4517-bedf-fc5526df57ad""#));
+    }
+
+    #[tokio::test]
+    async fn session_run_finished_event() {
+        let server = MockServer::start();
+        let mut runs: Value = read_json("resources/search_root.json");
+        let finished = runs.clone();
+        runs["data"][0]["attributes"]["metadata"]["stop"] = Value::Null;
+        mock_new_runs(&server).await;
+        let running = mock_recent_runs(&server, &runs).await;
+        let backends = Backends::single(TiledClient::for_mock_server(&server));
+        let events = tokio::spawn(session_body(backends, None, 1));
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        running.delete_async().await;
+        mock_recent_runs(&server, &finished).await;
+
+        let body = events.await.unwrap();
+        assert!(
+            body.starts_with("event: run_finished\n") && body.contains("\nid: ,"),
+            "Unexpected events {body}"
+        );
+        assert!(body.contains(r#""id":"4866611f-e6d9-4517-bedf-fc5526df57ad""#));
+        assert!(body.contains(r#""status":"finished""#));
+    }
+}
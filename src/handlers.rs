@@ -1,59 +1,353 @@
+use std::time::Duration;
+
+use async_graphql::Data;
 use async_graphql::http::GraphiQLSource;
-use async_graphql::{EmptyMutation, EmptySubscription, Schema};
-use async_graphql_axum::{GraphQLRequest, GraphQLResponse};
-use axum::Extension;
-use axum::body::Body;
-use axum::extract::{OptionalFromRequestParts, Path, State};
+use async_graphql_axum::{GraphQLProtocol, GraphQLRequest, GraphQLResponse, GraphQLWebSocket};
+use axum::extract::ws::WebSocketUpgrade;
+use axum::extract::{FromRef, FromRequestParts, OptionalFromRequestParts, Path, Query, State};
 use axum::http::{HeaderMap, HeaderValue, StatusCode};
-use axum::response::{Html, IntoResponse};
+use axum::response::{Html, IntoResponse, Response};
+use axum::{Extension, Json};
 use reqwest::header::AUTHORIZATION;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use sha2::{Digest as _, Sha256};
 use tracing::info;
 
-use crate::clients::TiledClient;
-use crate::model::TiledQuery;
+use crate::archive::{ArchiveParams, archive_response};
+use crate::audit::{AuditLog, AuditRecord};
+use crate::backends::{Backend, Backends};
+use crate::config::{PreviewConfig, SubscriptionConfig};
+use crate::digest::{ChecksumCache, ChecksumKey, DigestAlgorithm, with_digest};
+use crate::events::{RunEventParams, SessionEventParams, run_events, session_events};
+use crate::limits::{DownloadLimiter, LimitExceeded};
+use crate::loaders::TiledLoader;
+use crate::model::GlazedSchema;
+use crate::preview::{PreviewParams, render_preview};
+
+/// State shared between all non-GraphQL routes
+#[derive(Clone)]
+pub struct AppState {
+    pub backends: Backends,
+    pub downloads: DownloadLimiter,
+    pub audit: AuditLog,
+    pub digest: Option<DigestAlgorithm>,
+    pub subscriptions: SubscriptionConfig,
+    pub preview: PreviewConfig,
+    pub checksums: ChecksumCache,
+}
+
+impl FromRef<AppState> for Backends {
+    fn from_ref(state: &AppState) -> Self {
+        state.backends.clone()
+    }
+}
+
+impl FromRef<AppState> for DownloadLimiter {
+    fn from_ref(state: &AppState) -> Self {
+        state.downloads.clone()
+    }
+}
+
+impl FromRef<AppState> for AuditLog {
+    fn from_ref(state: &AppState) -> Self {
+        state.audit.clone()
+    }
+}
+
+impl FromRef<AppState> for Option<DigestAlgorithm> {
+    fn from_ref(state: &AppState) -> Self {
+        state.digest
+    }
+}
+
+impl FromRef<AppState> for SubscriptionConfig {
+    fn from_ref(state: &AppState) -> Self {
+        state.subscriptions.clone()
+    }
+}
+
+impl FromRef<AppState> for PreviewConfig {
+    fn from_ref(state: &AppState) -> Self {
+        state.preview.clone()
+    }
+}
 
 pub async fn graphql_handler(
     auth_token: Option<AuthHeader>,
-    schema: Extension<Schema<TiledQuery, EmptyMutation, EmptySubscription>>,
+    State(backends): State<Backends>,
+    schema: Extension<GlazedSchema>,
     req: GraphQLRequest,
 ) -> GraphQLResponse {
-    schema
-        .execute(req.into_inner().data(auth_token))
-        .await
-        .into()
+    // Loaders are created per request so that their caches never outlive a single operation
+    let request = req
+        .into_inner()
+        .data(auth_token)
+        .data(TiledLoader::data_loader(backends));
+    schema.execute(request).await.into()
 }
 
-pub async fn graphiql_handler(graphql_endpoint: Option<String>) -> impl IntoResponse {
+/// Serve GraphQL subscriptions over a websocket using either the graphql-ws or the older
+/// subscriptions-transport-ws protocol
+pub async fn graphql_ws_handler(
+    auth: Option<AuthHeader>,
+    State(backends): State<Backends>,
+    schema: Extension<GlazedSchema>,
+    protocol: GraphQLProtocol,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    upgrade
+        .protocols(async_graphql::http::ALL_WEBSOCKET_PROTOCOLS)
+        .on_upgrade(move |socket| {
+            GraphQLWebSocket::new(socket, schema.0, protocol)
+                .on_connection_init(move |payload| async move {
+                    Ok(connection_data(&payload, auth, backends))
+                })
+                .serve()
+        })
+}
+
+/// Data for the operations of a websocket connection
+fn connection_data(payload: &Value, auth: Option<AuthHeader>, backends: Backends) -> Data {
+    // Subscriptions live for as long as the connection so nothing is cached between events
+    let loader = TiledLoader::data_loader(backends);
+    loader.enable_all_cache(false);
+    let mut data = Data::default();
+    data.insert(connection_auth(payload).or(auth));
+    data.insert(loader);
+    data
+}
+
+/// Browsers can't set headers on websocket requests so credentials can also be given in the
+/// connection init payload, either as an `Authorization` field or in a `headers` object
+fn connection_auth(payload: &Value) -> Option<AuthHeader> {
+    let authorization = |value: &Value| {
+        value.as_object()?.iter().find_map(|(key, value)| {
+            key.eq_ignore_ascii_case("authorization")
+                .then(|| HeaderValue::from_str(value.as_str()?).ok())
+                .flatten()
+        })
+    };
+    authorization(payload)
+        .or_else(|| authorization(payload.get("headers")?))
+        .map(AuthHeader)
+}
+
+pub async fn graphiql_handler(
+    graphql_endpoint: Option<String>,
+    subscription_endpoint: Option<String>,
+) -> impl IntoResponse {
     Html(
         GraphiQLSource::build()
             .endpoint(graphql_endpoint.as_deref().unwrap_or("/graphql"))
+            .subscription_endpoint(subscription_endpoint.as_deref().unwrap_or("/graphql/ws"))
             .finish(),
     )
 }
 
 pub async fn download_handler(
     auth: Option<AuthHeader>,
-    State(client): State<TiledClient>,
-    Path((run, stream, det, id)): Path<(String, String, String, u32)>,
-) -> (StatusCode, HeaderMap, Body) {
-    info!("Downloading {run}/{stream}/{det}/{id}");
+    RoutedBackend(backend): RoutedBackend,
+    State(state): State<AppState>,
+    request_headers: HeaderMap,
+    Path(asset): Path<(String, String, String, u32)>,
+) -> Response {
+    download_file(&state, backend, auth, &request_headers, asset, None).await
+}
+
+pub async fn directory_file_handler(
+    auth: Option<AuthHeader>,
+    RoutedBackend(backend): RoutedBackend,
+    State(state): State<AppState>,
+    request_headers: HeaderMap,
+    Path((run, stream, det, id, path)): Path<(String, String, String, u32, String)>,
+) -> Response {
+    let asset = (run, stream, det, id);
+    download_file(&state, backend, auth, &request_headers, asset, Some(path)).await
+}
+
+/// Stream a file asset, or a single file from a directory asset, to the client within the
+/// download limits, recording the download in the audit log
+async fn download_file(
+    state: &AppState,
+    backend: Backend,
+    auth: Option<AuthHeader>,
+    request_headers: &HeaderMap,
+    (run, stream, det, id): (String, String, String, u32),
+    path: Option<String>,
+) -> Response {
+    let mut record = AuditRecord::new(auth.as_ref(), (&run, &stream, &det, id), path.clone());
+    state
+        .audit
+        .identify(&mut record, &backend.client, auth.as_ref())
+        .await;
+    let permit = match state
+        .downloads
+        .acquire(&AuthHeader::user_key(auth.as_ref()))
+        .await
+    {
+        Ok(permit) => permit,
+        Err(rejected) => return reject(&state.audit, record, rejected),
+    };
+    match &path {
+        Some(path) => info!("Downloading {path} from {run}/{stream}/{det}/{id}"),
+        None => info!("Downloading {run}/{stream}/{det}/{id}"),
+    }
+    let headers = auth.as_ref().map(AuthHeader::as_header_map);
+    let req = backend
+        .client
+        .download(&run, &stream, &det, id, path.as_deref(), headers)
+        .await;
+    let (status, mut headers, body) = crate::download::forward_download_response(req).await;
+    let body = state.audit.track(record, status, body);
+    let mut body = state.downloads.limit_body(body, permit);
+    // The digest has to be added last as the other wrappers only forward data, not trailers
+    if let (Some(algorithm), true) = (state.digest, status.is_success()) {
+        // Checksums are only computed for whole file assets
+        let known = path.is_none().then(|| ChecksumKey {
+            backend: backend.name.to_string(),
+            user: AuthHeader::user_key(auth.as_ref()),
+            run,
+            stream,
+            det,
+            id,
+            algorithm,
+        });
+        let known = known.and_then(|key| state.checksums.cached(&key));
+        body = with_digest(algorithm, known, request_headers, &mut headers, body);
+    }
+    (status, headers, body).into_response()
+}
+
+pub async fn archive_handler(
+    auth: Option<AuthHeader>,
+    RoutedBackend(backend): RoutedBackend,
+    State(limiter): State<DownloadLimiter>,
+    State(audit): State<AuditLog>,
+    Path(asset): Path<(String, String, String, u32)>,
+    Query(params): Query<ArchiveParams>,
+) -> Response {
+    let (run, stream, det, id) = &asset;
+    let mut record = AuditRecord::new(
+        auth.as_ref(),
+        (run, stream, det, *id),
+        Some(format!("*.{}", params.format.extension())),
+    );
+    audit
+        .identify(&mut record, &backend.client, auth.as_ref())
+        .await;
+    let permit = match limiter.acquire(&AuthHeader::user_key(auth.as_ref())).await {
+        Ok(permit) => permit,
+        Err(rejected) => return reject(&audit, record, rejected),
+    };
+    info!("Archiving {asset:?} as {:?}", params.format);
+    let headers = auth.as_ref().map(AuthHeader::as_header_map);
+    let (status, headers, body) =
+        archive_response(backend.client, asset, headers, params.format).await;
+    let body = audit.track(record, status, body);
+    (status, headers, limiter.limit_body(body, permit)).into_response()
+}
+
+fn reject(audit: &AuditLog, mut record: AuditRecord, rejected: LimitExceeded) -> Response {
+    let response = rejected.into_response();
+    record.status = response.status().as_u16();
+    audit.record(&record);
+    response
+}
+
+pub async fn status_handler(
+    State(backends): State<Backends>,
+    State(limiter): State<DownloadLimiter>,
+) -> Json<Value> {
+    let backends = backends
+        .all()
+        .iter()
+        .map(|backend| {
+            let status = json!({
+                "cache": backend.client.cache_stats(),
+                "tiled": backend.client.breaker_status(),
+                "version": backend.client.capabilities(),
+            });
+            (backend.name.to_string(), status)
+        })
+        .collect::<serde_json::Map<_, _>>();
+    Json(json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "downloads": limiter.queue_state(),
+        "backends": backends,
+    }))
+}
+
+pub async fn preview_handler(
+    auth: Option<AuthHeader>,
+    RoutedBackend(backend): RoutedBackend,
+    State(config): State<PreviewConfig>,
+    Path((run, stream, det)): Path<(String, String, String)>,
+    Query(params): Query<PreviewParams>,
+) -> Response {
+    info!("Previewing {run}/{stream}/{det} with {params:?}");
     let headers = auth.as_ref().map(AuthHeader::as_header_map);
-    let req = client.download(run, stream, det, id, headers).await;
-    crate::download::forward_download_response(req).await
+    let path = format!("{run}/{stream}/{det}");
+    match render_preview(&backend.client, &path, &params, &config, headers).await {
+        Ok(image) => ([("content-type", "image/png")], image).into_response(),
+        Err(err) => err.into_response(),
+    }
+}
+
+pub async fn session_events_handler(
+    auth: Option<AuthHeader>,
+    State(backends): State<Backends>,
+    State(config): State<SubscriptionConfig>,
+    Path(name): Path<String>,
+    Query(params): Query<SessionEventParams>,
+    headers: HeaderMap,
+) -> Response {
+    info!("Streaming events for session {name}");
+    let interval = Duration::from_millis(config.poll_interval);
+    session_events(&backends, name, params, auth, &headers, interval)
+}
+
+pub async fn run_events_handler(
+    auth: Option<AuthHeader>,
+    State(backends): State<Backends>,
+    State(config): State<SubscriptionConfig>,
+    Path(id): Path<String>,
+    Query(params): Query<RunEventParams>,
+    headers: HeaderMap,
+) -> Response {
+    info!("Streaming events for run {id}");
+    let interval = Duration::from_millis(config.poll_interval);
+    run_events(&backends, id, params, auth, &headers, interval).await
 }
 
 /// Extractor to accept an un-typed Authorization header (can be ApiKey/Bearer/Basic etc), and
 /// make it accessible as a HeaderValue to be forwarded rather than extracted into something to use
 /// locally (as the TypedHeader equivalent does).
+#[derive(Clone)]
 pub struct AuthHeader(HeaderValue);
 
 impl AuthHeader {
     pub fn as_header_map(&self) -> HeaderMap {
         [(AUTHORIZATION, self.0.clone())].into_iter().collect()
     }
+    pub fn header_value(&self) -> HeaderValue {
+        self.0.clone()
+    }
+    /// Key used to group requests made with the same credentials when applying per-user
+    /// limits or caching per-user results. This is a hash of the whole credential rather than
+    /// the claimed identity so that it can't be forged to use another user's limits, and the
+    /// credential itself is never kept in memory as a key.
+    pub fn user_key(auth: Option<&Self>) -> String {
+        match auth {
+            Some(auth) => Sha256::digest(auth.0.as_bytes())
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect(),
+            None => "anonymous".into(),
+        }
+    }
 }
 
-#[cfg(test)]
 impl From<HeaderValue> for AuthHeader {
     fn from(value: HeaderValue) -> Self {
         Self(value)
@@ -77,17 +371,53 @@ where
     }
 }
 
+#[derive(Deserialize)]
+struct BackendParam {
+    backend: Option<String>,
+}
+
+/// Extractor for the backend named by the `backend` query parameter, or the default backend if
+/// there isn't one
+pub struct RoutedBackend(pub Backend);
+
+impl<S> FromRequestParts<S> for RoutedBackend
+where
+    Backends: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut axum::http::request::Parts,
+        state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        let Query(param) =
+            Query::<BackendParam>::try_from_uri(&parts.uri).map_err(IntoResponse::into_response)?;
+        let backends = Backends::from_ref(state);
+        match backends.get_or_default(param.backend.as_deref()) {
+            Ok(backend) => Ok(Self(backend.clone())),
+            Err(unknown) => Err((
+                StatusCode::NOT_FOUND,
+                Json(json!({"detail": unknown.to_string()})),
+            )
+                .into_response()),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use axum::Router;
     use axum::body::Body;
-    use axum::http::Request;
+    use axum::http::{HeaderValue, Request};
     use axum::response::IntoResponse;
     use axum::routing::get;
     use http_body_util::BodyExt as _;
     use tower::ServiceExt;
 
-    use super::AuthHeader;
+    use serde_json::json;
+
+    use super::{AuthHeader, connection_auth};
 
     async fn auth_echo(auth: Option<AuthHeader>) -> impl IntoResponse {
         match auth {
@@ -116,6 +446,18 @@ mod tests {
             "auth_value"
         );
     }
+    #[test]
+    fn user_keys() {
+        let auth = AuthHeader::from(HeaderValue::from_static("Apikey secret"));
+        let forged = AuthHeader::from(HeaderValue::from_static("Apikey other"));
+        assert_ne!(
+            AuthHeader::user_key(Some(&auth)),
+            AuthHeader::user_key(Some(&forged))
+        );
+        assert_eq!(AuthHeader::user_key(Some(&auth)).len(), 64);
+        assert_eq!(AuthHeader::user_key(None), "anonymous");
+    }
+
     #[tokio::test]
     async fn no_auth_extract() {
         let app = app();
@@ -128,4 +470,19 @@ mod tests {
             "No auth"
         );
     }
+
+    #[test]
+    fn websocket_auth() {
+        let auth = |payload| connection_auth(&payload).map(|auth| auth.0);
+        assert_eq!(
+            auth(json!({"Authorization": "Bearer abc"})).unwrap(),
+            "Bearer abc"
+        );
+        assert_eq!(
+            auth(json!({"headers": {"authorization": "Bearer abc"}})).unwrap(),
+            "Bearer abc"
+        );
+        assert!(auth(json!({})).is_none());
+        assert!(auth(json!(null)).is_none());
+    }
 }
@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_graphql::{Error, ErrorExtensions};
+use axum::body::Body;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use futures_util::StreamExt as _;
+use serde::Serialize;
+use serde_json::json;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+use crate::config::DownloadConfig;
+
+/// Shared state used to limit the number and rate of downloads passing through glazed
+#[derive(Clone)]
+pub struct DownloadLimiter(Arc<LimiterState>);
+
+struct LimiterState {
+    config: DownloadConfig,
+    global: Option<Arc<Semaphore>>,
+    users: Mutex<HashMap<String, Arc<Semaphore>>>,
+    active: AtomicUsize,
+    queued: AtomicUsize,
+}
+
+/// Snapshot of the current download activity for the status endpoint
+#[derive(Debug, PartialEq, Serialize)]
+pub struct QueueState {
+    pub active: usize,
+    pub queued: usize,
+    pub users: usize,
+    pub max_global: Option<usize>,
+    pub max_per_user: Option<usize>,
+}
+
+/// Returned when no download slot became available in time
+#[derive(Debug)]
+pub struct LimitExceeded {
+    retry_after: u64,
+}
+
+impl IntoResponse for LimitExceeded {
+    fn into_response(self) -> Response {
+        (
+            StatusCode::TOO_MANY_REQUESTS,
+            [("retry-after", self.retry_after.to_string())],
+            json!({"detail": "Too many concurrent downloads"}).to_string(),
+        )
+            .into_response()
+    }
+}
+
+impl ErrorExtensions for LimitExceeded {
+    fn extend(&self) -> Error {
+        let retry_after = self.retry_after;
+        Error::new("Too many concurrent downloads").extend_with(|_, ext| {
+            ext.set("code", "RATE_LIMITED");
+            ext.set("retryAfter", retry_after);
+        })
+    }
+}
+
+/// A download slot that is held until the download completes or is abandoned
+pub struct DownloadPermit {
+    limiter: DownloadLimiter,
+    user: String,
+    user_permit: Option<OwnedSemaphorePermit>,
+    _global_permit: Option<OwnedSemaphorePermit>,
+}
+
+impl DownloadLimiter {
+    pub fn new(config: DownloadConfig) -> Self {
+        Self(Arc::new(LimiterState {
+            global: config.max_global.map(|n| Arc::new(Semaphore::new(n))),
+            config,
+            users: Mutex::new(HashMap::new()),
+            active: AtomicUsize::new(0),
+            queued: AtomicUsize::new(0),
+        }))
+    }
+
+    /// Wait for a download slot for the given user, giving up after the configured queue timeout
+    pub async fn acquire(&self, user: &str) -> Result<DownloadPermit, LimitExceeded> {
+        let user_limit = self.0.config.max_per_user.map(|max| {
+            self.0
+                .users
+                .lock()
+                .expect("Download limiter lock poisoned")
+                .entry(user.into())
+                .or_insert_with(|| Arc::new(Semaphore::new(max)))
+                .clone()
+        });
+        let global_limit = self.0.global.clone();
+
+        self.0.queued.fetch_add(1, Ordering::Relaxed);
+        // Wait for the user's own slot first so that a single user's queue does not tie up
+        // global slots that other users could be using.
+        let permits =
+            tokio::time::timeout(Duration::from_secs(self.0.config.queue_timeout), async {
+                let user = match user_limit {
+                    Some(sem) => Some(sem.acquire_owned().await),
+                    None => None,
+                };
+                let global = match global_limit {
+                    Some(sem) => Some(sem.acquire_owned().await),
+                    None => None,
+                };
+                (user, global)
+            })
+            .await;
+        self.0.queued.fetch_sub(1, Ordering::Relaxed);
+
+        match permits {
+            Ok((user_permit, global_permit)) => {
+                self.0.active.fetch_add(1, Ordering::Relaxed);
+                Ok(DownloadPermit {
+                    limiter: self.clone(),
+                    user: user.into(),
+                    // Semaphores are never closed so acquiring can't fail
+                    user_permit: user_permit.and_then(Result::ok),
+                    _global_permit: global_permit.and_then(Result::ok),
+                })
+            }
+            Err(_) => {
+                self.release_user(user);
+                Err(LimitExceeded {
+                    retry_after: self.0.config.retry_after,
+                })
+            }
+        }
+    }
+
+    /// Stream the given body while holding the permit, throttled to the configured rate
+    pub fn limit_body(&self, body: Body, permit: DownloadPermit) -> Body {
+        let rate = self.0.config.bytes_per_second.filter(|r| *r > 0);
+        let start = Instant::now();
+        let mut sent = 0u64;
+        let stream = body.into_data_stream().then(move |chunk| {
+            // The permit is owned by the stream so it is released when the body is dropped,
+            // whether the download completed or the client went away.
+            let _permit = &permit;
+            if let Ok(bytes) = &chunk {
+                sent += bytes.len() as u64;
+            }
+            let delay = rate.map(|rate| Duration::from_secs_f64(sent as f64 / rate as f64));
+            async move {
+                if let Some(delay) = delay {
+                    tokio::time::sleep_until(start + delay).await;
+                }
+                chunk
+            }
+        });
+        Body::from_stream(stream)
+    }
+
+    pub fn queue_state(&self) -> QueueState {
+        QueueState {
+            active: self.0.active.load(Ordering::Relaxed),
+            queued: self.0.queued.load(Ordering::Relaxed),
+            users: self
+                .0
+                .users
+                .lock()
+                .expect("Download limiter lock poisoned")
+                .len(),
+            max_global: self.0.config.max_global,
+            max_per_user: self.0.config.max_per_user,
+        }
+    }
+
+    /// Remove a user's semaphore once nothing is using or waiting for it
+    fn release_user(&self, user: &str) {
+        let mut users = self.0.users.lock().expect("Download limiter lock poisoned");
+        if users
+            .get(user)
+            .is_some_and(|sem| Arc::strong_count(sem) == 1)
+        {
+            users.remove(user);
+        }
+    }
+}
+
+impl Drop for DownloadPermit {
+    fn drop(&mut self) {
+        self.user_permit.take();
+        self.limiter.0.active.fetch_sub(1, Ordering::Relaxed);
+        self.limiter.release_user(&self.user);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::body::Body;
+    use axum::http::StatusCode;
+    use axum::response::IntoResponse as _;
+    use http_body_util::BodyExt as _;
+    use tokio::time::Instant;
+
+    use super::{DownloadLimiter, QueueState};
+    use crate::config::DownloadConfig;
+
+    fn limiter(max_per_user: Option<usize>, max_global: Option<usize>) -> DownloadLimiter {
+        DownloadLimiter::new(DownloadConfig {
+            max_per_user,
+            max_global,
+            ..DownloadConfig::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn unlimited() {
+        let limiter = limiter(None, None);
+        let _permits = [
+            limiter.acquire("alice").await.unwrap(),
+            limiter.acquire("alice").await.unwrap(),
+            limiter.acquire("bob").await.unwrap(),
+        ];
+        assert_eq!(limiter.queue_state().active, 3);
+    }
+
+    #[tokio::test]
+    async fn per_user_limit() {
+        let limiter = limiter(Some(1), None);
+        let permit = limiter.acquire("alice").await.unwrap();
+        let _bob = limiter.acquire("bob").await.unwrap();
+
+        let Err(rejected) = limiter.acquire("alice").await else {
+            panic!("Expected second download for alice to be rejected");
+        };
+        let rejected = rejected.into_response();
+        assert_eq!(rejected.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(rejected.headers()["retry-after"], "10");
+
+        drop(permit);
+        assert!(limiter.acquire("alice").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn global_limit() {
+        let limiter = limiter(None, Some(2));
+        let _permits = [
+            limiter.acquire("alice").await.unwrap(),
+            limiter.acquire("bob").await.unwrap(),
+        ];
+        assert!(limiter.acquire("carol").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn queued_downloads_wait_for_slot() {
+        let limiter = DownloadLimiter::new(DownloadConfig {
+            max_global: Some(1),
+            queue_timeout: 5,
+            ..DownloadConfig::default()
+        });
+        let permit = limiter.acquire("alice").await.unwrap();
+        let waiting = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.acquire("bob").await.is_ok() }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(
+            limiter.queue_state(),
+            QueueState {
+                active: 1,
+                queued: 1,
+                users: 0,
+                max_global: Some(1),
+                max_per_user: None,
+            }
+        );
+        drop(permit);
+        assert!(waiting.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn users_are_forgotten_when_idle() {
+        let limiter = limiter(Some(2), None);
+        let permit = limiter.acquire("alice").await.unwrap();
+        assert_eq!(limiter.queue_state().users, 1);
+        drop(permit);
+        assert_eq!(limiter.queue_state().users, 0);
+    }
+
+    #[tokio::test]
+    async fn permit_held_until_body_dropped() {
+        let limiter = limiter(None, Some(1));
+        let permit = limiter.acquire("alice").await.unwrap();
+        let body = limiter.limit_body(Body::from("content"), permit);
+        assert!(limiter.acquire("bob").await.is_err());
+        assert_eq!(body.collect().await.unwrap().to_bytes(), "content");
+        assert!(limiter.acquire("bob").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn throttled_body() {
+        let limiter = DownloadLimiter::new(DownloadConfig {
+            bytes_per_second: Some(4000),
+            ..DownloadConfig::default()
+        });
+        let permit = limiter.acquire("alice").await.unwrap();
+        let start = Instant::now();
+        let body = limiter.limit_body(Body::from(vec![0u8; 1000]), permit);
+        let content = body.collect().await.unwrap().to_bytes();
+        assert_eq!(content.len(), 1000);
+        assert!(start.elapsed() >= Duration::from_millis(250));
+    }
+}
@@ -0,0 +1,300 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use async_graphql::dataloader::{DataLoader, HashMapCache, Loader};
+use futures_util::future::try_join_all;
+use futures_util::{StreamExt as _, TryStreamExt as _, stream};
+use reqwest::header::{AUTHORIZATION, HeaderMap, HeaderValue};
+
+use crate::backends::{Backend, Backends};
+use crate::clients::{ClientError, TiledClient};
+use crate::compat::KEYS_FILTER;
+use crate::handlers::AuthHeader;
+use crate::model::node;
+
+/// DataLoader used by resolvers so that requests for the same nodes within a single GraphQL
+/// operation are only made once and requests for sibling nodes are combined where possible.
+pub type TiledDataLoader = DataLoader<TiledLoader, HashMapCache>;
+
+/// A node in one of the tiled backends as seen by a specific user. Responses depend on the
+/// permissions of the user so the credentials are part of the key.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct NodeKey {
+    pub backend: Arc<str>,
+    pub path: String,
+    pub auth: Option<HeaderValue>,
+}
+
+impl NodeKey {
+    pub fn new(backend: &Backend, path: impl Into<String>, auth: Option<&AuthHeader>) -> Self {
+        Self {
+            backend: backend.name.clone(),
+            path: path.into(),
+            auth: auth.map(AuthHeader::header_value),
+        }
+    }
+    fn headers(&self) -> Option<HeaderMap> {
+        self.auth
+            .clone()
+            .map(|auth| [(AUTHORIZATION, auth)].into_iter().collect())
+    }
+    /// Split the path into the path of the parent container and the id of the node
+    fn parent(&self) -> (&str, &str) {
+        self.path.rsplit_once('/').unwrap_or(("", &self.path))
+    }
+}
+
+/// Key to load the metadata of a single node
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Metadata(pub NodeKey);
+
+/// Key to load the metadata of all the children of a container
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Children(pub NodeKey);
+
+pub struct TiledLoader {
+    backends: Backends,
+}
+
+impl TiledLoader {
+    pub fn data_loader(backends: Backends) -> TiledDataLoader {
+        DataLoader::with_cache(Self { backends }, tokio::spawn, HashMapCache::default())
+    }
+
+    fn client(&self, key: &NodeKey) -> &TiledClient {
+        &self
+            .backends
+            .get(&key.backend)
+            .expect("Keys are only created for configured backends")
+            .client
+    }
+
+    async fn load_one(
+        &self,
+        key: &Metadata,
+    ) -> Result<Option<(Metadata, node::Data)>, ClientError> {
+        let client = self.client(&key.0);
+        match client.metadata(key.0.path.clone(), key.0.headers()).await {
+            Ok(meta) => Ok(Some((key.clone(), meta.into_data()))),
+            Err(ClientError::TiledRequest(404, _)) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Fetch the metadata of several nodes in the same container with a single search if tiled
+    /// supports it
+    async fn load_siblings(
+        &self,
+        parent: &str,
+        keys: &[&Metadata],
+    ) -> Result<Vec<(Metadata, node::Data)>, ClientError> {
+        let headers = keys[0].0.headers();
+        let client = self.client(&keys[0].0);
+        if let [key] = keys {
+            return Ok(self.load_one(key).await?.into_iter().collect());
+        }
+        if !client.supports_query(KEYS_FILTER) {
+            let results = try_join_all(keys.iter().map(|key| self.load_one(key))).await?;
+            return Ok(results.into_iter().flatten().collect());
+        }
+        let ids = keys
+            .iter()
+            .map(|key| key.0.parent().1)
+            .collect::<Vec<_>>()
+            .join(",");
+        let results = client
+            .search(
+                parent,
+                headers,
+                &[
+                    ("filter[keys_filter][condition][keys]", ids.into()),
+                    ("page[limit]", keys.len().to_string().into()),
+                    ("include_data_sources", Cow::Borrowed("true")),
+                ],
+            )
+            .await?;
+        let mut found = results
+            .into_data()
+            .map(|data| (data.id.clone(), data))
+            .collect::<HashMap<_, _>>();
+        Ok(keys
+            .iter()
+            .filter_map(|key| Some(((*key).clone(), found.remove(key.0.parent().1)?)))
+            .collect())
+    }
+}
+
+impl Loader<Metadata> for TiledLoader {
+    type Value = node::Data;
+    type Error = Arc<ClientError>;
+
+    async fn load(&self, keys: &[Metadata]) -> Result<HashMap<Metadata, Self::Value>, Self::Error> {
+        let mut groups = HashMap::<_, Vec<_>>::new();
+        for key in keys {
+            groups
+                .entry((&key.0.backend, key.0.parent().0, &key.0.auth))
+                .or_default()
+                .push(key);
+        }
+        let results =
+            try_join_all(groups.into_iter().map(|((_, parent, _), keys)| async move {
+                self.load_siblings(parent, &keys).await
+            }))
+            .await?;
+        Ok(results.into_iter().flatten().collect())
+    }
+}
+
+impl Loader<Children> for TiledLoader {
+    type Value = Vec<node::Data>;
+    type Error = Arc<ClientError>;
+
+    async fn load(&self, keys: &[Children]) -> Result<HashMap<Children, Self::Value>, Self::Error> {
+        let mut backends = HashMap::<_, Vec<_>>::new();
+        for key in keys {
+            backends
+                .entry(key.0.backend.clone())
+                .or_default()
+                .push(key.clone());
+        }
+        // Each backend has its own limit on the number of concurrent requests
+        let results = try_join_all(backends.into_values().map(|keys| {
+            let client = self.client(&keys[0].0);
+            stream::iter(keys)
+                .map(move |key| async move {
+                    let root = client
+                        .search(
+                            &key.0.path,
+                            key.0.headers(),
+                            &[("include_data_sources", "true".into())],
+                        )
+                        .await?;
+                    Ok::<_, ClientError>((key, root.into_data().collect::<Vec<_>>()))
+                })
+                .buffer_unordered(client.concurrency())
+                .try_collect::<Vec<_>>()
+        }))
+        .await?;
+        Ok(results.into_iter().flatten().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use httpmock::MockServer;
+
+    use super::{Children, Metadata, NodeKey, TiledDataLoader, TiledLoader};
+    use crate::backends::Backends;
+    use crate::clients::TiledClient;
+    use crate::test_utils::read_json;
+
+    fn loader(server: &MockServer) -> (Backends, TiledDataLoader) {
+        let backends = Backends::single(TiledClient::for_mock_server(server));
+        (backends.clone(), TiledLoader::data_loader(backends))
+    }
+
+    #[tokio::test]
+    async fn sibling_metadata_batched() {
+        let server = MockServer::start();
+        let search = server
+            .mock_async(|when, then| {
+                when.method("GET")
+                    .path("/api/v1/search/")
+                    .query_param_exists("filter[keys_filter][condition][keys]")
+                    .query_param("page[limit]", "3");
+                then.status(200)
+                    .body_from_file("resources/search_root.json");
+            })
+            .await;
+        let (backends, loader) = loader(&server);
+        let backend = backends.default_backend();
+        let keys = [
+            "4866611f-e6d9-4517-bedf-fc5526df57ad",
+            "1e37c0ed-e87e-470d-be18-9d7f62f69127",
+            "missing",
+        ]
+        .map(|id| Metadata(NodeKey::new(backend, id, None)));
+
+        let found = loader.load_many(keys.clone()).await.unwrap();
+        assert_eq!(found.len(), 2);
+        assert!(!found.contains_key(&keys[2]));
+        assert_eq!(found[&keys[1]].id, "1e37c0ed-e87e-470d-be18-9d7f62f69127");
+        search.assert();
+    }
+
+    #[tokio::test]
+    async fn sibling_metadata_without_keys_filter() {
+        let server = MockServer::start();
+        let mut app: serde_json::Value = read_json("resources/metadata_app.json");
+        app["queries"] = serde_json::json!(["eq"]);
+        server
+            .mock_async(|when, then| {
+                when.method("GET").path("/api/v1/");
+                then.status(200).json_body(app);
+            })
+            .await;
+        let metadata = server
+            .mock_async(|when, then| {
+                when.method("GET").path("/api/v1/metadata/run");
+                then.status(200)
+                    .body_from_file("resources/metadata_run.json");
+            })
+            .await;
+        let missing = server
+            .mock_async(|when, then| {
+                when.method("GET").path("/api/v1/metadata/missing");
+                then.status(404).body("{}");
+            })
+            .await;
+        let client = TiledClient::for_mock_server(&server);
+        client.negotiate().await.unwrap();
+        let backends = Backends::single(client);
+        let loader = TiledLoader::data_loader(backends.clone());
+        let keys = ["run", "missing"]
+            .map(|id| Metadata(NodeKey::new(backends.default_backend(), id, None)));
+
+        let found = loader.load_many(keys.clone()).await.unwrap();
+        assert_eq!(found.len(), 1);
+        assert!(found.contains_key(&keys[0]));
+        metadata.assert();
+        missing.assert();
+    }
+
+    #[tokio::test]
+    async fn single_metadata_not_found() {
+        let server = MockServer::start();
+        let metadata = server
+            .mock_async(|when, then| {
+                when.method("GET").path("/api/v1/metadata/missing");
+                then.status(404).body("{}");
+            })
+            .await;
+        let (backends, loader) = loader(&server);
+        let backend = backends.default_backend();
+        let found = loader
+            .load_one(Metadata(NodeKey::new(backend, "missing", None)))
+            .await
+            .unwrap();
+        assert_eq!(found, None);
+        metadata.assert();
+    }
+
+    #[tokio::test]
+    async fn children_deduplicated() {
+        let server = MockServer::start();
+        let search = server
+            .mock_async(|when, then| {
+                when.method("GET").path("/api/v1/search/run");
+                then.status(200)
+                    .body_from_file("resources/search_run_container.json");
+            })
+            .await;
+        let (backends, loader) = loader(&server);
+        let backend = backends.default_backend();
+        let key = Children(NodeKey::new(backend, "run", None));
+        let (first, second) = tokio::join!(loader.load_one(key.clone()), loader.load_one(key));
+        assert_eq!(first.unwrap(), second.unwrap());
+        search.assert_calls(1);
+    }
+}
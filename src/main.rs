@@ -1,29 +1,60 @@
-use async_graphql::{EmptyMutation, EmptySubscription, Schema};
+use async_graphql::Schema;
 use axum::http::StatusCode;
 use axum::response::{Html, IntoResponse};
 use axum::routing::{get, post};
-use axum::{Extension, Json, Router};
+use axum::{Extension, Router};
 
+mod archive;
+mod arrow;
+mod audit;
+mod backends;
+mod cache;
 mod cli;
 mod clients;
+mod compat;
 mod config;
+mod credentials;
+mod digest;
 mod download;
+mod events;
 mod handlers;
+mod limits;
+mod loaders;
 mod model;
+mod mutations;
+mod persisted;
+mod preview;
+mod retry;
+mod subscriptions;
 #[cfg(test)]
 mod test_utils;
+mod tls;
+mod userdata;
+mod watch;
+mod webhooks;
 
 use cli::{Cli, Commands};
-use serde_json::json;
 use tokio::select;
 use tokio::signal::unix::{SignalKind, signal};
 use tracing::info;
 use url::Url;
 
-use crate::clients::TiledClient;
+use crate::audit::AuditLog;
+use crate::backends::Backends;
 use crate::config::GlazedConfig;
-use crate::handlers::{download_handler, graphiql_handler, graphql_handler};
+use crate::digest::ChecksumCache;
+use crate::handlers::{
+    AppState, archive_handler, directory_file_handler, download_handler, graphiql_handler,
+    graphql_handler, graphql_ws_handler, preview_handler, run_events_handler,
+    session_events_handler, status_handler,
+};
+use crate::limits::DownloadLimiter;
 use crate::model::TiledQuery;
+use crate::mutations::TiledMutation;
+use crate::persisted::PersistedQueries;
+use crate::subscriptions::TiledSubscription;
+use crate::userdata::UserData;
+use crate::webhooks::Webhooks;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -50,29 +81,62 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 pub struct RootAddress(Url);
 
 async fn serve(config: GlazedConfig) -> Result<(), Box<dyn std::error::Error>> {
-    let client = TiledClient::new(config.tiled_client.address);
+    let backends = Backends::from_config(&config)?;
+    backends.negotiate().await?;
     let public_address = config
         .public_address
         .clone()
         .unwrap_or_else(|| Url::parse(&format!("http://{}", config.bind_address)).unwrap());
-    let schema = Schema::build(TiledQuery, EmptyMutation, EmptySubscription)
+    Webhooks::new(&config.webhooks, public_address.clone())?.start(&backends);
+    let downloads = DownloadLimiter::new(config.downloads.clone());
+    let audit = AuditLog::new(config.audit.as_ref())?;
+    let checksums = ChecksumCache::new(downloads.clone(), audit.clone());
+    let schema = Schema::build(TiledQuery, TiledMutation, TiledSubscription)
         .data(RootAddress(public_address))
-        .data(client.clone())
+        .data(backends.clone())
+        .data(checksums.clone())
+        .data(config.subscriptions.clone())
+        .data(UserData::new(config.user_data.as_ref())?)
+        .extension(PersistedQueries::new(&config.persisted_queries)?)
         .finish();
 
     let graphql_endpoint = config
         .public_address
+        .as_ref()
         .map(|u| u.join("graphql").unwrap().to_string());
+    let subscription_endpoint = config.public_address.map(|mut u| {
+        let scheme = if u.scheme() == "https" { "wss" } else { "ws" };
+        u.set_scheme(scheme)
+            .expect("http(s) and ws(s) are interchangeable");
+        u.join("graphql/ws").unwrap().to_string()
+    });
 
     let app = Router::new()
         .route("/graphql", post(graphql_handler).get(graphql_get_warning))
-        .route("/graphiql", get(|| graphiql_handler(graphql_endpoint)))
+        .route("/graphql/ws", get(graphql_ws_handler))
         .route(
-            "/status",
-            get(Json(json!({"version": env!("CARGO_PKG_VERSION")}))),
+            "/graphiql",
+            get(|| graphiql_handler(graphql_endpoint, subscription_endpoint)),
         )
+        .route("/status", get(status_handler))
         .route("/asset/{run}/{stream}/{det}/{id}", get(download_handler))
-        .with_state(client)
+        .route(
+            "/asset/{run}/{stream}/{det}/{id}/{*path}",
+            get(directory_file_handler),
+        )
+        .route("/archive/{run}/{stream}/{det}/{id}", get(archive_handler))
+        .route("/preview/{run}/{stream}/{det}", get(preview_handler))
+        .route("/events/session/{name}", get(session_events_handler))
+        .route("/events/run/{id}", get(run_events_handler))
+        .with_state(AppState {
+            backends,
+            downloads,
+            audit,
+            digest: config.downloads.digest,
+            subscriptions: config.subscriptions,
+            preview: config.preview,
+            checksums,
+        })
         .fallback((
             StatusCode::NOT_FOUND,
             Html(include_str!("../static/404.html")),
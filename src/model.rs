@@ -3,48 +3,229 @@ pub(crate) mod array;
 pub(crate) mod container;
 pub(crate) mod event_stream;
 pub(crate) mod node;
+pub(crate) mod principal;
+pub(crate) mod revision;
 pub(crate) mod run;
 pub(crate) mod table;
 
 use std::collections::HashMap;
 
-use async_graphql::{Context, Object, Result, Union};
+use async_graphql::{
+    Context, Error, ErrorExtensions, Object, Result, ResultExt, Schema, SimpleObject, Union,
+};
+use futures_util::future::join_all;
 use serde_json::Value;
 use tracing::{info, instrument};
+use url::Url;
 
 use crate::RootAddress;
-use crate::clients::{ClientError, TiledClient};
+use crate::archive::ArchiveFormat;
+use crate::backends::{Backend, Backends, find_node};
+use crate::clients::TiledClient;
+use crate::compat::EQ_FILTER;
+use crate::digest::{ChecksumCache, ChecksumKey, DigestAlgorithm};
 use crate::handlers::AuthHeader;
+use crate::loaders::{Children, Metadata, NodeKey, TiledDataLoader};
 use crate::model::node::NodeAttributes;
+use crate::model::revision::MetadataRevision;
+use crate::model::run::{RunMetadata, RunStatus};
+use crate::mutations::{DERIVED_SPEC, TiledMutation};
+use crate::subscriptions::TiledSubscription;
+use crate::userdata::{Me, current_user};
+
+pub(crate) type GlazedSchema = Schema<TiledQuery, TiledMutation, TiledSubscription>;
 
 pub(crate) struct TiledQuery;
 
 #[Object]
 impl TiledQuery {
+    /// Metadata of the default tiled backend or of the named backend
     #[instrument(skip(self, ctx))]
-    async fn app_metadata(&self, ctx: &Context<'_>) -> Result<app::AppMetadata> {
-        Ok(ctx.data::<TiledClient>()?.app_metadata().await?)
+    async fn app_metadata(
+        &self,
+        ctx: &Context<'_>,
+        backend: Option<String>,
+    ) -> Result<app::AppMetadata> {
+        let backends = ctx.data::<Backends>()?;
+        let backend = backends.get_or_default(backend.as_deref())?;
+        backend.client.app_metadata().await.extend()
     }
 
-    async fn instrument_session(&self, name: String) -> InstrumentSession {
-        InstrumentSession { name }
+    /// An instrument session, served by the named backend, the backend for the given
+    /// instrument, or the backend whose session prefixes match the name. Sessions that don't
+    /// match any backend are searched for in all backends.
+    async fn instrument_session(
+        &self,
+        ctx: &Context<'_>,
+        name: String,
+        instrument: Option<String>,
+        backend: Option<String>,
+    ) -> Result<InstrumentSession> {
+        let backends = ctx
+            .data::<Backends>()?
+            .route(backend.as_deref(), instrument.as_deref(), Some(&name))?
+            .into_iter()
+            .cloned()
+            .collect();
+        Ok(InstrumentSession { name, backends })
     }
 
-    async fn run(&self, ctx: &Context<'_>, id: String) -> Result<Option<Run>> {
+    /// Runs from every backend, or only from the backend for the given instrument
+    async fn runs(
+        &self,
+        ctx: &Context<'_>,
+        instrument: Option<String>,
+        backend: Option<String>,
+    ) -> Result<Vec<Run>> {
+        let backends =
+            ctx.data::<Backends>()?
+                .route(backend.as_deref(), instrument.as_deref(), None)?;
+        let filters = instrument
+            .map(|instrument| ("start.instrument", instrument))
+            .into_iter()
+            .collect::<Vec<_>>();
+        search_runs(ctx, &backends, &filters).await
+    }
+
+    /// The user making the request, as verified by tiled from their credentials, and the
+    /// bookmarks and searches they have saved
+    async fn me(&self, ctx: &Context<'_>) -> Result<Me> {
+        Ok(Me {
+            user: current_user(ctx).await?,
+        })
+    }
+
+    /// Find a run by its ID. Unless a backend is given, all backends are searched.
+    async fn run(
+        &self,
+        ctx: &Context<'_>,
+        id: String,
+        backend: Option<String>,
+    ) -> Result<Option<Run>> {
         let auth = ctx.data::<Option<AuthHeader>>()?;
-        let headers = auth.as_ref().map(AuthHeader::as_header_map);
-        match ctx.data::<TiledClient>()?.metadata(id, headers).await {
-            Ok(run) => Ok(Some(Run {
-                data: run.into_data(),
-            })),
-            Err(ClientError::TiledRequest(404, _)) => Ok(None),
-            Err(other) => Err(other.into()),
+        let loader = ctx.data::<TiledDataLoader>()?;
+        let backends = ctx
+            .data::<Backends>()?
+            .route(backend.as_deref(), None, None)?;
+        let runs =
+            join_all(backends.iter().map(|backend| {
+                loader.load_one(Metadata(NodeKey::new(backend, &id, auth.as_ref())))
+            }))
+            .await
+            .into_iter()
+            .map(|run| run.map_err(|e| e.extend()))
+            .collect();
+        let runs = partial_results(ctx, &backends, runs)?;
+        Ok(runs.into_iter().find_map(|(backend, run)| {
+            Some(Run {
+                backend: backend.clone(),
+                data: run?,
+            })
+        }))
+    }
+}
+
+/// Collect the results of a request made to several backends. Backends that failed are reported
+/// as errors alongside the results from the others, and only if every backend failed does the
+/// whole field fail.
+fn partial_results<'b, T, E: ErrorExtensions>(
+    ctx: &Context<'_>,
+    backends: &[&'b Backend],
+    results: Vec<std::result::Result<T, E>>,
+) -> Result<Vec<(&'b Backend, T)>> {
+    let mut found = Vec::new();
+    let mut errors = Vec::new();
+    for (backend, result) in backends.iter().zip(results) {
+        match result {
+            Ok(value) => found.push((*backend, value)),
+            Err(err) => {
+                let name = backend.name.to_string();
+                errors.push(err.extend_with(|_, ext| ext.set("backend", name)));
+            }
         }
     }
+    if found.is_empty()
+        && let Some(err) = errors.pop()
+    {
+        return Err(err);
+    }
+    for err in errors {
+        ctx.add_error(err.into_server_error(ctx.item.pos));
+    }
+    Ok(found)
+}
+
+/// Search for runs in each of the given backends, filtered by fields of their start documents,
+/// and merge the results
+pub(crate) async fn search_runs(
+    ctx: &Context<'_>,
+    backends: &[&Backend],
+    filters: &[(&str, String)],
+) -> Result<Vec<Run>> {
+    let auth = ctx.data::<Option<AuthHeader>>()?;
+    let mut query = vec![("include_data_sources", "true".into())];
+    if let Some((key, _)) = filters.first()
+        && let Some(backend) = backends
+            .iter()
+            .find(|b| !b.client.supports_query(EQ_FILTER))
+    {
+        return Err(Error::new(format!(
+            "Backend '{}' does not support filtering runs by {key}",
+            backend.name
+        )));
+    }
+    for (key, value) in filters {
+        query.push(("filter[eq][condition][key]", (*key).into()));
+        query.push((
+            "filter[eq][condition][value]",
+            format!(r#""{value}""#).into(),
+        ));
+    }
+    let results = join_all(backends.iter().map(|backend| {
+        let headers = auth.as_ref().map(AuthHeader::as_header_map);
+        backend.client.search("", headers, &query)
+    }))
+    .await;
+    Ok(partial_results(ctx, backends, results)?
+        .into_iter()
+        .flat_map(|(backend, root)| {
+            root.into_data().map(|data| Run {
+                backend: backend.clone(),
+                data,
+            })
+        })
+        .collect())
+}
+
+/// Asset IDs are sent to tiled as u32 so larger IDs cannot be requested
+fn asset_id(id: i64) -> Result<u32> {
+    u32::try_from(id).map_err(|_| {
+        Error::new(format!("Asset ID {id} is out of range"))
+            .extend_with(|_, ext| ext.set("code", "BAD_PATH"))
+    })
+}
+
+/// Find the backend containing a run, searching every backend unless one is named
+pub(crate) async fn find_run(
+    ctx: &Context<'_>,
+    id: &str,
+    backend: Option<&str>,
+) -> Result<Backend> {
+    let headers = ctx
+        .data::<Option<AuthHeader>>()?
+        .as_ref()
+        .map(AuthHeader::as_header_map);
+    let backends = ctx.data::<Backends>()?.route(backend, None, None)?;
+    match find_node(backends, id, headers).await.extend()? {
+        Some(backend) => Ok(backend.clone()),
+        None => Err(Error::new(format!("Run '{id}' not found"))
+            .extend_with(|_, ext| ext.set("code", "NOT_FOUND"))),
+    }
 }
 
 struct InstrumentSession {
     name: String,
+    backends: Vec<Backend>,
 }
 
 #[Object]
@@ -53,27 +234,9 @@ impl InstrumentSession {
         &self.name
     }
     async fn runs(&self, ctx: &Context<'_>) -> Result<Vec<Run>> {
-        let auth = ctx.data::<Option<AuthHeader>>()?;
-        let headers = auth.as_ref().map(AuthHeader::as_header_map);
-        let root = ctx
-            .data::<TiledClient>()?
-            .search(
-                "",
-                headers,
-                &[
-                    (
-                        "filter[eq][condition][key]",
-                        "start.instrument_session".into(),
-                    ),
-                    (
-                        "filter[eq][condition][value]",
-                        format!(r#""{}""#, self.name).into(),
-                    ),
-                    ("include_data_sources", "true".into()),
-                ],
-            )
-            .await?;
-        Ok(root.into_data().map(|d| Run { data: d }).collect())
+        let backends = self.backends.iter().collect::<Vec<_>>();
+        let filter = ("start.instrument_session", self.name.clone());
+        search_runs(ctx, &backends, &[filter]).await
     }
 }
 
@@ -108,6 +271,23 @@ impl<'run> ArrayData<'run> {
             })
             .collect()
     }
+    /// Link to a PNG preview of a frame of this array, if it is a 2-D or 3-D image
+    async fn preview_url(&self, ctx: &Context<'_>) -> Option<String> {
+        let dims = self.attrs.structure.dimensions()?.len();
+        if !(2..=3).contains(&dims) {
+            return None;
+        }
+        let mut preview = ctx.data::<RootAddress>().ok()?.0.clone();
+        preview
+            .path_segments_mut()
+            .ok()?
+            .push("preview")
+            .push(&self.run.data.id)
+            .push(&self.stream)
+            .push(&self.id);
+        self.run.backend_query(&mut preview);
+        Some(preview.to_string())
+    }
 }
 
 struct Asset<'a> {
@@ -120,22 +300,125 @@ impl Asset<'_> {
     async fn file(&self) -> &str {
         &self.asset.data_uri
     }
+    async fn is_directory(&self) -> bool {
+        self.asset.is_directory
+    }
+    /// Link to download this asset. Directories are downloaded as a tar archive.
     async fn download(&self, ctx: &Context<'_>) -> Option<String> {
+        if self.asset.is_directory {
+            return self.archive_link(ctx, ArchiveFormat::Tar);
+        }
+        Some(self.link(ctx, "asset")?.to_string())
+    }
+    /// Link to download all files in a directory asset as a single archive
+    async fn archive(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] format: ArchiveFormat,
+    ) -> Option<String> {
+        if !self.asset.is_directory {
+            return None;
+        }
+        self.archive_link(ctx, format)
+    }
+    /// Hex encoded checksum of the content of a file asset
+    async fn checksum(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] algorithm: DigestAlgorithm,
+    ) -> Result<Option<String>> {
+        let Some(id) = self.asset.id.filter(|_| !self.asset.is_directory) else {
+            return Ok(None);
+        };
+        let id = asset_id(id)?;
+        let auth = ctx.data::<Option<AuthHeader>>()?;
+        let key = ChecksumKey {
+            backend: self.data.run.backend.name.to_string(),
+            user: AuthHeader::user_key(auth.as_ref()),
+            run: self.data.run.data.id.clone(),
+            stream: self.data.stream.clone(),
+            det: self.data.id.clone(),
+            id,
+            algorithm,
+        };
+        let checksum = ctx
+            .data::<ChecksumCache>()?
+            .checksum(&self.data.run.backend.client, key, auth.as_ref())
+            .await?;
+        Ok(Some(checksum))
+    }
+    /// The files contained in a directory asset
+    async fn files(&self, ctx: &Context<'_>) -> Result<Option<Vec<AssetFile>>> {
+        let Some(id) = self.asset.id.filter(|_| self.asset.is_directory) else {
+            return Ok(None);
+        };
+        let id = asset_id(id)?;
+        let auth = ctx.data::<Option<AuthHeader>>()?;
+        let headers = auth.as_ref().map(AuthHeader::as_header_map);
+        let manifest = self
+            .data
+            .run
+            .backend
+            .client
+            .asset_manifest(
+                &self.data.run.data.id,
+                &self.data.stream,
+                &self.data.id,
+                id,
+                headers,
+            )
+            .await
+            .extend()?;
+        let base = self.link(ctx, "asset");
+        Ok(Some(
+            manifest
+                .into_iter()
+                .map(|path| {
+                    let download = base.clone().and_then(|mut url| {
+                        url.path_segments_mut().ok()?.extend(path.split('/'));
+                        Some(url.to_string())
+                    });
+                    AssetFile { path, download }
+                })
+                .collect(),
+        ))
+    }
+}
+
+impl Asset<'_> {
+    /// Build a link to this asset under the given top level route
+    fn link(&self, ctx: &Context<'_>, route: &str) -> Option<Url> {
         let id = self.asset.id?;
-        let mut download = ctx.data::<RootAddress>().ok()?.0.clone();
-        download
-            .path_segments_mut()
+        let mut link = ctx.data::<RootAddress>().ok()?.0.clone();
+        link.path_segments_mut()
             .ok()?
-            .push("asset")
+            .push(route)
             .push(&self.data.run.data.id)
             .push(&self.data.stream)
             .push(&self.data.id)
             .push(&id.to_string());
-        Some(download.to_string())
+        self.data.run.backend_query(&mut link);
+        Some(link)
+    }
+    fn archive_link(&self, ctx: &Context<'_>, format: ArchiveFormat) -> Option<String> {
+        let mut link = self.link(ctx, "archive")?;
+        if format != ArchiveFormat::default() {
+            link.query_pairs_mut()
+                .append_pair("format", format.extension());
+        }
+        Some(link.to_string())
     }
 }
 
+#[derive(SimpleObject)]
+struct AssetFile {
+    /// Path of the file relative to the directory asset
+    path: String,
+    download: Option<String>,
+}
+
 struct TableData {
+    client: TiledClient,
     id: String,
     attrs: node::Attributes<HashMap<String, Value>, table::TableStructure>,
 }
@@ -165,7 +448,6 @@ impl TableData {
     ) -> Result<HashMap<String, Vec<Value>>> {
         let auth = ctx.data::<Option<AuthHeader>>()?;
         let headers = auth.as_ref().map(AuthHeader::as_header_map);
-        let client = ctx.data::<TiledClient>()?;
         let p = self
             .attrs
             .ancestors
@@ -176,17 +458,35 @@ impl TableData {
             .join("/");
         info!("path: {:?}", p);
 
-        let table_data = client.table_full(&p, columns, headers).await?;
-        Ok(table_data)
+        self.client.table_full(&p, columns, headers).await.extend()
     }
 }
 
-struct Run {
-    data: node::Data,
+pub(crate) struct Run {
+    pub backend: Backend,
+    pub data: node::Data,
+}
+
+impl Run {
+    /// Add the backend of this run to the query of a link if it isn't the default backend
+    fn backend_query(&self, link: &mut Url) {
+        if !self.backend.is_default() {
+            link.query_pairs_mut()
+                .append_pair("backend", &self.backend.name);
+        }
+    }
 }
 
 #[Object]
 impl Run {
+    /// Whether the run is still running, finished or was aborted
+    async fn status(&self) -> Option<RunStatus> {
+        if let NodeAttributes::Container(attr) = &*self.data.attributes {
+            attr.metadata.run().map(RunMetadata::status)
+        } else {
+            None
+        }
+    }
     async fn scan_number(&self) -> Option<i64> {
         if let NodeAttributes::Container(attr) = &*self.data.attributes {
             attr.metadata.start_doc().map(|sd| sd.scan_id)
@@ -197,27 +497,125 @@ impl Run {
     async fn id(&self) -> &str {
         &self.data.id
     }
-    async fn data(&self, ctx: &Context<'_>) -> Result<Vec<RunData<'_>>> {
+    /// Name of the tiled backend this run is stored in
+    async fn backend(&self) -> &str {
+        &self.backend.name
+    }
+    /// Revision of the run's metadata, incremented each time it is changed. Pass this to
+    /// mutations to have them rejected if someone else has changed the metadata since.
+    async fn revision(&self, ctx: &Context<'_>) -> Result<usize> {
         let auth = ctx.data::<Option<AuthHeader>>()?;
-        let headers = auth.as_ref().map(AuthHeader::as_header_map);
-        let client = ctx.data::<TiledClient>()?;
-        let run_data = client
-            .search(
+        let revisions = self
+            .backend
+            .client
+            .revisions(
                 &self.data.id,
+                0,
+                1,
+                auth.as_ref().map(AuthHeader::as_header_map),
+            )
+            .await
+            .extend()?;
+        Ok(revisions.meta.count)
+    }
+    /// Versions of the run's metadata, oldest first, each with the changes made since the
+    /// revision before it. The current metadata is the last version. At most 100 versions are
+    /// returned at a time.
+    async fn revisions(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] offset: usize,
+        #[graphql(default = 20, validator(maximum = 100))] limit: usize,
+    ) -> Result<Vec<MetadataRevision>> {
+        let headers = ctx
+            .data::<Option<AuthHeader>>()?
+            .as_ref()
+            .map(AuthHeader::as_header_map);
+        // The revision before the page is needed to find the changes in the first of it
+        let before = usize::from(offset > 0);
+        let revisions = self
+            .backend
+            .client
+            .revisions(
+                &self.data.id,
+                offset - before,
+                limit + before,
                 headers.clone(),
-                &[("include_data_sources", "true".into())],
             )
-            .await?;
+            .await
+            .extend()?;
+        let count = revisions.meta.count;
+        let mut revisions = revisions
+            .data
+            .into_iter()
+            .map(|rev| rev.attributes)
+            .collect::<Vec<_>>();
+        let previous = if before > 0 && !revisions.is_empty() {
+            Some(revisions.remove(0))
+        } else {
+            None
+        };
+        // Tiled only keeps the versions that have been replaced so the latest change is found by
+        // comparing the last of them to the current metadata
+        let current = if revisions.len() < limit && offset <= count {
+            let latest = self
+                .backend
+                .client
+                .latest_metadata(&self.data.id, headers)
+                .await
+                .extend()?;
+            Some(latest["data"]["attributes"]["metadata"].clone())
+        } else {
+            None
+        };
+        Ok(revision::with_changes(previous, revisions, current))
+    }
+    async fn data(&self, ctx: &Context<'_>) -> Result<Vec<RunData<'_>>> {
+        let auth = ctx.data::<Option<AuthHeader>>()?;
+        let loader = ctx.data::<TiledDataLoader>()?;
+        let streams = loader
+            .load_one(Children(NodeKey::new(
+                &self.backend,
+                &self.data.id,
+                auth.as_ref(),
+            )))
+            .await
+            .map_err(|e| e.extend())?
+            .unwrap_or_default();
         let mut sources = Vec::new();
-        for stream in run_data.data() {
-            let stream_data = client
-                .search(
-                    &format!("{}/{}", self.data.id, stream.id),
-                    headers.clone(),
-                    &[("include_data_sources", "true".into())],
-                )
-                .await?;
-            for dataset in stream_data.into_data() {
+        // Tables derived from the run's data are stored alongside its streams. Other nodes there
+        // are not data of the run.
+        let (streams, others): (Vec<_>, Vec<_>) = streams
+            .into_iter()
+            .partition(|node| matches!(*node.attributes, NodeAttributes::Container(_)));
+        for node in others {
+            if let NodeAttributes::Table(attrs) = *node.attributes
+                && attrs.specs.iter().any(|spec| spec.name == DERIVED_SPEC)
+            {
+                sources.push(RunData::Internal(TableData {
+                    client: self.backend.client.clone(),
+                    id: node.id,
+                    attrs,
+                }));
+            }
+        }
+        // All streams are requested together so that the loader can fetch them concurrently
+        let keys = streams
+            .iter()
+            .map(|stream| {
+                Children(NodeKey::new(
+                    &self.backend,
+                    format!("{}/{}", self.data.id, stream.id),
+                    auth.as_ref(),
+                ))
+            })
+            .collect::<Vec<_>>();
+        let mut stream_data = loader
+            .load_many(keys.clone())
+            .await
+            .map_err(|e| e.extend())?;
+        for (stream, key) in streams.iter().zip(keys) {
+            for dataset in stream_data.remove(&key).unwrap_or_default() {
                 match *dataset.attributes {
                     NodeAttributes::Array(attrs) => sources.push(RunData::Array(ArrayData {
                         run: self,
@@ -226,6 +624,7 @@ impl Run {
                         attrs,
                     })),
                     NodeAttributes::Table(attrs) => sources.push(RunData::Internal(TableData {
+                        client: self.backend.client.clone(),
                         id: dataset.id,
                         attrs,
                     })),
@@ -239,19 +638,28 @@ impl Run {
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashSet;
+    use std::time::{Duration, Instant};
+
     use async_graphql::{EmptyMutation, EmptySubscription, Schema, value};
     use axum::http::HeaderValue;
     use httpmock::MockServer;
-    use serde_json::json;
+    use serde_json::{Value, json};
 
     use crate::TiledQuery;
+    use crate::backends::Backends;
     use crate::clients::TiledClient;
+    use crate::config::{BackendConfig, GlazedConfig, TiledClientConfig};
     use crate::handlers::AuthHeader;
+    use crate::loaders::TiledLoader;
+    use crate::test_utils::read_json;
 
     fn build_schema(url: &str) -> Schema<TiledQuery, EmptyMutation, EmptySubscription> {
+        let backends = Backends::single(TiledClient::new(url.parse().unwrap()));
         Schema::build(TiledQuery, EmptyMutation, EmptySubscription)
             .data(Option::<AuthHeader>::None)
-            .data(TiledClient::new(url.parse().unwrap()))
+            .data(TiledLoader::data_loader(backends.clone()))
+            .data(backends)
             .finish()
     }
 
@@ -273,6 +681,25 @@ mod tests {
         mock.assert();
     }
 
+    #[tokio::test]
+    async fn error_codes() {
+        let server = MockServer::start();
+        server
+            .mock_async(|when, then| {
+                when.method("GET").path("/api/v1/metadata/private");
+                then.status(401)
+                    .json_body(json!({"detail": "Invalid API key"}));
+            })
+            .await;
+        let schema = build_schema(&server.base_url());
+        let response = schema.execute(r#"{ run(id: "private") { id } }"#).await;
+        let error = &response.errors[0];
+        assert_eq!(error.message, "Not authenticated: Invalid API key");
+        let extensions = error.extensions.as_ref().unwrap();
+        assert_eq!(extensions.get("code"), Some(&value!("UNAUTHENTICATED")));
+        assert_eq!(extensions.get("status"), Some(&value!(401)));
+    }
+
     #[tokio::test]
     async fn invalid_runs() {
         let server = MockServer::start();
@@ -321,7 +748,9 @@ mod tests {
             })
             .await;
         let schema = Schema::build(TiledQuery, EmptyMutation, EmptySubscription)
-            .data(TiledClient::new(server.base_url().parse().unwrap()))
+            .data(Backends::single(TiledClient::new(
+                server.base_url().parse().unwrap(),
+            )))
             .data(Some(AuthHeader::from(HeaderValue::from_static(
                 "auth_value",
             ))))
@@ -333,4 +762,328 @@ mod tests {
         assert_eq!(response.data, value!({"instrumentSession": {"runs": []}}));
         mock_instrument_session.assert();
     }
+
+    #[tokio::test]
+    async fn unsupported_filter() {
+        let server = MockServer::start();
+        let mut app: Value = read_json("resources/metadata_app.json");
+        app["queries"] = json!(["keys_filter"]);
+        server
+            .mock_async(|when, then| {
+                when.method("GET").path("/api/v1/");
+                then.status(200).json_body(app);
+            })
+            .await;
+        let search = server
+            .mock_async(|when, then| {
+                when.method("GET").path("/api/v1/search/");
+                then.status(400);
+            })
+            .await;
+        let client = TiledClient::new(server.base_url().parse().unwrap());
+        client.negotiate().await.unwrap();
+        let schema = Schema::build(TiledQuery, EmptyMutation, EmptySubscription)
+            .data(Option::<AuthHeader>::None)
+            .data(Backends::single(client))
+            .finish();
+        let response = schema
+            .execute(r#"{ instrumentSession(name: "cm12345-6"){ runs { id }}}"#)
+            .await;
+        assert_eq!(
+            response.errors[0].message,
+            "Backend 'default' does not support filtering runs by start.instrument_session"
+        );
+        search.assert_calls(0);
+    }
+
+    #[tokio::test]
+    async fn streams_fetched_concurrently() {
+        let server = MockServer::start();
+        let read =
+            |path| serde_json::from_str::<Value>(&std::fs::read_to_string(path).unwrap()).unwrap();
+        let stream = read("resources/search_run_container.json")["data"][0].clone();
+        let table = read("resources/metadata_table.json")["data"].clone();
+        let delays = [("primary", 300), ("baseline", 100), ("monitor", 200)];
+
+        server
+            .mock_async(|when, then| {
+                when.method("GET").path("/api/v1/metadata/run");
+                then.status(200)
+                    .body_from_file("resources/metadata_run.json");
+            })
+            .await;
+        let run_id = "5d8f5c3e-0e00-4c5c-816d-70b4b0f41498";
+        let streams = delays
+            .iter()
+            .map(|(name, _)| {
+                let mut stream = stream.clone();
+                stream["id"] = json!(name);
+                stream
+            })
+            .collect::<Vec<_>>();
+        server
+            .mock_async(|when, then| {
+                when.method("GET").path(format!("/api/v1/search/{run_id}"));
+                then.status(200)
+                    .json_body(json!({"data": streams, "error": null, "links": null, "meta": {}}));
+            })
+            .await;
+        for (name, delay) in delays {
+            let mut table = table.clone();
+            table["id"] = json!(format!("{name}-table"));
+            server
+                .mock_async(|when, then| {
+                    when.method("GET")
+                        .path(format!("/api/v1/search/{run_id}/{name}"));
+                    then.status(200)
+                        .delay(Duration::from_millis(delay))
+                        .json_body(
+                            json!({"data": [table], "error": null, "links": null, "meta": {}}),
+                        );
+                })
+                .await;
+        }
+
+        let schema = build_schema(&server.base_url());
+        let start = Instant::now();
+        let response = schema
+            .execute(r#"{ run(id: "run") { data { ... on TableData { name }}}}"#)
+            .await;
+        let elapsed = start.elapsed();
+
+        assert_eq!(response.errors, &[]);
+        assert_eq!(
+            response.data,
+            value!({"run": {"data": [
+                {"name": "primary-table"},
+                {"name": "baseline-table"},
+                {"name": "monitor-table"},
+            ]}})
+        );
+        // Sequential requests would take at least 600ms
+        assert!(elapsed < Duration::from_millis(550), "Took {elapsed:?}");
+    }
+
+    #[tokio::test]
+    async fn revisions_end_with_current_metadata() {
+        let server = MockServer::start();
+        let mut run: Value = read_json("resources/metadata_run.json");
+        let original = run["data"]["attributes"]["metadata"].clone();
+        run["data"]["id"] = json!("run");
+        run["data"]["attributes"]["metadata"]["note"] = json!("Beam dump");
+        server
+            .mock_async(|when, then| {
+                when.method("GET").path("/api/v1/metadata/run");
+                then.status(200).json_body(run);
+            })
+            .await;
+        server
+            .mock_async(|when, then| {
+                when.method("GET").path("/api/v1/revisions/run");
+                then.status(200).json_body(json!({
+                    "data": [{"attributes": {
+                        "revision_number": 1,
+                        "metadata": original,
+                        "time_updated": "2026-10-01T12:00:00",
+                    }}],
+                    "meta": {"count": 1},
+                }));
+            })
+            .await;
+        let schema = build_schema(&server.base_url());
+        let response = schema
+            .execute(r#"{ run(id: "run") { revisions { revisionNumber timeUpdated changes } } }"#)
+            .await;
+        assert_eq!(response.errors, &[]);
+        assert_eq!(
+            response.data,
+            value!({"run": {"revisions": [
+                {"revisionNumber": 1, "timeUpdated": "2026-10-01T12:00:00", "changes": null},
+                {
+                    "revisionNumber": 2,
+                    "timeUpdated": null,
+                    "changes": [{"op": "add", "path": "/note", "value": "Beam dump"}],
+                },
+            ]}})
+        );
+        let response = schema
+            .execute(r#"{ run(id: "run") { revisions(limit: 101) { revisionNumber } } }"#)
+            .await;
+        assert_eq!(response.errors.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn only_derived_tables_listed_with_streams() {
+        let server = MockServer::start();
+        let mut run: Value = read_json("resources/metadata_run.json");
+        run["data"]["id"] = json!("run");
+        server
+            .mock_async(|when, then| {
+                when.method("GET").path("/api/v1/metadata/run");
+                then.status(200).json_body(run);
+            })
+            .await;
+        let table: Value = read_json("resources/metadata_table.json");
+        let mut derived = table["data"].clone();
+        derived["id"] = json!("peaks");
+        derived["attributes"]["specs"] = json!([{"name": "DerivedTable", "version": "1.0"}]);
+        let mut other = table["data"].clone();
+        other["id"] = json!("scratch");
+        server
+            .mock_async(|when, then| {
+                when.method("GET").path("/api/v1/search/run");
+                then.status(200).json_body(
+                    json!({"data": [derived, other], "error": null, "links": null, "meta": {}}),
+                );
+            })
+            .await;
+        let response = build_schema(&server.base_url())
+            .execute(r#"{ run(id: "run") { data { ... on TableData { name }}}}"#)
+            .await;
+        assert_eq!(response.errors, &[]);
+        assert_eq!(
+            response.data,
+            value!({"run": {"data": [{"name": "peaks"}]}})
+        );
+    }
+
+    #[tokio::test]
+    async fn multiple_backends() {
+        let default = MockServer::start();
+        let other = MockServer::start();
+        for server in [&default, &other] {
+            server
+                .mock_async(|when, then| {
+                    when.method("GET")
+                        .path("/api/v1/search/")
+                        .query_param("filter[eq][condition][key]", "start.instrument");
+                    then.status(200)
+                        .body_from_file("resources/search_root.json");
+                })
+                .await;
+        }
+        default
+            .mock_async(|when, then| {
+                when.method("GET").path("/api/v1/metadata/run");
+                then.status(404).body("{}");
+            })
+            .await;
+        other
+            .mock_async(|when, then| {
+                when.method("GET").path("/api/v1/metadata/run");
+                then.status(200)
+                    .body_from_file("resources/metadata_run.json");
+            })
+            .await;
+        let backends = Backends::from_config(&GlazedConfig {
+            tiled_client: TiledClientConfig {
+                address: default.base_url().parse().unwrap(),
+                ..GlazedConfig::default().tiled_client
+            },
+            backends: vec![BackendConfig {
+                name: "other".into(),
+                instruments: vec!["i22".into()],
+                session_prefixes: vec![],
+                tiled_client: TiledClientConfig {
+                    address: other.base_url().parse().unwrap(),
+                    ..GlazedConfig::default().tiled_client
+                },
+            }],
+            ..GlazedConfig::default()
+        })
+        .unwrap();
+        let schema = Schema::build(TiledQuery, EmptyMutation, EmptySubscription)
+            .data(Option::<AuthHeader>::None)
+            .data(TiledLoader::data_loader(backends.clone()))
+            .data(backends)
+            .finish();
+
+        let response = schema
+            .execute(
+                r#"{
+                    all: runs(instrument: "b21") { backend }
+                    routed: runs(instrument: "i22") { backend }
+                    run(id: "run") { backend }
+                }"#,
+            )
+            .await;
+        assert_eq!(response.errors, &[]);
+        let data = response.data.into_json().unwrap();
+        let backends = |field: &str| {
+            data[field]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|run| run["backend"].as_str().unwrap().to_owned())
+                .collect::<HashSet<_>>()
+        };
+        assert_eq!(
+            backends("all"),
+            HashSet::from(["default".into(), "other".into()])
+        );
+        assert_eq!(backends("routed"), HashSet::from(["other".into()]));
+        assert_eq!(data["run"]["backend"], "other");
+
+        let response = schema
+            .execute(r#"{ run(id: "run", backend: "missing") { id }}"#)
+            .await;
+        assert_eq!(response.errors[0].message, "Unknown backend: 'missing'");
+    }
+
+    #[tokio::test]
+    async fn failing_backend_reported() {
+        let default = MockServer::start();
+        let other = MockServer::start();
+        default
+            .mock_async(|when, then| {
+                when.method("GET")
+                    .path("/api/v1/search/")
+                    .header("authorization", "Bearer alice");
+                then.status(200)
+                    .body_from_file("resources/search_root.json");
+            })
+            .await;
+        // Credentials are sent to every backend a request is routed to
+        other
+            .mock_async(|when, then| {
+                when.method("GET")
+                    .path("/api/v1/search/")
+                    .header("authorization", "Bearer alice");
+                then.status(403).json_body(json!({"detail": "Not allowed"}));
+            })
+            .await;
+        let backends = Backends::from_config(&GlazedConfig {
+            tiled_client: TiledClientConfig {
+                address: default.base_url().parse().unwrap(),
+                ..GlazedConfig::default().tiled_client
+            },
+            backends: vec![BackendConfig {
+                name: "other".into(),
+                instruments: vec![],
+                session_prefixes: vec![],
+                tiled_client: TiledClientConfig {
+                    address: other.base_url().parse().unwrap(),
+                    ..GlazedConfig::default().tiled_client
+                },
+            }],
+            ..GlazedConfig::default()
+        })
+        .unwrap();
+        let auth = AuthHeader::from(HeaderValue::from_static("Bearer alice"));
+        let schema = Schema::build(TiledQuery, EmptyMutation, EmptySubscription)
+            .data(Some(auth))
+            .data(TiledLoader::data_loader(backends.clone()))
+            .data(backends)
+            .finish();
+
+        let response = schema.execute("{ runs { backend } }").await;
+        let data = response.data.into_json().unwrap();
+        let runs = data["runs"].as_array().unwrap();
+        assert!(!runs.is_empty());
+        assert!(runs.iter().all(|run| run["backend"] == "default"));
+        assert_eq!(response.errors.len(), 1);
+        let extensions = response.errors[0].extensions.as_ref().unwrap();
+        assert_eq!(extensions.get("backend"), Some(&value!("other")));
+        assert_eq!(extensions.get("code"), Some(&value!("FORBIDDEN")));
+    }
 }
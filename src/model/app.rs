@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use async_graphql::SimpleObject;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
@@ -8,6 +10,10 @@ use crate::model::node;
 pub struct AppMetadata {
     pub api_version: i64,
     pub library_version: String,
+    /// Formats each structure family can be requested in
+    #[serde(default)]
+    #[graphql(skip)]
+    pub formats: HashMap<String, Vec<String>>,
     pub queries: Vec<String>,
     pub links: node::Links,
     pub meta: Value,
@@ -11,6 +11,17 @@ pub struct ArrayStructure {
     resizable: bool,
 }
 
+impl ArrayStructure {
+    /// The size of each dimension of the array, if it is made up of valid sizes
+    pub fn dimensions(&self) -> Option<Vec<usize>> {
+        serde_json::from_value(self.shape.clone()).ok()
+    }
+    /// The sizes of the chunks along each dimension of the array
+    pub fn chunk_sizes(&self) -> Option<Vec<Vec<usize>>> {
+        serde_json::from_value(self.chunks.clone()).ok()
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, SimpleObject)]
 pub struct DataType {
     endianness: String,
@@ -18,3 +29,28 @@ pub struct DataType {
     itemsize: i64,
     dt_units: Value,
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::model::node::{self, NodeAttributes};
+    use crate::test_utils::assert_readable_as;
+
+    #[test]
+    fn metadata_array() {
+        assert_readable_as::<node::Metadata>("resources/metadata_array.json");
+    }
+
+    #[test]
+    fn array_shape_and_chunks() {
+        let file = std::fs::read_to_string("resources/metadata_array.json").unwrap();
+        let meta: node::Metadata = serde_json::from_str(&file).unwrap();
+        let NodeAttributes::Array(attrs) = *meta.into_data().attributes else {
+            panic!("Expected array attributes");
+        };
+        assert_eq!(attrs.structure.dimensions(), Some(vec![5, 1024, 1024]));
+        assert_eq!(
+            attrs.structure.chunk_sizes(),
+            Some(vec![vec![1, 1, 1, 1, 1], vec![1024], vec![1024]])
+        );
+    }
+}
@@ -13,6 +13,13 @@ pub enum ContainerMetadata {
 }
 
 impl ContainerMetadata {
+    pub fn run(&self) -> Option<&run::RunMetadata> {
+        if let ContainerMetadata::Run(run) = self {
+            Some(run)
+        } else {
+            None
+        }
+    }
     pub fn start_doc(&self) -> Option<&Start> {
         if let ContainerMetadata::Run(run) = self {
             Some(&run.start)
@@ -76,6 +76,8 @@ pub struct Attributes<Meta, S> {
     pub specs: Vec<Spec>,
     pub metadata: Meta,
     pub structure: S,
+    /// Not included by older versions of tiled
+    #[serde(default)]
     pub access_blob: Value,
     pub sorting: Option<Vec<Sorting>>,
     pub data_sources: Option<Vec<DataSource<S>>>,
@@ -115,12 +117,18 @@ pub enum Management {
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, SimpleObject)]
 pub struct Asset {
     pub data_uri: String,
-    is_directory: bool,
+    pub is_directory: bool,
     parameter: Option<String>,
     num: Option<i64>,
     pub id: Option<i64>,
 }
 
+/// Listing of the files contained in a directory asset
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AssetManifest {
+    pub manifest: Vec<String>,
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, SimpleObject)]
 pub struct Links {
     #[serde(rename = "self")]
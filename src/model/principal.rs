@@ -0,0 +1,25 @@
+use serde::Deserialize;
+
+/// A user or service known to tiled, as returned by tiled's whoami endpoint
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Principal {
+    pub uuid: String,
+    #[serde(default)]
+    pub identities: Vec<Identity>,
+}
+
+/// An identity of a principal with one of tiled's authentication providers
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Identity {
+    pub id: String,
+    pub provider: String,
+}
+
+impl Principal {
+    /// Name to show for the principal, from its first identity
+    pub fn name(&self) -> &str {
+        self.identities
+            .first()
+            .map_or(&self.uuid, |identity| &identity.id)
+    }
+}
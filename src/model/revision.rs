@@ -0,0 +1,152 @@
+use async_graphql::SimpleObject;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// A page of the previous versions of a node's metadata
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Revisions {
+    pub data: Vec<RevisionData>,
+    pub meta: RevisionsMeta,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct RevisionsMeta {
+    /// Total number of revisions, not only those in this page
+    pub count: usize,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct RevisionData {
+    pub attributes: Revision,
+}
+
+/// The metadata of a node before it was changed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Revision {
+    pub revision_number: i64,
+    pub metadata: Value,
+    /// When the metadata was replaced by the next revision
+    pub time_updated: String,
+}
+
+/// A version of a node's metadata and how it differed from the version before it
+#[derive(Debug, PartialEq, SimpleObject)]
+pub struct MetadataRevision {
+    pub revision_number: i64,
+    pub metadata: Value,
+    /// When the metadata was replaced by the next revision. Null for the current metadata.
+    pub time_updated: Option<String>,
+    /// JSON Patch (RFC 6902) from the metadata of the previous revision to this one. Null for
+    /// the first revision.
+    pub changes: Option<Value>,
+}
+
+/// Compare each revision to the one before it. The revision preceding the first of the list
+/// should be given unless the list starts with the first revision. If the current metadata is
+/// given, it is added after the revisions so that the latest change is included.
+pub fn with_changes(
+    previous: Option<Revision>,
+    revisions: Vec<Revision>,
+    current: Option<Value>,
+) -> Vec<MetadataRevision> {
+    let mut number = previous.as_ref().map_or(0, |rev| rev.revision_number);
+    let mut previous = previous.map(|rev| rev.metadata);
+    let revisions = revisions
+        .into_iter()
+        .map(|rev| (rev.revision_number, rev.metadata, Some(rev.time_updated)))
+        .chain(current.map(|metadata| (0, metadata, None)));
+    revisions
+        .map(|(revision_number, metadata, time_updated)| {
+            // The current metadata follows the last revision
+            number = match time_updated {
+                Some(_) => revision_number,
+                None => number + 1,
+            };
+            let changes = previous
+                .as_ref()
+                .map(|prev| serde_json::to_value(json_patch::diff(prev, &metadata)))
+                .and_then(Result::ok);
+            previous = Some(metadata.clone());
+            MetadataRevision {
+                revision_number: number,
+                metadata,
+                time_updated,
+                changes,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{Revision, with_changes};
+
+    fn revision(number: i64, metadata: serde_json::Value) -> Revision {
+        Revision {
+            revision_number: number,
+            metadata,
+            time_updated: format!("2026-01-0{number}T00:00:00"),
+        }
+    }
+
+    #[test]
+    fn changes_between_revisions() {
+        let revisions = with_changes(
+            None,
+            vec![
+                revision(1, json!({"start": {}})),
+                revision(2, json!({"start": {}, "tags": ["good"]})),
+                revision(
+                    3,
+                    json!({"start": {}, "tags": ["bad"], "note": "Beam dump"}),
+                ),
+            ],
+            None,
+        );
+        assert_eq!(revisions[0].changes, None);
+        assert_eq!(
+            revisions[1].changes,
+            Some(json!([{"op": "add", "path": "/tags", "value": ["good"]}]))
+        );
+        assert_eq!(
+            revisions[2].changes,
+            Some(json!([
+                {"op": "add", "path": "/note", "value": "Beam dump"},
+                {"op": "replace", "path": "/tags/0", "value": "bad"},
+            ]))
+        );
+    }
+
+    #[test]
+    fn changes_from_earlier_page() {
+        let revisions = with_changes(
+            Some(revision(1, json!({"note": "a"}))),
+            vec![revision(2, json!({"note": "b"}))],
+            None,
+        );
+        assert_eq!(
+            revisions[0].changes,
+            Some(json!([{"op": "replace", "path": "/note", "value": "b"}]))
+        );
+    }
+
+    #[test]
+    fn changes_to_current_metadata() {
+        let revisions = with_changes(
+            Some(revision(4, json!({"note": "a"}))),
+            vec![],
+            Some(json!({"note": "b"})),
+        );
+        assert_eq!(revisions[0].revision_number, 5);
+        assert_eq!(revisions[0].time_updated, None);
+        assert_eq!(
+            revisions[0].changes,
+            Some(json!([{"op": "replace", "path": "/note", "value": "b"}]))
+        );
+        let revisions = with_changes(None, vec![], Some(json!({"note": "a"})));
+        assert_eq!(revisions[0].revision_number, 1);
+        assert_eq!(revisions[0].changes, None);
+    }
+}
@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 
-use async_graphql::SimpleObject;
+use async_graphql::{Enum, SimpleObject};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use uuid::Uuid;
@@ -9,6 +9,32 @@ use uuid::Uuid;
 pub struct RunMetadata {
     pub start: Start,
     pub stop: Option<Stop>,
+    /// Labels added to the run by users
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Free text comment added to the run by users
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+}
+
+/// Stage of a run's lifecycle, from its stop document
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Enum)]
+#[serde(rename_all = "snake_case")]
+pub enum RunStatus {
+    Running,
+    Finished,
+    /// The run was aborted or failed
+    Aborted,
+}
+
+impl RunMetadata {
+    pub fn status(&self) -> RunStatus {
+        match &self.stop {
+            None => RunStatus::Running,
+            Some(stop) if stop.exit_status == "success" => RunStatus::Finished,
+            Some(_) => RunStatus::Aborted,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, SimpleObject)]
@@ -1,11 +1,21 @@
 use std::collections::HashMap;
 
-use async_graphql::SimpleObject;
+use async_graphql::{Enum, SimpleObject};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
 pub type Table = HashMap<String, Vec<Value>>;
 
+/// Type of the values in a column of a table created through glazed
+#[derive(Enum, Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColumnType {
+    Int,
+    Float,
+    String,
+    Boolean,
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, SimpleObject)]
 pub struct TableStructure {
     pub arrow_schema: String,
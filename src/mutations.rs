@@ -0,0 +1,1079 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use async_graphql::{
+    Context, Error, ErrorExtensions, InputObject, Json, Object, Result, ResultExt as _,
+    SimpleObject,
+};
+use serde_json::{Map, Value, json};
+use tracing::warn;
+
+use crate::arrow;
+use crate::audit::unix_millis;
+use crate::backends::Backend;
+use crate::clients::{ClientError, ClientResult};
+use crate::handlers::AuthHeader;
+use crate::model::node::{self, NodeAttributes};
+use crate::model::revision::Revision;
+use crate::model::run::RunMetadata;
+use crate::model::table::{ColumnType, Table};
+use crate::model::{Run, find_run};
+use crate::userdata::{Bookmark, RunSearch, SavedSearch, UserData, current_user};
+
+/// Name of the spec marking tables created from the analysis of a run's data
+pub(crate) const DERIVED_SPEC: &str = "DerivedTable";
+/// Metadata key holding the types of the columns of a derived table
+const COLUMN_TYPES: &str = "column_types";
+/// Number of revisions requested at a time when searching for a revision
+const REVISION_PAGE: usize = 100;
+
+#[derive(InputObject)]
+struct ColumnInput {
+    name: String,
+    #[graphql(name = "type")]
+    kind: ColumnType,
+}
+
+/// A table written to tiled alongside the data of a run
+#[derive(SimpleObject)]
+struct DerivedTable {
+    path: String,
+    columns: Vec<String>,
+    /// Number of rows in the table after the change
+    rows: usize,
+}
+
+fn bad_input(message: impl Into<String>) -> Error {
+    Error::new(message).extend_with(|_, ext| ext.set("code", "BAD_USER_INPUT"))
+}
+
+fn auth_headers(ctx: &Context<'_>) -> Result<Option<axum::http::HeaderMap>> {
+    Ok(ctx
+        .data::<Option<AuthHeader>>()?
+        .as_ref()
+        .map(AuthHeader::as_header_map))
+}
+
+/// Check that all columns in the rows are known and that every column has the same length,
+/// returning the number of rows
+fn row_count(columns: &[(String, ColumnType)], rows: &Table) -> Result<usize> {
+    if let Some(unknown) = rows
+        .keys()
+        .find(|k| !columns.iter().any(|(name, _)| name == *k))
+    {
+        return Err(bad_input(format!("Unknown column '{unknown}'")));
+    }
+    let mut lengths = rows.values().map(Vec::len);
+    let length = lengths.next().unwrap_or_default();
+    if lengths.any(|len| len != length) {
+        return Err(bad_input("All columns must have the same number of rows"));
+    }
+    Ok(length)
+}
+
+pub(crate) struct TiledMutation;
+
+#[Object]
+impl TiledMutation {
+    /// Apply a JSON Patch (RFC 6902) to the metadata of a run. The patch is rejected with a
+    /// REVISION_CONFLICT error if the metadata is changed by someone else while it is applied, or
+    /// has changed since the revision given.
+    async fn update_run_metadata(
+        &self,
+        ctx: &Context<'_>,
+        id: String,
+        patch: Json<Vec<Value>>,
+        revision: Option<usize>,
+        backend: Option<String>,
+    ) -> Result<Run> {
+        let update = RunUpdate::new(ctx, &id, backend.as_deref(), revision).await?;
+        update.apply(ctx, |_| patch.0).await
+    }
+
+    /// Add a tag to a run if it doesn't already have it
+    async fn add_tag(
+        &self,
+        ctx: &Context<'_>,
+        id: String,
+        tag: String,
+        revision: Option<usize>,
+        backend: Option<String>,
+    ) -> Result<Run> {
+        let update = RunUpdate::new(ctx, &id, backend.as_deref(), revision).await?;
+        update
+            .apply(ctx, |run| {
+                if run.tags.contains(&tag) {
+                    vec![]
+                } else if run.tags.is_empty() {
+                    vec![json!({"op": "add", "path": "/tags", "value": [tag]})]
+                } else {
+                    vec![json!({"op": "add", "path": "/tags/-", "value": tag})]
+                }
+            })
+            .await
+    }
+
+    /// Remove a tag from a run if it has it
+    async fn remove_tag(
+        &self,
+        ctx: &Context<'_>,
+        id: String,
+        tag: String,
+        revision: Option<usize>,
+        backend: Option<String>,
+    ) -> Result<Run> {
+        let update = RunUpdate::new(ctx, &id, backend.as_deref(), revision).await?;
+        update
+            .apply(ctx, |run| match run.tags.iter().position(|t| t == &tag) {
+                // The test makes the patch fail instead of removing another tag if the tags have
+                // been changed since they were read
+                Some(index) => vec![
+                    json!({"op": "test", "path": format!("/tags/{index}"), "value": tag}),
+                    json!({"op": "remove", "path": format!("/tags/{index}")}),
+                ],
+                None => vec![],
+            })
+            .await
+    }
+
+    /// Set the note of a run, or remove it if no note is given
+    async fn set_note(
+        &self,
+        ctx: &Context<'_>,
+        id: String,
+        note: Option<String>,
+        revision: Option<usize>,
+        backend: Option<String>,
+    ) -> Result<Run> {
+        let update = RunUpdate::new(ctx, &id, backend.as_deref(), revision).await?;
+        update
+            .apply(ctx, |run| match (note, &run.note) {
+                (Some(note), _) => vec![json!({"op": "add", "path": "/note", "value": note})],
+                (None, Some(_)) => vec![json!({"op": "remove", "path": "/note"})],
+                (None, None) => vec![],
+            })
+            .await
+    }
+
+    /// Restore the metadata of a run to how it was at a previous revision. The revert is itself
+    /// recorded as a new revision. If an expected revision is given, the revert is rejected with
+    /// a REVISION_CONFLICT error if the metadata has changed since then.
+    async fn revert_run_metadata(
+        &self,
+        ctx: &Context<'_>,
+        id: String,
+        revision: usize,
+        expected_revision: Option<usize>,
+        backend: Option<String>,
+    ) -> Result<Run> {
+        let update = RunUpdate::new(ctx, &id, backend.as_deref(), expected_revision).await?;
+        let target = find_revision(&update.backend, &id, revision, auth_headers(ctx)?)
+            .await
+            .extend()?;
+        let Some(target) = target else {
+            return Err(Error::new(format!("Run '{id}' has no revision {revision}"))
+                .extend_with(|_, ext| ext.set("code", "NOT_FOUND")));
+        };
+        update
+            .apply(ctx, |_| {
+                vec![json!({"op": "replace", "path": "", "value": target.metadata})]
+            })
+            .await
+    }
+
+    /// Bookmark a run for the current user. Unless a backend is given, all backends are searched
+    /// for the run.
+    async fn add_bookmark(
+        &self,
+        ctx: &Context<'_>,
+        run: String,
+        backend: Option<String>,
+    ) -> Result<Bookmark> {
+        let user = current_user(ctx).await?;
+        let backend = find_run(ctx, &run, backend.as_deref()).await?;
+        ctx.data::<UserData>()?
+            .add_bookmark(&user.id, &backend.name, &run)
+            .await
+            .extend()
+    }
+
+    /// Remove a bookmark of the current user, returning whether the run was bookmarked. Unless
+    /// a backend is given, bookmarks of the run in any backend are removed.
+    async fn remove_bookmark(
+        &self,
+        ctx: &Context<'_>,
+        run: String,
+        backend: Option<String>,
+    ) -> Result<bool> {
+        let user = current_user(ctx).await?;
+        ctx.data::<UserData>()?
+            .remove_bookmark(&user.id, backend.as_deref(), &run)
+            .await
+            .extend()
+    }
+
+    /// Save a search for the current user, replacing any previous search with the same name
+    async fn save_search(
+        &self,
+        ctx: &Context<'_>,
+        name: String,
+        search: RunSearch,
+    ) -> Result<SavedSearch> {
+        let user = current_user(ctx).await?;
+        if name.trim().is_empty() {
+            return Err(bad_input("Saved searches must have a name"));
+        }
+        ctx.data::<UserData>()?
+            .save_search(&user.id, &name, search)
+            .await
+            .extend()
+    }
+
+    /// Delete a search saved by the current user, returning whether it existed
+    async fn delete_saved_search(&self, ctx: &Context<'_>, name: String) -> Result<bool> {
+        let user = current_user(ctx).await?;
+        ctx.data::<UserData>()?
+            .delete_search(&user.id, &name)
+            .await
+            .extend()
+    }
+
+    /// Create a table as a child of a run, marked with the DerivedTable spec and recording
+    /// where it came from. Initial rows are given as a map of column name to values.
+    #[allow(clippy::too_many_arguments)]
+    async fn create_derived_table(
+        &self,
+        ctx: &Context<'_>,
+        run: String,
+        name: String,
+        columns: Vec<ColumnInput>,
+        rows: Option<Json<Table>>,
+        metadata: Option<Json<Map<String, Value>>>,
+        backend: Option<String>,
+    ) -> Result<DerivedTable> {
+        if name.is_empty() || name.contains('/') {
+            return Err(bad_input(format!("Invalid table name '{name}'")));
+        }
+        if columns.is_empty() {
+            return Err(bad_input("A table must have at least one column"));
+        }
+        let mut names = HashSet::new();
+        if let Some(duplicate) = columns.iter().find(|col| !names.insert(&col.name)) {
+            return Err(bad_input(format!("Duplicate column '{}'", duplicate.name)));
+        }
+        let columns = columns
+            .into_iter()
+            .map(|col| (col.name, col.kind))
+            .collect::<Vec<_>>();
+        let rows = rows.map(|rows| rows.0).unwrap_or_default();
+        let length = row_count(&columns, &rows)?;
+        let table = arrow::table_file(&columns, &rows).map_err(|e| bad_input(e.to_string()))?;
+
+        let user = current_user(ctx).await?;
+        let headers = auth_headers(ctx)?;
+        let backend = find_run(ctx, &run, backend.as_deref()).await?;
+        let mut metadata = metadata.map(|m| m.0).unwrap_or_default();
+        metadata.insert(
+            "provenance".into(),
+            json!({
+                "run": run,
+                "created_by": user.name,
+                "created_at": unix_millis(),
+                "software": concat!("glazed/", env!("CARGO_PKG_VERSION")),
+            }),
+        );
+        metadata.insert(
+            COLUMN_TYPES.into(),
+            columns.iter().map(|(n, t)| (n.clone(), json!(t))).collect(),
+        );
+        let names = columns.iter().map(|(n, _)| n.clone()).collect::<Vec<_>>();
+        let node = json!({
+            "id": name,
+            "structure_family": "table",
+            "specs": [{"name": DERIVED_SPEC, "version": "1.0"}],
+            "metadata": metadata,
+            "data_sources": [{
+                "structure_family": "table",
+                "structure": {
+                    "arrow_schema": arrow::schema_uri(&columns),
+                    "npartitions": 1,
+                    "columns": names,
+                    "resizable": false,
+                },
+                "mimetype": null,
+                "parameters": {},
+                "assets": [],
+                "management": "writable",
+            }],
+        });
+        backend
+            .client
+            .create_node(&run, &node, headers.clone())
+            .await
+            .extend()?;
+        let path = format!("{run}/{name}");
+        if let Err(err) = backend
+            .client
+            .put_table(&path, table, headers.clone())
+            .await
+        {
+            // An empty table would otherwise be left behind, blocking another attempt with the
+            // same name
+            if let Err(delete) = backend.client.delete_node(&path, headers).await {
+                warn!("Unable to delete table '{path}' after failing to write its rows: {delete}");
+            }
+            return Err(err.extend());
+        }
+        Ok(DerivedTable {
+            path,
+            columns: names,
+            rows: length,
+        })
+    }
+
+    /// Add rows to the end of a table created with createDerivedTable. Columns missing from the
+    /// new rows are filled with nulls.
+    ///
+    /// The existing rows are read and the whole table rewritten. The number of rows is checked
+    /// again just before the write and the append is rejected with a CONFLICT error if another
+    /// client added rows in the meantime. Tiled can't make the write itself conditional, so this
+    /// only narrows the window: rows appended between that check and the write are still lost.
+    async fn append_rows(
+        &self,
+        ctx: &Context<'_>,
+        run: String,
+        name: String,
+        rows: Json<Table>,
+        backend: Option<String>,
+    ) -> Result<DerivedTable> {
+        let headers = auth_headers(ctx)?;
+        let backend = find_run(ctx, &run, backend.as_deref()).await?;
+        let path = format!("{run}/{name}");
+        // The column types can be changed by another instance so are never read from the cache
+        let data = backend
+            .client
+            .uncached()
+            .metadata(path.clone(), headers.clone())
+            .await
+            .extend()?
+            .into_data();
+        let columns = match &*data.attributes {
+            NodeAttributes::Table(attrs) if attrs.specs.iter().any(|s| s.name == DERIVED_SPEC) => {
+                let types = attrs
+                    .metadata
+                    .get(COLUMN_TYPES)
+                    .cloned()
+                    .unwrap_or_default();
+                let types: HashMap<String, ColumnType> =
+                    serde_json::from_value(types).unwrap_or_default();
+                attrs
+                    .structure
+                    .columns
+                    .iter()
+                    .map(|col| types.get(col).map(|kind| (col.clone(), *kind)))
+                    .collect::<Option<Vec<_>>>()
+            }
+            _ => None,
+        };
+        let Some(columns) = columns else {
+            return Err(Error::new(format!("'{path}' is not a derived table"))
+                .extend_with(|_, ext| ext.set("code", "BAD_PATH")));
+        };
+        let added = row_count(&columns, &rows.0)?;
+        let mut table = backend
+            .client
+            .latest_table(&path, None, headers.clone())
+            .await
+            .extend()?;
+        let existing = table.values().map(Vec::len).max().unwrap_or_default();
+        let mut new_rows = rows.0;
+        for (column, _) in &columns {
+            let values = table.entry(column.clone()).or_default();
+            values.resize(existing, Value::Null);
+            match new_rows.remove(column) {
+                Some(new) => values.extend(new),
+                None => values.resize(existing + added, Value::Null),
+            }
+        }
+        let file = arrow::table_file(&columns, &table).map_err(|mut e| {
+            e.row = e.row.saturating_sub(existing);
+            bad_input(e.to_string())
+        })?;
+        let first = columns.first().map(|(name, _)| vec![name.clone()]);
+        let current = backend
+            .client
+            .latest_table(&path, first, headers.clone())
+            .await
+            .extend()?
+            .values()
+            .map(Vec::len)
+            .max()
+            .unwrap_or_default();
+        if current != existing {
+            return Err(Error::new(format!(
+                "Rows were added to '{path}' while appending (expected {existing}, found {current})"
+            ))
+            .extend_with(|_, ext| ext.set("code", "CONFLICT")));
+        }
+        backend
+            .client
+            .put_table(&path, file, headers)
+            .await
+            .extend()?;
+        Ok(DerivedTable {
+            path,
+            columns: columns.into_iter().map(|(n, _)| n).collect(),
+            rows: existing + added,
+        })
+    }
+}
+
+/// Search the revisions of a node for the one with the given number. Revisions can be deleted so
+/// the number is not necessarily the position of the revision in the list.
+async fn find_revision(
+    backend: &Backend,
+    id: &str,
+    number: usize,
+    headers: Option<axum::http::HeaderMap>,
+) -> ClientResult<Option<Revision>> {
+    let mut offset = 0;
+    loop {
+        let page = backend
+            .client
+            .revisions(id, offset, REVISION_PAGE, headers.clone())
+            .await?;
+        let count = page.data.len();
+        for revision in page.data.into_iter().map(|rev| rev.attributes) {
+            match revision.revision_number.cmp(&(number as i64)) {
+                Ordering::Less => {}
+                Ordering::Equal => return Ok(Some(revision)),
+                // Revisions are numbered in order so the rest are all later
+                Ordering::Greater => return Ok(None),
+            }
+        }
+        offset += count;
+        if count < REVISION_PAGE || offset >= page.meta.count {
+            return Ok(None);
+        }
+    }
+}
+
+/// The metadata of a run was changed after the revision that a change was based on, or after it
+/// was read if no revision was given
+#[derive(Debug)]
+pub struct RevisionConflict {
+    pub expected: Option<usize>,
+    pub current: usize,
+}
+
+impl fmt::Display for RevisionConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.expected {
+            Some(expected) => write!(
+                f,
+                "Metadata has changed since revision {expected} (current revision is {})",
+                self.current
+            ),
+            None => write!(
+                f,
+                "Metadata has changed since it was read (current revision is {})",
+                self.current
+            ),
+        }
+    }
+}
+
+impl ErrorExtensions for RevisionConflict {
+    fn extend(&self) -> Error {
+        Error::new(self.to_string()).extend_with(|_, ext| {
+            ext.set("code", "REVISION_CONFLICT");
+            if let Some(expected) = self.expected {
+                ext.set("expected", expected);
+            }
+            ext.set("current", self.current);
+        })
+    }
+}
+
+/// A run that is about to be changed, checked to be at the expected revision
+struct RunUpdate {
+    backend: Backend,
+    data: node::Data,
+    /// The metadata of the run as read from tiled. Patches only apply if it is unchanged.
+    metadata: Value,
+    revision: Option<usize>,
+}
+
+impl RunUpdate {
+    async fn new(
+        ctx: &Context<'_>,
+        id: &str,
+        backend: Option<&str>,
+        revision: Option<usize>,
+    ) -> Result<Self> {
+        let headers = auth_headers(ctx)?;
+        let backend = find_run(ctx, id, backend).await?;
+        // The metadata is read before the revision is checked so that any change made after the
+        // check fails the test added to the patch
+        let response = backend
+            .client
+            .latest_metadata(id, headers.clone())
+            .await
+            .extend()?;
+        let metadata = response["data"]["attributes"]["metadata"].clone();
+        let data = serde_json::from_value::<node::Metadata>(response)
+            .map_err(|e| Error::new(format!("Invalid response from tiled: {e}")))?
+            .into_data();
+        if let Some(expected) = revision {
+            let current = Self::current_revision(&backend, id, headers).await?;
+            if current != expected {
+                return Err(RevisionConflict {
+                    expected: Some(expected),
+                    current,
+                }
+                .extend());
+            }
+        }
+        Ok(Self {
+            backend,
+            data,
+            metadata,
+            revision,
+        })
+    }
+
+    async fn current_revision(
+        backend: &Backend,
+        id: &str,
+        headers: Option<axum::http::HeaderMap>,
+    ) -> Result<usize> {
+        Ok(backend
+            .client
+            .revisions(id, 0, 1, headers)
+            .await
+            .extend()?
+            .meta
+            .count)
+    }
+
+    /// Apply the patch built from the current metadata, returning the updated run. Nothing is
+    /// sent to tiled if the patch is empty. The patch is rejected with a REVISION_CONFLICT error
+    /// if the metadata has changed since it was read.
+    async fn apply(
+        self,
+        ctx: &Context<'_>,
+        patch: impl FnOnce(&RunMetadata) -> Vec<Value>,
+    ) -> Result<Run> {
+        let Self {
+            backend,
+            data,
+            metadata: read,
+            revision,
+        } = self;
+        let metadata = match &*data.attributes {
+            NodeAttributes::Container(attrs) => attrs.metadata.run(),
+            _ => None,
+        };
+        let Some(metadata) = metadata else {
+            return Err(Error::new(format!("'{}' is not a run", data.id))
+                .extend_with(|_, ext| ext.set("code", "BAD_PATH")));
+        };
+        let patch = patch(metadata);
+        if patch.is_empty() {
+            return Ok(Run { backend, data });
+        }
+        let headers = auth_headers(ctx)?;
+        let mut checked = vec![json!({"op": "test", "path": "", "value": read})];
+        checked.extend(patch);
+        let patched = backend
+            .client
+            .patch_metadata(&data.id, &Value::Array(checked), headers.clone())
+            .await;
+        if let Err(err) = patched {
+            // Tiled rejects the whole patch if a test fails, so check whether the metadata changed
+            // to tell a conflict apart from an invalid patch
+            if let ClientError::TiledRequest(400 | 409 | 422, _) = err {
+                let latest = backend
+                    .client
+                    .latest_metadata(&data.id, headers.clone())
+                    .await
+                    .extend()?;
+                if latest["data"]["attributes"]["metadata"] != read {
+                    let current = Self::current_revision(&backend, &data.id, headers).await?;
+                    return Err(RevisionConflict {
+                        expected: revision,
+                        current,
+                    }
+                    .extend());
+                }
+            }
+            return Err(err.extend());
+        }
+        let data = backend
+            .client
+            .metadata(data.id, headers)
+            .await
+            .extend()?
+            .into_data();
+        Ok(Run { backend, data })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_graphql::{EmptySubscription, Request, Schema, value};
+    use axum::http::HeaderValue;
+    use httpmock::MockServer;
+    use serde_json::{Value, json};
+
+    use super::TiledMutation;
+    use crate::TiledQuery;
+    use crate::backends::Backends;
+    use crate::clients::TiledClient;
+    use crate::handlers::AuthHeader;
+    use crate::test_utils::read_json;
+    use crate::userdata::UserData;
+
+    const RUN: &str = "5d8f5c3e-0e00-4c5c-816d-70b4b0f41498";
+
+    fn build_schema(server: &MockServer) -> Schema<TiledQuery, TiledMutation, EmptySubscription> {
+        Schema::build(TiledQuery, TiledMutation, EmptySubscription)
+            .data(Backends::single(TiledClient::for_mock_server(server)))
+            .data(UserData::default())
+            .finish()
+    }
+
+    async fn mock_whoami(server: &MockServer) {
+        server
+            .mock_async(|when, then| {
+                when.method("GET")
+                    .path("/api/v1/auth/whoami")
+                    .header("authorization", "Bearer abc");
+                then.status(200).json_body(json!({
+                    "uuid": "alice-uuid",
+                    "identities": [{"id": "alice", "provider": "toy"}],
+                }));
+            })
+            .await;
+    }
+
+    fn request(query: String) -> Request {
+        Request::new(query).data(Some(AuthHeader::from(HeaderValue::from_static(
+            "Bearer abc",
+        ))))
+    }
+
+    fn run_metadata(tags: &[&str]) -> Value {
+        let mut run: Value = read_json("resources/metadata_run.json");
+        run["data"]["attributes"]["metadata"]["tags"] = json!(tags);
+        run
+    }
+
+    /// The patch sent to tiled, checking that the metadata is unchanged before applying the ops
+    fn checked_patch(tags: &[&str], ops: Value) -> Value {
+        let read = run_metadata(tags)["data"]["attributes"]["metadata"].clone();
+        let mut patch = vec![json!({"op": "test", "path": "", "value": read})];
+        patch.extend(ops.as_array().unwrap().iter().cloned());
+        json!({"content-type": "application/json-patch+json", "metadata": patch})
+    }
+
+    async fn mock_run(server: &MockServer, tags: &[&str]) {
+        server
+            .mock_async(|when, then| {
+                when.method("GET").path(format!("/api/v1/metadata/{RUN}"));
+                then.status(200).json_body(run_metadata(tags));
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn add_tag() {
+        let server = MockServer::start();
+        mock_run(&server, &[]).await;
+        let patch = server
+            .mock_async(|when, then| {
+                when.method("PATCH")
+                    .path(format!("/api/v1/metadata/{RUN}"))
+                    .header("authorization", "Bearer abc")
+                    .json_body(checked_patch(
+                        &[],
+                        json!([{"op": "add", "path": "/tags", "value": ["good"]}]),
+                    ));
+                then.status(200).json_body(json!({"id": RUN}));
+            })
+            .await;
+        let response = build_schema(&server)
+            .execute(request(format!(
+                r#"mutation {{ addTag(id: "{RUN}", tag: "good") {{ id }} }}"#
+            )))
+            .await;
+        assert_eq!(response.errors, &[]);
+        assert_eq!(response.data, value!({"addTag": {"id": RUN}}));
+        patch.assert();
+    }
+
+    #[tokio::test]
+    async fn remove_tag() {
+        let server = MockServer::start();
+        mock_run(&server, &["good", "bad"]).await;
+        let patch = server
+            .mock_async(|when, then| {
+                when.method("PATCH")
+                    .path(format!("/api/v1/metadata/{RUN}"))
+                    .json_body(checked_patch(
+                        &["good", "bad"],
+                        json!([
+                            {"op": "test", "path": "/tags/1", "value": "bad"},
+                            {"op": "remove", "path": "/tags/1"},
+                        ]),
+                    ));
+                then.status(200).json_body(json!({"id": RUN}));
+            })
+            .await;
+        let schema = build_schema(&server);
+        let response = schema
+            .execute(request(format!(
+                r#"mutation {{ removeTag(id: "{RUN}", tag: "bad") {{ id }} }}"#
+            )))
+            .await;
+        assert_eq!(response.errors, &[]);
+        // Removing a tag the run doesn't have doesn't change anything
+        let response = schema
+            .execute(request(format!(
+                r#"mutation {{ removeTag(id: "{RUN}", tag: "missing") {{ id }} }}"#
+            )))
+            .await;
+        assert_eq!(response.errors, &[]);
+        patch.assert_calls(1);
+    }
+
+    #[tokio::test]
+    async fn revision_conflict() {
+        let server = MockServer::start();
+        mock_run(&server, &[]).await;
+        server
+            .mock_async(|when, then| {
+                when.method("GET").path(format!("/api/v1/revisions/{RUN}"));
+                then.status(200)
+                    .json_body(json!({"data": [], "meta": {"count": 3}}));
+            })
+            .await;
+        let patch = server
+            .mock_async(|when, then| {
+                when.method("PATCH");
+                then.status(200).json_body(json!({"id": RUN}));
+            })
+            .await;
+        let response = build_schema(&server)
+            .execute(request(format!(
+                r#"mutation {{ setNote(id: "{RUN}", note: "Beam dump", revision: 2) {{ id }} }}"#
+            )))
+            .await;
+        let extensions = response.errors[0].extensions.as_ref().unwrap();
+        assert_eq!(extensions.get("code"), Some(&value!("REVISION_CONFLICT")));
+        assert_eq!(extensions.get("expected"), Some(&value!(2)));
+        assert_eq!(extensions.get("current"), Some(&value!(3)));
+        patch.assert_calls(0);
+    }
+
+    #[tokio::test]
+    async fn concurrent_change_conflict() {
+        let server = MockServer::start();
+        let read = server
+            .mock_async(|when, then| {
+                when.method("GET").path(format!("/api/v1/metadata/{RUN}"));
+                then.status(200).json_body(run_metadata(&[]));
+            })
+            .await;
+        server
+            .mock_async(|when, then| {
+                when.method("GET").path(format!("/api/v1/revisions/{RUN}"));
+                then.status(200)
+                    .json_body(json!({"data": [], "meta": {"count": 4}}));
+            })
+            .await;
+        // Tiled rejects the patch as the test of the metadata fails
+        let patch = server
+            .mock_async(|when, then| {
+                when.method("PATCH");
+                then.status(422)
+                    .json_body(json!({"detail": "Test operation failed"}))
+                    .delay(std::time::Duration::from_millis(200));
+            })
+            .await;
+        let schema = build_schema(&server);
+        let query = format!(r#"mutation {{ addTag(id: "{RUN}", tag: "good") {{ id }} }}"#);
+        let change = async {
+            while patch.calls_async().await == 0 {
+                tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+            }
+            read.delete_async().await;
+            server
+                .mock_async(|when, then| {
+                    when.method("GET").path(format!("/api/v1/metadata/{RUN}"));
+                    then.status(200).json_body(run_metadata(&["other"]));
+                })
+                .await;
+        };
+        let (response, _) = tokio::join!(schema.execute(request(query)), change);
+        let extensions = response.errors[0].extensions.as_ref().unwrap();
+        assert_eq!(extensions.get("code"), Some(&value!("REVISION_CONFLICT")));
+        assert_eq!(extensions.get("expected"), None);
+        assert_eq!(extensions.get("current"), Some(&value!(4)));
+    }
+
+    #[tokio::test]
+    async fn invalid_patch_rejected() {
+        let server = MockServer::start();
+        mock_run(&server, &[]).await;
+        server
+            .mock_async(|when, then| {
+                when.method("PATCH");
+                then.status(422)
+                    .json_body(json!({"detail": "Invalid patch"}));
+            })
+            .await;
+        let response = build_schema(&server)
+            .execute(request(format!(
+                r#"mutation {{ updateRunMetadata(id: "{RUN}",
+                    patch: [{{op: "remove", path: "/missing"}}]) {{ id }} }}"#
+            )))
+            .await;
+        let extensions = response.errors[0].extensions.as_ref().unwrap();
+        assert_eq!(extensions.get("code"), Some(&value!("BAD_USER_INPUT")));
+        assert_eq!(extensions.get("detail"), Some(&value!("Invalid patch")));
+    }
+
+    #[tokio::test]
+    async fn create_derived_table() {
+        let server = MockServer::start();
+        mock_run(&server, &[]).await;
+        mock_whoami(&server).await;
+        let create = server
+            .mock_async(|when, then| {
+                when.method("POST")
+                    .path(format!("/api/v1/metadata/{RUN}"))
+                    .json_body_includes(r#"{"id": "peaks", "structure_family": "table"}"#)
+                    .json_body_includes(
+                        r#"{"specs": [{"name": "DerivedTable", "version": "1.0"}]}"#,
+                    )
+                    .json_body_includes(
+                        r#"{"metadata": {"column_types": {"x": "float", "label": "string"}}}"#,
+                    )
+                    // The creator is the user verified by tiled
+                    .json_body_includes(
+                        json!({"metadata": {"provenance": {"created_by": "alice"}}}).to_string(),
+                    );
+                then.status(200).json_body(json!({"id": "peaks"}));
+            })
+            .await;
+        let put = server
+            .mock_async(|when, then| {
+                when.method("PUT")
+                    .path(format!("/api/v1/table/full/{RUN}/peaks"))
+                    .header("content-type", "application/vnd.apache.arrow.file");
+                then.status(200);
+            })
+            .await;
+        let response = build_schema(&server)
+            .execute(request(format!(
+                r#"mutation {{ createDerivedTable(run: "{RUN}", name: "peaks",
+                    columns: [{{name: "x", type: FLOAT}}, {{name: "label", type: STRING}}],
+                    rows: {{x: [1.5, 2.5], label: ["a", null]}}) {{ path columns rows }} }}"#
+            )))
+            .await;
+        assert_eq!(response.errors, &[]);
+        assert_eq!(
+            response.data,
+            value!({"createDerivedTable": {
+                "path": format!("{RUN}/peaks"), "columns": ["x", "label"], "rows": 2
+            }})
+        );
+        create.assert();
+        put.assert();
+    }
+
+    #[tokio::test]
+    async fn derived_table_removed_if_rows_not_written() {
+        let server = MockServer::start();
+        mock_run(&server, &[]).await;
+        mock_whoami(&server).await;
+        server
+            .mock_async(|when, then| {
+                when.method("POST");
+                then.status(200).json_body(json!({"id": "peaks"}));
+            })
+            .await;
+        server
+            .mock_async(|when, then| {
+                when.method("PUT");
+                then.status(415)
+                    .json_body(json!({"detail": "Unsupported media type"}));
+            })
+            .await;
+        let delete = server
+            .mock_async(|when, then| {
+                when.method("DELETE")
+                    .path(format!("/api/v1/metadata/{RUN}/peaks"));
+                then.status(200).json_body(json!(null));
+            })
+            .await;
+        let response = build_schema(&server)
+            .execute(request(format!(
+                r#"mutation {{ createDerivedTable(run: "{RUN}", name: "peaks",
+                    columns: [{{name: "x", type: FLOAT}}]) {{ path }} }}"#
+            )))
+            .await;
+        assert_eq!(response.errors.len(), 1);
+        delete.assert();
+    }
+
+    #[tokio::test]
+    async fn derived_table_invalid_rows() {
+        let server = MockServer::start();
+        let create = server
+            .mock_async(|when, then| {
+                when.method("POST");
+                then.status(200).json_body(json!({"id": "peaks"}));
+            })
+            .await;
+        let schema = build_schema(&server);
+        for rows in [
+            r#"{x: [1], y: [2]}"#,
+            r#"{x: ["one"]}"#,
+            r#"{x: [1, 2], z: [1]}"#,
+        ] {
+            let response = schema
+                .execute(request(format!(
+                    r#"mutation {{ createDerivedTable(run: "{RUN}", name: "peaks",
+                        columns: [{{name: "x", type: INT}}, {{name: "z", type: INT}}],
+                        rows: {rows}) {{ path }} }}"#
+                )))
+                .await;
+            let extensions = response.errors[0].extensions.as_ref().unwrap();
+            assert_eq!(extensions.get("code"), Some(&value!("BAD_USER_INPUT")));
+        }
+        create.assert_calls(0);
+    }
+
+    #[tokio::test]
+    async fn append_rows() {
+        let server = MockServer::start();
+        mock_run(&server, &[]).await;
+        let mut table: Value = read_json("resources/metadata_table.json");
+        let attrs = &mut table["data"]["attributes"];
+        attrs["specs"] = json!([{"name": "DerivedTable", "version": "1.0"}]);
+        attrs["structure"]["columns"] = json!(["x", "label"]);
+        attrs["metadata"] = json!({"column_types": {"x": "float", "label": "string"}});
+        server
+            .mock_async(|when, then| {
+                when.method("GET")
+                    .path(format!("/api/v1/metadata/{RUN}/peaks"));
+                then.status(200).json_body(table);
+            })
+            .await;
+        server
+            .mock_async(|when, then| {
+                when.method("GET")
+                    .path(format!("/api/v1/table/full/{RUN}/peaks"))
+                    .query_param_missing("column");
+                then.status(200)
+                    .json_body(json!({"x": [1.5, 2.5], "label": ["a", "b"]}));
+            })
+            .await;
+        // The number of rows is checked again before the table is replaced
+        let count = server
+            .mock_async(|when, then| {
+                when.method("GET")
+                    .path(format!("/api/v1/table/full/{RUN}/peaks"))
+                    .query_param("column", "x");
+                then.status(200).json_body(json!({"x": [1.5, 2.5]}));
+            })
+            .await;
+        let put = server
+            .mock_async(|when, then| {
+                when.method("PUT")
+                    .path(format!("/api/v1/table/full/{RUN}/peaks"));
+                then.status(200);
+            })
+            .await;
+        let schema = build_schema(&server);
+        let query = format!(
+            r#"mutation {{ appendRows(run: "{RUN}", name: "peaks", rows: {{x: [3.5]}}) {{
+                columns rows }} }}"#
+        );
+        let response = schema.execute(request(query.clone())).await;
+        assert_eq!(response.errors, &[]);
+        assert_eq!(
+            response.data,
+            value!({"appendRows": {"columns": ["x", "label"], "rows": 3}})
+        );
+        put.assert();
+
+        // Rows appended by someone else after the table was read would be overwritten
+        count.delete_async().await;
+        server
+            .mock_async(|when, then| {
+                when.method("GET")
+                    .path(format!("/api/v1/table/full/{RUN}/peaks"))
+                    .query_param("column", "x");
+                then.status(200).json_body(json!({"x": [1.5, 2.5, 9.5]}));
+            })
+            .await;
+        let response = schema.execute(request(query)).await;
+        let extensions = response.errors[0].extensions.as_ref().unwrap();
+        assert_eq!(extensions.get("code"), Some(&value!("CONFLICT")));
+        put.assert_calls(1);
+    }
+
+    #[tokio::test]
+    async fn revert_run_metadata() {
+        let server = MockServer::start();
+        mock_run(&server, &["good"]).await;
+        let original = run_metadata(&[])["data"]["attributes"]["metadata"].clone();
+        server
+            .mock_async(|when, then| {
+                when.method("GET")
+                    .path(format!("/api/v1/revisions/{RUN}"))
+                    .query_param("page[offset]", "0");
+                // The first revision has been deleted
+                then.status(200).json_body(json!({
+                    "data": [{"attributes": {
+                        "revision_number": 2,
+                        "metadata": original,
+                        "time_updated": "2026-10-01T12:00:00",
+                    }}],
+                    "meta": {"count": 1},
+                }));
+            })
+            .await;
+        let patch = server
+            .mock_async(|when, then| {
+                when.method("PATCH")
+                    .path(format!("/api/v1/metadata/{RUN}"))
+                    .json_body(checked_patch(
+                        &["good"],
+                        json!([{"op": "replace", "path": "", "value": original}]),
+                    ));
+                then.status(200).json_body(json!({"id": RUN}));
+            })
+            .await;
+        let schema = build_schema(&server);
+        let response = schema
+            .execute(request(format!(
+                r#"mutation {{ revertRunMetadata(id: "{RUN}", revision: 2) {{ id }} }}"#
+            )))
+            .await;
+        assert_eq!(response.errors, &[]);
+        patch.assert();
+
+        let response = schema
+            .execute(request(format!(
+                r#"mutation {{ revertRunMetadata(id: "{RUN}", revision: 1) {{ id }} }}"#
+            )))
+            .await;
+        let extensions = response.errors[0].extensions.as_ref().unwrap();
+        assert_eq!(extensions.get("code"), Some(&value!("NOT_FOUND")));
+    }
+}
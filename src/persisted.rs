@@ -0,0 +1,349 @@
+//! Persisted queries, where clients send the hash of a query instead of its text. Queries are
+//! either remembered as clients send them (Apollo's automatic persisted queries) or read from a
+//! manifest that lists the only operations that may be run.
+
+use std::collections::{HashMap, HashSet};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::{fmt, fs, io};
+
+use async_graphql::extensions::{
+    Extension, ExtensionContext, ExtensionFactory, NextPrepareRequest,
+};
+use async_graphql::parser::Pos;
+use async_graphql::{Error, ErrorExtensions as _, Request, ServerResult};
+use lru::LruCache;
+use serde::Deserialize;
+use sha2::{Digest as _, Sha256};
+
+use crate::config::PersistedQueryConfig;
+
+/// Request extension used by clients to send the hash of a query
+const PERSISTED_QUERY: &str = "persistedQuery";
+
+#[derive(Debug)]
+pub enum ManifestError {
+    Read(PathBuf, io::Error),
+    Invalid(PathBuf, serde_json::Error),
+}
+
+impl fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ManifestError::Read(path, err) => {
+                write!(f, "Unable to read persisted query manifest {path:?}: {err}")
+            }
+            ManifestError::Invalid(path, err) => {
+                write!(f, "Invalid persisted query manifest {path:?}: {err}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ManifestError {}
+
+/// Apollo persisted query manifest, as generated by `@apollo/generate-persisted-query-manifest`
+#[derive(Debug, Deserialize)]
+struct Manifest {
+    operations: Vec<Operation>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Operation {
+    id: String,
+    body: String,
+}
+
+/// The operations listed in a manifest, by ID and by the hash of their text so that clients
+/// sending a listed query in full are not rejected
+#[derive(Debug, Default)]
+struct AllowList {
+    operations: HashMap<String, String>,
+    hashes: HashSet<String>,
+}
+
+impl AllowList {
+    fn read(path: &Path) -> Result<Self, ManifestError> {
+        let manifest =
+            fs::read_to_string(path).map_err(|e| ManifestError::Read(path.to_owned(), e))?;
+        let manifest: Manifest = serde_json::from_str(&manifest)
+            .map_err(|e| ManifestError::Invalid(path.to_owned(), e))?;
+        Ok(manifest.operations.into_iter().collect())
+    }
+}
+
+impl FromIterator<Operation> for AllowList {
+    fn from_iter<T: IntoIterator<Item = Operation>>(operations: T) -> Self {
+        let mut list = Self::default();
+        for op in operations {
+            list.hashes.insert(sha256(&op.body));
+            list.operations.insert(op.id, op.body);
+        }
+        list
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PersistedQuery {
+    version: i32,
+    sha256_hash: String,
+}
+
+fn sha256(query: &str) -> String {
+    Sha256::digest(query.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+fn persisted_query_error(code: &'static str, message: &str) -> Error {
+    Error::new(message).extend_with(|_, ext| ext.set("code", code))
+}
+
+fn not_in_list() -> Error {
+    persisted_query_error("PERSISTED_QUERY_NOT_IN_LIST", "PersistedQueryNotInList")
+}
+
+/// Schema extension replacing the hashes of persisted queries with the queries themselves
+/// before they are parsed, and rejecting queries missing from the allow list if there is one
+#[derive(Clone)]
+pub struct PersistedQueries(Arc<Queries>);
+
+struct Queries {
+    /// Queries sent by clients along with their hashes, if automatic persisted queries are
+    /// enabled
+    automatic: Option<Mutex<LruCache<String, String>>>,
+    allow_list: Option<AllowList>,
+}
+
+impl PersistedQueries {
+    pub fn new(config: &PersistedQueryConfig) -> Result<Self, ManifestError> {
+        let allow_list = config
+            .allow_list
+            .as_deref()
+            .map(AllowList::read)
+            .transpose()?;
+        Ok(Self::with_allow_list(config, allow_list))
+    }
+
+    fn with_allow_list(config: &PersistedQueryConfig, allow_list: Option<AllowList>) -> Self {
+        // Only listed queries can be run so there is nothing to gain from remembering others
+        let automatic = (config.automatic && allow_list.is_none()).then(|| {
+            let capacity = NonZeroUsize::new(config.cache_size).unwrap_or(NonZeroUsize::MIN);
+            Mutex::new(LruCache::new(capacity))
+        });
+        Self(Arc::new(Queries {
+            automatic,
+            allow_list,
+        }))
+    }
+
+    /// Fill in the query of a request that only has a hash, and check that the query can be
+    /// run. Error messages follow Apollo's so that its clients know to resend the full query.
+    fn resolve(&self, request: &mut Request) -> Result<(), Error> {
+        let Some(persisted) = request.extensions.remove(PERSISTED_QUERY) else {
+            return self.check_allowed(&request.query);
+        };
+        let persisted = persisted
+            .into_json()
+            .and_then(serde_json::from_value::<PersistedQuery>)
+            .map_err(|e| Error::new(format!("Invalid persistedQuery extension: {e}")))?;
+        if persisted.version != 1 {
+            return Err(Error::new(format!(
+                "Unsupported persistedQuery version {}",
+                persisted.version
+            )));
+        }
+        if request.query.is_empty() {
+            // Remembered queries were checked when they were first sent
+            request.query = self.lookup(&persisted.sha256_hash)?;
+            return Ok(());
+        }
+        if sha256(&request.query) != persisted.sha256_hash {
+            return Err(persisted_query_error(
+                "PERSISTED_QUERY_HASH_MISMATCH",
+                "provided sha does not match query",
+            ));
+        }
+        self.check_allowed(&request.query)?;
+        if let Some(cache) = &self.0.automatic {
+            cache
+                .lock()
+                .expect("Persisted query lock poisoned")
+                .put(persisted.sha256_hash, request.query.clone());
+        }
+        Ok(())
+    }
+
+    fn lookup(&self, hash: &str) -> Result<String, Error> {
+        match (&self.0.allow_list, &self.0.automatic) {
+            (Some(list), _) => list.operations.get(hash).cloned().ok_or_else(not_in_list),
+            (None, Some(cache)) => cache
+                .lock()
+                .expect("Persisted query lock poisoned")
+                .get(hash)
+                .cloned()
+                .ok_or_else(|| {
+                    persisted_query_error("PERSISTED_QUERY_NOT_FOUND", "PersistedQueryNotFound")
+                }),
+            (None, None) => Err(persisted_query_error(
+                "PERSISTED_QUERY_NOT_SUPPORTED",
+                "PersistedQueryNotSupported",
+            )),
+        }
+    }
+
+    fn check_allowed(&self, query: &str) -> Result<(), Error> {
+        match &self.0.allow_list {
+            Some(list) if !list.hashes.contains(&sha256(query)) => Err(not_in_list()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl ExtensionFactory for PersistedQueries {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(self.clone())
+    }
+}
+
+#[async_trait::async_trait]
+impl Extension for PersistedQueries {
+    async fn prepare_request(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        mut request: Request,
+        next: NextPrepareRequest<'_>,
+    ) -> ServerResult<Request> {
+        self.resolve(&mut request)
+            .map_err(|e| e.into_server_error(Pos::default()))?;
+        next.run(ctx, request).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write as _;
+
+    use async_graphql::{EmptyMutation, EmptySubscription, Request, Response, Schema, value};
+
+    use super::{AllowList, ManifestError, Operation, PersistedQueries, sha256};
+    use crate::TiledQuery;
+    use crate::config::PersistedQueryConfig;
+
+    const QUERY: &str = "{ __typename }";
+
+    fn schema(queries: PersistedQueries) -> Schema<TiledQuery, EmptyMutation, EmptySubscription> {
+        Schema::build(TiledQuery, EmptyMutation, EmptySubscription)
+            .extension(queries)
+            .finish()
+    }
+
+    fn persisted(query: &str, hash: &str) -> Request {
+        let mut request = Request::new(query);
+        request.extensions.insert(
+            "persistedQuery".into(),
+            value!({"version": 1, "sha256Hash": hash}),
+        );
+        request
+    }
+
+    fn error_code(response: &Response) -> String {
+        let extensions = response.errors[0].extensions.as_ref().unwrap();
+        extensions.get("code").unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn automatic_persisted_query() {
+        let schema = schema(PersistedQueries::with_allow_list(
+            &PersistedQueryConfig::default(),
+            None,
+        ));
+        let hash = sha256(QUERY);
+        let response = schema.execute(persisted("", &hash)).await;
+        assert_eq!(response.errors[0].message, "PersistedQueryNotFound");
+        assert_eq!(error_code(&response), r#""PERSISTED_QUERY_NOT_FOUND""#);
+
+        let response = schema.execute(persisted(QUERY, &hash)).await;
+        assert_eq!(response.data, value!({"__typename": "TiledQuery"}));
+        let response = schema.execute(persisted("", &hash)).await;
+        assert_eq!(response.errors, &[]);
+        assert_eq!(response.data, value!({"__typename": "TiledQuery"}));
+
+        let response = schema.execute(persisted(QUERY, "abc")).await;
+        assert_eq!(error_code(&response), r#""PERSISTED_QUERY_HASH_MISMATCH""#);
+    }
+
+    #[tokio::test]
+    async fn automatic_persisted_queries_disabled() {
+        let config = PersistedQueryConfig {
+            automatic: false,
+            ..PersistedQueryConfig::default()
+        };
+        let schema = schema(PersistedQueries::with_allow_list(&config, None));
+        let hash = sha256(QUERY);
+        schema.execute(persisted(QUERY, &hash)).await;
+        let response = schema.execute(persisted("", &hash)).await;
+        assert_eq!(error_code(&response), r#""PERSISTED_QUERY_NOT_SUPPORTED""#);
+    }
+
+    #[tokio::test]
+    async fn allow_list() {
+        let list = [Operation {
+            id: "typename".into(),
+            body: QUERY.into(),
+        }]
+        .into_iter()
+        .collect::<AllowList>();
+        let schema = schema(PersistedQueries::with_allow_list(
+            &PersistedQueryConfig::default(),
+            Some(list),
+        ));
+        for request in [persisted("", "typename"), Request::new(QUERY)] {
+            let response = schema.execute(request).await;
+            assert_eq!(response.errors, &[]);
+            assert_eq!(response.data, value!({"__typename": "TiledQuery"}));
+        }
+        let other = "{ __schema { queryType { name } } }";
+        for request in [
+            persisted("", "other"),
+            persisted(other, &sha256(other)),
+            Request::new(other),
+        ] {
+            let response = schema.execute(request).await;
+            assert_eq!(response.errors[0].message, "PersistedQueryNotInList");
+            assert_eq!(error_code(&response), r#""PERSISTED_QUERY_NOT_IN_LIST""#);
+        }
+    }
+
+    #[test]
+    fn read_manifest() {
+        let mut manifest = tempfile::NamedTempFile::new().unwrap();
+        write!(
+            manifest,
+            r#"{{
+                "format": "apollo-persisted-query-manifest",
+                "version": 1,
+                "operations": [
+                    {{"id": "abc", "name": "Typename", "type": "query", "body": "{QUERY}"}}
+                ]
+            }}"#
+        )
+        .unwrap();
+        let list = AllowList::read(manifest.path()).unwrap();
+        assert_eq!(list.operations["abc"], QUERY);
+        assert!(list.hashes.contains(&sha256(QUERY)));
+
+        write!(manifest, "not json").unwrap();
+        assert!(matches!(
+            AllowList::read(manifest.path()),
+            Err(ManifestError::Invalid(..))
+        ));
+        assert!(matches!(
+            AllowList::read("/missing/manifest.json".as_ref()),
+            Err(ManifestError::Read(..))
+        ));
+    }
+}
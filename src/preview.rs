@@ -0,0 +1,610 @@
+use std::fmt;
+
+use async_graphql::ErrorExtensions as _;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tracing::error;
+
+use crate::clients::{ClientError, TiledClient};
+use crate::config::PreviewConfig;
+use crate::model::node::NodeAttributes;
+
+const DEFAULT_SIZE: u32 = 256;
+const MAX_SIZE: u32 = 2048;
+
+#[derive(Debug, Deserialize)]
+pub struct PreviewParams {
+    /// Index of the frame to render for 3-D arrays. Ignored for 2-D arrays.
+    #[serde(default)]
+    pub frame: usize,
+    /// Size of the longest side of the generated image in pixels
+    pub size: Option<u32>,
+    #[serde(default)]
+    pub colormap: Colormap,
+    #[serde(default)]
+    pub scale: Scale,
+    /// Percentile below which all values are rendered as the lowest colour
+    #[serde(default = "default_low")]
+    pub low: f64,
+    /// Percentile above which all values are rendered as the highest colour
+    #[serde(default = "default_high")]
+    pub high: f64,
+}
+
+fn default_low() -> f64 {
+    1.0
+}
+fn default_high() -> f64 {
+    99.0
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Colormap {
+    #[default]
+    Gray,
+    Viridis,
+    Magma,
+    Inferno,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scale {
+    #[default]
+    Linear,
+    Log,
+}
+
+#[derive(Debug)]
+pub enum PreviewError {
+    Client(ClientError),
+    NotAnArray,
+    UnsupportedShape(Vec<usize>),
+    FrameOutOfRange(usize, usize),
+    /// The frame has more pixels than the configured limit
+    TooLarge(usize, usize),
+    InvalidData(String),
+    InvalidParameters(String),
+    Encoding(png::EncodingError),
+}
+
+impl From<ClientError> for PreviewError {
+    fn from(err: ClientError) -> Self {
+        Self::Client(err)
+    }
+}
+impl From<png::EncodingError> for PreviewError {
+    fn from(err: png::EncodingError) -> Self {
+        Self::Encoding(err)
+    }
+}
+
+impl fmt::Display for PreviewError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PreviewError::Client(err) => write!(f, "{err}"),
+            PreviewError::NotAnArray => write!(f, "Previews are only available for arrays"),
+            PreviewError::UnsupportedShape(shape) => {
+                write!(
+                    f,
+                    "Previews require a 2 or 3 dimensional array, not {shape:?}"
+                )
+            }
+            PreviewError::FrameOutOfRange(frame, frames) => {
+                write!(
+                    f,
+                    "Frame {frame} is out of range for array with {frames} frames"
+                )
+            }
+            PreviewError::TooLarge(pixels, max) => write!(
+                f,
+                "Frames of {pixels} pixels are too large to preview (the limit is {max})"
+            ),
+            PreviewError::InvalidData(msg) => write!(f, "Invalid array data: {msg}"),
+            PreviewError::InvalidParameters(msg) => write!(f, "Invalid parameters: {msg}"),
+            PreviewError::Encoding(err) => write!(f, "Unable to encode image: {err}"),
+        }
+    }
+}
+
+impl IntoResponse for PreviewError {
+    fn into_response(self) -> Response {
+        let status = match &self {
+            PreviewError::Client(ClientError::TiledRequest(status, _)) => {
+                StatusCode::from_u16(*status).unwrap_or(StatusCode::BAD_REQUEST)
+            }
+            PreviewError::Client(_) | PreviewError::InvalidData(_) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            PreviewError::NotAnArray
+            | PreviewError::UnsupportedShape(_)
+            | PreviewError::FrameOutOfRange(_, _)
+            | PreviewError::TooLarge(_, _)
+            | PreviewError::InvalidParameters(_) => StatusCode::BAD_REQUEST,
+            PreviewError::Encoding(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        if status.is_server_error() {
+            error!("Error generating preview: {self}");
+        }
+        // Errors from tiled are reported the same way as in the GraphQL API so that responses
+        // from tiled are never passed on verbatim
+        let detail = match &self {
+            PreviewError::Client(err) => err.extend().message,
+            _ => self.to_string(),
+        };
+        (status, json!({"detail": detail}).to_string()).into_response()
+    }
+}
+
+/// A single 2-D frame of array data in row-major order
+#[derive(Debug, PartialEq)]
+struct Frame {
+    width: usize,
+    height: usize,
+    values: Vec<f64>,
+}
+
+/// Fetch a single frame of the array at `path` and render it as a PNG image
+pub async fn render_preview(
+    client: &TiledClient,
+    path: &str,
+    params: &PreviewParams,
+    config: &PreviewConfig,
+    headers: Option<HeaderMap>,
+) -> Result<Vec<u8>, PreviewError> {
+    if !(0.0..=100.0).contains(&params.low)
+        || !(0.0..=100.0).contains(&params.high)
+        || params.low >= params.high
+    {
+        return Err(PreviewError::InvalidParameters(
+            "percentiles must satisfy 0 <= low < high <= 100".into(),
+        ));
+    }
+    let frame = fetch_frame(client, path, params.frame, config.max_pixels, headers).await?;
+    let size = params.size.unwrap_or(DEFAULT_SIZE).clamp(1, MAX_SIZE) as usize;
+    let frame = frame.downsample(size);
+    let pixels = frame.colorize(params);
+    encode_png(frame.width as u32, frame.height as u32, &pixels)
+}
+
+async fn fetch_frame(
+    client: &TiledClient,
+    path: &str,
+    frame: usize,
+    max_pixels: usize,
+    headers: Option<HeaderMap>,
+) -> Result<Frame, PreviewError> {
+    let NodeAttributes::Array(attrs) = *client
+        .metadata(path.into(), headers.clone())
+        .await?
+        .into_data()
+        .attributes
+    else {
+        return Err(PreviewError::NotAnArray);
+    };
+    let shape = attrs
+        .structure
+        .dimensions()
+        .ok_or_else(|| PreviewError::InvalidData("shape is not a list of sizes".into()))?;
+    let chunks = attrs
+        .structure
+        .chunk_sizes()
+        .ok_or_else(|| PreviewError::InvalidData("chunks are not a list of sizes".into()))?;
+
+    // For 3-D arrays, find the block containing the requested frame and the offset into it
+    let (frame_block, chunks) = match (shape.as_slice(), chunks.as_slice()) {
+        ([_, _], [_, _]) => (None, chunks.as_slice()),
+        ([frames, _, _], [frame_chunks, rest @ ..]) => {
+            if frame >= *frames {
+                return Err(PreviewError::FrameOutOfRange(frame, *frames));
+            }
+            (Some(locate(frame_chunks, frame)), rest)
+        }
+        _ => return Err(PreviewError::UnsupportedShape(shape)),
+    };
+    let [row_chunks, col_chunks] = chunks else {
+        return Err(PreviewError::InvalidData(
+            "chunks do not match shape".into(),
+        ));
+    };
+    let height = row_chunks.iter().sum::<usize>();
+    let width = col_chunks.iter().sum::<usize>();
+    // Checked before anything is read so that huge frames are never held in memory
+    let pixels = width.saturating_mul(height);
+    if pixels > max_pixels {
+        return Err(PreviewError::TooLarge(pixels, max_pixels));
+    }
+    let mut values = vec![0.0; width * height];
+
+    let mut row_offset = 0;
+    for (j, rows) in row_chunks.iter().enumerate() {
+        let mut col_offset = 0;
+        for (k, cols) in col_chunks.iter().enumerate() {
+            let (block, slice) = match frame_block {
+                Some((b, offset)) => (vec![b, j, k], Some(offset.to_string())),
+                None => (vec![j, k], None),
+            };
+            let data = client
+                .array_block(path, &block, slice, headers.clone())
+                .await?;
+            let block = Frame::from_json(&data)?;
+            if block.height != *rows || block.width != *cols {
+                return Err(PreviewError::InvalidData(format!(
+                    "block {j},{k} has shape [{}, {}], expected [{rows}, {cols}]",
+                    block.height, block.width
+                )));
+            }
+            for (r, row) in block.values.chunks(*cols).enumerate() {
+                let start = (row_offset + r) * width + col_offset;
+                values[start..start + cols].copy_from_slice(row);
+            }
+            col_offset += cols;
+        }
+        row_offset += rows;
+    }
+    Ok(Frame {
+        width,
+        height,
+        values,
+    })
+}
+
+/// Find the index of the chunk containing `index` and the offset of `index` within that chunk
+fn locate(chunks: &[usize], mut index: usize) -> (usize, usize) {
+    for (i, size) in chunks.iter().enumerate() {
+        if index < *size {
+            return (i, index);
+        }
+        index -= size;
+    }
+    (chunks.len(), index)
+}
+
+impl Frame {
+    fn from_json(data: &Value) -> Result<Self, PreviewError> {
+        let rows = data
+            .as_array()
+            .ok_or_else(|| PreviewError::InvalidData("expected a list of rows".into()))?;
+        let width = rows.first().and_then(Value::as_array).map_or(0, Vec::len);
+        let mut values = Vec::with_capacity(width * rows.len());
+        for row in rows {
+            let row = row.as_array().filter(|r| r.len() == width).ok_or_else(|| {
+                PreviewError::InvalidData("rows are not all the same length".into())
+            })?;
+            for value in row {
+                // Booleans are valid array data and NaN is sent as null
+                values.push(match value {
+                    Value::Number(n) => n.as_f64().unwrap_or(f64::NAN),
+                    Value::Bool(b) => f64::from(u8::from(*b)),
+                    _ => f64::NAN,
+                });
+            }
+        }
+        Ok(Self {
+            width,
+            height: rows.len(),
+            values,
+        })
+    }
+
+    /// Shrink the frame so that its longest side is at most `size` by averaging the pixels that
+    /// map to each output pixel. Frames that are already small enough are left unchanged.
+    fn downsample(self, size: usize) -> Self {
+        let longest = self.width.max(self.height);
+        if longest <= size {
+            return self;
+        }
+        let width = (self.width * size / longest).max(1);
+        let height = (self.height * size / longest).max(1);
+        let mut values = Vec::with_capacity(width * height);
+        for y in 0..height {
+            let (y0, y1) = (y * self.height / height, (y + 1) * self.height / height);
+            for x in 0..width {
+                let (x0, x1) = (x * self.width / width, (x + 1) * self.width / width);
+                let (mut total, mut count) = (0.0, 0);
+                for row in y0..y1 {
+                    for v in &self.values[row * self.width + x0..row * self.width + x1] {
+                        if v.is_finite() {
+                            total += v;
+                            count += 1;
+                        }
+                    }
+                }
+                values.push(if count > 0 {
+                    total / count as f64
+                } else {
+                    f64::NAN
+                });
+            }
+        }
+        Self {
+            width,
+            height,
+            values,
+        }
+    }
+
+    /// Map each value to an RGB pixel using the colormap, scaling and clipping requested
+    fn colorize(&self, params: &PreviewParams) -> Vec<u8> {
+        let (low, high) = percentiles(&self.values, params.low, params.high);
+        let norm = |v: f64| {
+            let v = (v.clamp(low, high) - low) / (high - low);
+            match params.scale {
+                Scale::Linear => v,
+                // log scale over the clipped range, mapping [0, 1] onto [0, 1]
+                Scale::Log => (1.0 + 1000.0 * v).log10() / 1001f64.log10(),
+            }
+        };
+        self.values
+            .iter()
+            .flat_map(|&v| {
+                if !v.is_finite() {
+                    [0, 0, 0]
+                } else if high > low {
+                    params.colormap.color(norm(v))
+                } else {
+                    params.colormap.color(0.0)
+                }
+            })
+            .collect()
+    }
+}
+
+/// Find the values at the given percentiles of the finite values in `values`
+fn percentiles(values: &[f64], low: f64, high: f64) -> (f64, f64) {
+    let mut finite = values
+        .iter()
+        .copied()
+        .filter(|v| v.is_finite())
+        .collect::<Vec<_>>();
+    if finite.is_empty() {
+        return (0.0, 0.0);
+    }
+    finite.sort_unstable_by(f64::total_cmp);
+    let at = |p: f64| finite[((finite.len() - 1) as f64 * p / 100.0).round() as usize];
+    (at(low), at(high))
+}
+
+impl Colormap {
+    fn color(&self, v: f64) -> [u8; 3] {
+        let stops: &[u32] = match self {
+            Colormap::Gray => &[0x000000, 0xffffff],
+            Colormap::Viridis => &[
+                0x440154, 0x482878, 0x3e4a89, 0x31688e, 0x26828e, 0x1f9e89, 0x35b779, 0x6dcd59,
+                0xb4de2c, 0xfde725,
+            ],
+            Colormap::Magma => &[
+                0x000004, 0x180f3e, 0x451077, 0x721f81, 0x9f2f7f, 0xcd4071, 0xf1605d, 0xfd9567,
+                0xfec98d, 0xfcfdbf,
+            ],
+            Colormap::Inferno => &[
+                0x000004, 0x1b0c42, 0x4b0c6b, 0x781c6d, 0xa52c60, 0xcf4446, 0xed6925, 0xfb9a06,
+                0xf7d03c, 0xfcffa4,
+            ],
+        };
+        let pos = v.clamp(0.0, 1.0) * (stops.len() - 1) as f64;
+        let i = (pos.floor() as usize).min(stops.len() - 2);
+        let t = pos - i as f64;
+        let channel = |shift: u32| {
+            let a = f64::from((stops[i] >> shift) & 0xff);
+            let b = f64::from((stops[i + 1] >> shift) & 0xff);
+            (a + (b - a) * t).round() as u8
+        };
+        [channel(16), channel(8), channel(0)]
+    }
+}
+
+fn encode_png(width: u32, height: u32, pixels: &[u8]) -> Result<Vec<u8>, PreviewError> {
+    let mut image = Vec::new();
+    let mut encoder = png::Encoder::new(&mut image, width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(pixels)?;
+    writer.finish()?;
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::to_bytes;
+    use axum::extract::Query;
+    use axum::http::StatusCode;
+    use axum::response::IntoResponse as _;
+    use httpmock::MockServer;
+    use serde_json::{Value, json};
+
+    use super::{Colormap, Frame, PreviewError, PreviewParams, Scale, locate, render_preview};
+    use crate::clients::{ClientError, TiledClient};
+    use crate::config::PreviewConfig;
+
+    fn params(query: &str) -> PreviewParams {
+        let uri = format!("/preview?{query}").parse().unwrap();
+        Query::try_from_uri(&uri).unwrap().0
+    }
+
+    #[test]
+    fn default_params() {
+        let params = params("");
+        assert_eq!(params.frame, 0);
+        assert_eq!(params.size, None);
+        assert_eq!(params.colormap, Colormap::Gray);
+        assert_eq!(params.scale, Scale::Linear);
+        assert_eq!((params.low, params.high), (1.0, 99.0));
+    }
+
+    #[test]
+    fn locate_frame_in_chunks() {
+        assert_eq!(locate(&[1, 1, 1], 2), (2, 0));
+        assert_eq!(locate(&[4, 4, 2], 5), (1, 1));
+        assert_eq!(locate(&[10], 9), (0, 9));
+    }
+
+    #[test]
+    fn colormap_endpoints() {
+        assert_eq!(Colormap::Gray.color(0.0), [0, 0, 0]);
+        assert_eq!(Colormap::Gray.color(1.0), [255, 255, 255]);
+        assert_eq!(Colormap::Gray.color(0.5), [128, 128, 128]);
+        assert_eq!(Colormap::Viridis.color(0.0), [0x44, 0x01, 0x54]);
+        assert_eq!(Colormap::Viridis.color(1.0), [0xfd, 0xe7, 0x25]);
+    }
+
+    #[test]
+    fn downsample_averages() {
+        let frame = Frame {
+            width: 4,
+            height: 2,
+            values: vec![0.0, 2.0, 4.0, 6.0, 2.0, 4.0, 6.0, 8.0],
+        };
+        assert_eq!(
+            frame.downsample(2),
+            Frame {
+                width: 2,
+                height: 1,
+                values: vec![2.0, 6.0]
+            }
+        );
+    }
+
+    #[test]
+    fn colorize_clips_percentiles() {
+        let frame = Frame {
+            width: 5,
+            height: 1,
+            values: vec![-1000.0, 0.0, 5.0, 10.0, 1000.0],
+        };
+        let pixels = frame.colorize(&params("low=25&high=75"));
+        let grays = pixels.chunks(3).map(|p| p[0]).collect::<Vec<_>>();
+        assert_eq!(grays, [0, 0, 128, 255, 255]);
+    }
+
+    #[test]
+    fn frame_from_json() {
+        let frame = Frame::from_json(&json!([[1, 2.5], [true, null]])).unwrap();
+        assert_eq!((frame.width, frame.height), (2, 2));
+        assert_eq!(frame.values[..3], [1.0, 2.5, 1.0]);
+        assert!(frame.values[3].is_nan());
+        assert!(matches!(
+            Frame::from_json(&json!([[1, 2], [3]])),
+            Err(PreviewError::InvalidData(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn render_frame_from_blocks() {
+        let server = MockServer::start();
+        let metadata = server
+            .mock_async(|when, then| {
+                when.method("GET")
+                    .path("/api/v1/metadata/4866611f-e6d9-4517-bedf-fc5526df57ad/primary/det");
+                then.status(200)
+                    .body_from_file("resources/metadata_array.json");
+            })
+            .await;
+        let block = server
+            .mock_async(|when, then| {
+                when.method("GET")
+                    .path("/api/v1/array/block/4866611f-e6d9-4517-bedf-fc5526df57ad/primary/det")
+                    .query_param("block", "3,0,0")
+                    .query_param("slice", "0")
+                    .header("accept", "application/json");
+                let rows = (0..1024).map(|r| vec![r; 1024]).collect::<Vec<_>>();
+                then.status(200).json_body(json!(rows));
+            })
+            .await;
+        let client = TiledClient::for_mock_server(&server);
+        let png = render_preview(
+            &client,
+            "4866611f-e6d9-4517-bedf-fc5526df57ad/primary/det",
+            &params("frame=3&size=64&colormap=viridis&scale=log"),
+            &PreviewConfig::default(),
+            None,
+        )
+        .await
+        .unwrap();
+
+        let decoder = png::Decoder::new(std::io::Cursor::new(png));
+        let reader = decoder.read_info().unwrap();
+        let info = reader.info();
+        assert_eq!((info.width, info.height), (64, 64));
+        metadata.assert();
+        block.assert();
+    }
+
+    #[tokio::test]
+    async fn frame_out_of_range() {
+        let server = MockServer::start();
+        server
+            .mock_async(|when, then| {
+                when.method("GET")
+                    .path("/api/v1/metadata/4866611f-e6d9-4517-bedf-fc5526df57ad/primary/det");
+                then.status(200)
+                    .body_from_file("resources/metadata_array.json");
+            })
+            .await;
+        let client = TiledClient::for_mock_server(&server);
+        let result = render_preview(
+            &client,
+            "4866611f-e6d9-4517-bedf-fc5526df57ad/primary/det",
+            &params("frame=5"),
+            &PreviewConfig::default(),
+            None,
+        )
+        .await;
+        assert!(matches!(result, Err(PreviewError::FrameOutOfRange(5, 5))));
+    }
+
+    #[tokio::test]
+    async fn large_frames_rejected() {
+        let server = MockServer::start();
+        server
+            .mock_async(|when, then| {
+                when.method("GET")
+                    .path("/api/v1/metadata/4866611f-e6d9-4517-bedf-fc5526df57ad/primary/det");
+                then.status(200)
+                    .body_from_file("resources/metadata_array.json");
+            })
+            .await;
+        let block = server
+            .mock_async(|when, then| {
+                when.method("GET").path_includes("/array/block/");
+                then.status(200).json_body(json!([[0]]));
+            })
+            .await;
+        let client = TiledClient::for_mock_server(&server);
+        let result = render_preview(
+            &client,
+            "4866611f-e6d9-4517-bedf-fc5526df57ad/primary/det",
+            &params("frame=0"),
+            &PreviewConfig { max_pixels: 1000 },
+            None,
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(PreviewError::TooLarge(1_048_576, 1000))
+        ));
+        block.assert_calls(0);
+    }
+
+    #[tokio::test]
+    async fn tiled_response_not_passed_on() {
+        let body = r#"{"detail": "Not allowed"}"#;
+        let err = PreviewError::Client(ClientError::TiledRequest(403, body.into()));
+        let response = err.into_response();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let detail: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(detail["detail"], "Not authorised: Not allowed");
+
+        let err = PreviewError::Client(ClientError::TiledInternal(500, "Traceback...".into()));
+        let body = to_bytes(err.into_response().into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let detail: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(detail["detail"], "Internal tiled error");
+    }
+}
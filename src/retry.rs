@@ -0,0 +1,238 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::time::Instant;
+use tracing::warn;
+
+use crate::config::{CircuitBreakerConfig, RetryConfig};
+
+impl RetryConfig {
+    /// Time to wait before making the given retry (starting from 0)
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let base = self
+            .initial_backoff
+            .saturating_mul(2u64.saturating_pow(retry))
+            .min(self.max_backoff);
+        // Jitter spreads out the retries from many clients that failed at the same time
+        let jitter = fastrand::u64(0..=base / 2);
+        Duration::from_millis(base - jitter)
+    }
+}
+
+/// Whether a request should be retried based on the way it failed. Requests that timed out are
+/// not retried as they would be likely to tie up the caller for just as long again.
+pub fn is_transient(result: &reqwest::Result<reqwest::Response>) -> bool {
+    match result {
+        Ok(response) => matches!(response.status().as_u16(), 502..=504),
+        Err(err) => err.is_connect() || (err.is_request() && !err.is_timeout()),
+    }
+}
+
+/// Whether a request failed in a way that suggests tiled is unavailable
+pub fn is_failure(result: &reqwest::Result<reqwest::Response>) -> bool {
+    is_transient(result) || result.as_ref().is_err_and(reqwest::Error::is_timeout)
+}
+
+/// Tracks failures of requests to tiled so that requests can be rejected immediately while it is
+/// unavailable instead of every query waiting for its own requests to fail.
+#[derive(Clone)]
+pub struct CircuitBreaker(Arc<BreakerState>);
+
+struct BreakerState {
+    config: CircuitBreakerConfig,
+    circuit: Mutex<Circuit>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Circuit {
+    /// Requests are allowed through
+    Closed { failures: u32 },
+    /// Requests are rejected until the given time
+    Open { until: Instant },
+    /// A single request has been allowed through to check whether tiled has recovered
+    HalfOpen { since: Instant },
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+/// State of the circuit breaker for the status endpoint
+#[derive(Debug, PartialEq, Serialize)]
+pub struct BreakerStatus {
+    pub state: CircuitState,
+    /// Consecutive failures while the circuit is closed
+    pub failures: u32,
+    /// Seconds until a request will be let through to check if tiled has recovered
+    pub retry_in: Option<f64>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        Self(Arc::new(BreakerState {
+            config,
+            circuit: Mutex::new(Circuit::Closed { failures: 0 }),
+        }))
+    }
+
+    fn reset_timeout(&self) -> Duration {
+        Duration::from_secs(self.0.config.reset_timeout)
+    }
+
+    /// Check whether a request can be made. If the circuit is open, a single request is allowed
+    /// once the reset timeout has passed and its outcome decides whether the circuit is closed
+    /// again.
+    pub fn allow(&self) -> bool {
+        let mut circuit = self
+            .0
+            .circuit
+            .lock()
+            .expect("Circuit breaker lock poisoned");
+        let now = Instant::now();
+        match *circuit {
+            Circuit::Closed { .. } => true,
+            Circuit::Open { until } if now < until => false,
+            // If the trial request never reported back (eg the client disconnected), allow
+            // another one rather than staying half open forever
+            Circuit::HalfOpen { since } if now < since + self.reset_timeout() => false,
+            Circuit::Open { .. } | Circuit::HalfOpen { .. } => {
+                *circuit = Circuit::HalfOpen { since: now };
+                true
+            }
+        }
+    }
+
+    pub fn success(&self) {
+        *self
+            .0
+            .circuit
+            .lock()
+            .expect("Circuit breaker lock poisoned") = Circuit::Closed { failures: 0 };
+    }
+
+    pub fn failure(&self) {
+        let threshold = self.0.config.failure_threshold;
+        if threshold == 0 {
+            return;
+        }
+        let mut circuit = self
+            .0
+            .circuit
+            .lock()
+            .expect("Circuit breaker lock poisoned");
+        let failures = match *circuit {
+            Circuit::Closed { failures } => failures + 1,
+            Circuit::Open { .. } | Circuit::HalfOpen { .. } => threshold,
+        };
+        *circuit = if failures >= threshold {
+            if matches!(*circuit, Circuit::Closed { .. }) {
+                warn!("Tiled has failed {failures} times, rejecting requests");
+            }
+            Circuit::Open {
+                until: Instant::now() + self.reset_timeout(),
+            }
+        } else {
+            Circuit::Closed { failures }
+        };
+    }
+
+    pub fn status(&self) -> BreakerStatus {
+        let circuit = *self
+            .0
+            .circuit
+            .lock()
+            .expect("Circuit breaker lock poisoned");
+        match circuit {
+            Circuit::Closed { failures } => BreakerStatus {
+                state: CircuitState::Closed,
+                failures,
+                retry_in: None,
+            },
+            Circuit::Open { until } => BreakerStatus {
+                state: CircuitState::Open,
+                failures: self.0.config.failure_threshold,
+                retry_in: Some((until - Instant::now().min(until)).as_secs_f64()),
+            },
+            Circuit::HalfOpen { .. } => BreakerStatus {
+                state: CircuitState::HalfOpen,
+                failures: self.0.config.failure_threshold,
+                retry_in: None,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{CircuitBreaker, CircuitState};
+    use crate::config::{CircuitBreakerConfig, RetryConfig};
+
+    #[test]
+    fn backoff_bounds() {
+        let config = RetryConfig {
+            retries: 10,
+            initial_backoff: 100,
+            max_backoff: 1000,
+        };
+        for (retry, max) in [
+            (0, 100),
+            (1, 200),
+            (2, 400),
+            (3, 800),
+            (4, 1000),
+            (20, 1000),
+        ] {
+            let delay = config.backoff(retry);
+            assert!(
+                delay <= Duration::from_millis(max) && delay >= Duration::from_millis(max / 2),
+                "Retry {retry} delayed by {delay:?}"
+            );
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn circuit_opens_and_recovers() {
+        let breaker = CircuitBreaker::new(CircuitBreakerConfig {
+            failure_threshold: 2,
+            reset_timeout: 10,
+        });
+        breaker.failure();
+        assert!(breaker.allow());
+        breaker.failure();
+        assert!(!breaker.allow());
+        assert_eq!(breaker.status().state, CircuitState::Open);
+
+        tokio::time::advance(Duration::from_secs(11)).await;
+        // Only one request is let through to check whether tiled is back
+        assert!(breaker.allow());
+        assert!(!breaker.allow());
+        assert_eq!(breaker.status().state, CircuitState::HalfOpen);
+
+        breaker.failure();
+        assert!(!breaker.allow());
+        tokio::time::advance(Duration::from_secs(11)).await;
+        assert!(breaker.allow());
+        breaker.success();
+        assert!(breaker.allow());
+        assert_eq!(breaker.status().state, CircuitState::Closed);
+    }
+
+    #[test]
+    fn disabled_breaker() {
+        let breaker = CircuitBreaker::new(CircuitBreakerConfig {
+            failure_threshold: 0,
+            reset_timeout: 10,
+        });
+        for _ in 0..10 {
+            breaker.failure();
+        }
+        assert!(breaker.allow());
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use async_graphql::{
+    Context, Error, ErrorExtensions as _, Result, ResultExt as _, SimpleObject, Subscription,
+};
+use futures_util::{Stream, StreamExt as _, stream};
+use serde_json::Value;
+
+use crate::backends::Backends;
+use crate::config::SubscriptionConfig;
+use crate::handlers::AuthHeader;
+use crate::model::node::NodeAttributes;
+use crate::model::{Run, find_run};
+use crate::watch::{self, RunStatusChange};
+
+pub(crate) struct TiledSubscription;
+
+#[Subscription]
+impl TiledSubscription {
+    /// Runs added to an instrument session after the subscription starts. The session is watched
+    /// in the named backend, the backend for the given instrument, or the backend whose session
+    /// prefixes match the name. Sessions that don't match any backend are watched in all
+    /// backends.
+    async fn runs_added(
+        &self,
+        ctx: &Context<'_>,
+        instrument_session: String,
+        instrument: Option<String>,
+        backend: Option<String>,
+    ) -> Result<impl Stream<Item = Result<Run>> + use<>> {
+        let config = ctx.data::<SubscriptionConfig>()?;
+        let interval = Duration::from_millis(config.poll_interval);
+        let headers = ctx
+            .data::<Option<AuthHeader>>()?
+            .as_ref()
+            .map(AuthHeader::as_header_map);
+        let backends = ctx.data::<Backends>()?.route(
+            backend.as_deref(),
+            instrument.as_deref(),
+            Some(&instrument_session),
+        )?;
+        let watches = backends
+            .into_iter()
+            .cloned()
+            .map(|backend| {
+                watch::runs_added(
+                    backend.clone(),
+                    instrument_session.clone(),
+                    headers.clone(),
+                    interval,
+                    None,
+                )
+                .map(move |run| {
+                    Ok(Run {
+                        backend: backend.clone(),
+                        data: run.map_err(|e| e.extend())?,
+                    })
+                })
+                .boxed()
+            })
+            .collect::<Vec<_>>();
+        Ok(stream::select_all(watches))
+    }
+
+    /// Changes to the status of a run and streams added to it. The current state of the run is
+    /// sent first and the subscription completes once the run has finished or been aborted.
+    async fn run_status_changed(
+        &self,
+        ctx: &Context<'_>,
+        id: String,
+        backend: Option<String>,
+    ) -> Result<impl Stream<Item = Result<RunStatusChange>> + use<>> {
+        let config = ctx.data::<SubscriptionConfig>()?;
+        let interval = Duration::from_millis(config.poll_interval);
+        let headers = ctx
+            .data::<Option<AuthHeader>>()?
+            .as_ref()
+            .map(AuthHeader::as_header_map);
+        let backend = find_run(ctx, &id, backend.as_deref()).await?;
+        Ok(watch::run_status_changes(backend, id, headers, interval).map(|c| c.extend()))
+    }
+
+    /// Rows of a table in a run as they are written. The rows that already exist are sent first
+    /// and the subscription completes once the run has finished and all its rows have been sent.
+    async fn table_rows(
+        &self,
+        ctx: &Context<'_>,
+        run: String,
+        stream: String,
+        table: String,
+        columns: Option<Vec<String>>,
+        backend: Option<String>,
+    ) -> Result<impl Stream<Item = Result<TableRows>> + use<>> {
+        let config = ctx.data::<SubscriptionConfig>()?;
+        let interval = Duration::from_millis(config.poll_interval);
+        let headers = ctx
+            .data::<Option<AuthHeader>>()?
+            .as_ref()
+            .map(AuthHeader::as_header_map);
+        let backend = find_run(ctx, &run, backend.as_deref()).await?;
+        let path = format!("{run}/{stream}/{table}");
+        let node = backend
+            .client
+            .metadata(path.clone(), headers.clone())
+            .await
+            .extend()?
+            .into_data();
+        if !matches!(*node.attributes, NodeAttributes::Table(_)) {
+            return Err(Error::new(format!("'{path}' is not a table"))
+                .extend_with(|_, ext| ext.set("code", "BAD_PATH")));
+        }
+        Ok(
+            watch::table_rows(backend, run, path, columns, headers, interval).map(|batch| {
+                let batch = batch.map_err(|e| e.extend())?;
+                Ok(TableRows {
+                    offset: batch.offset,
+                    count: batch.rows.values().map(Vec::len).max().unwrap_or_default(),
+                    data: batch.rows,
+                })
+            }),
+        )
+    }
+}
+
+/// Rows added to a table
+#[derive(SimpleObject)]
+struct TableRows {
+    /// Index of the first row
+    offset: usize,
+    /// Number of rows
+    count: usize,
+    data: HashMap<String, Vec<Value>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use async_graphql::{EmptyMutation, Schema};
+    use futures_util::StreamExt as _;
+    use httpmock::MockServer;
+    use serde_json::json;
+
+    use super::TiledSubscription;
+    use crate::TiledQuery;
+    use crate::backends::Backends;
+    use crate::clients::TiledClient;
+    use crate::config::SubscriptionConfig;
+    use crate::handlers::AuthHeader;
+
+    #[tokio::test]
+    async fn runs_added_forbidden() {
+        let server = MockServer::start();
+        let search = server
+            .mock_async(|when, then| {
+                when.method("GET")
+                    .path("/api/v1/search/")
+                    .header("authorization", "Bearer abc");
+                then.status(403).json_body(json!({"detail": "Forbidden"}));
+            })
+            .await;
+        let schema = Schema::build(TiledQuery, EmptyMutation, TiledSubscription)
+            .data(Backends::single(TiledClient::for_mock_server(&server)))
+            .data(SubscriptionConfig { poll_interval: 10 })
+            .finish();
+        let request = async_graphql::Request::new(
+            r#"subscription { runsAdded(instrumentSession: "cm12345-1") { id } }"#,
+        )
+        .data(Some(AuthHeader::from(
+            "Bearer abc".parse::<axum::http::HeaderValue>().unwrap(),
+        )));
+        let responses = schema.execute_stream(request).collect::<Vec<_>>().await;
+        assert_eq!(responses.len(), 1);
+        let extensions = responses[0].errors[0].extensions.as_ref().unwrap();
+        assert_eq!(
+            extensions.get("code"),
+            Some(&async_graphql::value!("FORBIDDEN"))
+        );
+        search.assert();
+    }
+}
@@ -4,7 +4,12 @@ use std::io::BufReader;
 use serde::de::DeserializeOwned;
 
 pub fn assert_readable_as<T: DeserializeOwned>(path: &str) {
+    read_json::<T>(path);
+}
+
+/// Read a JSON fixture, eg to use as the body of a mocked tiled response
+pub fn read_json<T: DeserializeOwned>(path: &str) -> T {
     let file = File::open(path).unwrap();
     let rdr = BufReader::new(file);
-    serde_json::from_reader::<_, T>(rdr).unwrap();
+    serde_json::from_reader(rdr).unwrap()
 }
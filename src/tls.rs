@@ -0,0 +1,307 @@
+use std::net::ToSocketAddrs;
+use std::path::Path;
+
+use reqwest::tls::{Certificate, Identity};
+use reqwest::{ClientBuilder, Url};
+use tracing::warn;
+
+use crate::clients::{ClientError, ClientResult};
+use crate::config::TlsConfig;
+
+fn read(path: &Path) -> ClientResult<Vec<u8>> {
+    std::fs::read(path).map_err(|e| ClientError::Tls(format!("Unable to read {path:?}: {e}")))
+}
+
+impl TlsConfig {
+    /// Add the TLS settings to a client builder. Returns the builder and the address that
+    /// requests to tiled should be made to, which differs from the configured address if the
+    /// server name is overridden.
+    pub fn configure(
+        &self,
+        mut builder: ClientBuilder,
+        address: &Url,
+    ) -> ClientResult<(ClientBuilder, Url)> {
+        for path in &self.ca_certs {
+            let certs = Certificate::from_pem_bundle(&read(path)?).map_err(|e| {
+                ClientError::Tls(format!("Invalid CA certificates in {path:?}: {e}"))
+            })?;
+            for cert in certs {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => {
+                let mut pem = read(cert)?;
+                pem.push(b'\n');
+                pem.extend(read(key)?);
+                let identity = Identity::from_pem(&pem)
+                    .map_err(|e| ClientError::Tls(format!("Invalid client certificate: {e}")))?;
+                builder = builder.identity(identity);
+            }
+            (None, None) => {}
+            _ => {
+                return Err(ClientError::Tls(
+                    "Client certificate and key must be given together".into(),
+                ));
+            }
+        }
+        if self.insecure {
+            warn!("TLS certificate verification is disabled for {address}");
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        let mut address = address.clone();
+        if let Some(name) = &self.server_name {
+            // Reqwest takes the SNI from the URL so requests are made to the server name and the
+            // name is resolved to the addresses of the configured host instead.
+            let host = address
+                .host_str()
+                .ok_or_else(|| ClientError::Tls("Tiled address has no host".into()))?;
+            let port = address
+                .port_or_known_default()
+                .ok_or_else(|| ClientError::Tls("Tiled address has no port".into()))?;
+            let addrs = (host.trim_matches(['[', ']']), port)
+                .to_socket_addrs()
+                .map_err(|e| ClientError::Tls(format!("Unable to resolve {host}: {e}")))?
+                .collect::<Vec<_>>();
+            builder = builder.resolve_to_addrs(name, &addrs);
+            address
+                .set_host(Some(name))
+                .map_err(|e| ClientError::Tls(format!("Invalid server name '{name}': {e}")))?;
+        }
+        Ok((builder, address))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, IsCa, KeyPair};
+    use tempfile::TempDir;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+    use tokio_rustls::rustls::crypto::ring;
+    use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+    use tokio_rustls::rustls::server::WebPkiClientVerifier;
+    use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+
+    use crate::clients::{ClientError, TiledClient};
+    use crate::config::{GlazedConfig, RetryConfig, TlsConfig};
+
+    /// A CA and the certificates it has issued, written to a temporary directory
+    struct Pki {
+        dir: TempDir,
+        ca: CertifiedIssuer<'static, KeyPair>,
+    }
+
+    struct Issued {
+        cert: CertificateDer<'static>,
+        key: PrivateKeyDer<'static>,
+        cert_path: PathBuf,
+        key_path: PathBuf,
+    }
+
+    impl Pki {
+        fn new() -> Self {
+            let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let ca = CertifiedIssuer::self_signed(params, KeyPair::generate().unwrap()).unwrap();
+            let dir = tempfile::tempdir().unwrap();
+            std::fs::write(dir.path().join("ca.pem"), ca.pem()).unwrap();
+            Self { dir, ca }
+        }
+
+        fn ca_path(&self) -> PathBuf {
+            self.dir.path().join("ca.pem")
+        }
+
+        fn issue(&self, name: &str) -> Issued {
+            let key = KeyPair::generate().unwrap();
+            let cert = CertificateParams::new(vec![name.to_owned()])
+                .unwrap()
+                .signed_by(&key, &self.ca)
+                .unwrap();
+            let cert_path = self.dir.path().join(format!("{name}.crt"));
+            let key_path = self.dir.path().join(format!("{name}.key"));
+            std::fs::write(&cert_path, cert.pem()).unwrap();
+            std::fs::write(&key_path, key.serialize_pem()).unwrap();
+            Issued {
+                cert: cert.der().clone(),
+                key: key.into(),
+                cert_path,
+                key_path,
+            }
+        }
+    }
+
+    /// Start a TLS server that responds to every request with the SNI sent by the client
+    async fn serve(pki: &Pki, name: &str, client_auth: bool) -> SocketAddr {
+        let provider = Arc::new(ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .unwrap();
+        let builder = if client_auth {
+            let mut roots = RootCertStore::empty();
+            roots.add(pki.ca.der().clone()).unwrap();
+            builder.with_client_cert_verifier(
+                WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                    .build()
+                    .unwrap(),
+            )
+        } else {
+            builder.with_no_client_auth()
+        };
+        let server = pki.issue(name);
+        let config = builder
+            .with_single_cert(vec![server.cert], server.key)
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let Ok(mut stream) = acceptor.accept(stream).await else {
+                        return;
+                    };
+                    let sni = stream.get_ref().1.server_name().unwrap_or_default();
+                    let body = serde_json::to_string(sni).unwrap();
+                    let mut request = Vec::new();
+                    while !request.ends_with(b"\r\n\r\n") {
+                        let mut buf = [0; 1024];
+                        let Ok(n @ 1..) = stream.read(&mut buf).await else {
+                            return;
+                        };
+                        request.extend(&buf[..n]);
+                    }
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\n\
+                        content-length: {}\r\nconnection: close\r\n\r\n{body}",
+                        body.len()
+                    );
+                    stream.write_all(response.as_bytes()).await.unwrap();
+                    stream.shutdown().await.unwrap();
+                });
+            }
+        });
+        addr
+    }
+
+    fn client(addr: SocketAddr, tls: TlsConfig) -> Result<TiledClient, ClientError> {
+        let mut config = GlazedConfig::default().tiled_client;
+        config.address = format!("https://{addr}").parse().unwrap();
+        config.retry = RetryConfig {
+            retries: 0,
+            ..RetryConfig::default()
+        };
+        config.tls = tls;
+        TiledClient::from_config(&config)
+    }
+
+    async fn sni(client: &TiledClient) -> Result<String, ClientError> {
+        let response = client
+            .download("run", "primary", "det", 1, None, None)
+            .await?;
+        Ok(response.json().await?)
+    }
+
+    #[tokio::test]
+    async fn custom_ca() {
+        let pki = Pki::new();
+        let addr = serve(&pki, "127.0.0.1", false).await;
+
+        let untrusted = client(addr, TlsConfig::default()).unwrap();
+        assert!(sni(&untrusted).await.is_err());
+
+        let trusted = client(
+            addr,
+            TlsConfig {
+                ca_certs: vec![pki.ca_path()],
+                ..TlsConfig::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(sni(&trusted).await.unwrap(), "");
+    }
+
+    #[tokio::test]
+    async fn insecure() {
+        let pki = Pki::new();
+        let addr = serve(&pki, "tiled.internal", false).await;
+        let client = client(
+            addr,
+            TlsConfig {
+                insecure: true,
+                ..TlsConfig::default()
+            },
+        )
+        .unwrap();
+        sni(&client).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn client_certificate() {
+        let pki = Pki::new();
+        let addr = serve(&pki, "127.0.0.1", true).await;
+
+        let anonymous = client(
+            addr,
+            TlsConfig {
+                ca_certs: vec![pki.ca_path()],
+                ..TlsConfig::default()
+            },
+        )
+        .unwrap();
+        assert!(sni(&anonymous).await.is_err());
+
+        let glazed = pki.issue("glazed");
+        let authenticated = client(
+            addr,
+            TlsConfig {
+                ca_certs: vec![pki.ca_path()],
+                client_cert: Some(glazed.cert_path),
+                client_key: Some(glazed.key_path),
+                ..TlsConfig::default()
+            },
+        )
+        .unwrap();
+        sni(&authenticated).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn server_name() {
+        let pki = Pki::new();
+        let addr = serve(&pki, "tiled.internal", false).await;
+        let client = client(
+            addr,
+            TlsConfig {
+                ca_certs: vec![pki.ca_path()],
+                server_name: Some("tiled.internal".into()),
+                ..TlsConfig::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(sni(&client).await.unwrap(), "tiled.internal");
+    }
+
+    #[test]
+    fn client_key_required() {
+        let pki = Pki::new();
+        let glazed = pki.issue("glazed");
+        let result = client(
+            "127.0.0.1:8443".parse().unwrap(),
+            TlsConfig {
+                client_cert: Some(glazed.cert_path),
+                ..TlsConfig::default()
+            },
+        );
+        let Err(ClientError::Tls(_)) = result else {
+            panic!("Expected TLS error");
+        };
+    }
+}
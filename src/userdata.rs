@@ -0,0 +1,630 @@
+//! Bookmarks and saved searches of each user, stored in a SQLite database
+
+use std::fmt;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_graphql::{
+    ComplexObject, Context, Error, ErrorExtensions, InputObject, Object, Result, SimpleObject,
+};
+use lru::LruCache;
+use rusqlite::{Connection, params};
+use serde::{Deserialize, Serialize};
+
+use crate::audit::unix_millis;
+use crate::backends::Backends;
+use crate::clients::TiledClient;
+use crate::config::UserDataConfig;
+use crate::handlers::AuthHeader;
+use crate::loaders::{Metadata, NodeKey, TiledDataLoader};
+use crate::model::node::NodeAttributes;
+use crate::model::{Run, search_runs};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS bookmarks (
+    user TEXT NOT NULL,
+    backend TEXT NOT NULL,
+    run TEXT NOT NULL,
+    created INTEGER NOT NULL,
+    PRIMARY KEY (user, backend, run)
+);
+CREATE TABLE IF NOT EXISTS saved_searches (
+    user TEXT NOT NULL,
+    name TEXT NOT NULL,
+    search TEXT NOT NULL,
+    created INTEGER NOT NULL,
+    PRIMARY KEY (user, name)
+);
+";
+
+/// Number of users whose credentials have been verified that are remembered
+const VERIFIED_USERS: usize = 1000;
+/// How long credentials verified by tiled are trusted before being checked again
+const VERIFIED_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub enum UserDataError {
+    /// No database has been configured
+    Disabled,
+    /// The request had no credentials or tiled did not identify a user from them
+    Unidentified,
+    Database(rusqlite::Error),
+}
+
+impl fmt::Display for UserDataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UserDataError::Disabled => f.write_str("User data is not enabled"),
+            UserDataError::Unidentified => {
+                f.write_str("User data requires credentials that identify the user")
+            }
+            UserDataError::Database(err) => write!(f, "Error accessing user data: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for UserDataError {}
+
+impl From<rusqlite::Error> for UserDataError {
+    fn from(err: rusqlite::Error) -> Self {
+        Self::Database(err)
+    }
+}
+
+impl ErrorExtensions for UserDataError {
+    fn extend(&self) -> Error {
+        let code = match self {
+            UserDataError::Disabled => "UNAVAILABLE",
+            UserDataError::Unidentified => "UNAUTHENTICATED",
+            UserDataError::Database(_) => "INTERNAL",
+        };
+        Error::new(self.to_string()).extend_with(|_, ext| ext.set("code", code))
+    }
+}
+
+type UserDataResult<T> = std::result::Result<T, UserDataError>;
+
+/// A user whose credentials have been verified by tiled
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct User {
+    /// UUID of the user's principal in tiled, used to key their data
+    pub id: String,
+    pub name: String,
+}
+
+/// Store of per-user data. Every operation fails with [`UserDataError::Disabled`] if no database
+/// is configured.
+#[derive(Clone)]
+pub struct UserData {
+    conn: Option<Arc<Mutex<Connection>>>,
+    /// Users verified by tiled, keyed by a hash of their credentials so that tiled is not asked
+    /// on every request
+    verified: Arc<Mutex<LruCache<String, (User, Instant)>>>,
+}
+
+impl Default for UserData {
+    fn default() -> Self {
+        Self::with_connection(None)
+    }
+}
+
+impl UserData {
+    pub fn new(config: Option<&UserDataConfig>) -> rusqlite::Result<Self> {
+        match config {
+            Some(config) => Self::from_connection(Connection::open(&config.path)?),
+            None => Ok(Self::default()),
+        }
+    }
+
+    fn with_connection(conn: Option<Connection>) -> Self {
+        let capacity = NonZeroUsize::new(VERIFIED_USERS).expect("Capacity is not zero");
+        Self {
+            conn: conn.map(|conn| Arc::new(Mutex::new(conn))),
+            verified: Arc::new(Mutex::new(LruCache::new(capacity))),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn in_memory() -> Self {
+        Self::from_connection(Connection::open_in_memory().unwrap()).unwrap()
+    }
+
+    fn from_connection(conn: Connection) -> rusqlite::Result<Self> {
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self::with_connection(Some(conn)))
+    }
+
+    /// The user that tiled identifies from the credentials. Tiled rejecting the credentials is
+    /// returned as an error.
+    pub(crate) async fn verify(&self, client: &TiledClient, auth: &AuthHeader) -> Result<User> {
+        let key = AuthHeader::user_key(Some(auth));
+        {
+            let mut verified = self.verified.lock().expect("Verified users lock poisoned");
+            match verified.get(&key) {
+                Some((user, expires)) if *expires > Instant::now() => return Ok(user.clone()),
+                Some(_) => {
+                    verified.pop(&key);
+                }
+                None => {}
+            }
+        }
+        let principal = client
+            .whoami(auth.as_header_map())
+            .await
+            .map_err(|e| e.extend())?
+            .ok_or_else(|| UserDataError::Unidentified.extend())?;
+        let user = User {
+            id: principal.uuid.clone(),
+            name: principal.name().into(),
+        };
+        self.verified
+            .lock()
+            .expect("Verified users lock poisoned")
+            .put(key, (user.clone(), Instant::now() + VERIFIED_TTL));
+        Ok(user)
+    }
+
+    /// Run a query on a blocking thread so that slow disks don't hold up the runtime
+    async fn call<T: Send + 'static>(
+        &self,
+        query: impl FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    ) -> UserDataResult<T> {
+        let Some(conn) = self.conn.clone() else {
+            return Err(UserDataError::Disabled);
+        };
+        tokio::task::spawn_blocking(move || {
+            // A panic while holding the lock can't leave a transaction open so the connection
+            // is still usable
+            let conn = conn.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            query(&conn)
+        })
+        .await
+        .expect("User data queries do not panic")
+        .map_err(UserDataError::from)
+    }
+
+    pub async fn bookmarks(&self, user: &str) -> UserDataResult<Vec<Bookmark>> {
+        let user = user.to_owned();
+        self.call(move |conn| {
+            conn.prepare(
+                "SELECT backend, run, created FROM bookmarks WHERE user = ?1 ORDER BY created",
+            )?
+            .query_map([user], |row| {
+                Ok(Bookmark {
+                    backend: row.get(0)?,
+                    id: row.get(1)?,
+                    created: row.get(2)?,
+                })
+            })?
+            .collect()
+        })
+        .await
+    }
+
+    /// Bookmark a run, returning the existing bookmark if it has already been added
+    pub async fn add_bookmark(
+        &self,
+        user: &str,
+        backend: &str,
+        run: &str,
+    ) -> UserDataResult<Bookmark> {
+        let (user, backend, run) = (user.to_owned(), backend.to_owned(), run.to_owned());
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT OR IGNORE INTO bookmarks (user, backend, run, created)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![user, backend, run, unix_millis() as i64],
+            )?;
+            let created = conn.query_row(
+                "SELECT created FROM bookmarks WHERE user = ?1 AND backend = ?2 AND run = ?3",
+                params![user, backend, run],
+                |row| row.get(0),
+            )?;
+            Ok(Bookmark {
+                backend,
+                id: run,
+                created,
+            })
+        })
+        .await
+    }
+
+    /// Remove a bookmark, returning whether the run was bookmarked. Bookmarks of the run in any
+    /// backend are removed unless one is given.
+    pub async fn remove_bookmark(
+        &self,
+        user: &str,
+        backend: Option<&str>,
+        run: &str,
+    ) -> UserDataResult<bool> {
+        let (user, backend, run) = (user.to_owned(), backend.map(str::to_owned), run.to_owned());
+        self.call(move |conn| {
+            conn.execute(
+                "DELETE FROM bookmarks
+                 WHERE user = ?1 AND (?2 IS NULL OR backend = ?2) AND run = ?3",
+                params![user, backend, run],
+            )
+            .map(|deleted| deleted > 0)
+        })
+        .await
+    }
+
+    pub async fn saved_searches(&self, user: &str) -> UserDataResult<Vec<SavedSearch>> {
+        let user = user.to_owned();
+        self.call(move |conn| {
+            conn.prepare(
+                "SELECT name, search, created FROM saved_searches WHERE user = ?1 ORDER BY name",
+            )?
+            .query_map([user], saved_search)?
+            .collect()
+        })
+        .await
+    }
+
+    /// Save a search, replacing any previous search with the same name
+    pub async fn save_search(
+        &self,
+        user: &str,
+        name: &str,
+        search: RunSearch,
+    ) -> UserDataResult<SavedSearch> {
+        let (user, name) = (user.to_owned(), name.to_owned());
+        let encoded = serde_json::to_string(&search).expect("Searches are serializable");
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT INTO saved_searches (user, name, search, created)
+                 VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT (user, name) DO UPDATE SET search = excluded.search",
+                params![user, name, encoded, unix_millis() as i64],
+            )?;
+            conn.query_row(
+                "SELECT name, search, created FROM saved_searches WHERE user = ?1 AND name = ?2",
+                params![user, name],
+                saved_search,
+            )
+        })
+        .await
+    }
+
+    /// Delete a saved search, returning whether it existed
+    pub async fn delete_search(&self, user: &str, name: &str) -> UserDataResult<bool> {
+        let (user, name) = (user.to_owned(), name.to_owned());
+        self.call(move |conn| {
+            conn.execute(
+                "DELETE FROM saved_searches WHERE user = ?1 AND name = ?2",
+                params![user, name],
+            )
+            .map(|deleted| deleted > 0)
+        })
+        .await
+    }
+}
+
+fn saved_search(row: &rusqlite::Row<'_>) -> rusqlite::Result<SavedSearch> {
+    let search: String = row.get(1)?;
+    Ok(SavedSearch {
+        name: row.get(0)?,
+        search: serde_json::from_str(&search).map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(1, rusqlite::types::Type::Text, e.into())
+        })?,
+        created: row.get(2)?,
+    })
+}
+
+/// The user making a request, as verified by the default backend's tiled server
+pub(crate) async fn current_user(ctx: &Context<'_>) -> Result<User> {
+    let Some(auth) = ctx.data::<Option<AuthHeader>>()? else {
+        return Err(UserDataError::Unidentified.extend());
+    };
+    let backend = ctx.data::<Backends>()?.default_backend();
+    ctx.data::<UserData>()?.verify(&backend.client, auth).await
+}
+
+/// The user making a request and the data they have saved
+pub(crate) struct Me {
+    pub user: User,
+}
+
+#[Object]
+impl Me {
+    async fn name(&self) -> &str {
+        &self.user.name
+    }
+    /// Runs bookmarked by the user, oldest bookmark first
+    async fn bookmarks(&self, ctx: &Context<'_>) -> Result<Vec<Bookmark>> {
+        let store = ctx.data::<UserData>()?;
+        store.bookmarks(&self.user.id).await.map_err(|e| e.extend())
+    }
+    /// Searches saved by the user, in order of name
+    async fn saved_searches(&self, ctx: &Context<'_>) -> Result<Vec<SavedSearch>> {
+        let store = ctx.data::<UserData>()?;
+        store
+            .saved_searches(&self.user.id)
+            .await
+            .map_err(|e| e.extend())
+    }
+}
+
+#[derive(Debug, PartialEq, SimpleObject)]
+#[graphql(complex)]
+pub struct Bookmark {
+    /// ID of the bookmarked run
+    pub id: String,
+    pub backend: String,
+    /// When the bookmark was added in milliseconds since the Unix epoch
+    pub created: i64,
+}
+
+#[ComplexObject]
+impl Bookmark {
+    /// The bookmarked run, or null if it no longer exists or its backend has been removed
+    async fn run(&self, ctx: &Context<'_>) -> Result<Option<Run>> {
+        let auth = ctx.data::<Option<AuthHeader>>()?;
+        let loader = ctx.data::<TiledDataLoader>()?;
+        let Ok(backend) = ctx.data::<Backends>()?.get(&self.backend) else {
+            return Ok(None);
+        };
+        let data = loader
+            .load_one(Metadata(NodeKey::new(backend, &self.id, auth.as_ref())))
+            .await
+            .map_err(|e| e.extend())?;
+        Ok(data.map(|data| Run {
+            backend: backend.clone(),
+            data,
+        }))
+    }
+}
+
+/// Filters selecting runs. Every filter that is set must match.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, SimpleObject, InputObject)]
+#[graphql(input_name = "RunSearchInput")]
+pub struct RunSearch {
+    pub instrument: Option<String>,
+    pub instrument_session: Option<String>,
+    pub plan_name: Option<String>,
+    pub tag: Option<String>,
+    /// Only search this backend instead of every backend that could hold matching runs
+    pub backend: Option<String>,
+}
+
+impl RunSearch {
+    pub(crate) async fn runs(&self, ctx: &Context<'_>) -> Result<Vec<Run>> {
+        let backends = ctx.data::<Backends>()?.route(
+            self.backend.as_deref(),
+            self.instrument.as_deref(),
+            self.instrument_session.as_deref(),
+        )?;
+        let filters = [
+            ("start.instrument", &self.instrument),
+            ("start.instrument_session", &self.instrument_session),
+            ("start.plan_name", &self.plan_name),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| Some((key, value.clone()?)))
+        .collect::<Vec<_>>();
+        let mut runs = search_runs(ctx, &backends, &filters).await?;
+        // Tags are edited by users rather than coming from the start document so are not
+        // searchable through tiled's equality filter
+        if let Some(tag) = &self.tag {
+            runs.retain(|run| match &*run.data.attributes {
+                NodeAttributes::Container(attrs) => attrs
+                    .metadata
+                    .run()
+                    .is_some_and(|metadata| metadata.tags.contains(tag)),
+                _ => false,
+            });
+        }
+        Ok(runs)
+    }
+}
+
+#[derive(Debug, PartialEq, SimpleObject)]
+#[graphql(complex)]
+pub struct SavedSearch {
+    pub name: String,
+    pub search: RunSearch,
+    /// When the search was first saved in milliseconds since the Unix epoch
+    pub created: i64,
+}
+
+#[ComplexObject]
+impl SavedSearch {
+    /// Runs currently matching the search
+    async fn runs(&self, ctx: &Context<'_>) -> Result<Vec<Run>> {
+        self.search.runs(ctx).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_graphql::{Request, Schema, value};
+    use axum::http::HeaderValue;
+    use httpmock::MockServer;
+    use serde_json::{Value, json};
+
+    use super::{RunSearch, UserData, UserDataError};
+    use crate::TiledQuery;
+    use crate::backends::Backends;
+    use crate::clients::TiledClient;
+    use crate::handlers::AuthHeader;
+    use crate::loaders::TiledLoader;
+    use crate::mutations::TiledMutation;
+    use crate::subscriptions::TiledSubscription;
+    use crate::test_utils::read_json;
+
+    const RUN: &str = "5d8f5c3e-0e00-4c5c-816d-70b4b0f41498";
+    const ALICE: &str = "Bearer alice-token";
+    const BOB: &str = "Basic Ym9iOnBhc3N3b3Jk";
+
+    fn request(query: &str, auth: &'static str) -> Request {
+        Request::new(query).data(Some(AuthHeader::from(HeaderValue::from_static(auth))))
+    }
+
+    /// Have tiled verify the credentials as the principal with the given name
+    async fn mock_whoami<'s>(server: &'s MockServer, auth: &str, name: &str) -> httpmock::Mock<'s> {
+        server
+            .mock_async(|when, then| {
+                when.method("GET")
+                    .path("/api/v1/auth/whoami")
+                    .header("authorization", auth);
+                then.status(200).json_body(json!({
+                    "uuid": format!("{name}-uuid"),
+                    "type": "user",
+                    "identities": [{"id": name, "provider": "toy"}],
+                }));
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn me() {
+        let server = MockServer::start();
+        let alice = mock_whoami(&server, ALICE, "alice").await;
+        mock_whoami(&server, BOB, "bob").await;
+        // Tokens are verified by tiled rather than trusting the claims they contain
+        server
+            .mock_async(|when, then| {
+                when.method("GET")
+                    .path("/api/v1/auth/whoami")
+                    .header("authorization", "Bearer forged");
+                then.status(401)
+                    .json_body(json!({"detail": "Invalid token"}));
+            })
+            .await;
+        server
+            .mock_async(|when, then| {
+                when.method("GET").path(format!("/api/v1/metadata/{RUN}"));
+                then.status(200)
+                    .body_from_file("resources/metadata_run.json");
+            })
+            .await;
+        let mut runs: Value = read_json("resources/search_root.json");
+        runs["data"][1]["attributes"]["metadata"]["tags"] = json!(["good"]);
+        let search = server
+            .mock_async(|when, then| {
+                when.method("GET")
+                    .path("/api/v1/search/")
+                    .query_param("filter[eq][condition][key]", "start.plan_name")
+                    .query_param("filter[eq][condition][value]", r#""spec_scan""#);
+                then.status(200).json_body(runs);
+            })
+            .await;
+        let backends = Backends::single(TiledClient::for_mock_server(&server));
+        let schema = Schema::build(TiledQuery, TiledMutation, TiledSubscription)
+            .data(TiledLoader::data_loader(backends.clone()))
+            .data(backends)
+            .data(UserData::in_memory())
+            .finish();
+        for mutation in [
+            format!(r#"mutation {{ addBookmark(run: "{RUN}") {{ id }} }}"#),
+            r#"mutation { saveSearch(name: "good scans",
+                search: {planName: "spec_scan", tag: "good"}) { name } }"#
+                .into(),
+        ] {
+            let response = schema.execute(request(&mutation, ALICE)).await;
+            assert_eq!(response.errors, &[]);
+        }
+        let query = "{ me { name bookmarks { id backend run { id } }
+            savedSearches { name search { planName tag } runs { id } } } }";
+        let response = schema.execute(request(query, ALICE)).await;
+        assert_eq!(response.errors, &[]);
+        assert_eq!(
+            response.data,
+            value!({"me": {
+                "name": "alice",
+                "bookmarks": [{"id": RUN, "backend": "default", "run": {"id": RUN}}],
+                "savedSearches": [{
+                    "name": "good scans",
+                    "search": {"planName": "spec_scan", "tag": "good"},
+                    "runs": [{"id": "1e37c0ed-e87e-470d-be18-9d7f62f69127"}],
+                }],
+            }})
+        );
+        search.assert();
+        // Verified users are remembered rather than checked with tiled for every request
+        alice.assert_calls(1);
+
+        // Other users don't see alice's data and unverified users have none
+        let response = schema
+            .execute(request("{ me { name bookmarks { id } } }", BOB))
+            .await;
+        assert_eq!(
+            response.data,
+            value!({"me": {"name": "bob", "bookmarks": []}})
+        );
+        let response = schema
+            .execute(request("{ me { name } }", "Bearer forged"))
+            .await;
+        let extensions = response.errors[0].extensions.as_ref().unwrap();
+        assert_eq!(extensions.get("code"), Some(&value!("UNAUTHENTICATED")));
+        let response = schema
+            .execute(Request::new("{ me { name } }").data(Option::<AuthHeader>::None))
+            .await;
+        let extensions = response.errors[0].extensions.as_ref().unwrap();
+        assert_eq!(extensions.get("code"), Some(&value!("UNAUTHENTICATED")));
+    }
+
+    #[tokio::test]
+    async fn bookmarks_are_per_user() {
+        let store = UserData::in_memory();
+        store.add_bookmark("alice", "default", "abc").await.unwrap();
+        store.add_bookmark("alice", "default", "def").await.unwrap();
+        store.add_bookmark("bob", "default", "abc").await.unwrap();
+        let first = store.bookmarks("alice").await.unwrap();
+        // Adding a bookmark twice keeps the original
+        let again = store.add_bookmark("alice", "default", "abc").await.unwrap();
+        assert_eq!(again, first[0]);
+        let ids = |bookmarks: Vec<super::Bookmark>| {
+            bookmarks.into_iter().map(|b| b.id).collect::<Vec<_>>()
+        };
+        assert_eq!(ids(store.bookmarks("alice").await.unwrap()), ["abc", "def"]);
+        assert!(store.remove_bookmark("alice", None, "abc").await.unwrap());
+        assert!(
+            !store
+                .remove_bookmark("alice", Some("default"), "abc")
+                .await
+                .unwrap()
+        );
+        assert_eq!(ids(store.bookmarks("alice").await.unwrap()), ["def"]);
+        assert_eq!(ids(store.bookmarks("bob").await.unwrap()), ["abc"]);
+    }
+
+    #[tokio::test]
+    async fn saved_search_replaced() {
+        let store = UserData::in_memory();
+        let search = RunSearch {
+            instrument: Some("i22".into()),
+            plan_name: Some("align".into()),
+            ..RunSearch::default()
+        };
+        let saved = store
+            .save_search("alice", "alignment", search.clone())
+            .await
+            .unwrap();
+        assert_eq!(saved.search, search);
+        let replacement = RunSearch {
+            instrument: Some("b21".into()),
+            ..RunSearch::default()
+        };
+        let replaced = store
+            .save_search("alice", "alignment", replacement.clone())
+            .await
+            .unwrap();
+        assert_eq!(replaced.search, replacement);
+        assert_eq!(replaced.created, saved.created);
+        assert_eq!(store.saved_searches("alice").await.unwrap(), [replaced]);
+        assert_eq!(store.saved_searches("bob").await.unwrap(), []);
+        assert!(store.delete_search("alice", "alignment").await.unwrap());
+        assert_eq!(store.saved_searches("alice").await.unwrap(), []);
+    }
+
+    #[tokio::test]
+    async fn disabled() {
+        let store = UserData::default();
+        assert!(matches!(
+            store.bookmarks("alice").await,
+            Err(UserDataError::Disabled)
+        ));
+    }
+}
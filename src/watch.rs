@@ -0,0 +1,1140 @@
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use async_graphql::SimpleObject;
+use futures_util::{Stream, StreamExt as _, stream};
+use reqwest::header::HeaderMap;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::backends::Backend;
+use crate::clients::{ClientError, ClientResult, TiledClient};
+use crate::config::RetryConfig;
+use crate::model::node::{self, NodeAttributes};
+use crate::model::run::{RunMetadata, RunStatus};
+use crate::model::table;
+
+/// Query type used to only fetch runs started after the runs already seen
+const COMPARISON_FILTER: &str = "comparison";
+
+/// Number of the most recently started runs checked for runs that have finished. Runs that were
+/// running in an earlier check are checked individually once they are no longer in this window.
+const RECENT_RUNS: usize = 50;
+
+/// Longest time between checks while tiled keeps failing
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Time the run was stopped, from its stop document
+pub fn stop_time(run: &node::Data) -> Option<f64> {
+    match &*run.attributes {
+        NodeAttributes::Container(attrs) => attrs
+            .metadata
+            .run()
+            .and_then(|run| run.stop.as_ref())
+            .map(|stop| stop.time),
+        _ => None,
+    }
+}
+
+/// Time the run was started, from its start document
+pub fn start_time(run: &node::Data) -> Option<f64> {
+    match &*run.attributes {
+        NodeAttributes::Container(attrs) => attrs.metadata.start_doc().map(|start| start.time),
+        _ => None,
+    }
+}
+
+/// Whether an error means that watching should stop rather than be retried at the next poll.
+/// Requests that tiled rejects (eg for missing permissions) will not succeed later, unless tiled
+/// only rejected them because too many requests were being made.
+fn is_fatal(err: &ClientError) -> bool {
+    matches!(err, ClientError::TiledRequest(status, _) if *status != 429)
+}
+
+/// Polls tiled for runs added to an instrument session
+struct SessionWatcher {
+    client: TiledClient,
+    headers: Option<HeaderMap>,
+    session: String,
+    /// Only runs started at or after this time are requested
+    cursor: Option<f64>,
+    /// Start times of the runs that have already been seen. If tiled can't filter by start time,
+    /// every run in the session is kept here.
+    seen: HashMap<String, f64>,
+    baselined: bool,
+    /// Only runs started after this time are reported, used to resume an earlier watch
+    after: Option<f64>,
+}
+
+impl SessionWatcher {
+    fn use_cursor(&self) -> bool {
+        self.client.supports_query(COMPARISON_FILTER)
+    }
+
+    async fn search_page(&self, extra: &[(&str, String)]) -> ClientResult<node::Root> {
+        let mut query = vec![
+            ("include_data_sources", "true".into()),
+            (
+                "filter[eq][condition][key]",
+                "start.instrument_session".into(),
+            ),
+            (
+                "filter[eq][condition][value]",
+                format!(r#""{}""#, self.session).into(),
+            ),
+        ];
+        query.extend(extra.iter().map(|(k, v)| (*k, v.as_str().into())));
+        self.client.search("", self.headers.clone(), &query).await
+    }
+
+    /// Every matching run in the session, in the order they were started, following tiled's
+    /// pagination until the last page
+    async fn search(&self, extra: &[(&str, String)]) -> ClientResult<Vec<node::Data>> {
+        let mut runs = Vec::new();
+        loop {
+            let mut query = extra.to_vec();
+            query.push(("sort", "start.time".into()));
+            query.push(("page[offset]", runs.len().to_string()));
+            let page = self.search_page(&query).await?;
+            let last = page.links.as_ref().is_none_or(|links| links.next.is_none());
+            let count = runs.len();
+            runs.extend(page.into_data());
+            if last || runs.len() == count {
+                return Ok(runs);
+            }
+        }
+    }
+
+    async fn baseline(&mut self) -> ClientResult<()> {
+        let runs = if self.use_cursor() {
+            self.search_page(&[("sort", "-start.time".into()), ("page[limit]", "1".into())])
+                .await?
+                .into_data()
+                .collect()
+        } else {
+            self.search(&[]).await?
+        };
+        self.record(&runs);
+        Ok(())
+    }
+
+    async fn poll(&mut self) -> ClientResult<Vec<node::Data>> {
+        let runs = match self.cursor.filter(|_| self.use_cursor()) {
+            Some(cursor) => {
+                self.search(&[
+                    ("filter[comparison][condition][operator]", "ge".into()),
+                    ("filter[comparison][condition][key]", "start.time".into()),
+                    ("filter[comparison][condition][value]", cursor.to_string()),
+                ])
+                .await?
+            }
+            None => self.search(&[]).await?,
+        };
+        let mut added = runs
+            .into_iter()
+            .filter(|run| !self.seen.contains_key(&run.id))
+            .filter(|run| {
+                self.after
+                    .is_none_or(|after| start_time(run).is_some_and(|time| time > after))
+            })
+            .collect::<Vec<_>>();
+        added.sort_by(|a, b| {
+            let (a, b) = (
+                start_time(a).unwrap_or_default(),
+                start_time(b).unwrap_or_default(),
+            );
+            a.total_cmp(&b)
+        });
+        self.record(&added);
+        Ok(added)
+    }
+
+    fn record(&mut self, runs: &[node::Data]) {
+        for run in runs {
+            let time = start_time(run).unwrap_or_default();
+            self.seen.insert(run.id.clone(), time);
+            self.cursor = Some(self.cursor.map_or(time, |cursor| cursor.max(time)));
+        }
+        // Runs started before the cursor will not be returned again so don't need to be kept
+        if self.use_cursor()
+            && let Some(cursor) = self.cursor
+        {
+            self.seen.retain(|_, time| *time >= cursor);
+        }
+    }
+}
+
+/// Something in tiled that is checked for changes periodically. Watchers never use the response
+/// cache so that every check sees the current state of tiled.
+trait Watcher: Send + 'static {
+    type Change: Send;
+    /// Check for changes since the previous check
+    fn check(&mut self) -> impl Future<Output = ClientResult<Self::Change>> + Send;
+    /// Whether no more changes are expected, ending the watch
+    fn done(&self) -> bool {
+        false
+    }
+    /// Whether every error should be retried, so that the watch only ends once it is done. Used
+    /// for watches in the background that no client would see the error from.
+    fn persistent(&self) -> bool {
+        false
+    }
+}
+
+/// Time to wait before the next check after the given number of consecutive failed checks
+fn delay(interval: Duration, failures: u32) -> Duration {
+    if failures == 0 {
+        return interval;
+    }
+    let backoff = RetryConfig {
+        retries: 0,
+        initial_backoff: interval.as_millis() as u64,
+        max_backoff: MAX_BACKOFF.as_millis() as u64,
+    };
+    backoff.backoff(failures).max(interval)
+}
+
+/// Check for changes until the watcher is done. Errors that may be temporary are logged and the
+/// next check tried after a backoff, other errors end the stream unless the watcher is
+/// persistent.
+fn watch<W: Watcher>(
+    watcher: W,
+    interval: Duration,
+    empty: fn() -> W::Change,
+) -> impl Stream<Item = ClientResult<W::Change>> {
+    stream::unfold(
+        (Some(watcher), true, 0),
+        move |(watcher, first, failures)| async move {
+            let mut watcher = watcher?;
+            if !first {
+                tokio::time::sleep(delay(interval, failures)).await;
+            }
+            match watcher.check().await {
+                Ok(change) => {
+                    let next = (!watcher.done()).then_some(watcher);
+                    Some((Ok(change), (next, false, 0)))
+                }
+                Err(err) if is_fatal(&err) && !watcher.persistent() => {
+                    Some((Err(err), (None, false, 0)))
+                }
+                Err(err) => {
+                    warn!("Unable to check tiled for changes: {err}");
+                    Some((Ok(empty()), (Some(watcher), false, failures + 1)))
+                }
+            }
+        },
+    )
+}
+
+impl Watcher for SessionWatcher {
+    type Change = Vec<node::Data>;
+    /// Check for new runs, returning them in the order they were started. The first check only
+    /// records the runs that already exist.
+    async fn check(&mut self) -> ClientResult<Self::Change> {
+        if !self.baselined {
+            self.baselined = true;
+            // When resuming, the runs started since the previous watch are reported straight away
+            if let Some(after) = self.after {
+                self.cursor = Some(after);
+            } else {
+                self.baseline().await?;
+                return Ok(vec![]);
+            }
+        }
+        self.poll().await
+    }
+}
+
+/// Watch a backend for runs added to an instrument session after the watch starts, or after the
+/// given start time if resuming an earlier watch
+pub fn runs_added(
+    backend: Backend,
+    session: String,
+    headers: Option<HeaderMap>,
+    interval: Duration,
+    after: Option<f64>,
+) -> impl Stream<Item = ClientResult<node::Data>> {
+    let watcher = SessionWatcher {
+        client: backend.client.uncached(),
+        headers,
+        session,
+        cursor: None,
+        seen: HashMap::new(),
+        baselined: false,
+        after,
+    };
+    watch(watcher, interval, Vec::new).flat_map(|runs| match runs {
+        Ok(runs) => stream::iter(runs.into_iter().map(Ok).collect::<Vec<_>>()),
+        Err(err) => stream::iter(vec![Err(err)]),
+    })
+}
+
+/// Polls tiled for recently started runs that have finished
+struct FinishWatcher {
+    client: TiledClient,
+    headers: Option<HeaderMap>,
+    /// Only runs in this instrument session are checked if set
+    session: Option<String>,
+    /// Runs that were still running at the previous check, including runs that have since left
+    /// the window of recent runs
+    running: HashSet<String>,
+    /// Recent runs in the previous check
+    seen: HashSet<String>,
+    baselined: bool,
+    /// Runs that stopped after this time are reported by the first check, used to catch up with
+    /// runs that finished while nothing was watching
+    after: Option<f64>,
+    persistent: bool,
+}
+
+impl FinishWatcher {
+    fn new(backend: Backend, session: Option<String>, headers: Option<HeaderMap>) -> Self {
+        Self {
+            client: backend.client.uncached(),
+            headers,
+            session,
+            running: HashSet::new(),
+            seen: HashSet::new(),
+            baselined: false,
+            after: None,
+            persistent: false,
+        }
+    }
+
+    async fn search(&self, extra: &[(&str, String)]) -> ClientResult<Vec<node::Data>> {
+        let mut query = extra
+            .iter()
+            .map(|(k, v)| (*k, v.as_str().into()))
+            .collect::<Vec<_>>();
+        if let Some(session) = &self.session {
+            query.push((
+                "filter[eq][condition][key]",
+                "start.instrument_session".into(),
+            ));
+            query.push((
+                "filter[eq][condition][value]",
+                format!(r#""{session}""#).into(),
+            ));
+        }
+        let root = self.client.search("", self.headers.clone(), &query).await?;
+        Ok(root.into_data().collect())
+    }
+
+    /// Runs that stopped after the given time. If tiled can't filter by stop time, only the
+    /// recent runs are checked.
+    async fn stopped_after(
+        &self,
+        after: f64,
+        recent: &[node::Data],
+    ) -> ClientResult<Vec<node::Data>> {
+        let runs = if self.client.supports_query(COMPARISON_FILTER) {
+            self.search(&[
+                ("filter[comparison][condition][operator]", "gt".into()),
+                ("filter[comparison][condition][key]", "stop.time".into()),
+                ("filter[comparison][condition][value]", after.to_string()),
+            ])
+            .await?
+        } else {
+            recent.to_vec()
+        };
+        Ok(runs
+            .into_iter()
+            .filter(|run| stop_time(run).is_some_and(|time| time > after))
+            .collect())
+    }
+
+    /// Check the runs that were running but are no longer recent enough to be in the search
+    async fn check_earlier(
+        &self,
+        ids: impl Iterator<Item = &String>,
+        running: &mut HashSet<String>,
+        finished: &mut Vec<node::Data>,
+    ) -> ClientResult<()> {
+        for id in ids {
+            match self.client.metadata(id.clone(), self.headers.clone()).await {
+                Ok(run) => {
+                    let run = run.into_data();
+                    if is_finished(&run) {
+                        finished.push(run);
+                    } else {
+                        running.insert(run.id);
+                    }
+                }
+                // Deleted runs will never finish
+                Err(ClientError::TiledRequest(404, _)) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Watcher for FinishWatcher {
+    type Change = Vec<node::Data>;
+    /// Check for runs that have finished since the previous check, returning them in the order
+    /// they were started. The first check only records the runs that already exist, unless
+    /// catching up with runs stopped after an earlier watch.
+    async fn check(&mut self) -> ClientResult<Self::Change> {
+        let runs = self
+            .search(&[
+                ("sort", "-start.time".into()),
+                ("page[limit]", RECENT_RUNS.to_string()),
+            ])
+            .await?;
+        let mut finished = match self.after.filter(|_| !self.baselined) {
+            Some(after) => self.stopped_after(after, &runs).await?,
+            None => Vec::new(),
+        };
+        let (mut running, mut seen) = (HashSet::new(), HashSet::new());
+        for run in runs {
+            seen.insert(run.id.clone());
+            if !is_finished(&run) {
+                running.insert(run.id.clone());
+            } else if self.baselined
+                // Runs can start and finish between checks
+                && (self.running.contains(&run.id) || !self.seen.contains(&run.id))
+            {
+                finished.push(run);
+            }
+        }
+        let earlier = self.running.iter().filter(|id| !seen.contains(*id));
+        self.check_earlier(earlier, &mut running, &mut finished)
+            .await?;
+        self.baselined = true;
+        self.running = running;
+        self.seen = seen;
+        finished.sort_by(|a, b| {
+            let (a, b) = (
+                start_time(a).unwrap_or_default(),
+                start_time(b).unwrap_or_default(),
+            );
+            a.total_cmp(&b)
+        });
+        Ok(finished)
+    }
+    fn persistent(&self) -> bool {
+        self.persistent
+    }
+}
+
+/// Watch a backend for runs that finish after the watch starts, or after the given time if
+/// catching up with an earlier watch. Tiled is searched using the backend's service credentials,
+/// if any. Errors are retried with a backoff so the stream never ends.
+pub fn runs_finished(
+    backend: Backend,
+    interval: Duration,
+    after: Option<f64>,
+) -> impl Stream<Item = ClientResult<node::Data>> {
+    let watcher = FinishWatcher {
+        after,
+        persistent: true,
+        ..FinishWatcher::new(backend, None, None)
+    };
+    watch_finished(watcher, interval)
+}
+
+/// Watch a backend for runs in an instrument session that finish after the watch starts, or
+/// after the given time if resuming an earlier watch
+pub fn session_runs_finished(
+    backend: Backend,
+    session: String,
+    headers: Option<HeaderMap>,
+    interval: Duration,
+    after: Option<f64>,
+) -> impl Stream<Item = ClientResult<node::Data>> {
+    let watcher = FinishWatcher {
+        after,
+        ..FinishWatcher::new(backend, Some(session), headers)
+    };
+    watch_finished(watcher, interval)
+}
+
+fn watch_finished(
+    watcher: FinishWatcher,
+    interval: Duration,
+) -> impl Stream<Item = ClientResult<node::Data>> {
+    watch(watcher, interval, Vec::new).flat_map(|runs| match runs {
+        Ok(runs) => stream::iter(runs.into_iter().map(Ok).collect::<Vec<_>>()),
+        Err(err) => stream::iter(vec![Err(err)]),
+    })
+}
+
+/// Status of the run, if the node is a run
+pub fn run_status(run: &node::Data) -> Option<RunStatus> {
+    match &*run.attributes {
+        NodeAttributes::Container(attrs) => attrs.metadata.run().map(RunMetadata::status),
+        _ => None,
+    }
+}
+
+/// Whether the run has a stop document
+pub fn is_finished(run: &node::Data) -> bool {
+    run_status(run).is_some_and(|status| status != RunStatus::Running)
+}
+
+/// The status and streams of a run after it changed
+#[derive(Debug, Clone, Serialize, SimpleObject)]
+pub struct RunStatusChange {
+    pub id: String,
+    pub status: RunStatus,
+    /// All the streams of the run
+    pub streams: Vec<String>,
+    /// Streams added since the previous change
+    pub added_streams: Vec<String>,
+}
+
+/// Watches a run for changes to its status and streams
+struct RunWatcher {
+    client: TiledClient,
+    headers: Option<HeaderMap>,
+    id: String,
+    status: Option<RunStatus>,
+    /// Number of children of the run container, used to avoid listing the streams when nothing
+    /// has been added
+    count: Option<i64>,
+    streams: Vec<String>,
+}
+
+impl Watcher for RunWatcher {
+    type Change = Option<RunStatusChange>;
+    async fn check(&mut self) -> ClientResult<Self::Change> {
+        let run = self
+            .client
+            .metadata(self.id.clone(), self.headers.clone())
+            .await?
+            .into_data();
+        let status = run_status(&run).unwrap_or(RunStatus::Running);
+        let count = match &*run.attributes {
+            NodeAttributes::Container(attrs) => Some(attrs.structure.count),
+            _ => None,
+        };
+        let mut added_streams = vec![];
+        if count != self.count {
+            let children = self
+                .client
+                .search(&self.id, self.headers.clone(), &[])
+                .await?;
+            for stream in children.into_data() {
+                if !self.streams.contains(&stream.id) {
+                    self.streams.push(stream.id.clone());
+                    added_streams.push(stream.id);
+                }
+            }
+            self.count = count;
+        }
+        if Some(status) == self.status && added_streams.is_empty() {
+            return Ok(None);
+        }
+        self.status = Some(status);
+        Ok(Some(RunStatusChange {
+            id: self.id.clone(),
+            status,
+            streams: self.streams.clone(),
+            added_streams,
+        }))
+    }
+    fn done(&self) -> bool {
+        self.status
+            .is_some_and(|status| status != RunStatus::Running)
+    }
+}
+
+/// Watch a run for changes to its status or streams. The current state of the run is sent
+/// first and the stream ends once the run has finished or been aborted.
+pub fn run_status_changes(
+    backend: Backend,
+    id: String,
+    headers: Option<HeaderMap>,
+    interval: Duration,
+) -> impl Stream<Item = ClientResult<RunStatusChange>> {
+    let watcher = RunWatcher {
+        client: backend.client.uncached(),
+        headers,
+        id,
+        status: None,
+        count: None,
+        streams: vec![],
+    };
+    watch(watcher, interval, || None).filter_map(|change| async move { change.transpose() })
+}
+
+/// Rows added to a table since the previous batch
+pub struct RowBatch {
+    /// Index of the first row in the batch
+    pub offset: usize,
+    pub rows: table::Table,
+}
+
+/// Reads rows from a table as they are added, until the run it belongs to is finished
+struct TableWatcher {
+    client: TiledClient,
+    headers: Option<HeaderMap>,
+    run: String,
+    path: String,
+    columns: Option<Vec<String>>,
+    /// Partition that rows were last read from. Earlier partitions are assumed to be complete.
+    partition: usize,
+    /// Rows already read from the current partition
+    partition_rows: usize,
+    /// Rows read from all partitions
+    rows: usize,
+    finished: bool,
+}
+
+impl Watcher for TableWatcher {
+    type Change = Option<RowBatch>;
+    async fn check(&mut self) -> ClientResult<Self::Change> {
+        // The run is checked before the table so that every row written before the run finished
+        // is read before the watch ends. Neither read is cached so the table can't be older than
+        // the run.
+        let run = self
+            .client
+            .metadata(self.run.clone(), self.headers.clone())
+            .await?;
+        self.finished = is_finished(&run.into_data());
+        let table = self
+            .client
+            .metadata(self.path.clone(), self.headers.clone())
+            .await?
+            .into_data();
+        let partitions = match &*table.attributes {
+            NodeAttributes::Table(attrs) => attrs.structure.npartitions.max(1) as usize,
+            _ => 1,
+        };
+        let offset = self.rows;
+        let mut batch = table::Table::new();
+        for partition in self.partition..partitions {
+            let rows = self
+                .client
+                .table_partition(
+                    &self.path,
+                    partition,
+                    self.columns.as_deref(),
+                    self.headers.clone(),
+                )
+                .await?;
+            let len = rows.values().map(Vec::len).max().unwrap_or_default();
+            let skip = if partition == self.partition {
+                self.partition_rows
+            } else {
+                0
+            };
+            for (column, values) in rows {
+                batch
+                    .entry(column)
+                    .or_default()
+                    .extend(values.into_iter().skip(skip));
+            }
+            self.rows += len.saturating_sub(skip);
+            self.partition = partition;
+            self.partition_rows = len;
+        }
+        Ok((self.rows > offset).then_some(RowBatch {
+            offset,
+            rows: batch,
+        }))
+    }
+    fn done(&self) -> bool {
+        self.finished
+    }
+}
+
+/// Watch a table of a run for new rows. The rows that already exist are included in the first
+/// batch and the stream ends once the run is finished and all its rows have been read.
+pub fn table_rows(
+    backend: Backend,
+    run: String,
+    path: String,
+    columns: Option<Vec<String>>,
+    headers: Option<HeaderMap>,
+    interval: Duration,
+) -> impl Stream<Item = ClientResult<RowBatch>> {
+    let watcher = TableWatcher {
+        client: backend.client.uncached(),
+        headers,
+        run,
+        path,
+        columns,
+        partition: 0,
+        partition_rows: 0,
+        rows: 0,
+        finished: false,
+    };
+    watch(watcher, interval, || None).filter_map(|batch| async move { batch.transpose() })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures_util::StreamExt as _;
+    use httpmock::MockServer;
+    use serde_json::{Value, json};
+    use tokio::time::timeout;
+
+    use super::{run_status_changes, runs_added, runs_finished, table_rows};
+    use crate::backends::Backends;
+    use crate::clients::{ClientError, TiledClient};
+    use crate::config::{CacheConfig, GlazedConfig, TiledClientConfig};
+    use crate::model::run::RunStatus;
+    use crate::test_utils::read_json;
+
+    fn search_results(ids: &[&str]) -> Value {
+        let template: Value = read_json("resources/search_root.json");
+        let run = template["data"][0].clone();
+        let data = ids
+            .iter()
+            .enumerate()
+            .map(|(i, id)| {
+                let mut run = run.clone();
+                run["id"] = json!(id);
+                run["attributes"]["metadata"]["start"]["time"] = json!(1000.0 + i as f64);
+                run
+            })
+            .collect::<Vec<_>>();
+        json!({"data": data, "error": null, "links": null, "meta": {}})
+    }
+
+    #[tokio::test]
+    async fn new_runs_reported() {
+        let server = MockServer::start();
+        let existing = server
+            .mock_async(|when, then| {
+                when.method("GET").path("/api/v1/search/");
+                then.status(200).json_body(search_results(&["first"]));
+            })
+            .await;
+        let backends = Backends::single(TiledClient::for_mock_server(&server));
+        let mut runs = Box::pin(runs_added(
+            backends.default_backend().clone(),
+            "cm12345-1".into(),
+            None,
+            Duration::from_millis(10),
+            None,
+        ));
+        // The first poll only records the existing runs
+        let first = tokio::time::timeout(Duration::from_millis(100), runs.next()).await;
+        assert!(first.is_err(), "Existing run should not be reported");
+        existing.delete_async().await;
+        server
+            .mock_async(|when, then| {
+                when.method("GET").path("/api/v1/search/");
+                then.status(200)
+                    .json_body(search_results(&["first", "second", "third"]));
+            })
+            .await;
+        let ids = runs
+            .take(2)
+            .map(|run| run.unwrap().id)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(ids, ["second", "third"]);
+    }
+
+    #[tokio::test]
+    async fn every_page_searched_without_cursor() {
+        let server = MockServer::start();
+        let mut app: Value = read_json("resources/metadata_app.json");
+        app["queries"] = json!(["eq"]);
+        server
+            .mock_async(|when, then| {
+                when.method("GET").path("/api/v1/");
+                then.status(200).json_body(app);
+            })
+            .await;
+        let mut first_page = search_results(&["first", "second"]);
+        first_page["links"] = json!({"self": "page-1", "next": "page-2"});
+        server
+            .mock_async(|when, then| {
+                when.method("GET")
+                    .path("/api/v1/search/")
+                    .query_param("sort", "start.time")
+                    .query_param("page[offset]", "0");
+                then.status(200).json_body(first_page);
+            })
+            .await;
+        let empty = server
+            .mock_async(|when, then| {
+                when.method("GET")
+                    .path("/api/v1/search/")
+                    .query_param("page[offset]", "2");
+                then.status(200).json_body(search_results(&[]));
+            })
+            .await;
+        let client = TiledClient::for_mock_server(&server);
+        client.negotiate().await.unwrap();
+        let backends = Backends::single(client);
+        let mut runs = Box::pin(runs_added(
+            backends.default_backend().clone(),
+            "cm12345-1".into(),
+            None,
+            Duration::from_millis(10),
+            None,
+        ));
+        let first = tokio::time::timeout(Duration::from_millis(100), runs.next()).await;
+        assert!(first.is_err(), "Existing runs should not be reported");
+        empty.delete_async().await;
+        server
+            .mock_async(|when, then| {
+                when.method("GET")
+                    .path("/api/v1/search/")
+                    .query_param("page[offset]", "2");
+                then.status(200).json_body(search_results(&["third"]));
+            })
+            .await;
+        assert_eq!(runs.next().await.unwrap().unwrap().id, "third");
+    }
+
+    /// Search results listing the newest run first, with the given runs still running
+    fn recent_runs(ids: &[&str], running: &[&str]) -> Value {
+        let mut results = search_results(ids);
+        for (i, run) in results["data"]
+            .as_array_mut()
+            .unwrap()
+            .iter_mut()
+            .enumerate()
+        {
+            run["attributes"]["metadata"]["start"]["time"] = json!(2000.0 - i as f64);
+            if running.contains(&run["id"].as_str().unwrap()) {
+                run["attributes"]["metadata"]["stop"] = Value::Null;
+            }
+        }
+        results
+    }
+
+    #[tokio::test]
+    async fn finished_runs_reported() {
+        let server = MockServer::start();
+        let existing = server
+            .mock_async(|when, then| {
+                when.method("GET")
+                    .path("/api/v1/search/")
+                    .query_param("sort", "-start.time");
+                then.status(200)
+                    .json_body(recent_runs(&["running", "old"], &["running"]));
+            })
+            .await;
+        let backends = Backends::single(TiledClient::for_mock_server(&server));
+        let mut runs = Box::pin(runs_finished(
+            backends.default_backend().clone(),
+            Duration::from_millis(10),
+            None,
+        ));
+        let first = tokio::time::timeout(Duration::from_millis(100), runs.next()).await;
+        assert!(
+            first.is_err(),
+            "Runs finished before the watch should not be reported"
+        );
+        existing.delete_async().await;
+        server
+            .mock_async(|when, then| {
+                when.method("GET").path("/api/v1/search/");
+                then.status(200).json_body(recent_runs(
+                    &["latest", "new", "running", "old"],
+                    &["latest"],
+                ));
+            })
+            .await;
+        let ids = runs
+            .take(2)
+            .map(|run| run.unwrap().id)
+            .collect::<Vec<_>>()
+            .await;
+        // Runs that started and finished between checks are reported too
+        assert_eq!(ids, ["running", "new"]);
+    }
+
+    #[tokio::test]
+    async fn long_running_runs_checked_individually() {
+        let server = MockServer::start();
+        let existing = server
+            .mock_async(|when, then| {
+                when.method("GET").path("/api/v1/search/");
+                then.status(200)
+                    .json_body(recent_runs(&["long"], &["long"]));
+            })
+            .await;
+        let backends = Backends::single(TiledClient::for_mock_server(&server));
+        let mut runs = Box::pin(runs_finished(
+            backends.default_backend().clone(),
+            Duration::from_millis(10),
+            None,
+        ));
+        let first = tokio::time::timeout(Duration::from_millis(100), runs.next()).await;
+        assert!(first.is_err(), "Running run should not be reported");
+        existing.delete_async().await;
+        // The running run has been pushed out of the recent runs by newer ones
+        server
+            .mock_async(|when, then| {
+                when.method("GET").path("/api/v1/search/");
+                then.status(200)
+                    .json_body(recent_runs(&["newer"], &["newer"]));
+            })
+            .await;
+        let mut long = run_metadata(true);
+        long["data"]["id"] = json!("long");
+        let metadata = server
+            .mock_async(|when, then| {
+                when.method("GET").path("/api/v1/metadata/long");
+                then.status(200).json_body(long);
+            })
+            .await;
+        assert_eq!(runs.next().await.unwrap().unwrap().id, "long");
+        metadata.assert();
+    }
+
+    #[tokio::test]
+    async fn runs_stopped_since_earlier_watch_reported() {
+        let server = MockServer::start();
+        let mut results = recent_runs(&["recent", "old"], &[]);
+        results["data"][1]["attributes"]["metadata"]["stop"]["time"] = json!(1000.0);
+        server
+            .mock_async(|when, then| {
+                when.method("GET").path("/api/v1/search/");
+                then.status(200).json_body(results);
+            })
+            .await;
+        let backends = Backends::single(TiledClient::for_mock_server(&server));
+        let mut runs = Box::pin(runs_finished(
+            backends.default_backend().clone(),
+            Duration::from_millis(10),
+            Some(2000.0),
+        ));
+        assert_eq!(runs.next().await.unwrap().unwrap().id, "recent");
+        let next = tokio::time::timeout(Duration::from_millis(100), runs.next()).await;
+        assert!(
+            next.is_err(),
+            "Runs stopped before the earlier watch should not be reported"
+        );
+    }
+
+    #[tokio::test]
+    async fn background_watch_retries_rejected_requests() {
+        let server = MockServer::start();
+        let rejected = server
+            .mock_async(|when, then| {
+                when.method("GET").path("/api/v1/search/");
+                then.status(403).json_body(json!({"detail": "Forbidden"}));
+            })
+            .await;
+        let backends = Backends::single(TiledClient::for_mock_server(&server));
+        let mut runs = Box::pin(runs_finished(
+            backends.default_backend().clone(),
+            Duration::from_millis(10),
+            Some(0.0),
+        ));
+        let first = tokio::time::timeout(Duration::from_millis(100), runs.next()).await;
+        assert!(first.is_err(), "Rejected requests should not end the watch");
+        assert!(rejected.calls() >= 2);
+        rejected.delete_async().await;
+        server
+            .mock_async(|when, then| {
+                when.method("GET").path("/api/v1/search/");
+                then.status(200).json_body(recent_runs(&["finished"], &[]));
+            })
+            .await;
+        assert_eq!(runs.next().await.unwrap().unwrap().id, "finished");
+    }
+
+    #[tokio::test]
+    async fn cursor_used_when_supported() {
+        let server = MockServer::start();
+        server
+            .mock_async(|when, then| {
+                when.method("GET").path("/api/v1/");
+                then.status(200)
+                    .body_from_file("resources/metadata_app.json");
+            })
+            .await;
+        let baseline = server
+            .mock_async(|when, then| {
+                when.method("GET")
+                    .path("/api/v1/search/")
+                    .query_param("sort", "-start.time")
+                    .query_param("page[limit]", "1");
+                then.status(200).json_body(search_results(&["first"]));
+            })
+            .await;
+        let poll = server
+            .mock_async(|when, then| {
+                when.method("GET")
+                    .path("/api/v1/search/")
+                    .query_param("filter[comparison][condition][operator]", "ge")
+                    .query_param("filter[comparison][condition][value]", "1000");
+                then.status(200)
+                    .json_body(search_results(&["first", "second"]));
+            })
+            .await;
+        let client = TiledClient::for_mock_server(&server);
+        client.negotiate().await.unwrap();
+        let backends = Backends::single(client);
+        let mut runs = Box::pin(runs_added(
+            backends.default_backend().clone(),
+            "cm12345-1".into(),
+            None,
+            Duration::from_millis(10),
+            None,
+        ));
+        assert_eq!(runs.next().await.unwrap().unwrap().id, "second");
+        baseline.assert();
+        assert!(poll.calls() >= 1);
+    }
+
+    #[tokio::test]
+    async fn rejected_requests_end_watch() {
+        let server = MockServer::start();
+        server
+            .mock_async(|when, then| {
+                when.method("GET").path("/api/v1/search/");
+                then.status(403).json_body(json!({"detail": "Forbidden"}));
+            })
+            .await;
+        let backends = Backends::single(TiledClient::for_mock_server(&server));
+        let runs = runs_added(
+            backends.default_backend().clone(),
+            "cm12345-1".into(),
+            None,
+            Duration::from_millis(10),
+            None,
+        )
+        .collect::<Vec<_>>()
+        .await;
+        let [Err(ClientError::TiledRequest(403, _))] = &runs[..] else {
+            panic!("Expected single rejected request error");
+        };
+    }
+
+    fn run_metadata(finished: bool) -> Value {
+        let mut run: Value = read_json("resources/metadata_run.json");
+        if !finished {
+            run["data"]["attributes"]["metadata"]["stop"] = Value::Null;
+        }
+        run
+    }
+
+    fn rows(count: usize) -> Value {
+        json!({"seq_num": (1..=count).collect::<Vec<_>>()})
+    }
+
+    /// A client that caches responses, to check that watchers don't use it
+    fn cached_client(server: &MockServer) -> TiledClient {
+        TiledClient::from_config(&TiledClientConfig {
+            address: server.base_url().parse().unwrap(),
+            cache: Some(CacheConfig::default()),
+            ..GlazedConfig::default().tiled_client
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn table_rows_until_finished() {
+        let server = MockServer::start();
+        let running = server
+            .mock_async(|when, then| {
+                when.method("GET").path("/api/v1/metadata/run");
+                then.status(200).json_body(run_metadata(false));
+            })
+            .await;
+        server
+            .mock_async(|when, then| {
+                when.method("GET")
+                    .path("/api/v1/metadata/run/primary/internal");
+                then.status(200)
+                    .body_from_file("resources/metadata_table.json");
+            })
+            .await;
+        let first_rows = server
+            .mock_async(|when, then| {
+                when.method("GET")
+                    .path("/api/v1/table/partition/run/primary/internal")
+                    .query_param("partition", "0")
+                    .query_param("column", "seq_num");
+                then.status(200).json_body(rows(2));
+            })
+            .await;
+        let backends = Backends::single(cached_client(&server));
+        let mut batches = Box::pin(table_rows(
+            backends.default_backend().clone(),
+            "run".into(),
+            "run/primary/internal".into(),
+            Some(vec!["seq_num".into()]),
+            None,
+            Duration::from_millis(10),
+        ));
+        let batch = batches.next().await.unwrap().unwrap();
+        assert_eq!(batch.offset, 0);
+        assert_eq!(batch.rows["seq_num"], [json!(1), json!(2)]);
+
+        running.delete_async().await;
+        first_rows.delete_async().await;
+        server
+            .mock_async(|when, then| {
+                when.method("GET").path("/api/v1/metadata/run");
+                then.status(200).json_body(run_metadata(true));
+            })
+            .await;
+        server
+            .mock_async(|when, then| {
+                when.method("GET")
+                    .path("/api/v1/table/partition/run/primary/internal");
+                then.status(200).json_body(rows(5));
+            })
+            .await;
+        // Well within the time responses are cached for
+        let batch = timeout(Duration::from_secs(1), batches.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(batch.offset, 2);
+        assert_eq!(batch.rows["seq_num"], [json!(3), json!(4), json!(5)]);
+        assert!(batches.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn run_status_until_finished() {
+        let server = MockServer::start();
+        let running = server
+            .mock_async(|when, then| {
+                when.method("GET").path("/api/v1/metadata/run");
+                then.status(200).json_body(run_metadata(false));
+            })
+            .await;
+        let streams = server
+            .mock_async(|when, then| {
+                when.method("GET").path("/api/v1/search/run");
+                then.status(200)
+                    .body_from_file("resources/search_run_container.json");
+            })
+            .await;
+        let backends = Backends::single(cached_client(&server));
+        let mut changes = Box::pin(run_status_changes(
+            backends.default_backend().clone(),
+            "run".into(),
+            None,
+            Duration::from_millis(10),
+        ));
+        let change = changes.next().await.unwrap().unwrap();
+        assert_eq!(change.status, RunStatus::Running);
+        assert_eq!(change.added_streams, ["primary"]);
+
+        running.delete_async().await;
+        server
+            .mock_async(|when, then| {
+                when.method("GET").path("/api/v1/metadata/run");
+                then.status(200).json_body(run_metadata(true));
+            })
+            .await;
+        let change = timeout(Duration::from_secs(1), changes.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(change.status, RunStatus::Finished);
+        assert_eq!(change.streams, ["primary"]);
+        assert!(change.added_streams.is_empty());
+        assert!(changes.next().await.is_none());
+        // The streams are only listed again if the number of children changes
+        streams.assert_calls(1);
+    }
+}
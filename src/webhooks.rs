@@ -0,0 +1,740 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufRead as _, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures_util::StreamExt as _;
+use futures_util::future::join_all;
+use reqwest::Client;
+use reqwest::header::CONTENT_TYPE;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tracing::{error, info, warn};
+use url::Url;
+
+use crate::audit::{self, RotatingFile};
+use crate::backends::{Backend, Backends};
+use crate::clients::{ClientError, ClientResult};
+use crate::config::{RetryConfig, Webhook, WebhookConfig};
+use crate::model::node::{self, NodeAttributes};
+use crate::model::run::{RunMetadata, Start};
+use crate::watch;
+
+const EVENT_HEADER: &str = "x-glazed-event";
+const DELIVERY_HEADER: &str = "x-glazed-delivery";
+/// Hex encoded HMAC-SHA256 of the request body, prefixed with `sha256=`
+const SIGNATURE_HEADER: &str = "x-glazed-signature";
+
+const RUN_FINISHED: &str = "run_finished";
+
+#[derive(Debug)]
+pub enum WebhookError {
+    Secret(ClientError),
+    DeliveryLog(PathBuf, io::Error),
+    Client(reqwest::Error),
+}
+
+impl std::fmt::Display for WebhookError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WebhookError::Secret(err) => write!(f, "Unable to read webhook secret: {err}"),
+            WebhookError::DeliveryLog(path, err) => {
+                write!(f, "Unable to open webhook delivery log {path:?}: {err}")
+            }
+            WebhookError::Client(err) => write!(f, "Unable to create webhook client: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for WebhookError {}
+
+impl Webhook {
+    /// Whether a run with the given start document should be sent to this webhook
+    fn matches(&self, start: &Start) -> bool {
+        let matches =
+            |filter: &Option<String>, value: &str| filter.as_deref().is_none_or(|f| f == value);
+        matches(&self.instrument, &start.instrument)
+            && matches(&self.plan_name, &start.plan_name)
+            && matches(&self.instrument_session, &start.instrument_session)
+    }
+}
+
+/// Record of a single delivery (including any retries), written as one line of JSON to the
+/// delivery log
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeliveryRecord {
+    /// Time the delivery was finished in milliseconds since the Unix epoch
+    pub timestamp: u64,
+    pub delivery: String,
+    pub url: Url,
+    pub backend: String,
+    pub run: String,
+    pub attempts: u32,
+    /// HTTP status of the last response from the receiver
+    pub status: Option<u16>,
+    pub delivered: bool,
+    /// Why the last attempt failed
+    pub error: Option<String>,
+}
+
+/// Destination for delivery records. Records are discarded if no log is configured.
+#[derive(Clone, Default)]
+struct DeliveryLog(Option<Arc<Mutex<RotatingFile>>>);
+
+impl DeliveryLog {
+    fn record(&self, record: &DeliveryRecord) {
+        let Some(file) = &self.0 else {
+            return;
+        };
+        let mut line = match serde_json::to_string(record) {
+            Ok(line) => line,
+            Err(err) => {
+                error!("Unable to serialize delivery record {record:?}: {err}");
+                return;
+            }
+        };
+        line.push('\n');
+        let mut file = file.lock().expect("Delivery log lock poisoned");
+        if let Err(err) = file.write_line(line.as_bytes()) {
+            error!("Unable to write delivery record {record:?}: {err}");
+        }
+    }
+}
+
+/// Deliveries made before glazed was last started, read back from the delivery log
+#[derive(Default)]
+struct History {
+    /// Backend, run and url of each delivery that was accepted by the receiver
+    delivered: HashSet<(String, String, Url)>,
+    /// Runs on each backend whose last delivery to any hook failed
+    undelivered: HashMap<String, HashSet<String>>,
+    /// Time (in milliseconds since the Unix epoch) of the last delivery
+    last: Option<u64>,
+}
+
+impl History {
+    /// Read the delivery log, if it exists. Lines that can't be read are skipped.
+    fn read(path: &Path) -> io::Result<Self> {
+        let file = match std::fs::File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(err),
+        };
+        let mut latest = HashMap::new();
+        let mut last = None;
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            match serde_json::from_str::<DeliveryRecord>(&line) {
+                Ok(record) => {
+                    last = last.max(Some(record.timestamp));
+                    let key = (record.backend, record.run, record.url);
+                    latest.insert(key, record.delivered);
+                }
+                Err(err) => warn!("Skipping unreadable delivery record '{line}': {err}"),
+            }
+        }
+        let mut history = Self {
+            last,
+            ..Self::default()
+        };
+        for (key, delivered) in latest {
+            if delivered {
+                history.delivered.insert(key);
+            } else {
+                let (backend, run, _) = key;
+                history.undelivered.entry(backend).or_default().insert(run);
+            }
+        }
+        Ok(history)
+    }
+
+    fn is_delivered(&self, backend: &str, run: &str, url: &Url) -> bool {
+        self.delivered
+            .contains(&(backend.into(), run.into(), url.clone()))
+    }
+}
+
+struct Hook {
+    config: Webhook,
+    key: Option<hmac::Key>,
+}
+
+/// Watches every backend for runs that finish and sends them to the configured webhooks
+#[derive(Clone)]
+pub struct Webhooks(Arc<WebhooksInner>);
+
+struct WebhooksInner {
+    hooks: Vec<Hook>,
+    client: Client,
+    retry: RetryConfig,
+    interval: Duration,
+    log: DeliveryLog,
+    history: History,
+    /// Address that glazed is served from, used to build links to the assets of a run
+    public_address: Url,
+}
+
+impl Webhooks {
+    pub fn new(config: &WebhookConfig, public_address: Url) -> Result<Self, WebhookError> {
+        let hooks = config
+            .hooks
+            .iter()
+            .map(|hook| {
+                let key = hook
+                    .secret
+                    .as_ref()
+                    .map(|secret| secret.read())
+                    .transpose()
+                    .map_err(WebhookError::Secret)?
+                    .map(|secret| hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()));
+                Ok(Hook {
+                    config: hook.clone(),
+                    key,
+                })
+            })
+            .collect::<Result<_, WebhookError>>()?;
+        let (log, history) = match &config.delivery_log {
+            Some(path) => {
+                let history =
+                    History::read(path).map_err(|e| WebhookError::DeliveryLog(path.clone(), e))?;
+                let file = RotatingFile::open(path.clone(), None, 0)
+                    .map_err(|e| WebhookError::DeliveryLog(path.clone(), e))?;
+                (DeliveryLog(Some(Arc::new(Mutex::new(file)))), history)
+            }
+            None => (DeliveryLog::default(), History::default()),
+        };
+        let client = Client::builder()
+            .timeout(Duration::from_secs(config.timeout))
+            .user_agent(concat!("glazed/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .map_err(WebhookError::Client)?;
+        Ok(Self(Arc::new(WebhooksInner {
+            hooks,
+            client,
+            retry: config.retry.clone(),
+            interval: Duration::from_millis(config.poll_interval),
+            log,
+            history,
+            public_address,
+        })))
+    }
+
+    /// Start watching each backend for finished runs. Nothing is watched if there are no hooks.
+    /// Runs that could not be delivered before glazed was restarted are retried and runs that
+    /// finished since the last delivery in the log are sent.
+    pub fn start(&self, backends: &Backends) {
+        if self.0.hooks.is_empty() {
+            return;
+        }
+        info!("Sending finished runs to {} webhook(s)", self.0.hooks.len());
+        for backend in backends.all() {
+            tokio::spawn(self.clone().watch(backend.as_service()));
+        }
+    }
+
+    async fn watch(self, backend: Backend) {
+        self.retry_undelivered(&backend).await;
+        let after = self.0.history.last.map(|last| last as f64 / 1000.0);
+        let mut runs = Box::pin(watch::runs_finished(
+            backend.clone(),
+            self.0.interval,
+            after,
+        ));
+        while let Some(run) = runs.next().await {
+            match run {
+                Ok(run) => {
+                    tokio::spawn(self.clone().notify(backend.clone(), run));
+                }
+                Err(err) => error!(
+                    "Unable to watch backend '{}' for finished runs: {err}",
+                    backend.name
+                ),
+            }
+        }
+    }
+
+    /// Send the runs whose last delivery failed before glazed was restarted again
+    async fn retry_undelivered(&self, backend: &Backend) {
+        let Some(runs) = self.0.history.undelivered.get(&*backend.name) else {
+            return;
+        };
+        info!(
+            "Retrying {} undelivered run(s) from backend '{}'",
+            runs.len(),
+            backend.name
+        );
+        for run in runs {
+            match backend.client.metadata(run.clone(), None).await {
+                Ok(metadata) => {
+                    tokio::spawn(self.clone().notify(backend.clone(), metadata.into_data()));
+                }
+                Err(err) => warn!("Unable to read run {run} to retry its delivery: {err}"),
+            }
+        }
+    }
+
+    /// Send a finished run to every hook whose filters it matches, unless it was delivered to the
+    /// hook before glazed was restarted
+    async fn notify(self, backend: Backend, run: node::Data) {
+        let NodeAttributes::Container(attrs) = &*run.attributes else {
+            return;
+        };
+        let Some(metadata) = attrs.metadata.run() else {
+            return;
+        };
+        let hooks = self
+            .0
+            .hooks
+            .iter()
+            .filter(|hook| {
+                hook.config.matches(&metadata.start)
+                    && !self
+                        .0
+                        .history
+                        .is_delivered(&backend.name, &run.id, &hook.config.url)
+            })
+            .collect::<Vec<_>>();
+        if hooks.is_empty() {
+            return;
+        }
+        let assets = self
+            .asset_links(&backend, &run.id)
+            .await
+            .unwrap_or_else(|err| {
+                warn!(
+                    "Unable to list assets of run {} for webhooks: {err}",
+                    run.id
+                );
+                vec![]
+            });
+        let body = payload(&backend, &run.id, metadata, assets).to_string();
+        join_all(hooks.into_iter().map(|hook| async {
+            let record = self
+                .deliver(hook, body.as_bytes(), &backend.name, &run.id)
+                .await;
+            self.0.log.record(&record);
+        }))
+        .await;
+    }
+
+    /// Links to download each asset of the arrays in the run
+    async fn asset_links(&self, backend: &Backend, run: &str) -> ClientResult<Vec<Value>> {
+        let client = &backend.client;
+        let query = [("include_data_sources", "true".into())];
+        let mut links = Vec::new();
+        for stream in client.search(run, None, &[]).await?.into_data() {
+            let path = format!("{run}/{}", stream.id);
+            for data in client.search(&path, None, &query).await?.into_data() {
+                let NodeAttributes::Array(attrs) = &*data.attributes else {
+                    continue;
+                };
+                let assets = attrs
+                    .data_sources
+                    .iter()
+                    .flatten()
+                    .flat_map(|source| &source.assets);
+                for asset in assets {
+                    let Some(id) = asset.id else {
+                        continue;
+                    };
+                    let route = if asset.is_directory {
+                        "archive"
+                    } else {
+                        "asset"
+                    };
+                    let mut download = self.0.public_address.clone();
+                    if let Ok(mut segments) = download.path_segments_mut() {
+                        segments.pop_if_empty().extend([
+                            route,
+                            run,
+                            &stream.id,
+                            &data.id,
+                            &id.to_string(),
+                        ]);
+                    }
+                    if !backend.is_default() {
+                        download
+                            .query_pairs_mut()
+                            .append_pair("backend", &backend.name);
+                    }
+                    links.push(json!({
+                        "stream": stream.id,
+                        "detector": data.id,
+                        "file": asset.data_uri,
+                        "download": download,
+                    }));
+                }
+            }
+        }
+        Ok(links)
+    }
+
+    /// POST the body to the hook, retrying with backoff until it is accepted or the retries are
+    /// used up. Requests rejected by the receiver (other 4xx responses) are not retried.
+    async fn deliver(&self, hook: &Hook, body: &[u8], backend: &str, run: &str) -> DeliveryRecord {
+        let mut record = DeliveryRecord {
+            timestamp: 0,
+            delivery: format!("{:032x}", fastrand::u128(..)),
+            url: hook.config.url.clone(),
+            backend: backend.into(),
+            run: run.into(),
+            attempts: 0,
+            status: None,
+            delivered: false,
+            error: None,
+        };
+        let signature = hook.key.as_ref().map(|key| {
+            let tag = hmac::sign(key, body);
+            let hex = tag
+                .as_ref()
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect::<String>();
+            format!("sha256={hex}")
+        });
+        for attempt in 0..=self.0.retry.retries {
+            if attempt > 0 {
+                tokio::time::sleep(self.0.retry.backoff(attempt - 1)).await;
+            }
+            record.attempts += 1;
+            let mut request = self
+                .0
+                .client
+                .post(hook.config.url.clone())
+                .header(CONTENT_TYPE, "application/json")
+                .header(EVENT_HEADER, RUN_FINISHED)
+                .header(DELIVERY_HEADER, &record.delivery)
+                .body(body.to_vec());
+            if let Some(signature) = &signature {
+                request = request.header(SIGNATURE_HEADER, signature);
+            }
+            match request.send().await {
+                Ok(response) if response.status().is_success() => {
+                    record.status = Some(response.status().as_u16());
+                    record.delivered = true;
+                    record.error = None;
+                    break;
+                }
+                Ok(response) => {
+                    let status = response.status();
+                    record.status = Some(status.as_u16());
+                    record.error = Some(format!("Receiver responded with {status}"));
+                    if status.is_client_error() && !matches!(status.as_u16(), 408 | 429) {
+                        break;
+                    }
+                }
+                Err(err) => record.error = Some(err.to_string()),
+            }
+        }
+        if !record.delivered {
+            warn!(
+                "Unable to deliver webhook to {} after {} attempt(s): {}",
+                record.url,
+                record.attempts,
+                record.error.as_deref().unwrap_or_default()
+            );
+        }
+        record.timestamp = audit::unix_millis();
+        record
+    }
+}
+
+/// Summary of a finished run sent to webhooks
+fn payload(backend: &Backend, id: &str, run: &RunMetadata, assets: Vec<Value>) -> Value {
+    let start = &run.start;
+    json!({
+        "event": RUN_FINISHED,
+        "backend": &*backend.name,
+        "id": id,
+        "status": run.status(),
+        "start": {
+            "uid": start.uid,
+            "time": start.time,
+            "instrument": start.instrument,
+            "instrument_session": start.instrument_session,
+            "scan_id": start.scan_id,
+            "plan_name": start.plan_name,
+            "detectors": start.detectors,
+        },
+        "stop": run.stop.as_ref().map(|stop| json!({
+            "time": stop.time,
+            "exit_status": stop.exit_status,
+            "reason": stop.reason,
+            "num_events": stop.num_events,
+        })),
+        "assets": assets,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use httpmock::MockServer;
+    use serde_json::json;
+
+    use super::{DeliveryRecord, Webhooks};
+    use crate::audit;
+    use crate::backends::Backends;
+    use crate::clients::TiledClient;
+    use crate::config::{RetryConfig, Secret, Webhook, WebhookConfig};
+    use crate::model::node;
+    use crate::test_utils::read_json;
+
+    fn hook(url: String) -> Webhook {
+        Webhook {
+            url: url.parse().unwrap(),
+            instrument: None,
+            plan_name: None,
+            instrument_session: None,
+            secret: None,
+        }
+    }
+
+    fn webhooks(hooks: Vec<Webhook>, delivery_log: Option<PathBuf>) -> Webhooks {
+        let config = WebhookConfig {
+            hooks,
+            retry: RetryConfig {
+                retries: 2,
+                initial_backoff: 1,
+                max_backoff: 1,
+            },
+            delivery_log,
+            ..WebhookConfig::default()
+        };
+        Webhooks::new(&config, "http://glazed.example/".parse().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn signed_delivery() {
+        let receiver = MockServer::start();
+        let mock = receiver
+            .mock_async(|when, then| {
+                when.method("POST")
+                    .path("/hook")
+                    .header("x-glazed-event", "run_finished")
+                    .header(
+                        "x-glazed-signature",
+                        "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8",
+                    )
+                    .body("The quick brown fox jumps over the lazy dog");
+                then.status(204);
+            })
+            .await;
+        let secret = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(secret.path(), "key\n").unwrap();
+        let hook = Webhook {
+            secret: Some(Secret::File(secret.path().into())),
+            ..hook(receiver.url("/hook"))
+        };
+        let webhooks = webhooks(vec![hook], None);
+        let record = webhooks
+            .deliver(
+                &webhooks.0.hooks[0],
+                b"The quick brown fox jumps over the lazy dog",
+                "default",
+                "run",
+            )
+            .await;
+        mock.assert();
+        assert!(record.delivered);
+        assert_eq!(record.attempts, 1);
+        assert_eq!(record.status, Some(204));
+    }
+
+    #[tokio::test]
+    async fn failed_delivery_retried() {
+        let receiver = MockServer::start();
+        let unavailable = receiver
+            .mock_async(|when, then| {
+                when.method("POST").path("/unavailable");
+                then.status(503);
+            })
+            .await;
+        let rejected = receiver
+            .mock_async(|when, then| {
+                when.method("POST").path("/rejected");
+                then.status(400);
+            })
+            .await;
+        let webhooks = webhooks(
+            vec![
+                hook(receiver.url("/unavailable")),
+                hook(receiver.url("/rejected")),
+            ],
+            None,
+        );
+        let record = webhooks
+            .deliver(&webhooks.0.hooks[0], b"{}", "default", "run")
+            .await;
+        unavailable.assert_calls(3);
+        assert!(!record.delivered);
+        assert_eq!(record.status, Some(503));
+        // Requests the receiver rejects would be rejected again
+        let record = webhooks
+            .deliver(&webhooks.0.hooks[1], b"{}", "default", "run")
+            .await;
+        rejected.assert_calls(1);
+        assert_eq!(record.attempts, 1);
+        assert_eq!(
+            record.error.as_deref(),
+            Some("Receiver responded with 400 Bad Request")
+        );
+    }
+
+    #[tokio::test]
+    async fn finished_run_sent_to_matching_hooks() {
+        let tiled = MockServer::start();
+        let run: node::Metadata = read_json("resources/metadata_run.json");
+        let run = run.into_data();
+        let id = run.id.clone();
+        tiled
+            .mock_async(|when, then| {
+                when.method("GET").path(format!("/api/v1/search/{id}"));
+                then.status(200)
+                    .body_from_file("resources/search_run_container.json");
+            })
+            .await;
+        let array: serde_json::Value = read_json("resources/metadata_array.json");
+        tiled
+            .mock_async(|when, then| {
+                when.method("GET")
+                    .path(format!("/api/v1/search/{id}/primary"))
+                    .query_param("include_data_sources", "true");
+                then.status(200).json_body(
+                    json!({"data": [array["data"]], "error": null, "links": null, "meta": {}}),
+                );
+            })
+            .await;
+        let receiver = MockServer::start();
+        let matching = receiver
+            .mock_async(|when, then| {
+                when.method("POST")
+                    .path("/matching")
+                    .json_body_includes(
+                        json!({"event": "run_finished", "id": id, "status": "finished"})
+                            .to_string(),
+                    )
+                    .json_body_includes(
+                        json!({"assets": [{
+                            "stream": "primary",
+                            "detector": "det",
+                            "file": "file://localhost/home/abi/data/adsim-2-det.h5",
+                            "download": format!("http://glazed.example/asset/{id}/primary/det/18"),
+                        }]})
+                        .to_string(),
+                    );
+                then.status(200);
+            })
+            .await;
+        let other = receiver
+            .mock_async(|when, then| {
+                when.method("POST").path("/other");
+                then.status(200);
+            })
+            .await;
+        let log = tempfile::tempdir().unwrap();
+        let log_path = log.path().join("deliveries.jsonl");
+        let webhooks = webhooks(
+            vec![
+                Webhook {
+                    instrument: Some("adsim".into()),
+                    plan_name: Some("spec_scan".into()),
+                    ..hook(receiver.url("/matching"))
+                },
+                Webhook {
+                    instrument: Some("i22".into()),
+                    ..hook(receiver.url("/other"))
+                },
+            ],
+            Some(log_path.clone()),
+        );
+        let backends = Backends::single(TiledClient::for_mock_server(&tiled));
+        webhooks
+            .clone()
+            .notify(backends.default_backend().clone(), run)
+            .await;
+        matching.assert();
+        other.assert_calls(0);
+        let records = std::fs::read_to_string(log_path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<DeliveryRecord>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(records.len(), 1);
+        assert!(records[0].delivered);
+        assert_eq!(records[0].run, id);
+    }
+
+    #[tokio::test]
+    async fn undelivered_runs_retried_after_restart() {
+        let tiled = MockServer::start();
+        let run: serde_json::Value = read_json("resources/metadata_run.json");
+        let id = run["data"]["id"].as_str().unwrap().to_string();
+        tiled
+            .mock_async(|when, then| {
+                when.method("GET").path(format!("/api/v1/metadata/{id}"));
+                then.status(200).json_body(run);
+            })
+            .await;
+        let receiver = MockServer::start();
+        let failed = receiver
+            .mock_async(|when, then| {
+                when.method("POST").path("/failed");
+                then.status(200);
+            })
+            .await;
+        let delivered = receiver
+            .mock_async(|when, then| {
+                when.method("POST").path("/delivered");
+                then.status(200);
+            })
+            .await;
+        let record = |url: String, delivered: bool| DeliveryRecord {
+            timestamp: 1_700_000_000_000,
+            delivery: "delivery".into(),
+            url: url.parse().unwrap(),
+            backend: "default".into(),
+            run: id.clone(),
+            attempts: 1,
+            status: Some(if delivered { 200 } else { 503 }),
+            delivered,
+            error: None,
+        };
+        let log = tempfile::tempdir().unwrap();
+        let log_path = log.path().join("deliveries.jsonl");
+        let lines = [
+            serde_json::to_string(&record(receiver.url("/failed"), false)).unwrap(),
+            serde_json::to_string(&record(receiver.url("/delivered"), true)).unwrap(),
+            "not a record".into(),
+        ];
+        std::fs::write(&log_path, lines.join("\n") + "\n").unwrap();
+        let webhooks = webhooks(
+            vec![
+                hook(receiver.url("/failed")),
+                hook(receiver.url("/delivered")),
+            ],
+            Some(log_path.clone()),
+        );
+        assert_eq!(webhooks.0.history.last, Some(1_700_000_000_000));
+        let backends = Backends::single(TiledClient::for_mock_server(&tiled));
+        webhooks.retry_undelivered(backends.default_backend()).await;
+        // Deliveries are made in the background
+        let mut written = String::new();
+        for _ in 0..100 {
+            written = std::fs::read_to_string(&log_path).unwrap();
+            if written.lines().count() > lines.len() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        failed.assert();
+        delivered.assert_calls(0);
+        let retried: DeliveryRecord =
+            serde_json::from_str(written.lines().last().unwrap()).unwrap();
+        assert!(retried.delivered);
+        assert!(retried.timestamp <= audit::unix_millis());
+    }
+}